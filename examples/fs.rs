@@ -2,7 +2,7 @@ use std::fs;
 use std::path::Path;
 
 use rusqlite::{Connection, OpenFlags};
-use sqlite_vfs::{register, OpenAccess, OpenOptions, Vfs};
+use sqlite_vfs::{register, OpenAccess, OpenOptions, Vfs, VfsEntries, VfsEntry};
 
 struct FsVfs;
 
@@ -32,10 +32,46 @@ impl Vfs for FsVfs {
     fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
         Ok(path.is_file())
     }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        // list a directory, or the files in the parent directory starting with the file name
+        let (dir, name) = if prefix.is_dir() {
+            (prefix, String::new())
+        } else {
+            let dir = prefix.parent().filter(|p| !p.as_os_str().is_empty());
+            let name = prefix.file_name().unwrap_or_default();
+            (
+                dir.unwrap_or(Path::new(".")),
+                name.to_string_lossy().into_owned(),
+            )
+        };
+        let entries = fs::read_dir(dir)?.filter_map(move |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            if !entry.file_name().to_string_lossy().starts_with(&name) {
+                return None;
+            }
+            match entry.metadata() {
+                Ok(meta) if meta.is_file() => Some(Ok(VfsEntry {
+                    path: entry.path(),
+                    size: meta.len(),
+                })),
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            }
+        });
+        Ok(Box::new(entries))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        fs::rename(from, to)
+    }
 }
 
 fn main() {
-    register("test", FsVfs).unwrap();
+    let _vfs = register("test", FsVfs).unwrap();
 
     let conn = Connection::open_with_flags_and_vfs(
         "db/main.db3",
@@ -0,0 +1,231 @@
+//! Hammer a VFS with a mixed workload for a long time, to qualify a storage integration before
+//! using it in production.
+//!
+//! Usage: `cargo run --release --example soak -- [duration in seconds] [database directory]`
+//!
+//! Every few seconds, the database is checked with `PRAGMA integrity_check` and the memory usage
+//! and the number of open file descriptors of the process are reported (on Linux). The soak test
+//! fails if the database gets corrupted or if the number of open file descriptors keeps growing.
+//!
+//! To soak your own VFS, replace [FsVfs] with it.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use rusqlite::{params, Connection, OpenFlags};
+use sqlite_vfs::{register, OpenAccess, OpenOptions, Vfs};
+
+const VFS_NAME: &str = "soak";
+
+/// How often to check the integrity of the database and report resource usage.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many operations to run on a connection before it is re-opened.
+const OPS_PER_CONNECTION: usize = 5_000;
+
+/// How many more file descriptors than at the start are tolerated before failing.
+const FD_LEEWAY: usize = 16;
+
+struct FsVfs;
+
+impl Vfs for FsVfs {
+    type File = fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let mut o = fs::OpenOptions::new();
+        o.read(true).write(opts.access != OpenAccess::Read);
+        match opts.access {
+            OpenAccess::Create => {
+                o.create(true);
+            }
+            OpenAccess::CreateNew => {
+                o.create_new(true);
+            }
+            _ => {}
+        }
+        let f = o.open(path)?;
+        Ok(f)
+    }
+
+    fn delete(&self, path: &std::path::Path) -> Result<(), std::io::Error> {
+        std::fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        Ok(path.is_file())
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let duration = Duration::from_secs(
+        args.next()
+            .map(|s| s.parse().expect("duration must be a number of seconds"))
+            .unwrap_or(60),
+    );
+    let dir = args.next().unwrap_or_else(|| "db".to_string());
+    fs::create_dir_all(&dir).unwrap();
+    let path = Path::new(&dir).join("soak.db3");
+    let _ = fs::remove_file(&path);
+
+    let _vfs = register(VFS_NAME, FsVfs).unwrap();
+
+    let start = Instant::now();
+    let fds_at_start = open_fds();
+    let mut last_check = Instant::now();
+    let mut ops = 0usize;
+    let mut ops_since_check = 0usize;
+    let mut conn = open(&path);
+    let mut rng = rand::thread_rng();
+
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS items (
+            id INTEGER PRIMARY KEY,
+            bucket INTEGER NOT NULL,
+            payload BLOB NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS items_bucket ON items (bucket);",
+    )
+    .unwrap();
+
+    while start.elapsed() < duration {
+        run_op(&mut conn, &mut rng);
+        ops += 1;
+        ops_since_check += 1;
+
+        if ops.is_multiple_of(OPS_PER_CONNECTION) {
+            // exercise the open/close paths of the VFS
+            conn = open(&path);
+        }
+
+        if last_check.elapsed() >= CHECK_INTERVAL {
+            integrity_check(&conn);
+
+            let fds = open_fds();
+            println!(
+                "[{:>6}s] ops={} ops/s={:.0} rss={} fds={}",
+                start.elapsed().as_secs(),
+                ops,
+                ops_since_check as f64 / last_check.elapsed().as_secs_f64(),
+                rss_bytes()
+                    .map(|n| format!("{}KiB", n / 1024))
+                    .unwrap_or_else(|| "n/a".to_string()),
+                fds.map(|n| n.to_string())
+                    .unwrap_or_else(|| "n/a".to_string()),
+            );
+            if let (Some(fds), Some(fds_at_start)) = (fds, fds_at_start) {
+                assert!(
+                    fds <= fds_at_start + FD_LEEWAY,
+                    "file descriptor leak: {} open, {} at start",
+                    fds,
+                    fds_at_start
+                );
+            }
+
+            last_check = Instant::now();
+            ops_since_check = 0;
+        }
+    }
+
+    integrity_check(&conn);
+    println!("Finished {} operations without errors", ops);
+}
+
+fn open(path: &Path) -> Connection {
+    let conn = Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        VFS_NAME,
+    )
+    .unwrap();
+    conn.busy_timeout(Duration::from_secs(5)).unwrap();
+    conn
+}
+
+fn run_op(conn: &mut Connection, rng: &mut impl Rng) {
+    let bucket = rng.gen_range(0..100i64);
+    match rng.gen_range(0..100) {
+        // single inserts
+        0..=29 => {
+            let len = rng.gen_range(10..4000);
+            conn.execute(
+                "INSERT INTO items (bucket, payload) VALUES (?1, randomblob(?2))",
+                params![bucket, len],
+            )
+            .unwrap();
+        }
+        // batched inserts in a transaction
+        30..=39 => {
+            let tx = conn.transaction().unwrap();
+            for _ in 0..rng.gen_range(1..100) {
+                tx.execute(
+                    "INSERT INTO items (bucket, payload) VALUES (?1, randomblob(?2))",
+                    params![bucket, rng.gen_range(10..400)],
+                )
+                .unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        // updates
+        40..=54 => {
+            conn.execute(
+                "UPDATE items SET payload = randomblob(?2) WHERE bucket = ?1",
+                params![bucket, rng.gen_range(10..2000)],
+            )
+            .unwrap();
+        }
+        // deletes
+        55..=64 => {
+            conn.execute("DELETE FROM items WHERE bucket = ?1", params![bucket])
+                .unwrap();
+        }
+        // rolled back changes
+        65..=69 => {
+            let mut tx = conn.transaction().unwrap();
+            {
+                let sp = tx.savepoint().unwrap();
+                sp.execute("DELETE FROM items", []).unwrap();
+            }
+            tx.execute("DELETE FROM items WHERE bucket = ?1", params![bucket])
+                .unwrap();
+            tx.rollback().unwrap();
+        }
+        // maintenance
+        70 => {
+            conn.execute_batch("VACUUM").unwrap();
+        }
+        // reads
+        _ => {
+            let _: (i64, i64) = conn
+                .query_row(
+                    "SELECT COUNT(*), COALESCE(SUM(length(payload)), 0) FROM items WHERE bucket = ?1",
+                    params![bucket],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+        }
+    }
+}
+
+fn integrity_check(conn: &Connection) {
+    let result: String = conn
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(result, "ok", "integrity check failed");
+}
+
+/// The number of open file descriptors of this process (only supported on Linux).
+fn open_fds() -> Option<usize> {
+    Some(fs::read_dir("/proc/self/fd").ok()?.count())
+}
+
+/// The resident set size of this process (only supported on Linux).
+fn rss_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
@@ -0,0 +1,147 @@
+//! A simple in-memory model backend and helpers to call into the registered VFS the same way
+//! SQLite does.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once};
+
+use libsqlite3_sys as ffi;
+use sqlite_vfs::{register, File, OpenAccess, OpenOptions, SyncOptions, Vfs};
+
+pub const VFS_NAME: &str = "fuzz";
+
+#[derive(Default)]
+pub struct ModelVfs {
+    files: Mutex<HashMap<PathBuf, Arc<Mutex<Vec<u8>>>>>,
+}
+
+pub struct ModelFile {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Vfs for ModelVfs {
+    type File = ModelFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let data = match (files.get(path), opts.access) {
+            (Some(_), OpenAccess::CreateNew) => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"))
+            }
+            (Some(data), _) => data.clone(),
+            (None, OpenAccess::Create | OpenAccess::CreateNew) => files
+                .entry(path.to_path_buf())
+                .or_default()
+                .clone(),
+            (None, _) => return Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+        };
+        Ok(ModelFile { data })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), io::Error> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, io::Error> {
+        Ok(self.files.lock().unwrap().contains_key(path))
+    }
+}
+
+impl File for ModelFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let start = to_usize(offset)?;
+        let end = start.checked_add(buf.len()).ok_or_else(too_large)?;
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self, _options: SyncOptions) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn file_size(&self) -> Result<u64, io::Error> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), io::Error> {
+        self.data.lock().unwrap().resize(to_usize(size)?, 0);
+        Ok(())
+    }
+}
+
+/// Convert an offset or size to an index into memory, which is smaller on 32-bit targets.
+fn to_usize(n: u64) -> io::Result<usize> {
+    usize::try_from(n).map_err(|_| too_large())
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::OutOfMemory, "file too large for memory")
+}
+
+/// Register the [ModelVfs] (once) and return the `sqlite3_vfs` SQLite would call into.
+pub fn vfs() -> &'static mut ffi::sqlite3_vfs {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        // stays registered for the rest of the process
+        std::mem::forget(register(VFS_NAME, ModelVfs::default()).unwrap());
+    });
+
+    let name = CString::new(VFS_NAME).unwrap();
+    unsafe { ffi::sqlite3_vfs_find(name.as_ptr()).as_mut().unwrap() }
+}
+
+/// Turn arbitrary bytes into a path the way SQLite would hand it to the VFS (nul-terminated, cut
+/// at the first interior nul byte).
+pub fn c_path(bytes: &[u8]) -> CString {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    CString::new(&bytes[..end]).unwrap()
+}
+
+/// Memory for a `sqlite3_file` of the size requested by the VFS.
+pub struct FileBuf(Vec<u64>);
+
+impl FileBuf {
+    pub fn new(vfs: &ffi::sqlite3_vfs) -> Self {
+        FileBuf(vec![0; (vfs.szOsFile as usize + 7) / 8])
+    }
+
+    pub fn as_ptr(&mut self) -> *mut ffi::sqlite3_file {
+        self.0.as_mut_ptr() as *mut ffi::sqlite3_file
+    }
+
+    pub fn methods(&mut self) -> &ffi::sqlite3_io_methods {
+        unsafe { (*self.as_ptr()).pMethods.as_ref().unwrap() }
+    }
+}
+
+pub fn open(vfs: &mut ffi::sqlite3_vfs, path: &CString, flags: c_int, file: &mut FileBuf) -> c_int {
+    let mut out_flags = 0;
+    unsafe { (vfs.xOpen.unwrap())(vfs, path.as_ptr(), file.as_ptr(), flags, &mut out_flags) }
+}
+
+pub fn close(file: &mut FileBuf) -> c_int {
+    let close = file.methods().xClose.unwrap();
+    unsafe { close(file.as_ptr()) }
+}
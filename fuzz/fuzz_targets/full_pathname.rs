@@ -0,0 +1,23 @@
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use libsqlite3_sys as ffi;
+
+fuzz_target!(|path: &[u8]| {
+    let vfs = common::vfs();
+    let path = common::c_path(path);
+
+    // SQLite always provides a buffer of `mxPathname + 1` bytes.
+    let n_out = vfs.mxPathname + 1;
+    let mut out = vec![0xffu8; n_out as usize];
+    let rc = unsafe {
+        (vfs.xFullPathname.unwrap())(vfs, path.as_ptr(), n_out, out.as_mut_ptr() as *mut _)
+    };
+
+    if rc == ffi::SQLITE_OK {
+        let len = out.iter().position(|b| *b == 0).expect("output is not nul-terminated");
+        assert!(len < n_out as usize);
+    }
+});
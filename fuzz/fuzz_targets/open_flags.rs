@@ -0,0 +1,35 @@
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use libsqlite3_sys as ffi;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    path: Vec<u8>,
+    flags: i32,
+    access_flags: i32,
+    delete: bool,
+}
+
+fuzz_target!(|input: Input| {
+    let vfs = common::vfs();
+    let path = common::c_path(&input.path);
+
+    let mut file = common::FileBuf::new(vfs);
+    if common::open(vfs, &path, input.flags, &mut file) == ffi::SQLITE_OK {
+        assert_eq!(common::close(&mut file), ffi::SQLITE_OK);
+    }
+
+    let mut res_out = 0;
+    unsafe {
+        (vfs.xAccess.unwrap())(vfs, path.as_ptr(), input.access_flags, &mut res_out);
+    }
+
+    if input.delete {
+        let rc = unsafe { (vfs.xDelete.unwrap())(vfs, path.as_ptr(), 0) };
+        assert!(rc == ffi::SQLITE_OK || rc == ffi::SQLITE_IOERR_DELETE);
+    }
+});
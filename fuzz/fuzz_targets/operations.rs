@@ -0,0 +1,134 @@
+#![no_main]
+
+mod common;
+
+use std::ffi::c_void;
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use libsqlite3_sys as ffi;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Write { offset: u16, data: Vec<u8> },
+    Read { offset: u16, len: u16 },
+    Truncate { size: u16 },
+    Sync { flags: i32 },
+    FileSize,
+    FileControl { op: i32 },
+    SectorSize,
+    DeviceCharacteristics,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    path: Vec<u8>,
+    ops: Vec<Op>,
+}
+
+fuzz_target!(|input: Input| {
+    let vfs = common::vfs();
+    let path = common::c_path(&input.path);
+
+    let mut file = common::FileBuf::new(vfs);
+    let flags = ffi::SQLITE_OPEN_MAIN_DB
+        | ffi::SQLITE_OPEN_READWRITE
+        | ffi::SQLITE_OPEN_CREATE
+        | ffi::SQLITE_OPEN_DELETEONCLOSE;
+    if common::open(vfs, &path, flags, &mut file) != ffi::SQLITE_OK {
+        return;
+    }
+    let methods = *file.methods();
+
+    // The expected content of the file.
+    let mut model = Vec::new();
+    unsafe {
+        (methods.xTruncate.unwrap())(file.as_ptr(), 0);
+    }
+
+    for op in input.ops {
+        match op {
+            Op::Write { offset, data } => {
+                let offset = offset as usize;
+                let rc = unsafe {
+                    (methods.xWrite.unwrap())(
+                        file.as_ptr(),
+                        data.as_ptr() as *const c_void,
+                        data.len() as i32,
+                        offset as i64,
+                    )
+                };
+                assert_eq!(rc, ffi::SQLITE_OK);
+                if !data.is_empty() {
+                    if model.len() < offset + data.len() {
+                        model.resize(offset + data.len(), 0);
+                    }
+                    model[offset..offset + data.len()].copy_from_slice(&data);
+                }
+            }
+            Op::Read { offset, len } => {
+                let (offset, len) = (offset as usize, len as usize);
+                let mut buf = vec![0u8; len];
+                let rc = unsafe {
+                    (methods.xRead.unwrap())(
+                        file.as_ptr(),
+                        buf.as_mut_ptr() as *mut c_void,
+                        len as i32,
+                        offset as i64,
+                    )
+                };
+                if len == 0 {
+                    assert_eq!(rc, ffi::SQLITE_OK);
+                } else if offset + len <= model.len() {
+                    assert_eq!(rc, ffi::SQLITE_OK);
+                    assert_eq!(buf, model[offset..offset + len]);
+                } else {
+                    assert_eq!(rc, ffi::SQLITE_IOERR_SHORT_READ);
+                }
+            }
+            Op::Truncate { size } => {
+                let rc = unsafe { (methods.xTruncate.unwrap())(file.as_ptr(), size as i64) };
+                assert_eq!(rc, ffi::SQLITE_OK);
+                model.resize(size as usize, 0);
+            }
+            Op::Sync { flags } => {
+                let rc = unsafe { (methods.xSync.unwrap())(file.as_ptr(), flags) };
+                assert_eq!(rc, ffi::SQLITE_OK);
+            }
+            Op::FileSize => {
+                let mut size = 0;
+                let rc = unsafe { (methods.xFileSize.unwrap())(file.as_ptr(), &mut size) };
+                assert_eq!(rc, ffi::SQLITE_OK);
+                assert_eq!(size as usize, model.len());
+            }
+            Op::FileControl { op } => {
+                // Skip opcodes whose argument has to be prepared by the caller or whose result has
+                // to be freed by the caller.
+                if op != ffi::SQLITE_FCNTL_LOCKSTATE
+                    && op != ffi::SQLITE_FCNTL_SIZE_HINT
+                    && op != ffi::SQLITE_FCNTL_CHUNK_SIZE
+                    && op != ffi::SQLITE_FCNTL_PRAGMA
+                    && op != ffi::SQLITE_FCNTL_VFSNAME
+                    && op != ffi::SQLITE_FCNTL_TEMPFILENAME
+                {
+                    let mut arg = [0u64; 4];
+                    unsafe {
+                        (methods.xFileControl.unwrap())(
+                            file.as_ptr(),
+                            op,
+                            arg.as_mut_ptr() as *mut c_void,
+                        );
+                    }
+                }
+            }
+            Op::SectorSize => unsafe {
+                (methods.xSectorSize.unwrap())(file.as_ptr());
+            },
+            Op::DeviceCharacteristics => unsafe {
+                (methods.xDeviceCharacteristics.unwrap())(file.as_ptr());
+            },
+        }
+    }
+
+    assert_eq!(common::close(&mut file), ffi::SQLITE_OK);
+});
@@ -0,0 +1,538 @@
+//! Open databases directly out of a zip or tar archive, without extracting them.
+//!
+//! An [ArchiveVfs] reads the index of an archive (stored in any [Vfs]) once, and then serves each
+//! member of the archive as a read-only file, at the path of the member in the archive (e.g.
+//! `data/lookup.db`). Reads are forwarded to the range of the archive that holds the member, so
+//! databases can be shipped inside an application bundle and queried in place:
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! # use sqlite_vfs::{OpenOptions, Vfs};
+//! # struct FsVfs;
+//! # impl Vfs for FsVfs {
+//! #     type File = std::fs::File;
+//! #     fn open(&self, path: &Path, _: OpenOptions) -> Result<Self::File, std::io::Error> {
+//! #         std::fs::File::open(path)
+//! #     }
+//! #     fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+//! #         std::fs::remove_file(path)
+//! #     }
+//! #     fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+//! #         Ok(path.is_file())
+//! #     }
+//! # }
+//! use sqlite_vfs::archive::ArchiveVfs;
+//!
+//! let vfs = ArchiveVfs::new(FsVfs, "assets/bundle.zip").unwrap();
+//! let _vfs = sqlite_vfs::register("bundle", vfs).unwrap();
+//! // then open `data/lookup.db` with `vfs=bundle` and `SQLITE_OPEN_READONLY`
+//! ```
+//!
+//! Members of zip archives have to be stored without compression (e.g. with `zip -0`); opening a
+//! compressed member fails with [ErrorKind::Unsupported]. Tar archives must not be compressed as a
+//! whole. Both the ustar format and the GNU and pax extensions for long names and large members
+//! are understood.
+//!
+//! Since the members cannot be written, SQLite treats them as immutable and neither locks them nor
+//! looks for journals. Databases in WAL mode have to be checkpointed before they are archived.
+//! Temporary files (e.g. of large sorts or temporary tables) are kept in memory.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::mem::{MemFile, MemVfs};
+use crate::{
+    DeviceCharacteristics, File, OpenAccess, OpenKind, OpenOptions, SyncOptions, Vfs, VfsEntries,
+    VfsEntry, VfsMetadata,
+};
+
+/// The size of the blocks of a tar archive.
+const TAR_BLOCK: u64 = 512;
+
+/// The size of the end of central directory record of a zip archive, without its comment.
+const ZIP_EOCD_LEN: usize = 22;
+
+/// A [Vfs] that serves the members of an archive as read-only files.
+pub struct ArchiveVfs<V> {
+    vfs: V,
+    archive: PathBuf,
+    members: HashMap<String, Member>,
+    temp: MemVfs,
+}
+
+/// A file opened by [ArchiveVfs].
+pub struct ArchiveFile<F>(Inner<F>);
+
+enum Inner<F> {
+    /// A member of the archive.
+    Member { file: F, offset: u64, len: u64 },
+    /// A temporary file.
+    Temp(MemFile),
+}
+
+/// Where a member is stored in the archive.
+#[derive(Debug, Clone, Copy)]
+struct Member {
+    offset: u64,
+    len: u64,
+    /// Whether the member is stored uncompressed (and can thus be opened).
+    stored: bool,
+}
+
+impl<V: Vfs> ArchiveVfs<V> {
+    /// Serve the members of the zip or tar archive at `archive`, read through `vfs`. Fails if the
+    /// archive cannot be read or is neither a zip nor a tar archive.
+    pub fn new(vfs: V, archive: impl Into<PathBuf>) -> Result<Self, std::io::Error> {
+        let archive = archive.into();
+        let mut file = vfs.open(
+            &archive,
+            OpenOptions {
+                kind: OpenKind::MainDb,
+                access: OpenAccess::Read,
+                delete_on_close: false,
+            },
+        )?;
+        let members = match read_zip(&mut file)? {
+            Some(members) => members,
+            None => read_tar(&mut file)?,
+        };
+        log::trace!(
+            "read {} members of archive {}",
+            members.len(),
+            archive.display()
+        );
+        Ok(ArchiveVfs {
+            vfs,
+            archive,
+            members,
+            temp: MemVfs::new(),
+        })
+    }
+
+    fn member(&self, path: &Path) -> Option<Member> {
+        let name = path.to_string_lossy();
+        self.members.get(name.trim_start_matches('/')).copied()
+    }
+}
+
+impl<V: Vfs> Vfs for ArchiveVfs<V> {
+    type File = ArchiveFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let Some(member) = self.member(path) else {
+            let temporary = opts.delete_on_close
+                || matches!(
+                    opts.kind,
+                    OpenKind::TempDb
+                        | OpenKind::TempJournal
+                        | OpenKind::TransientDb
+                        | OpenKind::SubJournal
+                );
+            return match opts.access {
+                _ if temporary => Ok(ArchiveFile(Inner::Temp(self.temp.open(path, opts)?))),
+                OpenAccess::Read | OpenAccess::Write => Err(ErrorKind::NotFound.into()),
+                OpenAccess::Create | OpenAccess::CreateNew => Err(read_only()),
+            };
+        };
+        if opts.access != OpenAccess::Read {
+            return Err(read_only());
+        }
+        if !member.stored {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "{} is compressed in the archive, only stored members can be opened",
+                    path.display()
+                ),
+            ));
+        }
+        let file = self.vfs.open(
+            &self.archive,
+            OpenOptions {
+                kind: opts.kind,
+                access: OpenAccess::Read,
+                delete_on_close: false,
+            },
+        )?;
+        Ok(ArchiveFile(Inner::Member {
+            file,
+            offset: member.offset,
+            len: member.len,
+        }))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        match self.member(path) {
+            Some(_) => Err(read_only()),
+            None => self.temp.delete(path),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        Ok(self.member(path).is_some() || self.temp.exists(path)?)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        match self.member(path) {
+            Some(_) => Ok(!write),
+            None => self.temp.exists(path),
+        }
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        let prefix = prefix.to_string_lossy();
+        let prefix = prefix.trim_start_matches('/');
+        let mut entries = self
+            .members
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, member)| VfsEntry {
+                path: PathBuf::from(name),
+                size: member.len,
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        match self.member(from) {
+            Some(_) => Err(read_only()),
+            None => self.temp.rename(from, to),
+        }
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+}
+
+fn read_only() -> std::io::Error {
+    std::io::Error::new(ErrorKind::ReadOnlyFilesystem, "archive is read-only")
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Read exactly `buf.len()` bytes at `offset`.
+fn read_exact_at(file: &mut impl File, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+    if file.read_at(buf, offset)? < buf.len() {
+        return Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "archive is truncated",
+        ));
+    }
+    Ok(())
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Read the central directory of a zip archive. Returns `None` if `file` is no zip archive.
+fn read_zip(file: &mut impl File) -> Result<Option<HashMap<String, Member>>, std::io::Error> {
+    // the end of central directory record is followed by a comment of up to 64KiB
+    let size = file.file_size()?;
+    let tail_len = size.min((ZIP_EOCD_LEN + usize::from(u16::MAX)) as u64);
+    let tail_start = size - tail_len;
+    let mut tail = vec![0; tail_len as usize];
+    read_exact_at(file, &mut tail, tail_start)?;
+    let Some(eocd) = (0..tail.len().saturating_sub(ZIP_EOCD_LEN - 1))
+        .rev()
+        .find(|&i| tail[i..i + 4] == *b"PK\x05\x06")
+    else {
+        return Ok(None);
+    };
+
+    let mut count = u64::from(u16_at(&tail, eocd + 10));
+    let mut dir_len = u64::from(u32_at(&tail, eocd + 12));
+    let mut dir_offset = u64::from(u32_at(&tail, eocd + 16));
+    if count == u64::from(u16::MAX)
+        || dir_len == u64::from(u32::MAX)
+        || dir_offset == u64::from(u32::MAX)
+    {
+        // zip64: the record is preceded by a locator of the zip64 end of central directory record
+        let locator = (tail_start + eocd as u64)
+            .checked_sub(20)
+            .ok_or_else(|| invalid("zip64 end of central directory locator is missing"))?;
+        let mut buf = [0; 56];
+        read_exact_at(file, &mut buf[..20], locator)?;
+        if buf[..4] != *b"PK\x06\x07" {
+            return Err(invalid("zip64 end of central directory locator is missing"));
+        }
+        let offset = u64_at(&buf, 8);
+        read_exact_at(file, &mut buf, offset)?;
+        if buf[..4] != *b"PK\x06\x06" {
+            return Err(invalid("zip64 end of central directory record is missing"));
+        }
+        count = u64_at(&buf, 32);
+        dir_len = u64_at(&buf, 40);
+        dir_offset = u64_at(&buf, 48);
+    }
+
+    let mut dir = vec![0; usize::try_from(dir_len).map_err(|_| invalid("zip is too large"))?];
+    read_exact_at(file, &mut dir, dir_offset)?;
+    let mut members = HashMap::new();
+    let mut pos = 0;
+    for _ in 0..count {
+        if dir.len() < pos + 46 || dir[pos..pos + 4] != *b"PK\x01\x02" {
+            return Err(invalid("zip central directory is corrupt"));
+        }
+        let header = &dir[pos..];
+        let flags = u16_at(header, 8);
+        let method = u16_at(header, 10);
+        let mut len = u64::from(u32_at(header, 24));
+        let mut compressed_len = u64::from(u32_at(header, 20));
+        let mut local = u64::from(u32_at(header, 42));
+        let name_len = usize::from(u16_at(header, 28));
+        let extra_len = usize::from(u16_at(header, 30));
+        let comment_len = usize::from(u16_at(header, 32));
+        let end = 46 + name_len + extra_len;
+        if header.len() < end + comment_len {
+            return Err(invalid("zip central directory is corrupt"));
+        }
+        let name = String::from_utf8_lossy(&header[46..46 + name_len]).into_owned();
+
+        // sizes and offsets that do not fit 32 bits are stored in the zip64 extra field
+        let mut extra = &header[46 + name_len..end];
+        while extra.len() >= 4 {
+            let (id, size) = (u16_at(extra, 0), usize::from(u16_at(extra, 2)));
+            let data = extra.get(4..4 + size).unwrap_or_default();
+            if id == 1 {
+                let mut values = data.chunks_exact(8).map(|v| u64_at(v, 0));
+                for value in [&mut len, &mut compressed_len, &mut local] {
+                    if *value == u64::from(u32::MAX) {
+                        *value = values.next().unwrap_or(*value);
+                    }
+                }
+            }
+            extra = extra.get(4 + size..).unwrap_or_default();
+        }
+        pos += end + comment_len;
+
+        if name.ends_with('/') {
+            continue;
+        }
+        // the data follows the local header, whose name and extra field may differ in length
+        let stored = method == 0 && flags & 1 == 0 && compressed_len == len;
+        let mut offset = 0;
+        if stored {
+            let mut buf = [0; 30];
+            read_exact_at(file, &mut buf, local)?;
+            if buf[..4] != *b"PK\x03\x04" {
+                return Err(invalid("zip local header is missing"));
+            }
+            offset = local + 30 + u64::from(u16_at(&buf, 26)) + u64::from(u16_at(&buf, 28));
+        }
+        members.insert(
+            name,
+            Member {
+                offset,
+                len,
+                stored,
+            },
+        );
+    }
+    Ok(Some(members))
+}
+
+/// Parse a numeric field of a tar header: octal digits, or a big-endian number if the high bit of
+/// the first byte is set (a GNU extension for large members).
+fn tar_number(field: &[u8]) -> Result<u64, std::io::Error> {
+    if field[0] & 0x80 != 0 {
+        let bytes = &field[field.len() - 8..];
+        return Ok(u64::from_be_bytes(bytes.try_into().unwrap()));
+    }
+    let digits = std::str::from_utf8(field)
+        .map_err(|_| invalid("tar header is corrupt"))?
+        .trim_matches(|c: char| c == '\0' || c == ' ');
+    match digits {
+        "" => Ok(0),
+        digits => u64::from_str_radix(digits, 8).map_err(|_| invalid("tar header is corrupt")),
+    }
+}
+
+/// A NUL-terminated string of a tar header.
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Read the headers of an uncompressed tar archive.
+fn read_tar(file: &mut impl File) -> Result<HashMap<String, Member>, std::io::Error> {
+    let size = file.file_size()?;
+    let mut members = HashMap::new();
+    let mut offset = 0;
+    // the name (and size) of the next member, set by a GNU long name or a pax header
+    let mut next_name = None;
+    let mut next_len = None;
+    let mut header = [0; TAR_BLOCK as usize];
+    while offset + TAR_BLOCK <= size {
+        read_exact_at(file, &mut header, offset)?;
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let checksum = header
+            .iter()
+            .enumerate()
+            .map(|(i, b)| match i {
+                148..=155 => u64::from(b' '),
+                _ => u64::from(*b),
+            })
+            .sum::<u64>();
+        if tar_number(&header[148..156]).ok() != Some(checksum) {
+            return Err(invalid(match offset {
+                0 => "archive is neither a zip nor an uncompressed tar archive",
+                _ => "tar header is corrupt",
+            }));
+        }
+
+        let data = offset + TAR_BLOCK;
+        let len = next_len.take().unwrap_or(tar_number(&header[124..136])?);
+        offset = data + len.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+        match header[156] {
+            // a GNU long name of the next member
+            b'L' => {
+                let mut buf = vec![0; len as usize];
+                read_exact_at(file, &mut buf, data)?;
+                next_name = Some(tar_string(&buf));
+            }
+            // pax extended attributes of the next member, as `<length> <key>=<value>\n` records
+            b'x' => {
+                let mut buf = vec![0; len as usize];
+                read_exact_at(file, &mut buf, data)?;
+                for record in String::from_utf8_lossy(&buf).split_terminator('\n') {
+                    let Some((_, pair)) = record.split_once(' ') else {
+                        continue;
+                    };
+                    match pair.split_once('=') {
+                        Some(("path", path)) => next_name = Some(path.to_string()),
+                        Some(("size", size)) => next_len = size.parse().ok(),
+                        _ => {}
+                    }
+                }
+            }
+            // regular and contiguous files
+            b'0' | b'\0' | b'7' => {
+                let name = next_name.take().unwrap_or_else(|| {
+                    let name = tar_string(&header[..100]);
+                    let prefix = tar_string(&header[345..500]);
+                    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                        format!("{}/{}", prefix, name)
+                    } else {
+                        name
+                    }
+                });
+                let name = name.trim_start_matches("./").to_string();
+                members.insert(
+                    name,
+                    Member {
+                        offset: data,
+                        len,
+                        stored: true,
+                    },
+                );
+            }
+            _ => next_name = None,
+        }
+    }
+    Ok(members)
+}
+
+impl<F: File> File for ArchiveFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        match &mut self.0 {
+            Inner::Member {
+                file,
+                offset: start,
+                len,
+            } => {
+                let n = (buf.len() as u64).min(len.saturating_sub(offset)) as usize;
+                file.read_at(&mut buf[..n], *start + offset)
+            }
+            Inner::Temp(file) => file.read_at(buf, offset),
+        }
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.0 {
+            Inner::Member { .. } => Err(read_only()),
+            Inner::Temp(file) => file.write_all_at(buf, offset),
+        }
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        match &mut self.0 {
+            Inner::Member { .. } => Ok(()),
+            Inner::Temp(file) => file.sync(options),
+        }
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        match &self.0 {
+            Inner::Member { len, .. } => Ok(*len),
+            Inner::Temp(file) => file.file_size(),
+        }
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        match &mut self.0 {
+            Inner::Member { .. } => Err(read_only()),
+            Inner::Temp(file) => file.truncate(size),
+        }
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        match &self.0 {
+            Inner::Member { file, .. } => file.metadata(),
+            Inner::Temp(file) => file.metadata(),
+        }
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        match &self.0 {
+            Inner::Member { .. } => DeviceCharacteristics::empty().immutable(),
+            Inner::Temp(file) => file.device_characteristics(kind),
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        matches!(self.0, Inner::Member { .. })
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        if let Inner::Temp(file) = &mut self.0 {
+            file.set_persist_wal(persist)
+        }
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        match &self.0 {
+            Inner::Member { .. } => None,
+            Inner::Temp(file) => file.persist_wal(),
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! Register backends with an async API (e.g. object stores or remote services) as a [Vfs].
+//!
+//! SQLite calls into a VFS synchronously, so the futures of an [AsyncVfs] and its [AsyncFile]s
+//! have to be driven to completion on the thread SQLite calls from. A [BlockingVfs] does that with
+//! a [Bridge]: [CurrentThread] polls the futures on the calling thread itself, which works for
+//! futures that do not depend on a particular runtime. Futures that need a runtime (e.g. tokio's
+//! IO types) need a [Bridge] that blocks on that runtime instead, like
+//! `tokio::runtime::Handle::block_on` (which must not be called from within the runtime, so the
+//! connections have to be used outside of it, e.g. in `spawn_blocking`).
+//!
+//! Only the required methods of [Vfs] and the locking methods of [File] have async counterparts.
+//! All other methods use their default implementation.
+//!
+//! Operations that are still in flight (e.g. a long read from a remote service) can be cancelled
+//! with a [CancellationToken], so they fail right away instead of blocking the connection until
+//! they complete (e.g. when a statement is interrupted or a connection is closed). Each
+//! [BlockingVfs] has a token that cancels the operations of all of its files
+//! ([BlockingVfs::cancellation_token]), and each of its files has one that only cancels the
+//! operations of that file, which applications get through `sqlite3_file_control` (see
+//! [cancellation_token]). Cancelling an operation drops its future, so the futures of an
+//! [AsyncFile] have to leave the file in a consistent state at every `.await`. The cancelled
+//! operation fails with an I/O error that wraps [Cancelled].
+
+use std::ffi::CStr;
+use std::future::{poll_fn, Future};
+use std::path::Path;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use libsqlite3_sys as ffi;
+
+use crate::{File, FileControl, LockKind, OpenOptions, SyncOptions, Vfs};
+
+/// The `sqlite3_file_control` opcode with which applications get the [CancellationToken] of a file
+/// opened by a [BlockingVfs] (well beyond the `SQLITE_FCNTL_*` opcodes of SQLite). The argument is
+/// a `*mut Option<CancellationToken>`, which the file sets to its token. See [cancellation_token].
+pub const FCNTL_CANCELLATION_TOKEN: i32 = 0x4376_0001;
+
+/// The async counterpart of [Vfs].
+pub trait AsyncVfs: Send + Sync {
+    /// The file returned by [AsyncVfs::open].
+    type File: AsyncFile;
+
+    /// See [Vfs::open].
+    fn open(
+        &self,
+        path: &Path,
+        opts: OpenOptions,
+    ) -> impl Future<Output = Result<Self::File, std::io::Error>>;
+
+    /// See [Vfs::delete].
+    fn delete(&self, path: &Path) -> impl Future<Output = Result<(), std::io::Error>>;
+
+    /// See [Vfs::exists].
+    fn exists(&self, path: &Path) -> impl Future<Output = Result<bool, std::io::Error>>;
+
+    /// See [Vfs::access]. The default implementation always returns `true`.
+    fn access(
+        &self,
+        _path: &Path,
+        _write: bool,
+    ) -> impl Future<Output = Result<bool, std::io::Error>> {
+        async { Ok(true) }
+    }
+}
+
+/// The async counterpart of [File].
+///
+/// The futures of a file are dropped before they complete if the operation is cancelled with a
+/// [CancellationToken] (except for [AsyncFile::unlock]), so they must not leave the file in an
+/// inconsistent state at any `.await`.
+pub trait AsyncFile: Send {
+    /// See [File::read_at].
+    fn read_at(
+        &mut self,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> impl Future<Output = Result<usize, std::io::Error>>;
+
+    /// See [File::write_all_at].
+    fn write_all_at(
+        &mut self,
+        buf: &[u8],
+        offset: u64,
+    ) -> impl Future<Output = Result<(), std::io::Error>>;
+
+    /// See [File::sync].
+    fn sync(&mut self, options: SyncOptions) -> impl Future<Output = Result<(), std::io::Error>>;
+
+    /// See [File::file_size].
+    fn file_size(&self) -> impl Future<Output = Result<u64, std::io::Error>>;
+
+    /// See [File::truncate].
+    fn truncate(&mut self, size: u64) -> impl Future<Output = Result<(), std::io::Error>>;
+
+    /// See [File::lock]. The default implementation always acquires the lock.
+    fn lock(&mut self, _lock: LockKind) -> impl Future<Output = Result<bool, std::io::Error>> {
+        async { Ok(true) }
+    }
+
+    /// See [File::unlock]. The default implementation does nothing.
+    fn unlock(&mut self, _lock: LockKind) -> impl Future<Output = Result<(), std::io::Error>> {
+        async { Ok(()) }
+    }
+
+    /// See [File::check_reserved_lock]. The default implementation returns `false`.
+    fn check_reserved_lock(&self) -> impl Future<Output = Result<bool, std::io::Error>> {
+        async { Ok(false) }
+    }
+}
+
+/// Drives the futures of an [AsyncVfs] to completion on the thread SQLite calls from.
+pub trait Bridge: Send + Sync {
+    /// Block the current thread until `future` completed, and return its output.
+    fn block_on<F: Future>(&self, future: F) -> F::Output;
+}
+
+/// A [Bridge] that polls futures on the calling thread, parking it until they are woken.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CurrentThread;
+
+/// Wakes a thread parked by [CurrentThread].
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl Bridge for CurrentThread {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+}
+
+/// Cancels the operations of a [BlockingVfs] (or of one of its files) that are in flight.
+///
+/// Once cancelled, the token fails every operation that does not complete right away, until it is
+/// [reset](CancellationToken::reset), so the remaining operations of an interrupted statement fail
+/// fast as well.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<Cancellation>);
+
+#[derive(Debug, Default)]
+struct Cancellation {
+    cancelled: AtomicBool,
+    /// The wakers of the operations that are in flight.
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl CancellationToken {
+    /// Create a token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the operations that are in flight, and all that are started until the token is
+    /// reset.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        for waker in std::mem::take(&mut *self.wakers()) {
+            waker.wake();
+        }
+    }
+
+    /// Whether the token is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Stop cancelling operations, e.g. before the connection is used again.
+    pub fn reset(&self) {
+        self.0.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    fn wakers(&self) -> MutexGuard<'_, Vec<Waker>> {
+        self.0.wakers.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Wake `waker` when the token is cancelled.
+    fn register(&self, waker: &Waker) {
+        self.wakers().push(waker.clone());
+    }
+
+    fn deregister(&self, waker: &Waker) {
+        let mut wakers = self.wakers();
+        if let Some(index) = wakers.iter().position(|w| w.will_wake(waker)) {
+            wakers.swap_remove(index);
+        }
+    }
+}
+
+/// The error an operation that was cancelled with a [CancellationToken] fails with (wrapped in a
+/// [std::io::Error]). SQLite reports it as an I/O error of the operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::error::Error for Cancelled {}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("operation was cancelled")
+    }
+}
+
+/// The [CancellationToken] of the file of the database `schema` (e.g. `c"main"`) of the connection
+/// `db`, or `None` if it was not opened by a [BlockingVfs] (or a shim in between does not forward
+/// file controls to it).
+///
+/// # Safety
+///
+/// `db` must be a valid connection.
+pub unsafe fn cancellation_token(
+    db: *mut ffi::sqlite3,
+    schema: &CStr,
+) -> Option<CancellationToken> {
+    let mut token = None::<CancellationToken>;
+    ffi::sqlite3_file_control(
+        db,
+        schema.as_ptr(),
+        FCNTL_CANCELLATION_TOKEN,
+        &mut token as *mut Option<CancellationToken> as *mut _,
+    );
+    token
+}
+
+/// Block on `future` with `bridge` until it completes, or until one of `tokens` is cancelled while
+/// it is pending (which drops it).
+fn block_on<B: Bridge, T>(
+    bridge: &B,
+    tokens: &[&CancellationToken],
+    future: impl Future<Output = Result<T, std::io::Error>>,
+) -> Result<T, std::io::Error> {
+    let mut future = pin!(future);
+    let mut registered: Option<Waker> = None;
+    let result = bridge.block_on(poll_fn(|cx| {
+        if !registered.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+            for token in tokens {
+                if let Some(waker) = &registered {
+                    token.deregister(waker);
+                }
+                token.register(cx.waker());
+            }
+            registered = Some(cx.waker().clone());
+        }
+        match future.as_mut().poll(cx) {
+            Poll::Pending if tokens.iter().any(|token| token.is_cancelled()) => {
+                Poll::Ready(Err(std::io::Error::other(Cancelled)))
+            }
+            poll => poll,
+        }
+    }));
+    if let Some(waker) = registered {
+        for token in tokens {
+            token.deregister(&waker);
+        }
+    }
+    result
+}
+
+/// A [Vfs] that blocks on the futures of the wrapped [AsyncVfs] with a [Bridge].
+pub struct BlockingVfs<V, B = CurrentThread> {
+    vfs: V,
+    bridge: Arc<B>,
+    token: CancellationToken,
+}
+
+/// A file opened by [BlockingVfs].
+pub struct BlockingFile<F, B = CurrentThread> {
+    file: F,
+    bridge: Arc<B>,
+    /// The token of the [BlockingVfs].
+    vfs_token: CancellationToken,
+    token: CancellationToken,
+}
+
+impl<V, B> BlockingVfs<V, B> {
+    /// Wrap `vfs` and block on its futures with `bridge`.
+    pub fn new(vfs: V, bridge: B) -> Self {
+        BlockingVfs {
+            vfs,
+            bridge: Arc::new(bridge),
+            token: CancellationToken::new(),
+        }
+    }
+
+    /// The token that cancels the operations of the VFS and all of its files. Clone it before the
+    /// VFS is registered.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl<V: AsyncVfs, B: Bridge> Vfs for BlockingVfs<V, B> {
+    type File = BlockingFile<V::File, B>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let file = block_on(&*self.bridge, &[&self.token], self.vfs.open(path, opts))?;
+        Ok(BlockingFile {
+            file,
+            bridge: Arc::clone(&self.bridge),
+            vfs_token: self.token.clone(),
+            token: CancellationToken::new(),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        block_on(&*self.bridge, &[&self.token], self.vfs.delete(path))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        block_on(&*self.bridge, &[&self.token], self.vfs.exists(path))
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        block_on(&*self.bridge, &[&self.token], self.vfs.access(path, write))
+    }
+}
+
+impl<F: AsyncFile, B: Bridge> File for BlockingFile<F, B> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let tokens = [&self.vfs_token, &self.token];
+        block_on(&*self.bridge, &tokens, self.file.read_at(buf, offset))
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let tokens = [&self.vfs_token, &self.token];
+        block_on(&*self.bridge, &tokens, self.file.write_all_at(buf, offset))
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        let tokens = [&self.vfs_token, &self.token];
+        block_on(&*self.bridge, &tokens, self.file.sync(options))
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        let tokens = [&self.vfs_token, &self.token];
+        block_on(&*self.bridge, &tokens, self.file.file_size())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let tokens = [&self.vfs_token, &self.token];
+        block_on(&*self.bridge, &tokens, self.file.truncate(size))
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let tokens = [&self.vfs_token, &self.token];
+        block_on(&*self.bridge, &tokens, self.file.lock(lock))
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        // never cancelled, so the locks of an interrupted connection are released
+        self.bridge.block_on(self.file.unlock(lock))
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        let tokens = [&self.vfs_token, &self.token];
+        block_on(&*self.bridge, &tokens, self.file.check_reserved_lock())
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        match op {
+            FileControl::Raw {
+                op: FCNTL_CANCELLATION_TOKEN,
+                arg,
+            } if !arg.is_null() => {
+                unsafe { *(arg as *mut Option<CancellationToken>) = Some(self.token.clone()) };
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Bound the memory the storage layer uses across the whole process with a [MemoryBudget].
+//!
+//! A [MemoryBudget] is shared by all components that keep data in memory, no matter which VFS they
+//! belong to: the files of a [MemVfs](crate::mem::MemVfs) created with
+//! [MemVfs::with_budget](crate::mem::MemVfs::with_budget), and the chunks an `S3Vfs` caches (see
+//! `S3Options::budget`, with the `s3` feature). Cached data is evicted to make room for new data,
+//! least recently used first across all caches. Files cannot be evicted, so once the budget is
+//! used up by files, writes that grow them fail with [ErrorKind::StorageFull] (`SQLITE_FULL`), and
+//! nothing more is cached.
+//!
+//! Other components, e.g. the buffers or caches of a custom [Vfs](crate::Vfs), can draw from a
+//! budget as well: memory that cannot be evicted with [MemoryBudget::charge], and the entries of a
+//! cache that implements [Evict] with [MemoryBudget::cache].
+//!
+//! ```
+//! use sqlite_vfs::budget::MemoryBudget;
+//! use sqlite_vfs::mem::MemVfs;
+//!
+//! let budget = MemoryBudget::new(64 * 1024 * 1024);
+//! let _a = sqlite_vfs::register("mem-a", MemVfs::with_budget(budget.clone())).unwrap();
+//! let _b = sqlite_vfs::register("mem-b", MemVfs::with_budget(budget.clone())).unwrap();
+//!
+//! // ... later
+//! let stats = budget.stats();
+//! println!("{} of {} bytes used", stats.used, stats.limit);
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+/// A limit on the bytes kept in memory by the components that share it. Clones share the same
+/// budget.
+#[derive(Clone)]
+pub struct MemoryBudget(Arc<Mutex<Budget>>);
+
+/// How much of a [MemoryBudget] is used, and how often it had to evict or deny memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BudgetStats {
+    /// The maximum number of bytes.
+    pub limit: u64,
+    /// The bytes currently drawn from the budget, including those of caches.
+    pub used: u64,
+    /// The bytes currently drawn by caches, which can be evicted.
+    pub cached: u64,
+    /// The number of cached entries that were evicted to make room for others.
+    pub evictions: u64,
+    /// The bytes of the evicted entries.
+    pub evicted: u64,
+    /// The number of requests for memory that could not be granted, even after evicting all
+    /// entries that could be.
+    pub denied: u64,
+}
+
+struct Budget {
+    stats: BudgetStats,
+    /// The cached entries by the tick they were last used at, so the first one is the least
+    /// recently used.
+    entries: BTreeMap<u64, Entry>,
+    tick: u64,
+}
+
+struct Entry {
+    size: u64,
+    owner: Weak<dyn Evict>,
+    key: u64,
+}
+
+/// A cache whose entries can be evicted to make room in a [MemoryBudget].
+pub trait Evict: Send + Sync {
+    /// Drop the entry `key` (which drops its [Charge]), unless it cannot be dropped right now (e.g.
+    /// because the cache is in use). Returns whether it was dropped.
+    ///
+    /// The budget may ask for this while the cache is in use on the same thread, so the cache must
+    /// not block on its own locks, but give up instead (e.g. with `Mutex::try_lock`).
+    fn evict(&self, key: u64) -> bool;
+}
+
+/// Memory drawn from a [MemoryBudget], which is returned to it when dropped.
+#[derive(Debug)]
+pub struct Charge {
+    budget: MemoryBudget,
+    size: u64,
+    /// The tick of the entry of a cache, which can be evicted.
+    tick: Option<u64>,
+}
+
+impl MemoryBudget {
+    /// Create a budget of `limit` bytes.
+    pub fn new(limit: u64) -> Self {
+        MemoryBudget(Arc::new(Mutex::new(Budget {
+            stats: BudgetStats {
+                limit,
+                ..Default::default()
+            },
+            entries: BTreeMap::new(),
+            tick: 0,
+        })))
+    }
+
+    /// How much of the budget is used.
+    pub fn stats(&self) -> BudgetStats {
+        self.budget().stats
+    }
+
+    fn budget(&self) -> MutexGuard<'_, Budget> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Draw nothing from the budget yet, to be grown with [Charge::resize] for memory that cannot
+    /// be evicted.
+    pub fn charge(&self) -> Charge {
+        Charge {
+            budget: self.clone(),
+            size: 0,
+            tick: None,
+        }
+    }
+
+    /// Draw `size` bytes for the entry `key` of the cache `owner`, which may be evicted later, or
+    /// return `None` if there is no room for it (in which case it should not be cached).
+    pub fn cache(&self, size: u64, owner: Weak<dyn Evict>, key: u64) -> Option<Charge> {
+        self.reserve(size).ok()?;
+        let mut budget = self.budget();
+        budget.tick += 1;
+        let tick = budget.tick;
+        budget.stats.cached += size;
+        budget.entries.insert(tick, Entry { size, owner, key });
+        Some(Charge {
+            budget: self.clone(),
+            size,
+            tick: Some(tick),
+        })
+    }
+
+    /// Add `size` bytes to the used ones, evicting the least recently used entries of caches until
+    /// they fit.
+    fn reserve(&self, size: u64) -> Result<(), std::io::Error> {
+        // the ticks of the entries whose cache refused to evict them
+        let mut refused = Vec::new();
+        loop {
+            let (owner, key, tick) = {
+                let mut budget = self.budget();
+                if budget.stats.used + size <= budget.stats.limit {
+                    budget.stats.used += size;
+                    return Ok(());
+                }
+                let victim = budget
+                    .entries
+                    .iter()
+                    .find(|(tick, _)| !refused.contains(*tick))
+                    .map(|(tick, entry)| (entry.owner.clone(), entry.key, *tick));
+                match victim {
+                    Some(victim) => victim,
+                    None => {
+                        budget.stats.denied += 1;
+                        return Err(std::io::Error::new(
+                            ErrorKind::StorageFull,
+                            format!("memory budget of {} bytes is exhausted", budget.stats.limit),
+                        ));
+                    }
+                }
+            };
+
+            // the budget must not be locked while the cache is, as dropping a charge locks it
+            let size = self
+                .budget()
+                .entries
+                .get(&tick)
+                .map_or(0, |entry| entry.size);
+            match owner.upgrade() {
+                Some(owner) if owner.evict(key) => {
+                    let mut budget = self.budget();
+                    budget.stats.evictions += 1;
+                    budget.stats.evicted += size;
+                }
+                _ => refused.push(tick),
+            }
+        }
+    }
+
+    fn release(&self, size: u64, tick: Option<u64>) {
+        let mut budget = self.budget();
+        budget.stats.used -= size;
+        if let Some(entry) = tick.and_then(|tick| budget.entries.remove(&tick)) {
+            budget.stats.cached -= entry.size;
+        }
+    }
+}
+
+impl std::fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stats = self.stats();
+        f.debug_struct("MemoryBudget")
+            .field("limit", &stats.limit)
+            .field("used", &stats.used)
+            .finish()
+    }
+}
+
+impl Charge {
+    /// Grow or shrink the memory drawn to `size` bytes. Fails with [ErrorKind::StorageFull] (and
+    /// keeps the memory drawn so far) if the budget has no room for it.
+    pub fn resize(&mut self, size: u64) -> Result<(), std::io::Error> {
+        debug_assert!(self.tick.is_none(), "cached entries are not resized");
+        if size > self.size {
+            self.budget.reserve(size - self.size)?;
+        } else {
+            self.budget.release(self.size - size, None);
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    /// Mark the cached entry as the most recently used one.
+    pub fn touch(&mut self) {
+        let Some(tick) = self.tick else {
+            return;
+        };
+        let mut budget = self.budget.budget();
+        if let Some(entry) = budget.entries.remove(&tick) {
+            budget.tick += 1;
+            let tick = budget.tick;
+            budget.entries.insert(tick, entry);
+            self.tick = Some(tick);
+        }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.release(self.size, self.tick);
+    }
+}
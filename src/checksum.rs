@@ -0,0 +1,71 @@
+//! Detect corrupted pages with a checksum in the reserved bytes of each page, like SQLite's
+//! `cksumvfs` extension.
+//!
+//! [Checksum] is a [PageTransform] that stores an 8 byte checksum of each page in the last 8 bytes
+//! of the page and verifies it whenever the page is read. A page whose checksum does not match
+//! fails to read with `SQLITE_IOERR_DATA`, so bit rot in the storage is reported instead of being
+//! silently returned to queries. The checksum is the one `cksumvfs` uses, so databases can be moved
+//! between both.
+//!
+//! The database must reserve 8 bytes per page, which has to be set before it is created (or
+//! before a `VACUUM`):
+//!
+//! ```
+//! use sqlite_vfs::checksum::{Checksum, ChecksumVfs};
+//! use sqlite_vfs::mem::MemVfs;
+//!
+//! let _vfs = sqlite_vfs::register("cksum", ChecksumVfs::new(MemVfs::new(), Checksum)).unwrap();
+//! // then, for each new database (with `RESERVE_BYTES` from this module):
+//! // unsafe { sqlite_vfs::set_reserve_bytes(db, c"main", RESERVE_BYTES) }?;
+//! ```
+
+use crate::transform::{PageLocation, PageTransform, TransformVfs};
+use crate::VfsError;
+
+/// The number of bytes a database has to reserve at the end of each page for [Checksum].
+pub const RESERVE_BYTES: u8 = 8;
+
+/// A [TransformVfs] that checksums all pages.
+pub type ChecksumVfs<V> = TransformVfs<V, Checksum>;
+
+/// Stores a checksum of each page in its reserved bytes and verifies it on read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Checksum;
+
+impl Checksum {
+    /// The checksum of `data`, whose length must be a multiple of 8.
+    fn compute(data: &[u8]) -> [u8; 8] {
+        let (mut s1, mut s2) = (0u32, 0u32);
+        for words in data.chunks_exact(8) {
+            let a = u32::from_le_bytes(words[..4].try_into().unwrap());
+            let b = u32::from_le_bytes(words[4..].try_into().unwrap());
+            s1 = s1.wrapping_add(a).wrapping_add(s2);
+            s2 = s2.wrapping_add(b).wrapping_add(s1);
+        }
+        let mut checksum = [0; 8];
+        checksum[..4].copy_from_slice(&s1.to_le_bytes());
+        checksum[4..].copy_from_slice(&s2.to_le_bytes());
+        checksum
+    }
+}
+
+impl PageTransform for Checksum {
+    fn reserve_bytes(&self) -> u8 {
+        RESERVE_BYTES
+    }
+
+    fn encode(&self, page: &mut [u8], _location: PageLocation) -> Result<(), std::io::Error> {
+        let (data, checksum) = page.split_at_mut(page.len() - RESERVE_BYTES as usize);
+        checksum.copy_from_slice(&Self::compute(data));
+        Ok(())
+    }
+
+    fn decode(&self, page: &mut [u8], location: PageLocation) -> Result<(), std::io::Error> {
+        let (data, checksum) = page.split_at(page.len() - RESERVE_BYTES as usize);
+        if checksum != Self::compute(data) {
+            log::warn!("checksum mismatch for {:?}", location);
+            return Err(VfsError::Code(libsqlite3_sys::SQLITE_IOERR_DATA).into());
+        }
+        Ok(())
+    }
+}
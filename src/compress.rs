@@ -0,0 +1,1073 @@
+//! Compress the main database files stored by a [Vfs].
+//!
+//! A [CompressedVfs] splits each main database file into blocks of the page size of the database,
+//! compresses each block with a [Codec] and stores it in the file of the [Vfs] it wraps, along with
+//! an index of where each block is stored. Blocks that are rewritten stay where they are if their
+//! compressed size still fits, and are moved to free space (or the end of the file) otherwise. The
+//! index is written to free space when the file is synced, followed by the header pointing to it.
+//!
+//! Blocks that do not get smaller are stored uncompressed. Journals, WAL files and temporary files
+//! are stored as they are. After a crash, the index is the one of the last sync, which may point to
+//! blocks that were overwritten since. This is repaired by SQLite rolling back the hot journal (or
+//! replaying the WAL), which rewrites all pages a crashed transaction changed. It relies on the
+//! blocks having the size of the pages though, which is the page size the database was created
+//! with: a database whose page size was changed by `VACUUM` is not safe from crashes.
+//!
+//! Files are read-write for connections within this process: the index of a database is shared by
+//! all connections that opened it through the same [CompressedVfs], but connections of other
+//! processes would not see its changes. Compressed files that are only read (e.g. archives created
+//! with `VACUUM INTO` through a [CompressedVfs], which stores all blocks without any gaps) can be
+//! opened by any number of processes, including from read-only storage.
+//!
+//! The `zstd` and `lz4` features provide the [Zstd] and [Lz4] codecs. Any other compression can be
+//! plugged in by implementing [Codec].
+//!
+//! Pages compress far better with a dictionary trained on similar pages, e.g. with
+//! [train_dictionary] on a copy of the database (or of a database with the same schema). The
+//! dictionary of a database is set (as hex) with `PRAGMA compression_dictionary = '...'`, which
+//! stores it in the compressed file; blocks written from then on are compressed with it. Setting
+//! another dictionary later rotates it: blocks compressed with earlier dictionaries keep them until
+//! they are rewritten (which `VACUUM` does for all of them), and unused dictionaries are dropped
+//! when the file is synced. `PRAGMA compression_dictionary = ''` stops using dictionaries, and
+//! `PRAGMA compression_dictionary` returns the id of the current one (`0` for none).
+//!
+//! ```
+//! # #[cfg(feature = "zstd")]
+//! # fn example(
+//! #     handle: &sqlite_vfs::VfsHandle,
+//! #     conn: &rusqlite::Connection,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::fmt::Write;
+//!
+//! use sqlite_vfs::compress::train_dictionary;
+//!
+//! let dictionary = train_dictionary(handle.snapshot("main.db")?, 16 * 1024)?;
+//! let hex = dictionary.iter().fold(String::new(), |mut hex, b| {
+//!     write!(hex, "{:02x}", b).unwrap();
+//!     hex
+//! });
+//! conn.pragma_update(None, "compression_dictionary", hex)?;
+//! // recompress all pages with the dictionary
+//! conn.execute_batch("VACUUM")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+#[cfg(feature = "zstd")]
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenKind, OpenOptions, RecoveryPhase, SharedMemory,
+    SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// Identifies a compressed file (and the version of its format).
+const MAGIC: &[u8; 8] = b"SQLVFSZ1";
+
+/// Identifies a compressed file with dictionaries, whose header and index entries have additional
+/// fields. Files without dictionaries keep the first format.
+const MAGIC_DICTIONARIES: &[u8; 8] = b"SQLVFSZ2";
+
+/// Space reserved for the header at the start of a compressed file.
+const HEADER_SIZE: u64 = 512;
+
+/// Size of the header fields: magic, block size, file size, index offset and index length.
+const HEADER_LEN: usize = 8 + 4 + 8 + 8 + 8;
+
+/// Size of the header fields that follow in files with dictionaries: offset and length of the
+/// dictionaries, and the id of the current one.
+const DICTIONARIES_HEADER_LEN: usize = 8 + 8 + 4;
+
+/// Size of an index entry: offset, length and capacity of a block.
+const ENTRY_SIZE: usize = 8 + 4 + 4;
+
+/// Size of an index entry in files with dictionaries, which adds the id of the block's dictionary.
+const DICTIONARY_ENTRY_SIZE: usize = ENTRY_SIZE + 4;
+
+/// Compressed blocks are stored in slots of a multiple of this size, so that they can be rewritten
+/// in place when their compressed size changes slightly.
+const SLOT_ALIGNMENT: u32 = 64;
+
+/// The block size of files that are not written page by page.
+const DEFAULT_BLOCK_SIZE: u32 = 4096;
+
+/// A compression algorithm for the blocks of a [CompressedVfs].
+pub trait Codec: Send + Sync {
+    /// Compress `block`.
+    fn compress(&self, block: &[u8]) -> Result<Vec<u8>, std::io::Error>;
+
+    /// Decompress `data` (as returned by [Codec::compress]) into `block`, which has the size of the
+    /// original block.
+    fn decompress(&self, data: &[u8], block: &mut [u8]) -> Result<(), std::io::Error>;
+
+    /// Compress `block` with the dictionary of the database (see the [module](self) docs). The
+    /// default implementation ignores the dictionary, for codecs that don't support any.
+    fn compress_with_dictionary(
+        &self,
+        block: &[u8],
+        _dictionary: &[u8],
+    ) -> Result<Vec<u8>, std::io::Error> {
+        self.compress(block)
+    }
+
+    /// Decompress `data` (as returned by [Codec::compress_with_dictionary] with the same
+    /// `dictionary`) into `block`. The default implementation ignores the dictionary.
+    fn decompress_with_dictionary(
+        &self,
+        data: &[u8],
+        block: &mut [u8],
+        _dictionary: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self.decompress(data, block)
+    }
+}
+
+/// Compresses blocks with zstd (only with the `zstd` feature).
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct Zstd {
+    /// The compression level (default: 3).
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Self {
+        Zstd { level: 3 }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn compress(&self, block: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        zstd::bulk::compress(block, self.level)
+    }
+
+    fn decompress(&self, data: &[u8], block: &mut [u8]) -> Result<(), std::io::Error> {
+        let n = zstd::bulk::decompress_to_buffer(data, block)?;
+        check_decompressed(n, block.len())
+    }
+
+    fn compress_with_dictionary(
+        &self,
+        block: &[u8],
+        dictionary: &[u8],
+    ) -> Result<Vec<u8>, std::io::Error> {
+        zstd::bulk::Compressor::with_dictionary(self.level, dictionary)?.compress(block)
+    }
+
+    fn decompress_with_dictionary(
+        &self,
+        data: &[u8],
+        block: &mut [u8],
+        dictionary: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let mut decompressor = zstd::bulk::Decompressor::with_dictionary(dictionary)?;
+        let n = decompressor.decompress_to_buffer(data, block)?;
+        check_decompressed(n, block.len())
+    }
+}
+
+/// Train a zstd dictionary of at most `max_size` bytes (e.g. 16 KiB) on the pages of the
+/// (uncompressed) database read from `database`, e.g. a [Snapshot](crate::snapshot::Snapshot) or
+/// a database file, to set with `PRAGMA compression_dictionary` (only with the `zstd` feature).
+/// The pages are sampled evenly from all over the database, up to about 100 times `max_size`.
+#[cfg(feature = "zstd")]
+pub fn train_dictionary(
+    mut database: impl Read,
+    max_size: usize,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut data = Vec::new();
+    database.read_to_end(&mut data)?;
+    if data.len() < 100 || !data.starts_with(b"SQLite format 3\0") {
+        return Err(invalid_data("not a database"));
+    }
+    let page_size = match u16::from_be_bytes([data[16], data[17]]) {
+        1 => 65536,
+        size => usize::from(size),
+    };
+
+    let budget = (max_size * 100 / page_size).max(1);
+    let step = (data.len() / page_size).div_ceil(budget).max(1);
+    let mut samples = Vec::new();
+    let mut sizes = Vec::new();
+    for page in data.chunks_exact(page_size).step_by(step) {
+        samples.extend_from_slice(page);
+        sizes.push(page.len());
+    }
+    zstd::dict::from_continuous(&samples, &sizes, max_size)
+}
+
+/// Compresses blocks with LZ4, which is faster but compresses less than [Zstd] (only with the
+/// `lz4` feature).
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    fn compress(&self, block: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        Ok(lz4_flex::block::compress(block))
+    }
+
+    fn decompress(&self, data: &[u8], block: &mut [u8]) -> Result<(), std::io::Error> {
+        let n = lz4_flex::block::decompress_into(data, block)
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+        check_decompressed(n, block.len())
+    }
+
+    fn compress_with_dictionary(
+        &self,
+        block: &[u8],
+        dictionary: &[u8],
+    ) -> Result<Vec<u8>, std::io::Error> {
+        Ok(lz4_flex::block::compress_with_dict(block, dictionary))
+    }
+
+    fn decompress_with_dictionary(
+        &self,
+        data: &[u8],
+        block: &mut [u8],
+        dictionary: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let n = lz4_flex::block::decompress_into_with_dict(data, block, dictionary)
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+        check_decompressed(n, block.len())
+    }
+}
+
+/// A [Vfs] that compresses the main database files stored in the [Vfs] it wraps.
+pub struct CompressedVfs<V, C> {
+    vfs: V,
+    codec: Arc<C>,
+    indexes: Mutex<HashMap<PathBuf, Weak<Mutex<Index>>>>,
+}
+
+/// A file opened by [CompressedVfs].
+pub struct CompressedFile<F: File, C> {
+    file: F,
+    compressed: Option<Compressed<C>>,
+}
+
+/// The state of a compressed main database file.
+struct Compressed<C> {
+    codec: Arc<C>,
+    index: Arc<Mutex<Index>>,
+}
+
+/// Where the blocks of a compressed file are stored, shared by all connections that opened it.
+struct Index {
+    /// The size of the uncompressed blocks, or 0 if nothing has been written yet.
+    block_size: u32,
+    /// The size of the uncompressed file.
+    size: u64,
+    blocks: Vec<Slot>,
+    /// The index as last written to the file.
+    stored: Option<Range<u64>>,
+    /// Unused ranges of the file, sorted by their start.
+    free: Vec<Range<u64>>,
+    /// The end of the used part of the file.
+    end: u64,
+    /// Whether the index changed since it was last written.
+    dirty: bool,
+    /// The dictionaries blocks are compressed with, sorted by their id.
+    dictionaries: Vec<Dictionary>,
+    /// The id of the dictionary new blocks are compressed with, or 0 for none.
+    dictionary: u32,
+    /// The dictionaries as last written to the file.
+    dictionaries_stored: Option<Range<u64>>,
+    /// Whether the dictionaries changed since they were last written.
+    dictionaries_dirty: bool,
+}
+
+/// Where a compressed block is stored. Blocks that have never been written are stored nowhere and
+/// read as zeros.
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot {
+    offset: u64,
+    len: u32,
+    capacity: u32,
+    /// The id of the dictionary the block is compressed with, or 0 for none.
+    dictionary: u32,
+}
+
+struct Dictionary {
+    id: u32,
+    data: Vec<u8>,
+}
+
+impl<V, C> CompressedVfs<V, C> {
+    /// Wrap `vfs` and compress all main database files with `codec`.
+    pub fn new(vfs: V, codec: C) -> Self {
+        CompressedVfs {
+            vfs,
+            codec: Arc::new(codec),
+            indexes: Default::default(),
+        }
+    }
+}
+
+impl<V: Vfs, C: Codec> Vfs for CompressedVfs<V, C> {
+    type File = CompressedFile<V::File, C>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let kind = opts.kind;
+        let mut file = self.vfs.open(path, opts)?;
+        if kind != OpenKind::MainDb {
+            return Ok(CompressedFile {
+                file,
+                compressed: None,
+            });
+        }
+
+        let mut indexes = self.indexes.lock().unwrap_or_else(|err| err.into_inner());
+        let index = match indexes.get(path).and_then(Weak::upgrade) {
+            Some(index) => index,
+            None => {
+                let index = Arc::new(Mutex::new(Index::load(&mut file)?));
+                indexes.insert(path.to_path_buf(), Arc::downgrade(&index));
+                index
+            }
+        };
+        indexes.retain(|_, index| index.strong_count() > 0);
+        Ok(CompressedFile {
+            file,
+            compressed: Some(Compressed {
+                codec: Arc::clone(&self.codec),
+                index,
+            }),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.vfs.rename(from, to)
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.vfs.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl Index {
+    /// Read the header and the index of `file`. Empty files get an empty index.
+    fn load<F: File>(file: &mut F) -> Result<Self, std::io::Error> {
+        let mut index = Index {
+            block_size: 0,
+            size: 0,
+            blocks: Vec::new(),
+            stored: None,
+            free: Vec::new(),
+            end: HEADER_SIZE,
+            dirty: false,
+            dictionaries: Vec::new(),
+            dictionary: 0,
+            dictionaries_stored: None,
+            dictionaries_dirty: false,
+        };
+        if file.file_size()? == 0 {
+            return Ok(index);
+        }
+
+        let mut header = [0; HEADER_LEN + DICTIONARIES_HEADER_LEN];
+        read_exact(file, &mut header[..HEADER_LEN], 0)?;
+        let with_dictionaries = match &header[..8] {
+            magic if magic == MAGIC => false,
+            magic if magic == MAGIC_DICTIONARIES => true,
+            _ => return Err(invalid_data("not a compressed database")),
+        };
+        index.block_size = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        index.size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+        let offset = u64::from_be_bytes(header[20..28].try_into().unwrap());
+        let len = u64::from_be_bytes(header[28..36].try_into().unwrap());
+        let entry_size = match with_dictionaries {
+            true => DICTIONARY_ENTRY_SIZE,
+            false => ENTRY_SIZE,
+        };
+        // a file only has no block size yet if a dictionary was set before anything was written
+        if (index.block_size == 0 && (index.size > 0 || len > 0)) || len % entry_size as u64 != 0 {
+            return Err(invalid_data("corrupt compressed database header"));
+        }
+
+        let mut used = vec![0..HEADER_SIZE, offset..offset + len];
+        if with_dictionaries {
+            read_exact(file, &mut header[HEADER_LEN..], HEADER_LEN as u64)?;
+            let fields = &header[HEADER_LEN..];
+            let offset = u64::from_be_bytes(fields[0..8].try_into().unwrap());
+            let len = u64::from_be_bytes(fields[8..16].try_into().unwrap());
+            index.dictionary = u32::from_be_bytes(fields[16..20].try_into().unwrap());
+            let mut data = vec![0; len as usize];
+            read_exact(file, &mut data, offset)?;
+            index.dictionaries = parse_dictionaries(&data)?;
+            index.dictionaries_stored = Some(offset..offset + len);
+            used.push(offset..offset + len);
+        }
+
+        let mut entries = vec![0; len as usize];
+        read_exact(file, &mut entries, offset)?;
+        index.blocks = entries
+            .chunks_exact(entry_size)
+            .map(|entry| Slot {
+                offset: u64::from_be_bytes(entry[0..8].try_into().unwrap()),
+                len: u32::from_be_bytes(entry[8..12].try_into().unwrap()),
+                capacity: u32::from_be_bytes(entry[12..16].try_into().unwrap()),
+                dictionary: match with_dictionaries {
+                    true => u32::from_be_bytes(entry[16..20].try_into().unwrap()),
+                    false => 0,
+                },
+            })
+            .collect();
+        index.stored = Some(offset..offset + len);
+
+        // everything that is neither the header, the index, the dictionaries nor a block is free
+        let mut used = index
+            .blocks
+            .iter()
+            .filter(|slot| slot.offset != 0)
+            .map(|slot| slot.offset..slot.offset + u64::from(slot.capacity))
+            .chain(used)
+            .collect::<Vec<_>>();
+        used.sort_by_key(|range| range.start);
+        for range in used {
+            if range.start > index.end {
+                index.free.push(index.end..range.start);
+            }
+            index.end = index.end.max(range.end);
+        }
+        Ok(index)
+    }
+
+    fn block_count(&self) -> u64 {
+        match self.block_size {
+            0 => 0,
+            block_size => self.size.div_ceil(u64::from(block_size)),
+        }
+    }
+
+    /// Find space for `len` bytes, from the free ranges or at the end of the file.
+    fn allocate(&mut self, len: u64) -> u64 {
+        match self
+            .free
+            .iter()
+            .position(|range| range.end - range.start >= len)
+        {
+            Some(i) => {
+                let offset = self.free[i].start;
+                self.free[i].start += len;
+                if self.free[i].is_empty() {
+                    self.free.remove(i);
+                }
+                offset
+            }
+            None => {
+                let offset = self.end;
+                self.end += len;
+                offset
+            }
+        }
+    }
+
+    /// Mark the space of `slot` as unused.
+    fn release(&mut self, slot: Slot) {
+        if slot.offset != 0 {
+            self.free(slot.offset..slot.offset + u64::from(slot.capacity));
+        }
+    }
+
+    /// Mark `range` as unused.
+    fn free(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        let i = self.free.partition_point(|free| free.start < range.start);
+        self.free.insert(i, range);
+        // merge with the neighbours
+        if i + 1 < self.free.len() && self.free[i].end == self.free[i + 1].start {
+            self.free[i].end = self.free.remove(i + 1).end;
+        }
+        if i > 0 && self.free[i - 1].end == self.free[i].start {
+            self.free[i - 1].end = self.free.remove(i).end;
+        }
+        // give up free space at the end of the file
+        if let Some(last) = self.free.last() {
+            if last.end == self.end {
+                self.end = last.start;
+                self.free.pop();
+            }
+        }
+    }
+
+    /// Read and decompress the block at `index` into `block`.
+    fn read_block<F: File, C: Codec>(
+        &self,
+        file: &mut F,
+        codec: &C,
+        index: u64,
+        block: &mut [u8],
+    ) -> Result<(), std::io::Error> {
+        let slot = self.blocks.get(index as usize).copied().unwrap_or_default();
+        if slot.offset == 0 {
+            block.fill(0);
+            return Ok(());
+        }
+        if slot.len as usize == block.len() {
+            // the block did not compress
+            return read_exact(file, block, slot.offset);
+        }
+        let mut data = vec![0; slot.len as usize];
+        read_exact(file, &mut data, slot.offset)?;
+        match slot.dictionary {
+            0 => codec.decompress(&data, block),
+            id => match self
+                .dictionaries
+                .iter()
+                .find(|dictionary| dictionary.id == id)
+            {
+                Some(dictionary) => {
+                    codec.decompress_with_dictionary(&data, block, &dictionary.data)
+                }
+                None => Err(invalid_data("unknown compression dictionary")),
+            },
+        }
+    }
+
+    /// Compress `block` and store it at `index`.
+    fn write_block<F: File, C: Codec>(
+        &mut self,
+        file: &mut F,
+        codec: &C,
+        index: u64,
+        block: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let dictionary = self
+            .dictionaries
+            .iter()
+            .find(|dictionary| dictionary.id == self.dictionary);
+        let compressed = match dictionary {
+            Some(dictionary) => codec.compress_with_dictionary(block, &dictionary.data)?,
+            None => codec.compress(block)?,
+        };
+        let (data, dictionary) = match compressed.len() < block.len() {
+            true => (&compressed[..], self.dictionary),
+            false => (block, 0),
+        };
+        let len = data.len() as u32;
+        let index = index as usize;
+        if self.blocks.len() <= index {
+            self.blocks.resize(index + 1, Slot::default());
+        }
+        let mut slot = self.blocks[index];
+        if slot.offset == 0 || len > slot.capacity {
+            self.release(slot);
+            slot.capacity = len.div_ceil(SLOT_ALIGNMENT).max(1) * SLOT_ALIGNMENT;
+            slot.offset = self.allocate(u64::from(slot.capacity));
+        }
+        slot.len = len;
+        slot.dictionary = dictionary;
+        file.write_all_at(data, slot.offset)?;
+        self.blocks[index] = slot;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Use `dictionary` (or none) for the blocks written from now on.
+    fn set_dictionary(&mut self, dictionary: Option<Vec<u8>>) {
+        self.dictionary = match dictionary {
+            Some(data) => {
+                let id = self.dictionaries.last().map_or(1, |last| last.id + 1);
+                self.dictionaries.push(Dictionary { id, data });
+                id
+            }
+            None => 0,
+        };
+        self.dictionaries_dirty = true;
+        self.dirty = true;
+    }
+
+    /// Write the index (and the dictionaries, if they changed) to free space and the header
+    /// pointing to it.
+    fn store<F: File>(&mut self, file: &mut F, options: SyncOptions) -> Result<(), std::io::Error> {
+        // drop the dictionaries no block is compressed with anymore
+        let used = self
+            .blocks
+            .iter()
+            .map(|slot| slot.dictionary)
+            .chain([self.dictionary])
+            .collect::<HashSet<_>>();
+        let count = self.dictionaries.len();
+        self.dictionaries
+            .retain(|dictionary| used.contains(&dictionary.id));
+        self.dictionaries_dirty |= self.dictionaries.len() != count;
+
+        let free = self
+            .free
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum::<u64>();
+        let moved = match free * 4 > self.end {
+            true => self.compact(file)?,
+            false => Vec::new(),
+        };
+
+        let with_dictionaries = !self.dictionaries.is_empty();
+        let mut entries = Vec::with_capacity(self.blocks.len() * DICTIONARY_ENTRY_SIZE);
+        for slot in &self.blocks {
+            entries.extend_from_slice(&slot.offset.to_be_bytes());
+            entries.extend_from_slice(&slot.len.to_be_bytes());
+            entries.extend_from_slice(&slot.capacity.to_be_bytes());
+            if with_dictionaries {
+                entries.extend_from_slice(&slot.dictionary.to_be_bytes());
+            }
+        }
+        // the current index stays intact until the header points to the new one
+        let len = entries.len() as u64;
+        let offset = self.allocate(len);
+        file.write_all_at(&entries, offset)?;
+
+        // as do the current dictionaries
+        let dictionaries = match (with_dictionaries, self.dictionaries_dirty) {
+            (false, _) => None,
+            (true, false) => self.dictionaries_stored.clone(),
+            (true, true) => {
+                let mut data = Vec::new();
+                for dictionary in &self.dictionaries {
+                    data.extend_from_slice(&dictionary.id.to_be_bytes());
+                    data.extend_from_slice(&(dictionary.data.len() as u32).to_be_bytes());
+                    data.extend_from_slice(&dictionary.data);
+                }
+                let offset = self.allocate(data.len() as u64);
+                file.write_all_at(&data, offset)?;
+                Some(offset..offset + data.len() as u64)
+            }
+        };
+        file.sync(options)?;
+
+        let mut header = Vec::with_capacity(HEADER_LEN + DICTIONARIES_HEADER_LEN);
+        header.extend_from_slice(match with_dictionaries {
+            true => MAGIC_DICTIONARIES,
+            false => MAGIC,
+        });
+        header.extend_from_slice(&self.block_size.to_be_bytes());
+        header.extend_from_slice(&self.size.to_be_bytes());
+        header.extend_from_slice(&offset.to_be_bytes());
+        header.extend_from_slice(&len.to_be_bytes());
+        if let Some(range) = &dictionaries {
+            header.extend_from_slice(&range.start.to_be_bytes());
+            header.extend_from_slice(&(range.end - range.start).to_be_bytes());
+            header.extend_from_slice(&self.dictionary.to_be_bytes());
+        }
+        file.write_all_at(&header, 0)?;
+        file.sync(options)?;
+        self.dirty = false;
+        self.dictionaries_dirty = false;
+
+        // only now the space of the previous index (and dictionaries) and of moved blocks can be
+        // reused
+        if let Some(stored) = self.stored.replace(offset..offset + len) {
+            self.free(stored);
+        }
+        if self.dictionaries_stored != dictionaries {
+            if let Some(stored) = std::mem::replace(&mut self.dictionaries_stored, dictionaries) {
+                self.free(stored);
+            }
+        }
+        for range in moved {
+            self.free(range);
+        }
+        if file.file_size()? > self.end {
+            file.truncate(self.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl Index {
+    /// Move the blocks at the end of the file to free space before them. Returns the ranges the
+    /// blocks were moved from, which are still referenced by the stored index.
+    fn compact<F: File>(&mut self, file: &mut F) -> Result<Vec<Range<u64>>, std::io::Error> {
+        let mut order = (0..self.blocks.len())
+            .filter(|i| self.blocks[*i].offset != 0)
+            .collect::<Vec<_>>();
+        order.sort_by_key(|i| std::cmp::Reverse(self.blocks[*i].offset));
+
+        let mut moved = Vec::new();
+        for i in order {
+            let slot = self.blocks[i];
+            let capacity = u64::from(slot.capacity);
+            let Some(gap) = self.free.iter().position(|range| {
+                range.end - range.start >= capacity && range.start + capacity <= slot.offset
+            }) else {
+                continue;
+            };
+            let offset = self.free[gap].start;
+            self.free[gap].start += capacity;
+            if self.free[gap].is_empty() {
+                self.free.remove(gap);
+            }
+
+            let mut data = vec![0; slot.len as usize];
+            read_exact(file, &mut data, slot.offset)?;
+            file.write_all_at(&data, offset)?;
+            self.blocks[i].offset = offset;
+            moved.push(slot.offset..slot.offset + capacity);
+        }
+        Ok(moved)
+    }
+}
+
+impl<C> Compressed<C> {
+    fn index(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<F: File, C: Codec> File for CompressedFile<F, C> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let Some(compressed) = &self.compressed else {
+            return self.file.read_at(buf, offset);
+        };
+        let index = compressed.index();
+        if index.block_size == 0 {
+            return Ok(0);
+        }
+        let block_size = u64::from(index.block_size);
+        let end = (offset + buf.len() as u64).min(index.size);
+        let mut block = vec![0; block_size as usize];
+        let mut pos = offset;
+        while pos < end {
+            let i = pos / block_size;
+            let start = (pos - i * block_size) as usize;
+            let n = (end - pos).min(block_size - start as u64) as usize;
+            index.read_block(&mut self.file, &*compressed.codec, i, &mut block)?;
+            buf[(pos - offset) as usize..][..n].copy_from_slice(&block[start..start + n]);
+            pos += n as u64;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let Some(compressed) = &self.compressed else {
+            return self.file.write_all_at(buf, offset);
+        };
+        let mut index = compressed.index();
+        if index.block_size == 0 {
+            // use the page size of the database, which is known from the first write of page 1
+            let len = buf.len() as u64;
+            index.block_size = if offset == 0 && len.is_power_of_two() && len >= 512 {
+                len as u32
+            } else {
+                DEFAULT_BLOCK_SIZE
+            };
+        }
+
+        let block_size = u64::from(index.block_size);
+        let end = offset + buf.len() as u64;
+        let mut block = vec![0; block_size as usize];
+        let mut pos = offset;
+        while pos < end {
+            let i = pos / block_size;
+            let start = (pos - i * block_size) as usize;
+            let n = (end - pos).min(block_size - start as u64) as usize;
+            if n < block.len() {
+                // only part of the block changes
+                if i * block_size < index.size {
+                    index.read_block(&mut self.file, &*compressed.codec, i, &mut block)?;
+                } else {
+                    block.fill(0);
+                }
+            }
+            block[start..start + n].copy_from_slice(&buf[(pos - offset) as usize..][..n]);
+            index.write_block(&mut self.file, &*compressed.codec, i, &block)?;
+            pos += n as u64;
+        }
+        index.size = index.size.max(end);
+        Ok(())
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        if let Some(compressed) = &self.compressed {
+            let mut index = compressed.index();
+            if index.dirty {
+                return index.store(&mut self.file, options);
+            }
+        }
+        self.file.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        match &self.compressed {
+            Some(compressed) => Ok(compressed.index().size),
+            None => self.file.file_size(),
+        }
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let Some(compressed) = &self.compressed else {
+            return self.file.truncate(size);
+        };
+        let mut index = compressed.index();
+        if index.block_size == 0 {
+            if size == 0 {
+                return Ok(());
+            }
+            index.block_size = DEFAULT_BLOCK_SIZE;
+        }
+        if size < index.size {
+            // zero the rest of the last block, in case the file grows again
+            let block_size = u64::from(index.block_size);
+            let (i, len) = (size / block_size, (size % block_size) as usize);
+            if len > 0 {
+                let mut block = vec![0; block_size as usize];
+                index.read_block(&mut self.file, &*compressed.codec, i, &mut block)?;
+                block[len..].fill(0);
+                index.write_block(&mut self.file, &*compressed.codec, i, &block)?;
+            }
+        }
+        index.size = size;
+        let count = index.block_count() as usize;
+        if index.blocks.len() > count {
+            for slot in index.blocks.split_off(count) {
+                index.release(slot);
+            }
+        }
+        // SQLite does not always sync after truncating (e.g. at the end of `VACUUM`)
+        index.store(&mut self.file, SyncOptions::default())
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.file.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.file.unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.file.shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        match self.compressed {
+            // the blocks are stored compressed
+            Some(_) => None,
+            None => self.file.memory_mapped(),
+        }
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        match self.compressed {
+            // the index is only written when the file is synced
+            Some(_) => None,
+            None => self.file.batch_atomic_write(),
+        }
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.file.file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        match self.compressed {
+            // writing part of a block rewrites all of it
+            Some(_) => DeviceCharacteristics::empty(),
+            None => self.file.device_characteristics(kind),
+        }
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        let compressed = match &self.compressed {
+            Some(compressed) if name.eq_ignore_ascii_case("compression_dictionary") => compressed,
+            _ => return self.file.pragma(name, value),
+        };
+        let mut index = compressed.index();
+        let Some(value) = value else {
+            return Some(Ok(Some(index.dictionary.to_string())));
+        };
+        if self.file.read_only() {
+            return Some(Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "the database is read-only",
+            )));
+        }
+
+        let result = parse_hex(value).and_then(|dictionary| {
+            index.set_dictionary((!dictionary.is_empty()).then_some(dictionary));
+            index.store(&mut self.file, SyncOptions::default())?;
+            Ok(Some(index.dictionary.to_string()))
+        });
+        Some(result)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
+
+impl<F: File, C> Drop for CompressedFile<F, C> {
+    fn drop(&mut self) {
+        // SQLite does not sync some files before closing them (e.g. those created by `VACUUM INTO`)
+        if let Some(compressed) = &self.compressed {
+            let mut index = compressed.index();
+            if index.dirty {
+                if let Err(err) = index.store(&mut self.file, SyncOptions::default()) {
+                    log::warn!("failed to store the index of a compressed file: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Parse the dictionaries as stored in a compressed file: the id, length and data of each.
+fn parse_dictionaries(mut data: &[u8]) -> Result<Vec<Dictionary>, std::io::Error> {
+    let mut dictionaries = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return Err(invalid_data("corrupt compression dictionaries"));
+        }
+        let id = u32::from_be_bytes(data[0..4].try_into().unwrap());
+        let len = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        let Some(dictionary) = data[8..].get(..len) else {
+            return Err(invalid_data("corrupt compression dictionaries"));
+        };
+        dictionaries.push(Dictionary {
+            id,
+            data: dictionary.to_vec(),
+        });
+        data = &data[8 + len..];
+    }
+    Ok(dictionaries)
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, std::io::Error> {
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    };
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some(digit(*high)? << 4 | digit(*low)?),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "the dictionary is not hex"))
+}
+
+fn read_exact<F: File>(file: &mut F, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+    if file.read_at(buf, offset)? < buf.len() {
+        return Err(invalid_data("compressed database is truncated"));
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn check_decompressed(n: usize, len: usize) -> Result<(), std::io::Error> {
+    if n != len {
+        return Err(invalid_data("decompressed block has the wrong size"));
+    }
+    Ok(())
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,503 @@
+//! Forward all operations to a VFS that is already registered to SQLite, by default the platform's
+//! own (`unix` or `win32`).
+//!
+//! A shim that only wants to intercept a few operations (e.g. to log them, collect metrics or
+//! verify checksums) wraps a [DelegatingVfs] in its own [Vfs] and forwards everything else to it,
+//! instead of implementing the file I/O itself:
+//!
+//! ```
+//! use std::path::Path;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//!
+//! use sqlite_vfs::delegate::DelegatingVfs;
+//! use sqlite_vfs::{OpenOptions, Vfs};
+//!
+//! /// Counts the files that are opened.
+//! struct CountingVfs {
+//!     vfs: DelegatingVfs,
+//!     opened: AtomicUsize,
+//! }
+//!
+//! impl Vfs for CountingVfs {
+//!     type File = <DelegatingVfs as Vfs>::File;
+//!
+//!     fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+//!         self.opened.fetch_add(1, Ordering::SeqCst);
+//!         self.vfs.open(path, opts)
+//!     }
+//!
+//!     fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+//!         self.vfs.delete(path)
+//!     }
+//!
+//!     fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+//!         self.vfs.exists(path)
+//!     }
+//!
+//!     fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+//!         self.vfs.access(path, write)
+//!     }
+//!
+//!     fn full_pathname(&self, path: &Path) -> Result<std::path::PathBuf, std::io::Error> {
+//!         self.vfs.full_pathname(path)
+//!     }
+//! }
+//!
+//! let vfs = CountingVfs {
+//!     vfs: DelegatingVfs::new().unwrap(),
+//!     opened: AtomicUsize::new(0),
+//! };
+//! let _vfs = sqlite_vfs::register("counting", vfs).unwrap();
+//! ```
+//!
+//! The files support locking and the shared memory of WAL mode just like the delegate does. Memory
+//! mapping is not forwarded, so pages are always read with [File::read_at].
+
+use std::ffi::{c_void, CStr, CString};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr::{null, null_mut, NonNull};
+use std::time::Duration;
+
+use libsqlite3_sys as ffi;
+
+use crate::{
+    path_from_bytes, path_to_cstring, DeviceCharacteristics, File, FileControl, LockKind, OpenKind,
+    OpenOptions, SharedMemory, ShmLock, SyncOptions, Vfs, VfsError,
+};
+
+/// A [Vfs] that forwards all operations to a VFS registered to SQLite.
+pub struct DelegatingVfs {
+    vfs: NonNull<ffi::sqlite3_vfs>,
+}
+
+/// A file opened by [DelegatingVfs].
+pub struct DelegatingFile {
+    /// The file of the delegate, which is `szOsFile` bytes large (allocated as `len` `u64`s to align
+    /// it).
+    file: NonNull<ffi::sqlite3_file>,
+    len: usize,
+    /// The path the file was opened with, which has to stay valid until it is closed.
+    path: CString,
+}
+
+// SAFETY: registered VFSes are never freed while they are used, and SQLite synchronizes the access
+// to a file (it is only used by one connection at a time).
+unsafe impl Send for DelegatingVfs {}
+unsafe impl Sync for DelegatingVfs {}
+unsafe impl Send for DelegatingFile {}
+
+impl DelegatingVfs {
+    /// Delegate to SQLite's default VFS. Must be called before the VFS wrapping it is registered as
+    /// the default itself.
+    pub fn new() -> Result<Self, std::io::Error> {
+        Self::find(None)
+    }
+
+    /// Delegate to the VFS registered as `name`, or to the default VFS if `name` is `None`.
+    pub fn find(name: Option<&str>) -> Result<Self, std::io::Error> {
+        let name = name
+            .map(CString::new)
+            .transpose()
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
+        let vfs = unsafe {
+            ffi::sqlite3_initialize();
+            ffi::sqlite3_vfs_find(name.as_ref().map(|n| n.as_ptr()).unwrap_or(null()))
+        };
+        match NonNull::new(vfs) {
+            Some(vfs) => Ok(DelegatingVfs { vfs }),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// The name of the VFS that is delegated to.
+    pub fn name(&self) -> &str {
+        unsafe { CStr::from_ptr(self.vfs.as_ref().zName) }
+            .to_str()
+            .unwrap_or_default()
+    }
+
+    fn raw(&self) -> *mut ffi::sqlite3_vfs {
+        self.vfs.as_ptr()
+    }
+
+    fn methods(&self) -> &ffi::sqlite3_vfs {
+        unsafe { self.vfs.as_ref() }
+    }
+}
+
+impl Vfs for DelegatingVfs {
+    type File = DelegatingFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let vfs = self.methods();
+        let x_open = vfs.xOpen.ok_or(ErrorKind::Unsupported)?;
+        // SQLite reads URI parameters after the terminating nul of a database path, so end it with
+        // an empty list of parameters
+        let mut name = to_cstring(path)?.into_bytes_with_nul();
+        name.push(0);
+        let path = unsafe { CString::from_vec_with_nul_unchecked(name) };
+
+        let len = usize::try_from(vfs.szOsFile)
+            .unwrap_or(0)
+            .div_ceil(8)
+            .max(1);
+        let memory = Box::into_raw(vec![0u64; len].into_boxed_slice());
+        let file = DelegatingFile {
+            file: NonNull::new(memory as *mut ffi::sqlite3_file).unwrap(),
+            len,
+            path,
+        };
+        let mut out_flags = 0;
+        let code = unsafe {
+            x_open(
+                self.raw(),
+                file.path.as_ptr(),
+                file.file.as_ptr(),
+                opts.to_flags(),
+                &mut out_flags,
+            )
+        };
+        check(code)?;
+        Ok(file)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        let path = to_cstring(path)?;
+        let x_delete = self.methods().xDelete.ok_or(ErrorKind::Unsupported)?;
+        match unsafe { x_delete(self.raw(), path.as_ptr(), 0) } {
+            ffi::SQLITE_IOERR_DELETE_NOENT => Err(ErrorKind::NotFound.into()),
+            code => check(code),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.access_flag(path, ffi::SQLITE_ACCESS_EXISTS)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.access_flag(
+            path,
+            if write {
+                ffi::SQLITE_ACCESS_READWRITE
+            } else {
+                ffi::SQLITE_ACCESS_READ
+            },
+        )
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        let vfs = self.methods();
+        let x_full_pathname = vfs.xFullPathname.ok_or(ErrorKind::Unsupported)?;
+        let path = to_cstring(path)?;
+        let mut out = vec![0u8; usize::try_from(vfs.mxPathname).unwrap_or(0) + 1];
+        let code = unsafe {
+            x_full_pathname(
+                self.raw(),
+                path.as_ptr(),
+                out.len() as c_int,
+                out.as_mut_ptr() as *mut _,
+            )
+        };
+        check(code)?;
+        let len = out.iter().position(|b| *b == 0).unwrap_or(out.len());
+        Ok(path_from_bytes(&out[..len]))
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        match self.methods().xRandomness {
+            Some(x_randomness) => unsafe {
+                x_randomness(self.raw(), buf.len() as c_int, buf.as_mut_ptr() as *mut _);
+            },
+            None => {
+                use rand::Rng;
+
+                rand::thread_rng().fill(buf);
+            }
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        let micros = c_int::try_from(duration.as_micros()).unwrap_or(c_int::MAX);
+        match self.methods().xSleep {
+            Some(x_sleep) => {
+                Duration::from_micros(unsafe { x_sleep(self.raw(), micros) }.max(0) as u64)
+            }
+            None => {
+                std::thread::sleep(duration);
+                duration
+            }
+        }
+    }
+}
+
+impl DelegatingVfs {
+    fn access_flag(&self, path: &Path, flag: c_int) -> Result<bool, std::io::Error> {
+        let path = to_cstring(path)?;
+        let x_access = self.methods().xAccess.ok_or(ErrorKind::Unsupported)?;
+        let mut result = 0;
+        check(unsafe { x_access(self.raw(), path.as_ptr(), flag, &mut result) })?;
+        Ok(result != 0)
+    }
+}
+
+impl DelegatingFile {
+    /// The methods of the file, or `None` if it failed to open.
+    fn methods(&self) -> Option<&ffi::sqlite3_io_methods> {
+        unsafe { self.file.as_ref().pMethods.as_ref() }
+    }
+}
+
+/// Call the method `$method` of the delegated `$file` with the remaining arguments, and return its
+/// result code.
+macro_rules! call {
+    ($file:expr, $method:ident $(, $arg:expr)*) => {
+        match $file.methods().and_then(|methods| methods.$method) {
+            Some(method) => Ok(unsafe { method($file.file.as_ptr() $(, $arg)*) }),
+            None => Err(std::io::Error::from(ErrorKind::Unsupported)),
+        }
+    };
+}
+
+impl File for DelegatingFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let len = c_int::try_from(buf.len()).map_err(invalid_input)?;
+        let offset_i64 = i64::try_from(offset).map_err(invalid_input)?;
+        match call!(
+            self,
+            xRead,
+            buf.as_mut_ptr() as *mut c_void,
+            len,
+            offset_i64
+        )? {
+            ffi::SQLITE_IOERR_SHORT_READ => {
+                // the rest of the buffer is zeroed, so find out where the file ends
+                let size = self.file_size()?;
+                Ok(size.saturating_sub(offset).min(buf.len() as u64) as usize)
+            }
+            code => check(code).map(|()| buf.len()),
+        }
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let len = c_int::try_from(buf.len()).map_err(invalid_input)?;
+        let offset = i64::try_from(offset).map_err(invalid_input)?;
+        check(call!(
+            self,
+            xWrite,
+            buf.as_ptr() as *const c_void,
+            len,
+            offset
+        )?)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        let mut flags = if options.full {
+            ffi::SQLITE_SYNC_FULL
+        } else {
+            ffi::SQLITE_SYNC_NORMAL
+        };
+        if options.data_only {
+            flags |= ffi::SQLITE_SYNC_DATAONLY;
+        }
+        check(call!(self, xSync, flags)?)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        let mut size = 0;
+        check(call!(self, xFileSize, &mut size)?)?;
+        Ok(size.max(0) as u64)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let size = i64::try_from(size).map_err(invalid_input)?;
+        check(call!(self, xTruncate, size)?)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        // SQLite's VFSes acquire the pending lock on their own on the way to an exclusive lock
+        if lock == LockKind::Pending {
+            return Ok(true);
+        }
+        match call!(self, xLock, lock.to_i32())? {
+            ffi::SQLITE_BUSY => Ok(false),
+            code => check(code).map(|()| true),
+        }
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        check(call!(self, xUnlock, lock.to_i32())?)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        let mut reserved = 0;
+        check(call!(self, xCheckReservedLock, &mut reserved)?)?;
+        Ok(reserved != 0)
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        let methods = self.methods()?;
+        if methods.iVersion < 2 || methods.xShmMap.is_none() {
+            return None;
+        }
+        Some(self)
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        let (mut size, mut chunk_size);
+        let (op, arg) = match op {
+            FileControl::SizeHint(hint) => {
+                size = i64::try_from(hint).map_err(invalid_input)?;
+                (
+                    ffi::SQLITE_FCNTL_SIZE_HINT,
+                    &mut size as *mut i64 as *mut c_void,
+                )
+            }
+            FileControl::SyncOmitted => (ffi::SQLITE_FCNTL_SYNC_OMITTED, null_mut()),
+            FileControl::ChunkSize(chunk) => {
+                chunk_size = c_int::try_from(chunk).map_err(invalid_input)?;
+                (
+                    ffi::SQLITE_FCNTL_CHUNK_SIZE,
+                    &mut chunk_size as *mut c_int as *mut c_void,
+                )
+            }
+            FileControl::Raw { op, arg } => (op, arg),
+        };
+        match call!(self, xFileControl, op, arg)? {
+            ffi::SQLITE_NOTFOUND => Ok(false),
+            code => check(code).map(|()| true),
+        }
+    }
+
+    fn sector_size(&self, _kind: OpenKind) -> u32 {
+        match call!(self, xSectorSize) {
+            Ok(size) => u32::try_from(size).unwrap_or(0),
+            Err(_) => 1024,
+        }
+    }
+
+    fn device_characteristics(&self, _kind: OpenKind) -> DeviceCharacteristics {
+        match call!(self, xDeviceCharacteristics) {
+            Ok(characteristics) => DeviceCharacteristics(characteristics),
+            Err(_) => DeviceCharacteristics::empty(),
+        }
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        let mut flag = c_int::from(persist);
+        let arg = &mut flag as *mut c_int as *mut c_void;
+        if let Err(err) = call!(self, xFileControl, ffi::SQLITE_FCNTL_PERSIST_WAL, arg) {
+            log::debug!(
+                "delegate does not support SQLITE_FCNTL_PERSIST_WAL: {}",
+                err
+            );
+        }
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        let mut flag: c_int = -1;
+        let arg = &mut flag as *mut c_int as *mut c_void;
+        match call!(self, xFileControl, ffi::SQLITE_FCNTL_PERSIST_WAL, arg) {
+            Ok(ffi::SQLITE_OK) => Some(flag > 0),
+            _ => None,
+        }
+    }
+}
+
+unsafe impl SharedMemory for DelegatingFile {
+    fn map(
+        &mut self,
+        index: u32,
+        size: usize,
+        extend: bool,
+    ) -> Result<Option<NonNull<u8>>, std::io::Error> {
+        let index = c_int::try_from(index).map_err(invalid_input)?;
+        let size = c_int::try_from(size).map_err(invalid_input)?;
+        let mut region = null_mut();
+        check(call!(
+            self,
+            xShmMap,
+            index,
+            size,
+            extend as c_int,
+            &mut region
+        )?)?;
+        Ok(NonNull::new(region as *mut u8))
+    }
+
+    fn lock(&mut self, slots: Range<u8>, lock: ShmLock) -> Result<bool, std::io::Error> {
+        match self.shm_lock(slots, ffi::SQLITE_SHM_LOCK, lock)? {
+            ffi::SQLITE_BUSY => Ok(false),
+            code => check(code).map(|()| true),
+        }
+    }
+
+    fn unlock(&mut self, slots: Range<u8>, lock: ShmLock) -> Result<(), std::io::Error> {
+        check(self.shm_lock(slots, ffi::SQLITE_SHM_UNLOCK, lock)?)
+    }
+
+    fn barrier(&mut self) {
+        let _ = call!(self, xShmBarrier);
+    }
+
+    fn external_reader(&mut self) -> Result<bool, std::io::Error> {
+        let mut reader: c_int = 0;
+        let arg = &mut reader as *mut c_int as *mut c_void;
+        match call!(self, xFileControl, ffi::SQLITE_FCNTL_EXTERNAL_READER, arg)? {
+            ffi::SQLITE_NOTFOUND => Ok(false),
+            code => check(code).map(|()| reader != 0),
+        }
+    }
+
+    fn unmap(&mut self, delete: bool) -> Result<(), std::io::Error> {
+        check(call!(self, xShmUnmap, delete as c_int)?)
+    }
+}
+
+impl DelegatingFile {
+    fn shm_lock(
+        &mut self,
+        slots: Range<u8>,
+        op: c_int,
+        lock: ShmLock,
+    ) -> Result<c_int, std::io::Error> {
+        let kind = match lock {
+            ShmLock::Shared => ffi::SQLITE_SHM_SHARED,
+            ShmLock::Exclusive => ffi::SQLITE_SHM_EXCLUSIVE,
+        };
+        let n = c_int::from(slots.end.saturating_sub(slots.start));
+        call!(self, xShmLock, c_int::from(slots.start), n, op | kind)
+    }
+}
+
+impl Drop for DelegatingFile {
+    fn drop(&mut self) {
+        match call!(self, xClose) {
+            Ok(ffi::SQLITE_OK) | Err(_) => {}
+            Ok(code) => log::warn!("failed to close a delegated file (code {})", code),
+        }
+        let memory = std::ptr::slice_from_raw_parts_mut(self.file.as_ptr() as *mut u64, self.len);
+        drop(unsafe { Box::from_raw(memory) });
+    }
+}
+
+/// Turn the result `code` of the delegate into an error.
+fn check(code: c_int) -> Result<(), std::io::Error> {
+    match code {
+        ffi::SQLITE_OK => Ok(()),
+        code => Err(VfsError::Code(code).into()),
+    }
+}
+
+fn to_cstring(path: &Path) -> Result<CString, std::io::Error> {
+    path_to_cstring(path).map_err(invalid_input)
+}
+
+fn invalid_input<E>(err: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(ErrorKind::InvalidInput, err)
+}
@@ -0,0 +1,89 @@
+//! Counters of the objects the glue allocates for registered VFSs and open files (requires the
+//! `diagnostics` feature).
+//!
+//! All of these objects are handed to SQLite as raw pointers and are only freed once SQLite closes
+//! the corresponding file. Compare [VfsHandle::live_objects](crate::VfsHandle::live_objects) (or
+//! [live_objects] for all VFSs) before and after a workload to detect leaks from files that are
+//! never closed or from mismatched allocations in the glue.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static ALL: Counters = Counters::new();
+
+/// The number of objects currently allocated by the glue.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LiveObjects {
+    /// The state of each registered VFS.
+    pub vfs_states: usize,
+
+    /// The state of each open file.
+    pub file_states: usize,
+
+    /// The boxed files returned by [Vfs::open](crate::Vfs::open).
+    pub files: usize,
+
+    /// The names of registered VFSs and of open files.
+    pub names: usize,
+}
+
+/// Return the number of objects currently allocated by the glue, across all registered VFSs.
+pub fn live_objects() -> LiveObjects {
+    ALL.load()
+}
+
+/// The live object counters of a single VFS, kept in its state.
+#[derive(Default)]
+pub(crate) struct Counters {
+    vfs_states: AtomicUsize,
+    file_states: AtomicUsize,
+    files: AtomicUsize,
+    names: AtomicUsize,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            vfs_states: AtomicUsize::new(0),
+            file_states: AtomicUsize::new(0),
+            files: AtomicUsize::new(0),
+            names: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn load(&self) -> LiveObjects {
+        LiveObjects {
+            vfs_states: self.vfs_states.load(Ordering::SeqCst),
+            file_states: self.file_states.load(Ordering::SeqCst),
+            files: self.files.load(Ordering::SeqCst),
+            names: self.names.load(Ordering::SeqCst),
+        }
+    }
+
+    fn counter(&self, object: &Object) -> &AtomicUsize {
+        match object {
+            Object::VfsState => &self.vfs_states,
+            Object::FileState => &self.file_states,
+            Object::File => &self.files,
+            Object::Name => &self.names,
+        }
+    }
+}
+
+pub(crate) enum Object {
+    VfsState,
+    FileState,
+    File,
+    Name,
+}
+
+/// Count an object allocated for the VFS with the given counters.
+pub(crate) fn allocated(counters: &Counters, object: Object) {
+    counters.counter(&object).fetch_add(1, Ordering::SeqCst);
+    ALL.counter(&object).fetch_add(1, Ordering::SeqCst);
+}
+
+/// Count an object freed by the VFS with the given counters.
+pub(crate) fn freed(counters: &Counters, object: Object) {
+    counters.counter(&object).fetch_sub(1, Ordering::SeqCst);
+    ALL.counter(&object).fetch_sub(1, Ordering::SeqCst);
+}
@@ -0,0 +1,605 @@
+//! Differential testing of a [Vfs] against SQLite's default VFS.
+//!
+//! [run] executes the same SQL statements against a database opened through the [Vfs] under test
+//! and against a database opened through the platform's default VFS. It compares the rows and the
+//! result code of each statement and, at the end, the final database images. The first divergence
+//! is reported together with the operations the [Vfs] under test performed for the statement that
+//! triggered it.
+//!
+//! # Example
+//! ```no_run
+//! # fn example<V: sqlite_vfs::Vfs>(vfs: V) -> Result<(), std::io::Error> {
+//! let divergence = sqlite_vfs::differential::run(
+//!     vfs,
+//!     "main.db",
+//!     &[
+//!         "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT)",
+//!         "INSERT INTO vals (val) VALUES ('a'), ('b')",
+//!         "SELECT * FROM vals",
+//!     ],
+//! )?;
+//! if let Some(divergence) = divergence {
+//!     panic!("{}", divergence);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use libsqlite3_sys as ffi;
+
+use crate::{
+    register, BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenAccess, OpenKind, OpenOptions, RecoveryPhase,
+    SharedMemory, SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// Size of the database header at the start of page 1, which is not compared.
+const DB_HEADER_SIZE: usize = 100;
+
+/// A value of a result row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// The outcome of executing a statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// The rows returned by the statement.
+    pub rows: Vec<Vec<Value>>,
+
+    /// The (extended) result code of the statement.
+    pub code: c_int,
+}
+
+/// The first difference between the [Vfs] under test and the default VFS.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence {
+    /// A statement returned different rows or a different result code.
+    Statement {
+        /// The index of the statement.
+        index: usize,
+        /// The SQL of the statement.
+        sql: String,
+        /// The outcome when using the default VFS.
+        expected: Outcome,
+        /// The outcome when using the [Vfs] under test.
+        actual: Outcome,
+        /// The operations the [Vfs] under test performed while executing the statement.
+        operations: Vec<String>,
+    },
+
+    /// All statements had the same outcome, but the final database images differ.
+    Image {
+        /// The first differing byte offset.
+        offset: u64,
+        /// The size of the database image when using the default VFS.
+        expected_len: u64,
+        /// The size of the database image when using the [Vfs] under test.
+        actual_len: u64,
+        /// The operations the [Vfs] under test performed while executing the last statement.
+        operations: Vec<String>,
+    },
+}
+
+/// Execute `statements` against a database at `path` opened through `vfs`, and against a fresh
+/// database in a temporary directory opened through SQLite's default VFS. Returns the first
+/// [Divergence] found, if any.
+///
+/// The database at `path` should not exist beforehand, as it is compared against a database that
+/// starts out empty.
+pub fn run<V: Vfs>(
+    vfs: V,
+    path: &str,
+    statements: &[&str],
+) -> Result<Option<Divergence>, std::io::Error> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+
+    let operations = Arc::new(Mutex::new(Vec::new()));
+    let vfs = Arc::new(vfs);
+    let name = format!("differential-{}", n);
+    let _handle = register(
+        &name,
+        LoggedVfs {
+            vfs: Arc::clone(&vfs),
+            operations: Arc::clone(&operations),
+        },
+    )
+    .map_err(std::io::Error::other)?;
+
+    let dir = std::env::temp_dir().join(format!(
+        "sqlite-vfs-differential-{}-{}",
+        std::process::id(),
+        n
+    ));
+    std::fs::create_dir_all(&dir)?;
+    let expected_path = dir.join("main.db");
+
+    let result = (|| {
+        let expected_db = Connection::open(&expected_path.to_string_lossy(), None)?;
+        let actual_db = Connection::open(path, Some(&name))?;
+
+        for (index, sql) in statements.iter().enumerate() {
+            operations.lock().unwrap().clear();
+            let expected = expected_db.execute(sql);
+            let actual = actual_db.execute(sql);
+            if expected != actual {
+                return Ok(Some(Divergence::Statement {
+                    index,
+                    sql: sql.to_string(),
+                    expected,
+                    actual,
+                    operations: operations.lock().unwrap().clone(),
+                }));
+            }
+        }
+
+        drop(expected_db);
+        drop(actual_db);
+
+        let expected = std::fs::read(&expected_path)?;
+        let actual = read_image(&*vfs, path)?;
+        let offset = expected
+            .iter()
+            .zip(actual.iter())
+            .enumerate()
+            .skip(DB_HEADER_SIZE)
+            .find(|(_, (a, b))| a != b)
+            .map(|(i, _)| i)
+            .or_else(|| (expected.len() != actual.len()).then(|| expected.len().min(actual.len())));
+        if let Some(offset) = offset {
+            return Ok(Some(Divergence::Image {
+                offset: offset as u64,
+                expected_len: expected.len() as u64,
+                actual_len: actual.len() as u64,
+                operations: operations.lock().unwrap().clone(),
+            }));
+        }
+
+        Ok(None)
+    })();
+
+    std::fs::remove_dir_all(&dir)?;
+
+    result
+}
+
+fn read_image<V: Vfs>(vfs: &V, path: &str) -> Result<Vec<u8>, std::io::Error> {
+    let mut file = vfs.open(
+        path.as_ref(),
+        OpenOptions {
+            kind: OpenKind::MainDb,
+            access: OpenAccess::Read,
+            delete_on_close: false,
+        },
+    )?;
+    let mut data = vec![0; file.file_size()? as usize];
+    let n = file.read_at(&mut data, 0)?;
+    data.truncate(n);
+    Ok(data)
+}
+
+struct Connection(*mut ffi::sqlite3);
+
+impl Connection {
+    fn open(path: &str, vfs: Option<&str>) -> Result<Self, std::io::Error> {
+        let path = CString::new(path)?;
+        let vfs = vfs.map(CString::new).transpose()?;
+        let mut db = null_mut();
+        let code = unsafe {
+            ffi::sqlite3_open_v2(
+                path.as_ptr(),
+                &mut db,
+                ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+                vfs.as_ref().map(|vfs| vfs.as_ptr()).unwrap_or(null_mut()),
+            )
+        };
+        let conn = Connection(db);
+        if code != ffi::SQLITE_OK {
+            return Err(std::io::Error::other(format!(
+                "opening database failed with error code: {}",
+                code
+            )));
+        }
+        unsafe {
+            ffi::sqlite3_extended_result_codes(db, 1);
+        }
+        Ok(conn)
+    }
+
+    fn execute(&self, sql: &str) -> Outcome {
+        let mut rows = Vec::new();
+        let sql = match CString::new(sql) {
+            Ok(sql) => sql,
+            Err(_) => {
+                return Outcome {
+                    rows,
+                    code: ffi::SQLITE_MISUSE,
+                }
+            }
+        };
+
+        unsafe {
+            let mut tail = sql.as_ptr();
+            while *tail != 0 {
+                let mut stmt = null_mut();
+                let code = ffi::sqlite3_prepare_v2(self.0, tail, -1, &mut stmt, &mut tail);
+                if code != ffi::SQLITE_OK {
+                    return Outcome { rows, code };
+                }
+                if stmt.is_null() {
+                    // only whitespace or a comment left
+                    break;
+                }
+
+                let code = loop {
+                    match ffi::sqlite3_step(stmt) {
+                        ffi::SQLITE_ROW => rows.push(row(stmt)),
+                        code => break code,
+                    }
+                };
+                ffi::sqlite3_finalize(stmt);
+                if code != ffi::SQLITE_DONE {
+                    return Outcome { rows, code };
+                }
+            }
+        }
+
+        Outcome {
+            rows,
+            code: ffi::SQLITE_OK,
+        }
+    }
+}
+
+unsafe fn row(stmt: *mut ffi::sqlite3_stmt) -> Vec<Value> {
+    (0..ffi::sqlite3_column_count(stmt))
+        .map(|i| match ffi::sqlite3_column_type(stmt, i) {
+            ffi::SQLITE_INTEGER => Value::Integer(ffi::sqlite3_column_int64(stmt, i)),
+            ffi::SQLITE_FLOAT => Value::Real(ffi::sqlite3_column_double(stmt, i)),
+            ffi::SQLITE_TEXT => {
+                let text = ffi::sqlite3_column_text(stmt, i);
+                Value::Text(CStr::from_ptr(text as _).to_string_lossy().to_string())
+            }
+            ffi::SQLITE_BLOB => {
+                let len = ffi::sqlite3_column_bytes(stmt, i) as usize;
+                let blob = ffi::sqlite3_column_blob(stmt, i) as *const u8;
+                if blob.is_null() {
+                    Value::Blob(Vec::new())
+                } else {
+                    Value::Blob(std::slice::from_raw_parts(blob, len).to_vec())
+                }
+            }
+            _ => Value::Null,
+        })
+        .collect()
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_close(self.0);
+        }
+    }
+}
+
+/// A [Vfs] that records all operations of the [Vfs] it wraps.
+struct LoggedVfs<V> {
+    vfs: Arc<V>,
+    operations: Arc<Mutex<Vec<String>>>,
+}
+
+struct LoggedFile<F> {
+    file: F,
+    path: PathBuf,
+    operations: Arc<Mutex<Vec<String>>>,
+}
+
+impl<V> LoggedVfs<V> {
+    fn log<T, E: std::fmt::Display>(&self, result: &Result<T, E>, operation: String) {
+        log_operation(&self.operations, result, operation);
+    }
+}
+
+impl<F> LoggedFile<F> {
+    fn log<T, E: std::fmt::Display>(&self, result: &Result<T, E>, operation: String) {
+        log_operation(&self.operations, result, operation);
+    }
+}
+
+fn log_operation<T, E: std::fmt::Display>(
+    operations: &Mutex<Vec<String>>,
+    result: &Result<T, E>,
+    operation: String,
+) {
+    let entry = match result {
+        Ok(_) => operation,
+        Err(err) => format!("{} -> {}", operation, err),
+    };
+    operations.lock().unwrap().push(entry);
+}
+
+impl<V: Vfs> Vfs for LoggedVfs<V> {
+    type File = LoggedFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let operation = format!("open {} {:?}", path.display(), opts);
+        let result = self.vfs.open(path, opts);
+        self.log(&result, operation);
+        Ok(LoggedFile {
+            file: result?,
+            path: path.to_path_buf(),
+            operations: Arc::clone(&self.operations),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        let result = self.vfs.delete(path);
+        self.log(&result, format!("delete {}", path.display()));
+        result
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        let result = self.vfs.exists(path);
+        self.log(&result, format!("exists {}", path.display()));
+        result
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        let result = self.vfs.access(path, write);
+        self.log(
+            &result,
+            format!("access {} write={}", path.display(), write),
+        );
+        result
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        let result = self.vfs.list(prefix);
+        self.log(&result, format!("list {}", prefix.display()));
+        result
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        let result = self.vfs.rename(from, to);
+        self.log(
+            &result,
+            format!("rename {} {}", from.display(), to.display()),
+        );
+        result
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        let result = self.vfs.on_recovery(path, phase);
+        self.log(
+            &result,
+            format!("on_recovery {} {:?}", path.display(), phase),
+        );
+        result
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl<F: File> File for LoggedFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let result = self.file.read_at(buf, offset);
+        self.log(
+            &result,
+            format!(
+                "read {} offset={} len={}",
+                self.path.display(),
+                offset,
+                buf.len()
+            ),
+        );
+        result
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let result = self.file.write_all_at(buf, offset);
+        self.log(
+            &result,
+            format!(
+                "write {} offset={} len={}",
+                self.path.display(),
+                offset,
+                buf.len()
+            ),
+        );
+        result
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        let result = self.file.sync(options);
+        self.log(&result, format!("sync {}", self.path.display()));
+        result
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        let result = self.file.file_size();
+        self.log(&result, format!("file_size {}", self.path.display()));
+        result
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let result = self.file.truncate(size);
+        self.log(
+            &result,
+            format!("truncate {} size={}", self.path.display(), size),
+        );
+        result
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        let result = self.file.metadata();
+        self.log(&result, format!("metadata {}", self.path.display()));
+        result
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let result = self.file.lock(lock);
+        self.log(&result, format!("lock {} {:?}", self.path.display(), lock));
+        result
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        let result = self.file.unlock(lock);
+        self.log(
+            &result,
+            format!("unlock {} {:?}", self.path.display(), lock),
+        );
+        result
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.file.shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        self.file.memory_mapped()
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        self.file.batch_atomic_write()
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.file.file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        self.file.device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Statement {
+                index,
+                sql,
+                expected,
+                actual,
+                operations,
+            } => {
+                writeln!(f, "statement #{} diverged: {}", index, sql)?;
+                writeln!(f, "  expected: {:?}", expected)?;
+                writeln!(f, "  actual:   {:?}", actual)?;
+                write_operations(f, operations)
+            }
+            Self::Image {
+                offset,
+                expected_len,
+                actual_len,
+                operations,
+            } => {
+                writeln!(
+                    f,
+                    "database images differ at offset {} (expected {} bytes, got {} bytes)",
+                    offset, expected_len, actual_len
+                )?;
+                write_operations(f, operations)
+            }
+        }
+    }
+}
+
+fn write_operations(f: &mut std::fmt::Formatter<'_>, operations: &[String]) -> std::fmt::Result {
+    writeln!(f, "  operations:")?;
+    for op in operations {
+        writeln!(f, "    {}", op)?;
+    }
+    Ok(())
+}
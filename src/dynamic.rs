@@ -0,0 +1,296 @@
+//! Register a [Vfs] that is only chosen at runtime (e.g. from a configuration file).
+//!
+//! [register](crate::register) needs to know the type of the files a [Vfs] opens, so different
+//! backends cannot be put behind the same type directly. A [DynVfs] is a boxed [Vfs] whose files
+//! are boxed as well ([DynFile]), and can be registered like any other [Vfs]. Use [boxed] to turn
+//! any [Vfs] into a [DynVfs].
+//!
+//! ```
+//! use sqlite_vfs::dynamic::{boxed, DynVfs};
+//! # use std::path::Path;
+//! # use sqlite_vfs::{OpenOptions, Vfs};
+//! # struct FsVfs;
+//! # impl Vfs for FsVfs {
+//! #     type File = std::fs::File;
+//! #     fn open(&self, path: &Path, _: OpenOptions) -> Result<Self::File, std::io::Error> {
+//! #         std::fs::File::open(path)
+//! #     }
+//! #     fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+//! #         std::fs::remove_file(path)
+//! #     }
+//! #     fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+//! #         Ok(path.is_file())
+//! #     }
+//! # }
+//!
+//! fn from_config(backend: &str) -> Option<DynVfs> {
+//!     match backend {
+//!         "fs" => Some(boxed(FsVfs)),
+//!         "fenced-fs" => Some(boxed(sqlite_vfs::fencing::FencedVfs::new(FsVfs))),
+//!         _ => None,
+//!     }
+//! }
+//!
+//! let _vfs = sqlite_vfs::register("configured", from_config("fenced-fs").unwrap()).unwrap();
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenKind, OpenOptions, RecoveryPhase, SharedMemory,
+    SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// A file opened by a [DynVfs].
+pub type DynFile = Box<dyn File>;
+
+/// A [Vfs] whose type is only known at runtime.
+pub type DynVfs = Box<dyn Vfs<File = DynFile>>;
+
+/// Box `vfs` and the files it opens.
+pub fn boxed<V>(vfs: V) -> DynVfs
+where
+    V: Vfs + 'static,
+    V::File: 'static,
+{
+    Box::new(BoxedFiles(vfs))
+}
+
+/// A [Vfs] that boxes the files opened by the wrapped [Vfs].
+struct BoxedFiles<V>(V);
+
+impl<V> Vfs for BoxedFiles<V>
+where
+    V: Vfs,
+    V::File: 'static,
+{
+    type File = DynFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(Box::new(self.0.open(path, opts)?))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.0.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.0.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.0.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.0.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.0.rename(from, to)
+    }
+
+    fn health(&self) -> HealthReport {
+        self.0.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.0.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.0.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.0.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.0.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.0.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.0.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.0.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.0.system_calls()
+    }
+}
+
+impl<V: Vfs + ?Sized> Vfs for Box<V> {
+    type File = V::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        (**self).open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        (**self).delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        (**self).exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        (**self).access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        (**self).list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        (**self).rename(from, to)
+    }
+
+    fn health(&self) -> HealthReport {
+        (**self).health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        (**self).checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        (**self).on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        (**self).full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        (**self).random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        (**self).sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        (**self).current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        (**self).temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        (**self).system_calls()
+    }
+}
+
+impl File for DynFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        (**self).write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        (**self).sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        (**self).file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        (**self).truncate(size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        (**self).metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        (**self).lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        (**self).unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        (**self).check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        (**self).shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        (**self).memory_mapped()
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        (**self).batch_atomic_write()
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        (**self).file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        (**self).sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        (**self).device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        (**self).pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        (**self).read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        (**self).moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        (**self).pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        (**self).post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        (**self).begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        (**self).data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        (**self).set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        (**self).persist_wal()
+    }
+}
@@ -0,0 +1,484 @@
+//! Inject I/O errors and crashes into a [Vfs], to test how an application (and a custom backend)
+//! copes with them.
+//!
+//! A [FaultVfs] forwards everything to the [Vfs] it wraps, until it is told otherwise through its
+//! [Faults]: it can fail the Nth write, return a short read, pretend to sync without syncing, or
+//! crash. A crash discards everything written since the last sync of each file (as a power loss
+//! would), after which all files that were open fail every operation. Files opened after the crash
+//! see what survived it, as if the application had been restarted:
+//!
+//! ```
+//! use sqlite_vfs::fault::FaultVfs;
+//! use sqlite_vfs::mem::MemVfs;
+//!
+//! let vfs = FaultVfs::new(MemVfs::new());
+//! let faults = vfs.faults();
+//! let _vfs = sqlite_vfs::register("faulty", vfs).unwrap();
+//!
+//! // crash at the third write from now (e.g. in the middle of the next transaction), and then
+//! // reopen the database to check that it is still consistent
+//! faults.crash_at_write(3);
+//! ```
+//!
+//! Only the contents of files are rolled back by a crash. Files that were deleted or renamed stay
+//! that way, and shared memory (e.g. of a [ShmVfs](crate::shm::ShmVfs) wrapping the [FaultVfs])
+//! is kept.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenAccess, OpenKind, OpenOptions, RecoveryPhase,
+    SharedMemory, SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// A [Vfs] that injects the faults scripted through its [Faults] into the [Vfs] it wraps.
+pub struct FaultVfs<V> {
+    vfs: Arc<V>,
+    shared: Arc<Shared>,
+}
+
+/// A file opened by [FaultVfs].
+pub struct FaultFile<F> {
+    file: F,
+    path: PathBuf,
+    kind: OpenKind,
+    /// Whether writes are undone by a crash (which is not necessary for files deleted on close).
+    tracked: bool,
+    /// The number of crashes before the file was opened.
+    epoch: u64,
+    shared: Arc<Shared>,
+}
+
+/// Scripts the faults of a [FaultVfs]. Writes and reads are counted across all files of the
+/// [FaultVfs], starting at 1 for the next one after a fault is scheduled.
+#[derive(Clone)]
+pub struct Faults {
+    shared: Arc<Shared>,
+}
+
+/// Restores a file to its contents of the last sync, by opening it again through the wrapped [Vfs].
+type Restore = dyn Fn(&Path, Unsynced) -> Result<(), std::io::Error> + Send + Sync;
+
+struct Shared {
+    state: Mutex<State>,
+    restore: Box<Restore>,
+}
+
+#[derive(Default)]
+struct State {
+    writes: u64,
+    reads: u64,
+    fail_writes: BTreeSet<u64>,
+    short_reads: BTreeSet<u64>,
+    crash_at: Option<u64>,
+    drop_syncs: bool,
+    crashes: u64,
+    unsynced: HashMap<PathBuf, Unsynced>,
+}
+
+/// What has to be undone to restore a file to its contents of the last sync.
+struct Unsynced {
+    kind: OpenKind,
+    size: u64,
+    /// The previous contents of the ranges that have been overwritten, oldest first.
+    undo: Vec<(u64, Vec<u8>)>,
+}
+
+/// What to do instead of a write.
+enum WriteFault {
+    Fail,
+    Crash,
+}
+
+impl<V: Vfs + 'static> FaultVfs<V> {
+    /// Wrap `vfs`, without injecting any faults yet.
+    pub fn new(vfs: V) -> Self {
+        let vfs = Arc::new(vfs);
+        let restore = {
+            let vfs = Arc::clone(&vfs);
+            move |path: &Path, unsynced: Unsynced| restore(&*vfs, path, unsynced)
+        };
+        FaultVfs {
+            vfs,
+            shared: Arc::new(Shared {
+                state: Mutex::default(),
+                restore: Box::new(restore),
+            }),
+        }
+    }
+}
+
+impl<V> FaultVfs<V> {
+    /// The [Faults] to script the faults of this [FaultVfs] (also after it has been registered).
+    pub fn faults(&self) -> Faults {
+        Faults {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Faults {
+    /// Fail the `n`th write from now with an I/O error (`SQLITE_IOERR_WRITE`).
+    pub fn fail_write(&self, n: u64) {
+        let mut state = self.shared.state();
+        let at = state.writes + n;
+        state.fail_writes.insert(at);
+    }
+
+    /// Return only half of the requested bytes for the `n`th read from now (which SQLite reports
+    /// as `SQLITE_IOERR_SHORT_READ`).
+    pub fn short_read(&self, n: u64) {
+        let mut state = self.shared.state();
+        let at = state.reads + n;
+        state.short_reads.insert(at);
+    }
+
+    /// Crash instead of doing the `n`th write from now.
+    pub fn crash_at_write(&self, n: u64) {
+        let mut state = self.shared.state();
+        state.crash_at = Some(state.writes + n);
+    }
+
+    /// Let syncs succeed without syncing anything, so that a crash discards all writes since
+    /// syncs started to be dropped.
+    pub fn drop_syncs(&self, drop: bool) {
+        self.shared.state().drop_syncs = drop;
+    }
+
+    /// Crash now: discard all writes since the last sync of each file, and fail all operations on
+    /// the files that are open.
+    pub fn crash(&self) -> Result<(), std::io::Error> {
+        self.shared.crash()
+    }
+
+    /// The number of crashes so far.
+    pub fn crashes(&self) -> u64 {
+        self.shared.state().crashes
+    }
+
+    /// The number of writes so far.
+    pub fn writes(&self) -> u64 {
+        self.shared.state().writes
+    }
+
+    /// Cancel all scheduled faults, and sync normally again.
+    pub fn clear(&self) {
+        let mut state = self.shared.state();
+        state.fail_writes.clear();
+        state.short_reads.clear();
+        state.crash_at = None;
+        state.drop_syncs = false;
+    }
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn crash(&self) -> Result<(), std::io::Error> {
+        let unsynced = {
+            let mut state = self.state();
+            state.crashes += 1;
+            state.crash_at = None;
+            std::mem::take(&mut state.unsynced)
+        };
+        log::trace!(
+            "crash, discarding unsynced writes to {} files",
+            unsynced.len()
+        );
+        for (path, unsynced) in unsynced {
+            (self.restore)(&path, unsynced)?;
+        }
+        Ok(())
+    }
+}
+
+impl<V: Vfs> Vfs for FaultVfs<V> {
+    type File = FaultFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let kind = opts.kind;
+        let tracked = !opts.delete_on_close;
+        let file = self.vfs.open(path, opts)?;
+        Ok(FaultFile {
+            file,
+            path: path.to_path_buf(),
+            kind,
+            tracked,
+            epoch: self.shared.state().crashes,
+            shared: Arc::clone(&self.shared),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)?;
+        self.shared.state().unsynced.remove(path);
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.vfs.rename(from, to)?;
+        let mut state = self.shared.state();
+        if let Some(unsynced) = state.unsynced.remove(from) {
+            state.unsynced.insert(to.to_path_buf(), unsynced);
+        }
+        Ok(())
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.vfs.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+/// Undo the unsynced writes to the file at `path`.
+fn restore<V: Vfs>(vfs: &V, path: &Path, unsynced: Unsynced) -> Result<(), std::io::Error> {
+    let opts = OpenOptions {
+        kind: unsynced.kind,
+        access: OpenAccess::Write,
+        delete_on_close: false,
+    };
+    let mut file = vfs.open(path, opts)?;
+    for (offset, data) in unsynced.undo.iter().rev() {
+        file.write_all_at(data, *offset)?;
+    }
+    file.truncate(unsynced.size)?;
+    file.sync(SyncOptions::default())
+}
+
+impl<F: File> FaultFile<F> {
+    /// Fail if the [FaultVfs] crashed since the file was opened.
+    fn check(&self) -> Result<(), std::io::Error> {
+        if self.shared.state().crashes > self.epoch {
+            return Err(std::io::Error::other("file was open during a crash"));
+        }
+        Ok(())
+    }
+
+    /// Remember the contents of `len` bytes at `offset`, before they are overwritten or truncated.
+    fn save(&mut self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        if !self.tracked {
+            return Ok(());
+        }
+        let size = self.file.file_size()?;
+        let mut data = vec![0; len.min(size.saturating_sub(offset)) as usize];
+        if !data.is_empty() {
+            let n = self.file.read_at(&mut data, offset)?;
+            data.truncate(n);
+        }
+        let mut state = self.shared.state();
+        let unsynced = state
+            .unsynced
+            .entry(self.path.clone())
+            .or_insert_with(|| Unsynced {
+                kind: self.kind,
+                size,
+                undo: Vec::new(),
+            });
+        if !data.is_empty() {
+            unsynced.undo.push((offset, data));
+        }
+        Ok(())
+    }
+}
+
+impl<F: File> File for FaultFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.check()?;
+        let short = {
+            let mut state = self.shared.state();
+            state.reads += 1;
+            let n = state.reads;
+            state.short_reads.remove(&n)
+        };
+        if short {
+            log::trace!("inject short read at {}", offset);
+            let len = buf.len() / 2;
+            return self.file.read_at(&mut buf[..len], offset);
+        }
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.check()?;
+        let fault = {
+            let mut state = self.shared.state();
+            state.writes += 1;
+            let n = state.writes;
+            if state.crash_at == Some(n) {
+                Some(WriteFault::Crash)
+            } else if state.fail_writes.remove(&n) {
+                Some(WriteFault::Fail)
+            } else {
+                None
+            }
+        };
+        match fault {
+            Some(WriteFault::Fail) => {
+                log::trace!("inject write fault at {}", offset);
+                Err(std::io::Error::other("injected write fault"))
+            }
+            Some(WriteFault::Crash) => {
+                self.shared.crash()?;
+                Err(std::io::Error::other("file was open during a crash"))
+            }
+            None => {
+                self.save(offset, buf.len() as u64)?;
+                self.file.write_all_at(buf, offset)
+            }
+        }
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.check()?;
+        if self.shared.state().drop_syncs {
+            return Ok(());
+        }
+        self.file.sync(options)?;
+        self.shared.state().unsynced.remove(&self.path);
+        Ok(())
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.check()?;
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.check()?;
+        self.save(size, u64::MAX)?;
+        self.file.truncate(size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.check()?;
+        self.file.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        // locks are released as a crashed process would release them
+        self.file.unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.file.shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        // writes through mapped memory could not be undone
+        None
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        // neither could those of an atomic batch
+        None
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.file.file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        self.file.device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
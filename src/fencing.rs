@@ -0,0 +1,327 @@
+//! Detect a second writer that changed the database behind the back of a connection (e.g. after a
+//! split brain of a replicated backend, where locks do not protect the database anymore).
+//!
+//! SQLite increments the file change counter in the database header with each commit (in rollback
+//! journal mode). A [FencedVfs] remembers the counter each main database file last read or wrote.
+//! Before the first write of a transaction and before the header is written, it checks that the
+//! counter stored by the backend is still the same. If it is not, someone else committed in the
+//! meantime, and the write fails with [VfsError::Fenced] (`SQLITE_IOERR_VNODE`) instead of
+//! overwriting their changes. If nothing has been written to the database yet, the rollback journal
+//! of the fenced transaction is deleted, as rolling back its outdated pages would overwrite the
+//! changes of the other writer as well.
+//!
+//! A check and the following write are two separate operations, so a writer committing right in
+//! between is not detected. Backends with conditional writes should use them in addition.
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenKind, OpenOptions, RecoveryPhase, SharedMemory,
+    SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsError, VfsMetadata,
+};
+
+/// Location of the file change counter in the database header.
+const CHANGE_COUNTER: Range<u64> = 24..28;
+
+/// A [Vfs] that fences off writes to main databases that another writer changed in the meantime.
+pub struct FencedVfs<V> {
+    vfs: Arc<V>,
+}
+
+/// A file opened by [FencedVfs].
+pub struct FencedFile<F> {
+    file: F,
+    fence: Option<Fence>,
+}
+
+/// The fencing state of a main database file.
+struct Fence {
+    /// The change counter last read from or written to the database.
+    generation: Option<[u8; 4]>,
+    /// Whether the change counter has been checked since it was last read.
+    verified: bool,
+    /// Delete the rollback journal of the database.
+    discard_journal: Box<dyn Fn() -> Result<(), std::io::Error> + Send>,
+}
+
+impl<V> FencedVfs<V> {
+    /// Wrap `vfs` and fence writes to all main databases opened through it.
+    pub fn new(vfs: V) -> Self {
+        FencedVfs { vfs: Arc::new(vfs) }
+    }
+}
+
+impl<V: Vfs + 'static> Vfs for FencedVfs<V> {
+    type File = FencedFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let fence = (opts.kind == OpenKind::MainDb).then(|| {
+            let vfs = Arc::clone(&self.vfs);
+            let mut journal = path.as_os_str().to_owned();
+            journal.push("-journal");
+            let journal = PathBuf::from(journal);
+            Fence {
+                generation: None,
+                verified: false,
+                discard_journal: Box::new(move || match vfs.delete(&journal) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+                    _ => Ok(()),
+                }),
+            }
+        });
+        let file = self.vfs.open(path, opts)?;
+        Ok(FencedFile { file, fence })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.vfs.rename(from, to)
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.vfs.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl<F: File> FencedFile<F> {
+    /// The change counter contained in `buf` if it was read from or written to a main database at
+    /// `offset`.
+    fn change_counter(&self, offset: u64, buf: &[u8]) -> Option<[u8; 4]> {
+        if self.fence.is_none()
+            || offset > CHANGE_COUNTER.start
+            || offset + (buf.len() as u64) < CHANGE_COUNTER.end
+        {
+            return None;
+        }
+        let start = (CHANGE_COUNTER.start - offset) as usize;
+        let mut counter = [0; 4];
+        counter.copy_from_slice(&buf[start..start + 4]);
+        Some(counter)
+    }
+
+    /// Read the change counter currently stored by the backend (if the file contains a header).
+    fn stored_change_counter(&mut self) -> Result<Option<[u8; 4]>, std::io::Error> {
+        if self.file.file_size()? < CHANGE_COUNTER.end {
+            return Ok(None);
+        }
+        let mut counter = [0; 4];
+        if self.file.read_at(&mut counter, CHANGE_COUNTER.start)? < counter.len() {
+            return Ok(None);
+        }
+        Ok(Some(counter))
+    }
+
+    /// Make sure nobody else changed the database before writing `buf` at `offset`.
+    fn check(&mut self, offset: u64, buf: &[u8]) -> Result<(), std::io::Error> {
+        let writes_header = self.change_counter(offset, buf).is_some();
+        let (generation, verified) = match &self.fence {
+            Some(fence) => (fence.generation, fence.verified),
+            None => return Ok(()),
+        };
+        let generation = match generation {
+            Some(generation) if !verified || writes_header => generation,
+            _ => return Ok(()),
+        };
+
+        match self.stored_change_counter()? {
+            Some(stored) if stored != generation => {
+                log::warn!(
+                    "database changed by another writer (expected change counter {}, found {})",
+                    u32::from_be_bytes(generation),
+                    u32::from_be_bytes(stored)
+                );
+                if let Some(fence) = &self.fence {
+                    if !verified {
+                        // nothing has been written yet, so there is nothing to roll back
+                        (fence.discard_journal)()?;
+                    }
+                }
+                Err(VfsError::Fenced.into())
+            }
+            _ => {
+                if let Some(fence) = &mut self.fence {
+                    fence.verified = true;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<F: File> File for FencedFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let n = self.file.read_at(buf, offset)?;
+
+        // a new transaction starts by reading the header
+        if let Some(counter) = self.change_counter(offset, &buf[..n]) {
+            if let Some(fence) = &mut self.fence {
+                fence.generation = Some(counter);
+                fence.verified = false;
+            }
+        }
+
+        Ok(n)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.check(offset, buf)?;
+        self.file.write_all_at(buf, offset)?;
+
+        // only remember the new change counter once it is stored completely
+        if let Some(counter) = self.change_counter(offset, buf) {
+            if let Some(fence) = &mut self.fence {
+                fence.generation = Some(counter);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.file.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        if size < CHANGE_COUNTER.end {
+            if let Some(fence) = &mut self.fence {
+                fence.generation = None;
+            }
+        }
+        self.file.truncate(size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.file.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.file.unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.file.shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        self.file.memory_mapped()
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        self.file.batch_atomic_write()
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.file.file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        self.file.device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
@@ -0,0 +1,250 @@
+//! Remove the journals and WALs that crashed processes left behind, with
+//! [VfsHandle::collect_garbage].
+//!
+//! A side file is only removed while the garbage collector holds an exclusive lock on its
+//! database (so no connection reads or writes it), and only if it holds nothing SQLite still has to
+//! recover: a hot journal (whose pages have to be rolled back) or a WAL with frames (which may hold
+//! committed transactions) is kept, as removing it would corrupt the database or lose commits.
+//! SQLite recovers those the next time the database is opened.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use libsqlite3_sys as ffi;
+
+use crate::{
+    database_path, path_from_bytes, path_to_cstring, File, OpenAccess, OpenKind, OpenOptions,
+    RawFile, State, Vfs, VfsEntry, VfsError, VfsHandle,
+};
+
+/// The size of the header of a WAL, which holds no frames if it is not any longer.
+const WAL_HEADER_SIZE: u64 = 32;
+
+/// The options of [VfsHandle::collect_garbage].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GarbageOptions {
+    /// Only side files last modified at least this long ago are collected. Side files whose
+    /// modification time is unknown (see [File::metadata]) are only collected if this is zero.
+    pub min_age: Duration,
+
+    /// Only report the orphaned side files instead of removing them.
+    pub dry_run: bool,
+}
+
+/// The side files found by [VfsHandle::collect_garbage], old enough to be collected.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct GarbageReport {
+    /// The orphaned side files, which were removed (unless [GarbageOptions::dry_run] is set).
+    pub orphaned: Vec<VfsEntry>,
+
+    /// The side files of databases that connections currently use, which were kept.
+    pub in_use: Vec<VfsEntry>,
+
+    /// The side files that SQLite still has to recover (a hot journal or a WAL with frames), which
+    /// were kept.
+    pub needs_recovery: Vec<VfsEntry>,
+}
+
+/// A journal or WAL listed by [Vfs::list], as inspected by [side_files].
+pub(crate) struct SideFile {
+    entry: VfsEntry,
+    kind: OpenKind,
+    database: PathBuf,
+    modified: Option<SystemTime>,
+    /// The first byte of the file, which is zero for a journal that is not hot.
+    first_byte: u8,
+}
+
+impl VfsHandle {
+    /// Find the rollback journals and WALs whose path starts with `prefix` (see [Vfs::list]) and
+    /// remove those that are orphaned: side files of databases that do not exist anymore, and side
+    /// files of unused databases that hold nothing to recover. Whether a database is unused is
+    /// checked with the locking API: the side files are only removed while holding an exclusive
+    /// lock on their database, which fails as long as any connection reads or writes it (or keeps
+    /// it open in WAL mode).
+    ///
+    /// Fails with [ErrorKind::Unsupported](std::io::ErrorKind::Unsupported) if the VFS cannot
+    /// list its objects.
+    pub fn collect_garbage(
+        &self,
+        prefix: impl AsRef<Path>,
+        options: GarbageOptions,
+    ) -> Result<GarbageReport, std::io::Error> {
+        let files = unsafe { (self.side_files)(self.vfs.as_ptr(), prefix.as_ref())? };
+        let now = SystemTime::now();
+        let mut databases = BTreeMap::<PathBuf, Vec<SideFile>>::new();
+        for file in files {
+            let old_enough = match file.modified {
+                Some(modified) => {
+                    now.duration_since(modified).unwrap_or_default() >= options.min_age
+                }
+                None => options.min_age.is_zero(),
+            };
+            if old_enough {
+                databases
+                    .entry(file.database.clone())
+                    .or_default()
+                    .push(file);
+            }
+        }
+
+        let mut report = GarbageReport::default();
+        for (database, files) in databases {
+            if !self.access(&database)? {
+                for file in files {
+                    self.collect(file.entry, &options, &mut report)?;
+                }
+                continue;
+            }
+
+            let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE;
+            let mut db = unsafe { self.open_raw(&database, flags)? };
+            let size = match unsafe { lock_exclusive(&mut db)? } {
+                Some(size) => size,
+                None => {
+                    report
+                        .in_use
+                        .extend(files.into_iter().map(|file| file.entry));
+                    continue;
+                }
+            };
+            for file in files {
+                // SQLite deletes the side files of an empty database instead of recovering them
+                let recover = size > 0
+                    && match file.kind {
+                        OpenKind::Wal => file.entry.size > WAL_HEADER_SIZE,
+                        _ => file.first_byte != 0,
+                    };
+                if recover {
+                    report.needs_recovery.push(file.entry);
+                } else {
+                    self.collect(file.entry, &options, &mut report)?;
+                }
+            }
+            // closing the database releases the lock
+            drop(db);
+        }
+        Ok(report)
+    }
+
+    /// Remove an orphaned side file (unless it is a dry run) and report it.
+    fn collect(
+        &self,
+        entry: VfsEntry,
+        options: &GarbageOptions,
+        report: &mut GarbageReport,
+    ) -> Result<(), std::io::Error> {
+        if !options.dry_run {
+            self.delete(&entry.path)?;
+            log::info!("removed the orphaned {}", entry.path.display());
+        }
+        report.orphaned.push(entry);
+        Ok(())
+    }
+
+    /// Delete the object at `path`, as SQLite asks the VFS.
+    pub(crate) fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        let path = path_to_cstring(path)?;
+        let vfs = unsafe { self.vfs.as_ref() };
+        let delete = vfs.xDelete.ok_or(VfsError::Code(ffi::SQLITE_MISUSE))?;
+        let code = unsafe { delete(self.vfs.as_ptr(), path.as_ptr(), 0) };
+        if code != ffi::SQLITE_OK {
+            return Err(VfsError::Code(code).into());
+        }
+        Ok(())
+    }
+
+    /// Whether the object at `path` exists, as SQLite asks the VFS.
+    pub(crate) fn access(&self, path: &Path) -> Result<bool, std::io::Error> {
+        let path = path_to_cstring(path)?;
+        let vfs = unsafe { self.vfs.as_ref() };
+        let access = vfs.xAccess.ok_or(VfsError::Code(ffi::SQLITE_MISUSE))?;
+        let mut exists = 0;
+        let code = unsafe {
+            access(
+                self.vfs.as_ptr(),
+                path.as_ptr(),
+                ffi::SQLITE_ACCESS_EXISTS,
+                &mut exists,
+            )
+        };
+        if code != ffi::SQLITE_OK {
+            return Err(VfsError::Code(code).into());
+        }
+        Ok(exists != 0)
+    }
+}
+
+/// Lock the database `file` exclusively, the way a writer does, and return its size, or `None` if
+/// another connection holds a lock on it.
+pub(crate) unsafe fn lock_exclusive(file: &mut RawFile) -> Result<Option<u64>, std::io::Error> {
+    let file = file.as_ptr();
+    let methods = &*(*file).pMethods;
+    let lock = methods.xLock.ok_or(VfsError::Code(ffi::SQLITE_MISUSE))?;
+    for kind in [
+        ffi::SQLITE_LOCK_SHARED,
+        ffi::SQLITE_LOCK_RESERVED,
+        ffi::SQLITE_LOCK_EXCLUSIVE,
+    ] {
+        match lock(file, kind) {
+            ffi::SQLITE_OK => {}
+            ffi::SQLITE_BUSY => return Ok(None),
+            code => return Err(VfsError::Code(code).into()),
+        }
+    }
+    let file_size = methods
+        .xFileSize
+        .ok_or(VfsError::Code(ffi::SQLITE_MISUSE))?;
+    let mut size = 0;
+    let code = file_size(file, &mut size);
+    if code != ffi::SQLITE_OK {
+        return Err(VfsError::Code(code).into());
+    }
+    Ok(Some(size as u64))
+}
+
+/// List the journals and WALs of a [Vfs] whose path starts with `prefix`, along with what
+/// [VfsHandle::collect_garbage] needs to know about them. Set as the `side_files` of the
+/// [VfsHandle], which does not know the type of the VFS.
+pub(crate) unsafe fn side_files<V: Vfs>(
+    ptr: *mut ffi::sqlite3_vfs,
+    prefix: &Path,
+) -> Result<Vec<SideFile>, std::io::Error> {
+    let state = &*((*ptr).pAppData as *const State<V>);
+    let mut files = Vec::new();
+    for entry in state.vfs.list(prefix)? {
+        let entry = entry?;
+        let path = path_to_cstring(&entry.path)?;
+        let (kind, database) = match path.to_bytes().strip_suffix(b"-wal") {
+            Some(database) => (OpenKind::Wal, path_from_bytes(database)),
+            None if path.to_bytes().ends_with(b"-journal") => {
+                (OpenKind::MainJournal, database_path(&path))
+            }
+            None => continue,
+        };
+        let opts = OpenOptions {
+            kind,
+            access: OpenAccess::Read,
+            delete_on_close: false,
+        };
+        let mut file = match state.vfs.open(&entry.path, opts) {
+            Ok(file) => file,
+            // removed since it was listed
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let modified = file.metadata()?.modified;
+        let mut first_byte = [0];
+        file.read_at(&mut first_byte, 0)?;
+        files.push(SideFile {
+            entry,
+            kind,
+            database,
+            modified,
+            first_byte: first_byte[0],
+        });
+    }
+    Ok(files)
+}
@@ -0,0 +1,174 @@
+//! Decode the header at the start of a database file without opening a connection.
+//!
+//! Shims need to know the page size or the number of reserved bytes of a database before SQLite
+//! tells them, and management tooling wants to inspect databases without going through SQLite. The
+//! header is read through the [Vfs] (or [File]) the database is stored in.
+//!
+//! See <https://www.sqlite.org/fileformat2.html#the_database_header> for the layout.
+
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::{File, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+/// Size of the database header at the start of page 1.
+pub const HEADER_SIZE: usize = 100;
+
+/// The magic string every database file starts with.
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// The decoded database header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseHeader {
+    /// The size of each page in bytes.
+    pub page_size: u32,
+
+    /// Whether the database was last written in WAL mode or with a rollback journal.
+    pub journal_mode: JournalMode,
+
+    /// The number of bytes reserved at the end of each page.
+    pub reserve_bytes: u8,
+
+    /// Incremented by each transaction that changes the database (only in rollback journal mode).
+    pub change_counter: u32,
+
+    /// The size of the database in pages. Only valid if [DatabaseHeader::version_valid_for]
+    /// matches the change counter, as older versions of SQLite do not keep it up to date.
+    pub page_count: u32,
+
+    /// The value of the change counter when [DatabaseHeader::page_count] was last written.
+    pub version_valid_for: u32,
+
+    /// The page number of the first freelist trunk page (0 if there are no free pages).
+    pub first_freelist_page: u32,
+
+    /// The number of free pages.
+    pub freelist_pages: u32,
+
+    /// Incremented whenever the schema changes.
+    pub schema_cookie: u32,
+
+    /// The encoding of all text in the database, or `None` if the database does not contain any
+    /// tables yet.
+    pub text_encoding: Option<TextEncoding>,
+
+    /// The value of `PRAGMA user_version`.
+    pub user_version: u32,
+
+    /// The value of `PRAGMA application_id`.
+    pub application_id: u32,
+
+    /// The version of SQLite that last wrote the database (e.g. 3038002 for 3.38.2).
+    pub sqlite_version: u32,
+}
+
+/// The journal mode recorded in the file format version numbers of the header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalMode {
+    /// A rollback journal (legacy file format).
+    Rollback,
+
+    /// A write-ahead log.
+    Wal,
+}
+
+/// The encoding of all text stored in a database.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl DatabaseHeader {
+    /// Decode the first [HEADER_SIZE] bytes of `page`.
+    pub fn parse(page: &[u8]) -> Result<Self, std::io::Error> {
+        if page.len() < HEADER_SIZE || &page[..16] != MAGIC {
+            return Err(invalid_data("file is not a SQLite database"));
+        }
+
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                page[offset],
+                page[offset + 1],
+                page[offset + 2],
+                page[offset + 3],
+            ])
+        };
+
+        // page sizes of 65536 do not fit into two bytes and are stored as 1
+        let page_size = match u16::from_be_bytes([page[16], page[17]]) {
+            1 => 65536,
+            n => n as u32,
+        };
+        if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+            return Err(invalid_data(format!("invalid page size {}", page_size)));
+        }
+
+        let journal_mode = match (page[18], page[19]) {
+            (1, 1) => JournalMode::Rollback,
+            (2, 2) => JournalMode::Wal,
+            (write, read) => {
+                return Err(invalid_data(format!(
+                    "unsupported file format version (write {}, read {})",
+                    write, read
+                )))
+            }
+        };
+
+        let text_encoding = match u32_at(56) {
+            0 => None,
+            1 => Some(TextEncoding::Utf8),
+            2 => Some(TextEncoding::Utf16Le),
+            3 => Some(TextEncoding::Utf16Be),
+            n => return Err(invalid_data(format!("invalid text encoding {}", n))),
+        };
+
+        Ok(DatabaseHeader {
+            page_size,
+            journal_mode,
+            reserve_bytes: page[20],
+            change_counter: u32_at(24),
+            page_count: u32_at(28),
+            version_valid_for: u32_at(92),
+            first_freelist_page: u32_at(32),
+            freelist_pages: u32_at(36),
+            schema_cookie: u32_at(40),
+            text_encoding,
+            user_version: u32_at(60),
+            application_id: u32_at(68),
+            sqlite_version: u32_at(96),
+        })
+    }
+
+    /// Read the header from the start of `file`. Returns `None` if the file is empty, as it does not
+    /// contain a database yet.
+    pub fn read<F: File>(file: &mut F) -> Result<Option<Self>, std::io::Error> {
+        if file.file_size()? == 0 {
+            return Ok(None);
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        if file.read_at(&mut header, 0)? < HEADER_SIZE {
+            return Err(invalid_data("file is not a SQLite database"));
+        }
+        Self::parse(&header).map(Some)
+    }
+}
+
+/// Read the header of the database stored at `path` in `vfs`. Returns `None` if the file is empty.
+pub fn read_header<V: Vfs>(vfs: &V, path: &Path) -> Result<Option<DatabaseHeader>, std::io::Error> {
+    let mut file = vfs.open(
+        path,
+        OpenOptions {
+            kind: OpenKind::MainDb,
+            access: OpenAccess::Read,
+            delete_on_close: false,
+        },
+    )?;
+    DatabaseHeader::read(&mut file)
+}
+
+fn invalid_data(msg: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg.into())
+}
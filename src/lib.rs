@@ -2,30 +2,547 @@
 //! Create a custom SQLite virtual file system by implementing the [Vfs] trait and registering it
 //! using [register].
 
-use std::cell::Cell;
+use std::collections::HashMap;
 use std::ffi::{c_void, CStr, CString};
 use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
-use std::mem::{size_of, ManuallyDrop};
+use std::mem::size_of;
+use std::ops::Range;
 use std::os::raw::{c_char, c_int};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr::null;
 use std::ptr::null_mut;
-use std::rc::Rc;
+use std::ptr::NonNull;
 use std::slice;
-use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 
 use libsqlite3_sys as ffi;
 
+pub mod archive;
+pub mod async_vfs;
+pub mod budget;
+pub mod checksum;
+pub mod compress;
+pub mod delegate;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+pub mod differential;
+pub mod dynamic;
+pub mod fault;
+pub mod fencing;
+pub mod gc;
+pub mod header;
+pub mod mem;
+pub mod multiplex;
+pub mod page;
+pub mod paged;
+pub mod registry;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod shm;
+pub mod snapshot;
+#[cfg(all(feature = "sparse", unix))]
+pub mod sparse;
+pub mod stats;
+pub mod throttle;
+#[cfg(feature = "tracing")]
+pub mod trace;
+pub mod transform;
+
+/// Update the live object counters of a VFS state (only with the `diagnostics` feature).
+macro_rules! track {
+    ($state:expr, $event:ident, $object:ident) => {
+        #[cfg(feature = "diagnostics")]
+        diagnostics::$event(&$state.objects, diagnostics::Object::$object);
+    };
+}
+
 /// A file opened by [Vfs].
-pub trait File: Read + Seek + Write {
+///
+/// Reads and writes are addressed by offset. Types that implement [Read], [Seek] and [Write]
+/// (like [std::fs::File]) can implement [StreamFile] instead.
+///
+/// SQLite only uses a file from one thread at a time, but not necessarily always from the same
+/// thread (e.g. when a connection is moved to another thread), so files have to be [Send].
+pub trait File: Send {
+    /// Read `buf.len()` bytes at `offset` into `buf`. Fewer bytes are only read at the end of the
+    /// file. Returns the number of bytes read.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error>;
+
+    /// Write all of `buf` at `offset`, extending the file if necessary.
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error>;
+
+    /// Make sure all writes reached durable storage, as far as requested by `options`.
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error>;
+
+    fn file_size(&self) -> Result<u64, std::io::Error>;
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error>;
+
+    /// Return what is known about the file beyond its contents (e.g. to find out whether it
+    /// changed). The default implementation returns empty [VfsMetadata].
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        Ok(VfsMetadata::default())
+    }
+
+    /// Acquire `lock` on the file. Return `false` if it cannot be acquired right now because of a
+    /// lock held by another connection (SQLite then returns `SQLITE_BUSY`).
+    ///
+    /// Only valid transitions are requested: [LockKind::Shared] while not holding any lock,
+    /// [LockKind::Reserved] while holding a shared lock, [LockKind::Pending] while holding a shared
+    /// or reserved lock, and [LockKind::Exclusive] while holding a pending lock.
+    ///
+    /// The default implementation always acquires the lock, which is only safe if no more than one
+    /// connection uses the database at a time.
+    fn lock(&mut self, _lock: LockKind) -> Result<bool, std::io::Error> {
+        Ok(true)
+    }
+
+    /// Release all locks above `lock`, which is either [LockKind::Shared] or [LockKind::None]. The
+    /// default implementation does nothing.
+    fn unlock(&mut self, _lock: LockKind) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Check whether any other connection holds a [LockKind::Reserved] lock or above on the file.
+    /// The default implementation returns `false`.
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    /// The memory this database file shares with all other connections to it, which is required to
+    /// use WAL mode. The default implementation returns `None`, in which case WAL mode can only be
+    /// used after `PRAGMA locking_mode = EXCLUSIVE` (so that SQLite keeps the WAL index in heap
+    /// memory).
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        None
+    }
+
+    /// The pages of this database file that SQLite can read from memory directly (with
+    /// `PRAGMA mmap_size`), instead of copying them with [File::read_at]. The default
+    /// implementation returns `None`.
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        None
+    }
+
+    /// The batches of writes this database file can apply atomically, which let SQLite commit
+    /// transactions without a rollback journal. The file then reports
+    /// [DeviceCharacteristics::batch_atomic] as well. The default implementation returns `None`.
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        None
+    }
+
+    /// Handle a file control `op`, which SQLite sends for hints and for `sqlite3_file_control()`
+    /// calls of the application. Return `false` if the file does not handle `op`, which is
+    /// reported as `SQLITE_NOTFOUND` (what the default implementation does for all of them).
+    fn file_control(&mut self, _op: FileControl) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    /// The size of the sector (the minimum unit the storage writes at once), which SQLite uses to
+    /// pad journal headers and to decide how much data around a change it has to journal as well.
+    /// `kind` is what the file was opened as, since journals and WAL files may be kept on other
+    /// storage than the database. Values below 32 are treated as 512 and values above 65536 as
+    /// 65536. The default implementation returns 1024 for any `kind`.
+    fn sector_size(&self, _kind: OpenKind) -> u32 {
+        1024
+    }
+
+    /// The guarantees the storage of the file gives, which allow SQLite to skip some of the work it
+    /// does to keep the database consistent. Declaring a guarantee the storage does not give can
+    /// corrupt the database after a crash. `kind` is what the file was opened as, like for
+    /// [File::sector_size]. The default implementation declares none for any `kind`.
+    fn device_characteristics(&self, _kind: OpenKind) -> DeviceCharacteristics {
+        DeviceCharacteristics::empty()
+    }
+
+    /// Handle `PRAGMA name` (`value` is `None`) or `PRAGMA name = value` on a main database. The
+    /// name is passed as written in the statement (so it should be compared case-insensitively),
+    /// and the value without quotes. Return the text the pragma results in (if any), or `None` for
+    /// the pragmas the file does not know, which are left to SQLite (what the default
+    /// implementation does for all of them). The message of an error is returned to the
+    /// application.
+    fn pragma(
+        &mut self,
+        _name: &str,
+        _value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        None
+    }
+
+    /// Whether the file can only be read, although it was opened for writing (e.g. because the
+    /// backend fell back to a read-only replica). SQLite is then told that the file is read-only and
+    /// fails writes with `SQLITE_READONLY`. The default implementation returns `false`.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Whether the database file was moved, replaced or deleted since it was opened (e.g. by
+    /// another process or on a network mount). SQLite then refuses to write to it with
+    /// `SQLITE_READONLY_DBMOVED`. The default implementation returns `false`.
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    /// Called when SQLite is about to sync a committed transaction to the database file
+    /// (`SQLITE_FCNTL_SYNC`), i.e. after all its pages were written. Only sent in rollback journal
+    /// modes; in WAL mode, the commit is written to the WAL instead. An error aborts the commit.
+    /// The default implementation does nothing.
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Called after a transaction was committed (`SQLITE_FCNTL_COMMIT_PHASETWO`), in all journal
+    /// modes, e.g. to ship the change to a replica. The default implementation does nothing.
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Called when the current write transaction is about to rewrite the whole database file with
+    /// `size` bytes (`SQLITE_FCNTL_OVERWRITE`), which `VACUUM` does. Unless the transaction is
+    /// rolled back, the old contents are then no longer needed, e.g. so the backend can switch to
+    /// a bulk upload. An error aborts the `VACUUM`. The default implementation does nothing.
+    fn begin_overwrite(&mut self, _size: u64) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// A counter the file increments whenever it detects that the database was changed other than
+    /// through SQLite (e.g. replaced on a remote storage). SQLite then discards its page cache
+    /// when it starts the next transaction, and `PRAGMA data_version` (as well as
+    /// `SQLITE_FCNTL_DATA_VERSION`) changes. Only checked in rollback journal modes, and not with
+    /// `PRAGMA locking_mode = EXCLUSIVE`. The default implementation returns 0.
+    fn data_version(&self) -> u64 {
+        0
+    }
+
+    /// Called on a main database file when the application sets whether its WAL file is kept when
+    /// the last connection to the database closes (`SQLITE_FCNTL_PERSIST_WAL`), instead of being
+    /// deleted. A backend can use it e.g. to keep a remote `-wal` object that is expensive to
+    /// create. The default implementation does nothing.
+    fn set_persist_wal(&mut self, _persist: bool) {}
+
+    /// Whether the WAL file of this main database file is kept when the last connection to the
+    /// database closes, which SQLite asks for when it closes the WAL. Return `None` to use what the
+    /// application last set (`false` if it never did), which the default implementation does.
+    fn persist_wal(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// The guarantees the storage of a file gives, as returned by [File::device_characteristics]. See
+/// <https://www.sqlite.org/c3ref/c_iocap_atomic.html> for details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceCharacteristics(c_int);
+
+impl DeviceCharacteristics {
+    /// No guarantees at all.
+    pub const fn empty() -> Self {
+        DeviceCharacteristics(0)
+    }
+
+    /// Writes of any size are atomic.
+    pub const fn atomic(self) -> Self {
+        DeviceCharacteristics(self.0 | ffi::SQLITE_IOCAP_ATOMIC)
+    }
+
+    /// Data appended to a file is written before the size of the file is extended, so a crash
+    /// never leaves garbage at the end of a file.
+    pub const fn safe_append(self) -> Self {
+        DeviceCharacteristics(self.0 | ffi::SQLITE_IOCAP_SAFE_APPEND)
+    }
+
+    /// Writes reach the storage in the order they are made.
+    pub const fn sequential(self) -> Self {
+        DeviceCharacteristics(self.0 | ffi::SQLITE_IOCAP_SEQUENTIAL)
+    }
+
+    /// A crash while writing leaves the bytes around the written ones unchanged, even within the
+    /// same sector.
+    pub const fn powersafe_overwrite(self) -> Self {
+        DeviceCharacteristics(self.0 | ffi::SQLITE_IOCAP_POWERSAFE_OVERWRITE)
+    }
+
+    /// Files cannot be deleted while they are open.
+    pub const fn undeletable_when_open(self) -> Self {
+        DeviceCharacteristics(self.0 | ffi::SQLITE_IOCAP_UNDELETABLE_WHEN_OPEN)
+    }
+
+    /// The file cannot change while it is open, neither through SQLite nor in any other way (e.g.
+    /// by other processes, or on the remote storage it is read from). SQLite then skips locking,
+    /// checking for hot journals and detecting changes made by other connections, which saves
+    /// many round trips for databases that are only ever read (e.g. from a CDN or an archive). A
+    /// file that reports it when it is opened is opened read-only.
+    ///
+    /// This is a promise the backend has to keep: if the file changes after all, connections may
+    /// read a mix of old and new pages, which leads to wrong results or `SQLITE_CORRUPT` errors.
+    pub const fn immutable(self) -> Self {
+        DeviceCharacteristics(self.0 | ffi::SQLITE_IOCAP_IMMUTABLE)
+    }
+
+    /// Writes between [BatchAtomicWrite::begin] and [BatchAtomicWrite::commit] are applied
+    /// atomically. Reported for all files that implement [File::batch_atomic_write].
+    pub const fn batch_atomic(self) -> Self {
+        DeviceCharacteristics(self.0 | ffi::SQLITE_IOCAP_BATCH_ATOMIC)
+    }
+
+    /// Whether all guarantees of `other` are given as well.
+    pub const fn contains(&self, other: DeviceCharacteristics) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The `SQLITE_IOCAP_*` flags of the guarantees.
+    pub const fn bits(&self) -> i32 {
+        self.0
+    }
+}
+
+/// A file control operation, as passed to [File::file_control]. See
+/// <https://www.sqlite.org/c3ref/c_fcntl_begin_atomic_write.html> for all of them.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum FileControl {
+    /// `SQLITE_FCNTL_SIZE_HINT`: the file is about to grow to (about) the given size, e.g. so the
+    /// space can be allocated up front.
+    SizeHint(u64),
+
+    /// `SQLITE_FCNTL_SYNC_OMITTED`: a sync of the file was skipped (`PRAGMA synchronous = OFF`).
+    SyncOmitted,
+
+    /// `SQLITE_FCNTL_CHUNK_SIZE`: grow and shrink the file in multiples of the given number of
+    /// bytes (e.g. to reduce fragmentation), or byte by byte again if it is `0`.
+    ChunkSize(u32),
+
+    /// Any other operation, with the argument passed by SQLite (or the application), whose type
+    /// depends on `op`.
+    Raw { op: i32, arg: *mut c_void },
+}
+
+impl FileControl {
+    unsafe fn from_raw(op: c_int, arg: *mut c_void) -> Self {
+        match op {
+            ffi::SQLITE_FCNTL_SIZE_HINT => {
+                match (arg as *const ffi::sqlite3_int64)
+                    .as_ref()
+                    .and_then(|size| u64::try_from(*size).ok())
+                {
+                    Some(size) => FileControl::SizeHint(size),
+                    None => FileControl::Raw { op, arg },
+                }
+            }
+            ffi::SQLITE_FCNTL_SYNC_OMITTED => FileControl::SyncOmitted,
+            ffi::SQLITE_FCNTL_CHUNK_SIZE => {
+                match (arg as *const c_int)
+                    .as_ref()
+                    .and_then(|size| u32::try_from(*size).ok())
+                {
+                    Some(size) => FileControl::ChunkSize(size),
+                    None => FileControl::Raw { op, arg },
+                }
+            }
+            _ => FileControl::Raw { op, arg },
+        }
+    }
+}
+
+/// Memory shared by all connections to a database, which SQLite uses for the index of the WAL, as
+/// returned by [File::shared_memory]. See <https://www.sqlite.org/walformat.html#shm> for details.
+///
+/// # Safety
+///
+/// The memory returned by [SharedMemory::map] is accessed by SQLite through the returned pointer
+/// until [SharedMemory::unmap] is called, so it must stay valid (and at the same address) until
+/// then.
+pub unsafe trait SharedMemory {
+    /// Return a pointer to the region `index` of the shared memory (all regions are `size` bytes).
+    /// If the region does not exist yet, create it (zeroed) if `extend` is `true`, or return `None`
+    /// otherwise. The region must be aligned to 8 bytes.
+    fn map(
+        &mut self,
+        index: u32,
+        size: usize,
+        extend: bool,
+    ) -> Result<Option<NonNull<u8>>, std::io::Error>;
+
+    /// Acquire `lock` on the lock `slots` (out of 8). Return `false` if it is held by another
+    /// connection.
+    fn lock(&mut self, slots: Range<u8>, lock: ShmLock) -> Result<bool, std::io::Error>;
+
+    /// Release the `lock` held on the lock `slots`.
+    fn unlock(&mut self, slots: Range<u8>, lock: ShmLock) -> Result<(), std::io::Error>;
+
+    /// Make sure that all writes to the shared memory before are visible to other connections. The
+    /// default implementation issues a memory fence, which is enough for memory shared within a
+    /// process.
+    fn barrier(&mut self) {
+        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Whether another connection has a read transaction open on the database, i.e. holds a
+    /// shared lock on one of the read lock slots (3 to 7), which applications ask for with
+    /// `SQLITE_FCNTL_EXTERNAL_READER` (e.g. to find out whether a checkpoint can restart the WAL).
+    /// The default implementation returns `false`.
+    fn external_reader(&mut self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    /// Release all regions mapped by this connection. If `delete` is `true`, the shared memory is
+    /// not needed anymore and may be deleted.
+    fn unmap(&mut self, delete: bool) -> Result<(), std::io::Error>;
+}
+
+/// The contents of a database file that can be read from memory without copying them, returned
+/// by [File::memory_mapped].
+///
+/// # Safety
+///
+/// The bytes returned by [MemoryMapped::fetch] are read by SQLite through a pointer until
+/// [MemoryMapped::unfetch] is called for the same offset, so they must stay valid (and at the same
+/// address) until then, and reflect all writes to the file in the meantime.
+pub unsafe trait MemoryMapped {
+    /// Return the `len` bytes at `offset`, or `None` if they cannot be served from memory right now
+    /// (SQLite then reads them with [File::read_at] instead).
+    fn fetch(&mut self, offset: u64, len: usize) -> Option<&[u8]>;
+
+    /// Release the bytes at `offset` returned by [MemoryMapped::fetch].
+    fn unfetch(&mut self, offset: u64);
+
+    /// SQLite wants to map up to `limit` bytes of the file (`PRAGMA mmap_size`). Return how many
+    /// bytes it may map instead, e.g. less (or `0`) to cap (or refuse) memory mapping. Pages beyond
+    /// the limit are not fetched. The default implementation allows `limit`.
+    fn set_size_limit(&mut self, limit: u64) -> u64 {
+        limit
+    }
+}
+
+/// A database file that can apply a batch of writes atomically (e.g. with a single request to an
+/// object store or a transaction of a key-value store), as returned by [File::batch_atomic_write].
+///
+/// To commit a transaction, SQLite begins a batch, writes all changed pages with
+/// [File::write_all_at], and commits the batch, without writing a rollback journal first. If the
+/// batch cannot be begun or committed, SQLite rolls it back and commits with a journal instead.
+/// SQLite only uses batches if it is compiled with `SQLITE_ENABLE_BATCH_ATOMIC_WRITE`.
+pub trait BatchAtomicWrite {
+    /// Begin a batch. All writes until [BatchAtomicWrite::commit] or [BatchAtomicWrite::rollback]
+    /// have to be applied all at once or not at all.
+    fn begin(&mut self) -> Result<(), std::io::Error>;
+
+    /// Apply (and persist) all writes of the batch atomically.
+    fn commit(&mut self) -> Result<(), std::io::Error>;
+
+    /// Discard all writes of the batch.
+    fn rollback(&mut self) -> Result<(), std::io::Error>;
+}
+
+/// A lock on the slots of a [SharedMemory].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShmLock {
+    /// Any number of connections can hold a shared lock on a slot at the same time.
+    Shared,
+
+    /// No other connection can hold any lock on the slot at the same time.
+    Exclusive,
+}
+
+/// The locks a connection can hold on a database file, in increasing order. See
+/// <https://www.sqlite.org/lockingv3.html> for details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockKind {
+    /// No lock is held.
+    None,
+
+    /// The database may be read, but not written. Any number of connections can hold a shared lock
+    /// at the same time.
+    Shared,
+
+    /// The connection plans to write to the database. Only one connection can hold a reserved lock
+    /// at a time, while other connections can still acquire shared locks.
+    Reserved,
+
+    /// The connection waits for all shared locks to be released to acquire an exclusive lock. No
+    /// new shared locks can be acquired while a pending lock is held.
+    Pending,
+
+    /// The database is written. No other locks can be held at the same time.
+    Exclusive,
+}
+
+/// A file accessed through [Read], [Seek] and [Write]. Each type that implements it also
+/// implements [File], seeking to the offset before each read and write. The optional methods of
+/// [File] (like locking) keep their default implementation; implement [File] directly to override
+/// them.
+pub trait StreamFile: Read + Seek + Write {
     fn file_size(&self) -> Result<u64, std::io::Error>;
     fn truncate(&mut self, size: u64) -> Result<(), std::io::Error>;
+
+    /// See [File::metadata].
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        Ok(VfsMetadata::default())
+    }
+}
+
+impl<F: StreamFile + Send> File for F {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.seek(SeekFrom::Start(offset))?;
+        let mut n = 0;
+        while n < buf.len() {
+            match self.read(&mut buf[n..]) {
+                Ok(0) => break,
+                Ok(read) => n += read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(n)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+
+    fn sync(&mut self, _options: SyncOptions) -> Result<(), std::io::Error> {
+        self.flush()
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        StreamFile::file_size(self)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        StreamFile::truncate(self, size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        StreamFile::metadata(self)
+    }
+}
+
+/// Metadata of a [File], as returned by [File::metadata].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VfsMetadata {
+    /// When the file was last modified.
+    pub modified: Option<SystemTime>,
+
+    /// When the file was created.
+    pub created: Option<SystemTime>,
+
+    /// An opaque token that changes whenever the file changes (e.g. an ETag or an object
+    /// generation).
+    pub generation: Option<String>,
+
+    /// The bytes of storage allocated to the file, which is less than its size if the file is
+    /// sparse (has holes that read as zeros, see [sparse](crate::sparse)).
+    pub allocated: Option<u64>,
 }
 
 /// A virtual file system for SQLite.
 ///
+/// The connections to a VFS may be used from any thread (with SQLite's default serialized or
+/// multi-thread threading modes), and call into it concurrently, so it has to be [Send] and [Sync].
+///
 /// # Example
 /// This example uses [std::fs] to to persist the database to disk.
 /// ```
@@ -64,7 +581,7 @@ pub trait File: Read + Seek + Write {
 ///     }
 /// }
 /// ```
-pub trait Vfs {
+pub trait Vfs: Send + Sync {
     /// The file returned by [Vfs::open].
     type File: File;
 
@@ -81,6 +598,235 @@ pub trait Vfs {
     fn access(&self, _path: &Path, _write: bool) -> Result<bool, std::io::Error> {
         Ok(true)
     }
+
+    /// List the objects whose path starts with `prefix` (e.g. a database and its journal and WAL
+    /// files), for management tooling. The default implementation returns an error of kind
+    /// [ErrorKind::Unsupported].
+    fn list(&self, _prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "listing objects is not supported by this vfs",
+        ))
+    }
+
+    /// Move the object at `from` to `to`, replacing any object at `to`.
+    ///
+    /// The default implementation copies the object (opening both as [OpenKind::MainDb]) and
+    /// deletes `from` afterwards. It is not atomic: when interrupted, `to` may be incomplete while
+    /// `from` still exists. Backends with an atomic rename should override it.
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        let mut src = self.open(
+            from,
+            OpenOptions {
+                kind: OpenKind::MainDb,
+                access: OpenAccess::Read,
+                delete_on_close: false,
+            },
+        )?;
+        let mut dst = self.open(
+            to,
+            OpenOptions {
+                kind: OpenKind::MainDb,
+                access: OpenAccess::Create,
+                delete_on_close: false,
+            },
+        )?;
+        dst.truncate(0)?;
+        let mut buf = vec![0; 64 * 1024];
+        let mut offset = 0;
+        loop {
+            let n = src.read_at(&mut buf, offset)?;
+            if n == 0 {
+                break;
+            }
+            dst.write_all_at(&buf[..n], offset)?;
+            offset += n as u64;
+        }
+        dst.sync(SyncOptions::default())?;
+        drop(src);
+        drop(dst);
+        self.delete(from)
+    }
+
+    /// Check the health of the storage. Called for `PRAGMA vfs_health`, which returns the report
+    /// as text. The default implementation reports the storage as reachable without any details.
+    fn health(&self) -> HealthReport {
+        HealthReport::reachable()
+    }
+
+    /// The coordinator to notify around checkpoints of databases in WAL mode, if any. The default
+    /// implementation returns `None`.
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        None
+    }
+
+    /// Called when SQLite starts and finishes rolling back a hot journal of the database at `path`
+    /// (left behind by a crashed writer), which rewrites pages of the database file. Returning an
+    /// error when the recovery starts prevents it (SQLite fails to open the journal). The default
+    /// implementation does nothing.
+    fn on_recovery(&self, _path: &Path, _phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Resolve the `path` a database is opened with to the path SQLite uses for it (and which the
+    /// paths of its journals are derived from), e.g. to make relative paths absolute or to prefix
+    /// them with a tenant. The default implementation returns `path` unchanged.
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        Ok(path.to_path_buf())
+    }
+
+    /// Fill `buf` with random bytes. SQLite seeds its own pseudo-random number generator (used e.g.
+    /// by `random()` and for the names of temporary files) with them, but only asks the default
+    /// VFS. The default implementation uses the thread-local generator of the `rand` crate.
+    fn random(&self, buf: &mut [u8]) {
+        use rand::Rng;
+
+        rand::thread_rng().fill(buf);
+    }
+
+    /// Wait for (at least) `duration`, which SQLite does e.g. between attempts to acquire a busy
+    /// lock, and return how long it actually waited. The default implementation blocks the current
+    /// thread.
+    fn sleep(&self, duration: Duration) -> Duration {
+        let instant = Instant::now();
+        thread::sleep(duration);
+        instant.elapsed()
+    }
+
+    /// The current time, which SQLite uses e.g. for `datetime('now')`. The default implementation
+    /// returns [SystemTime::now].
+    fn current_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// The path to open a temporary file at, which SQLite requests without a name (e.g. for
+    /// `VACUUM`, large sorts and temporary tables). The file is opened with [Vfs::open] and deleted
+    /// with [Vfs::delete] once it is closed. Applications get such paths with
+    /// `SQLITE_FCNTL_TEMPFILENAME` as well. The default implementation returns a random name in
+    /// [std::env::temp_dir].
+    fn temporary_path(&self) -> PathBuf {
+        use rand::Rng;
+
+        std::env::temp_dir().join(format!("etilqs_{:016x}", rand::thread_rng().gen::<u64>()))
+    }
+
+    /// The system calls of the VFS that can be replaced at runtime (with `xSetSystemCall`), e.g.
+    /// by test harnesses that inject faults. Only checked once, when the VFS is registered. The
+    /// default implementation returns `None`, in which case SQLite is told that the VFS does not
+    /// support it.
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        None
+    }
+}
+
+/// The progress of a hot journal rollback, as passed to [Vfs::on_recovery].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryPhase {
+    /// The hot journal has been opened, and its pages are about to be written to the database.
+    Start,
+
+    /// The database has been restored and synced, and the hot journal has been closed (it is
+    /// deleted, truncated or zeroed afterwards, depending on the journal mode).
+    Done,
+}
+
+/// Notified around checkpoints of databases in WAL mode, as returned by
+/// [Vfs::checkpoint_coordinator].
+///
+/// While a checkpoint runs, SQLite copies pages from the WAL back into the main database file.
+/// Remote backends can use this to e.g. pause uploads of the database file or to switch to a new
+/// object for the WAL afterwards.
+pub trait CheckpointCoordinator {
+    /// Called before the first page of the WAL is written to the database file at `path`.
+    fn checkpoint_start(&self, path: &Path);
+
+    /// Called after the checkpoint of the database at `path` finished writing (and syncing) the
+    /// database file.
+    fn checkpoint_done(&self, path: &Path);
+}
+
+/// A system call, as exchanged with `xSetSystemCall` and `xGetSystemCall`. It is cast to its actual
+/// signature before it is called.
+pub type SystemCall = unsafe extern "C" fn();
+
+/// The system calls of a [Vfs] that can be replaced at runtime, as returned by
+/// [Vfs::system_calls]. The VFS has to look up the current implementation of a call (with
+/// [SystemCallOverrides::get]) whenever it makes it, for a replacement to take effect.
+pub trait SystemCallOverrides {
+    /// The names of all system calls that can be replaced.
+    fn names(&self) -> &[&'static CStr];
+
+    /// The current implementation of the system call `name`, or `None` if there is no such call.
+    fn get(&self, name: &CStr) -> Option<SystemCall>;
+
+    /// Replace the system call `name` with `call`, or restore its original implementation if
+    /// `call` is `None`. Return `false` if there is no such call.
+    fn set(&self, name: &CStr, call: Option<SystemCall>) -> bool;
+}
+
+/// An object stored by a [Vfs], as returned by [Vfs::list].
+#[derive(Debug, Clone, PartialEq)]
+pub struct VfsEntry {
+    /// The path of the object, as it would be passed to [Vfs::open].
+    pub path: PathBuf,
+
+    /// The size of the object in bytes.
+    pub size: u64,
+}
+
+/// The objects returned by [Vfs::list].
+pub type VfsEntries<'a> = Box<dyn Iterator<Item = Result<VfsEntry, std::io::Error>> + 'a>;
+
+/// The health of the storage of a [Vfs], as returned by [Vfs::health].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    /// Whether the storage can currently be reached.
+    pub reachable: bool,
+
+    /// How far replicas of the storage are behind, if applicable.
+    pub replication_lag: Option<Duration>,
+
+    /// The ratio of reads served from a cache (between `0.0` and `1.0`), if applicable.
+    pub cache_hit_rate: Option<f64>,
+
+    /// Any further backend specific checks, as name and value.
+    pub details: Vec<(String, String)>,
+}
+
+impl HealthReport {
+    /// A report for reachable storage, without any further details.
+    pub fn reachable() -> Self {
+        HealthReport {
+            reachable: true,
+            replication_lag: None,
+            cache_hit_rate: None,
+            details: Vec::new(),
+        }
+    }
+
+    /// A report for storage that cannot be reached.
+    pub fn unreachable() -> Self {
+        HealthReport {
+            reachable: false,
+            ..Self::reachable()
+        }
+    }
+}
+
+impl std::fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reachable={}", self.reachable)?;
+        if let Some(lag) = self.replication_lag {
+            write!(f, " replication_lag={}ms", lag.as_millis())?;
+        }
+        if let Some(rate) = self.cache_hit_rate {
+            write!(f, " cache_hit_rate={:.3}", rate)?;
+        }
+        for (name, value) in &self.details {
+            write!(f, " {}={}", name, value)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -95,8 +841,20 @@ pub struct OpenOptions {
     pub delete_on_close: bool,
 }
 
+/// How thoroughly [File::sync] has to persist the writes to a file.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SyncOptions {
+    /// Make sure the writes survive a power loss even if the storage device has a volatile write
+    /// cache, like `F_FULLFSYNC` on macOS (requested with `PRAGMA fullfsync = ON`).
+    pub full: bool,
+
+    /// Only the contents of the file have to be persisted, not its metadata, like `fdatasync`
+    /// (requested for rollback journals along with `full`).
+    pub data_only: bool,
+}
+
 /// The object type that is being opened.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OpenKind {
     MainDb,
     MainJournal,
@@ -124,86 +882,418 @@ pub enum OpenAccess {
     CreateNew,
 }
 
+// The functions that do not need the [Vfs] access the state as `State<()>`, so all other fields
+// must come before `vfs` to have the same offsets for any `V`.
+#[repr(C)]
 struct State<V> {
-    vfs: V,
     io_methods: ffi::sqlite3_io_methods,
-    last_error: Rc<Cell<Option<std::io::Error>>>,
+    last_error: Arc<LastError>,
+    options: RegisterOptions,
+    /// The number of files opened through the VFS that are not closed yet.
+    open_files: AtomicUsize,
+    /// The objects allocated by the glue for the VFS that are not freed yet.
+    #[cfg(feature = "diagnostics")]
+    objects: diagnostics::Counters,
+    vfs: V,
+}
+
+/// The last error of a VFS, which SQLite asks for with `xGetLastError` right after a failed call
+/// (on the same thread). The connections to the VFS may run on any thread, so the error is kept
+/// per thread, to not report the error of another connection.
+///
+/// Every call clears the error of the calling thread, while errors are rare, so the number of
+/// errors kept is counted to skip locking the map while it is empty.
+#[derive(Default)]
+struct LastError {
+    errors: Mutex<HashMap<ThreadId, std::io::Error>>,
+    /// The number of errors in `errors`.
+    len: AtomicUsize,
+}
+
+impl LastError {
+    fn set(&self, err: Option<std::io::Error>) {
+        let err = match err {
+            Some(err) => err,
+            None => {
+                self.take();
+                return;
+            }
+        };
+        let mut errors = self.errors();
+        if errors.insert(thread::current().id(), err).is_none() {
+            self.len.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn take(&self) -> Option<std::io::Error> {
+        // a thread always sees the count including its own error
+        if self.len.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let err = self.errors().remove(&thread::current().id())?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        Some(err)
+    }
+
+    fn errors(&self) -> MutexGuard<'_, HashMap<ThreadId, std::io::Error>> {
+        self.errors.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Options for [register_with_options].
+#[derive(Debug, Clone)]
+pub struct RegisterOptions {
+    /// How many [sources](std::error::Error::source) of an error to include in the error message
+    /// reported to SQLite (default: 8). Each source is appended to the message, separated by `: `.
+    pub error_sources: usize,
+
+    /// Mark files as immutable (`SQLITE_IOCAP_IMMUTABLE`) if they are opened for reading only and
+    /// [Vfs::access] reports that they cannot be written (default: `false`). SQLite then skips
+    /// locking and checking for hot journals, which is only safe for media nobody can write, like
+    /// HTTP snapshots or squashfs images. That this process lacks write permission does not mean
+    /// that no other process (or user) writes the database, so this is left to the application.
+    pub immutable_when_read_only: bool,
+
+    /// Open files for reading only if opening them for writing fails with
+    /// [ErrorKind::PermissionDenied] or [ErrorKind::ReadOnlyFilesystem] (default: `true`). SQLite
+    /// is told that the file is read-only and fails writes with `SQLITE_READONLY`.
+    pub read_only_fallback: bool,
+
+    /// Make the VFS the default of the process (default: `false`), which is used by all
+    /// connections that do not name a VFS when they are opened. Once the VFS is unregistered,
+    /// SQLite picks an arbitrary other VFS as the default.
+    pub make_default: bool,
+
+    /// Check that every write to a main database file starts at a page boundary and covers whole
+    /// pages, with the page size read from the database header (default: `false`). SQLite itself
+    /// only ever writes whole pages to the database, so other writes are a bug in a layer in
+    /// between; they fail with `SQLITE_IOERR_WRITE` (and are logged) before they reach the [File].
+    /// Each write reads the header first, so this is meant for tests and debugging.
+    pub validate_writes: bool,
 }
 
-/// Register a virtual file system ([Vfs]) to SQLite.
-pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<(), RegisterError> {
-    let name = ManuallyDrop::new(CString::new(name)?);
+impl Default for RegisterOptions {
+    fn default() -> Self {
+        RegisterOptions {
+            error_sources: 8,
+            immutable_when_read_only: false,
+            read_only_fallback: true,
+            make_default: false,
+            validate_writes: false,
+        }
+    }
+}
+
+/// Register a virtual file system ([Vfs]) to SQLite. The VFS stays registered until the returned
+/// [VfsHandle] is dropped.
+pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<VfsHandle, RegisterError> {
+    register_with_options(name, vfs, RegisterOptions::default())
+}
+
+/// Register a virtual file system ([Vfs]) to SQLite, using the given [RegisterOptions].
+pub fn register_with_options<F: File, V: Vfs<File = F>>(
+    name: &str,
+    vfs: V,
+    options: RegisterOptions,
+) -> Result<VfsHandle, RegisterError> {
+    let name = CString::new(name)?.into_raw();
+    let make_default = options.make_default;
+    let system_calls = vfs.system_calls().is_some();
     let io_methods = ffi::sqlite3_io_methods {
         iVersion: 3,
-        xClose: Some(io::close::<F>),
+        xClose: Some(io::close::<V>),
         xRead: Some(io::read::<F>),
         xWrite: Some(io::write::<F>),
         xTruncate: Some(io::truncate::<F>),
         xSync: Some(io::sync::<F>),
         xFileSize: Some(io::file_size::<F>),
-        xLock: Some(io::lock),
-        xUnlock: Some(io::unlock),
-        xCheckReservedLock: Some(io::check_reserved_lock),
-        xFileControl: Some(io::file_control),
-        xSectorSize: Some(io::sector_size),
-        xDeviceCharacteristics: Some(io::device_characteristics),
-        xShmMap: Some(io::shm_map),
-        xShmLock: Some(io::shm_lock),
-        xShmBarrier: Some(io::shm_barrier),
-        xShmUnmap: Some(io::shm_unmap),
+        xLock: Some(io::lock::<F>),
+        xUnlock: Some(io::unlock::<F>),
+        xCheckReservedLock: Some(io::check_reserved_lock::<F>),
+        xFileControl: Some(io::file_control::<V>),
+        xSectorSize: Some(io::sector_size::<F>),
+        xDeviceCharacteristics: Some(io::device_characteristics::<F>),
+        xShmMap: Some(io::shm_map::<F>),
+        xShmLock: Some(io::shm_lock::<F>),
+        xShmBarrier: Some(io::shm_barrier::<F>),
+        xShmUnmap: Some(io::shm_unmap::<F>),
         xFetch: Some(io::mem_fetch::<F>),
-        xUnfetch: Some(io::mem_unfetch),
+        xUnfetch: Some(io::mem_unfetch::<F>),
     };
-    let ptr = Box::into_raw(Box::new(State {
-        vfs,
+    let state = State {
         io_methods,
         last_error: Default::default(),
-    }));
+        options,
+        open_files: AtomicUsize::new(0),
+        #[cfg(feature = "diagnostics")]
+        objects: Default::default(),
+        vfs,
+    };
+    track!(state, allocated, VfsState);
+    track!(state, allocated, Name);
+    let ptr = Box::into_raw(Box::new(state));
     let vfs = Box::into_raw(Box::new(ffi::sqlite3_vfs {
         iVersion: 3,
         szOsFile: size_of::<FileState<F>>() as i32,
         mxPathname: MAX_PATH_LENGTH as i32, // max path length supported by VFS
         pNext: null_mut(),
-        zName: name.as_ptr(),
+        zName: name,
         pAppData: ptr as _,
         xOpen: Some(vfs::open::<F, V>),
         xDelete: Some(vfs::delete::<V>),
         xAccess: Some(vfs::access::<V>),
-        xFullPathname: Some(vfs::full_pathname),
+        xFullPathname: Some(vfs::full_pathname::<V>),
         xDlOpen: Some(vfs::dlopen),
         xDlError: Some(vfs::dlerror),
         xDlSym: Some(vfs::dlsym),
         xDlClose: Some(vfs::dlclose),
-        xRandomness: Some(vfs::randomness),
-        xSleep: Some(vfs::sleep),
-        xCurrentTime: Some(vfs::current_time),
+        xRandomness: Some(vfs::randomness::<V>),
+        xSleep: Some(vfs::sleep::<V>),
+        xCurrentTime: Some(vfs::current_time::<V>),
         xGetLastError: Some(vfs::get_last_error),
-        xCurrentTimeInt64: Some(vfs::current_time_int64),
-        xSetSystemCall: None,
-        xGetSystemCall: None,
-        xNextSystemCall: None,
+        xCurrentTimeInt64: Some(vfs::current_time_int64::<V>),
+        xSetSystemCall: system_calls.then_some(vfs::set_system_call::<V>),
+        xGetSystemCall: system_calls.then_some(vfs::get_system_call::<V>),
+        xNextSystemCall: system_calls.then_some(vfs::next_system_call::<V>),
     }));
 
-    let result = unsafe { ffi::sqlite3_vfs_register(vfs, false as i32) };
+    let result = unsafe { ffi::sqlite3_vfs_register(vfs, make_default as i32) };
     if result != ffi::SQLITE_OK {
+        unsafe { free_vfs::<V>(vfs) };
         return Err(RegisterError::Register(result));
     }
 
-    // TODO: return object that allows to unregister (and cleanup the memory)?
+    Ok(VfsHandle {
+        vfs: NonNull::new(vfs).unwrap(),
+        free: free_vfs::<V>,
+        side_files: gc::side_files::<V>,
+        rename: snapshot::rename::<V>,
+    })
+}
 
-    Ok(())
+/// A [Vfs] registered to SQLite by [register].
+///
+/// Dropping the handle unregisters the VFS and frees it. SQLite does not keep track of which
+/// connections use a VFS, so all connections to it have to be closed first. As long as files are
+/// still open through the VFS, it is only unregistered but not freed (and a warning is logged),
+/// since the connections that opened them keep calling into it.
+#[must_use = "the VFS is unregistered as soon as the handle is dropped"]
+pub struct VfsHandle {
+    vfs: NonNull<ffi::sqlite3_vfs>,
+    free: unsafe fn(*mut ffi::sqlite3_vfs),
+    side_files:
+        unsafe fn(*mut ffi::sqlite3_vfs, &Path) -> Result<Vec<gc::SideFile>, std::io::Error>,
+    rename: unsafe fn(*mut ffi::sqlite3_vfs, &Path, &Path) -> Result<(), std::io::Error>,
 }
 
-// TODO: add to [Vfs]?
-const MAX_PATH_LENGTH: usize = 512;
+impl VfsHandle {
+    /// The name the VFS is registered as.
+    pub fn name(&self) -> &str {
+        unsafe { CStr::from_ptr(self.vfs.as_ref().zName) }
+            .to_str()
+            .unwrap()
+    }
 
-#[repr(C)]
-struct FileState<F> {
-    base: ffi::sqlite3_file,
-    name: *mut i8,
-    file: *mut F,
-    last_error: *const Cell<Option<std::io::Error>>,
-}
+    /// The number of files currently open through the VFS.
+    pub fn open_files(&self) -> usize {
+        self.state().open_files.load(Ordering::SeqCst)
+    }
+
+    /// The number of objects the glue currently allocates for the VFS and the files open through
+    /// it, including the state and name of the VFS itself (requires the `diagnostics` feature).
+    #[cfg(feature = "diagnostics")]
+    pub fn live_objects(&self) -> diagnostics::LiveObjects {
+        self.state().objects.load()
+    }
+
+    /// The `sqlite3_vfs` registered to SQLite, e.g. to wrap it with a VFS shim written in C. The
+    /// pointer is valid until the handle is dropped.
+    pub fn as_raw(&self) -> *mut ffi::sqlite3_vfs {
+        self.vfs.as_ptr()
+    }
+
+    /// Open the file at `path` through the VFS like SQLite does, but without a connection, e.g. to
+    /// hand it to C code that expects an `sqlite3_file`. `flags` are the `SQLITE_OPEN_*` flags
+    /// passed to `xOpen`. The file is closed when the returned [RawFile] is dropped, and counts as
+    /// open until then.
+    ///
+    /// # Safety
+    ///
+    /// `flags` must be valid flags for `xOpen`: exactly one of the `SQLITE_OPEN_*` flags for the
+    /// kind of file (e.g. `SQLITE_OPEN_MAIN_DB`), along with `SQLITE_OPEN_READONLY` or
+    /// `SQLITE_OPEN_READWRITE`. Nothing coordinates the file with the connections to the same
+    /// database, so the caller has to follow SQLite's locking protocol (see [File::lock]) before
+    /// reading or writing a database that connections use.
+    pub unsafe fn open_raw(
+        &self,
+        path: impl AsRef<Path>,
+        flags: c_int,
+    ) -> Result<RawFile, std::io::Error> {
+        let path = path_to_cstring(path.as_ref())?;
+        // the VFS may look up URI parameters of the path, which SQLite stores after it
+        let name =
+            ffi::sqlite3_create_filename(path.as_ptr(), c"".as_ptr(), c"".as_ptr(), 0, null_mut());
+        let name = NonNull::new(name).ok_or(VfsError::Code(ffi::SQLITE_NOMEM))?;
+        let vfs = self.vfs.as_ref();
+        let size = (vfs.szOsFile.max(0) as usize).div_ceil(size_of::<u64>());
+        let mut file = RawFile {
+            file: vec![0; size.max(1)].into_boxed_slice(),
+            name,
+            out_flags: 0,
+        };
+        let open = vfs.xOpen.ok_or(VfsError::Code(ffi::SQLITE_MISUSE))?;
+        let code = open(
+            self.vfs.as_ptr(),
+            name.as_ptr(),
+            file.as_ptr(),
+            flags,
+            &mut file.out_flags,
+        );
+        if code != ffi::SQLITE_OK {
+            return Err(VfsError::Code(code).into());
+        }
+        Ok(file)
+    }
+
+    /// Unregister the VFS and free it. Fails and returns the handle again if files are still open
+    /// through the VFS, in which case it stays registered.
+    pub fn unregister(self) -> Result<(), Self> {
+        if self.open_files() > 0 {
+            return Err(self);
+        }
+        drop(self);
+        Ok(())
+    }
+
+    fn state(&self) -> &State<()> {
+        unsafe { &*(self.vfs.as_ref().pAppData as *const State<()>) }
+    }
+}
+
+// the handle only reads the registered VFS and its atomic file count, and frees the [Vfs]
+// (which is [Send]) when dropped
+unsafe impl Send for VfsHandle {}
+unsafe impl Sync for VfsHandle {}
+
+impl std::fmt::Debug for VfsHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VfsHandle")
+            .field("name", &self.name())
+            .field("open_files", &self.open_files())
+            .finish()
+    }
+}
+
+impl Drop for VfsHandle {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_vfs_unregister(self.vfs.as_ptr()) };
+        let open_files = self.open_files();
+        if open_files > 0 {
+            log::warn!(
+                "leaking the unregistered vfs {} as {} of its files are still open",
+                self.name(),
+                open_files
+            );
+            return;
+        }
+        unsafe { (self.free)(self.vfs.as_ptr()) };
+    }
+}
+
+/// An `sqlite3_file` opened by [VfsHandle::open_raw], which is closed when dropped.
+pub struct RawFile {
+    /// The file, with the size the VFS asks for (`szOsFile`), aligned to 8 bytes.
+    file: Box<[u64]>,
+    /// The path of the file, which the VFS may keep a pointer to until the file is closed.
+    name: NonNull<c_char>,
+    out_flags: c_int,
+}
+
+impl RawFile {
+    /// The `sqlite3_file`, whose `pMethods` are the I/O methods of the VFS. The pointer is valid
+    /// until the [RawFile] is dropped, which closes the file (so it must not be closed with
+    /// `xClose`).
+    pub fn as_ptr(&mut self) -> *mut ffi::sqlite3_file {
+        self.file.as_mut_ptr() as *mut ffi::sqlite3_file
+    }
+
+    /// The flags the VFS set when opening the file (e.g. `SQLITE_OPEN_READONLY` if it could only
+    /// open the file for reading).
+    pub fn out_flags(&self) -> c_int {
+        self.out_flags
+    }
+}
+
+impl std::fmt::Debug for RawFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawFile")
+            .field("name", &unsafe { CStr::from_ptr(self.name.as_ptr()) })
+            .field("out_flags", &self.out_flags)
+            .finish()
+    }
+}
+
+impl Drop for RawFile {
+    fn drop(&mut self) {
+        unsafe {
+            let file = self.as_ptr();
+            if let Some(close) = (*file).pMethods.as_ref().and_then(|methods| methods.xClose) {
+                let code = close(file);
+                if code != ffi::SQLITE_OK {
+                    log::warn!("failed to close a raw file: {}", VfsError::Code(code));
+                }
+            }
+            ffi::sqlite3_free_filename(self.name.as_ptr());
+        }
+    }
+}
+
+/// Free a `sqlite3_vfs` allocated by [register_with_options], including its name and state.
+unsafe fn free_vfs<V>(ptr: *mut ffi::sqlite3_vfs) {
+    let vfs = Box::from_raw(ptr);
+    let state = Box::from_raw(vfs.pAppData as *mut State<V>);
+    drop(CString::from_raw(vfs.zName as *mut c_char));
+    track!(state, freed, Name);
+    track!(state, freed, VfsState);
+    drop(state);
+}
+
+// TODO: add to [Vfs]?
+const MAX_PATH_LENGTH: usize = 512;
+
+/// The bytes of the database header SQLite compares to find out whether the database changed since
+/// it last read it (the file change counter and the fields after it).
+const DATA_VERSION_BYTES: std::ops::Range<usize> = 24..40;
+
+#[repr(C)]
+struct FileState<F> {
+    base: ffi::sqlite3_file,
+    name: *mut i8,
+    file: *mut F,
+    last_error: *const LastError,
+    vfs: *mut ffi::sqlite3_vfs,
+    immutable: bool,
+    /// The file is a hot journal that is rolled back.
+    recovering: bool,
+    /// The file was opened at a [Vfs::temporary_path] (as SQLite did not name it) and is deleted
+    /// once it is closed. Files SQLite names are left to the [Vfs] ([OpenOptions::delete_on_close]).
+    temporary: bool,
+    /// What the application last set with `SQLITE_FCNTL_PERSIST_WAL` (see [File::persist_wal]).
+    persist_wal: bool,
+    /// Overrides whether the file reports [DeviceCharacteristics::powersafe_overwrite]
+    /// (`SQLITE_FCNTL_POWERSAFE_OVERWRITE`).
+    powersafe_overwrite: Option<bool>,
+    /// How many bytes of the file may be memory-mapped (`SQLITE_FCNTL_MMAP_SIZE`).
+    mmap_size: u64,
+    /// The lock currently held on the file.
+    lock: LockKind,
+    /// The [File::data_version] when SQLite last read or wrote the database header.
+    data_version: u64,
+    /// Writes to the file are checked to be page-aligned ([RegisterOptions::validate_writes]).
+    validate_writes: bool,
+    /// What the file was opened as.
+    kind: OpenKind,
+}
 
 // Example mem-fs implementation:
 // https://github.com/sqlite/sqlite/blob/a959bf53110bfada67a3a52187acd57aa2f34e19/ext/misc/memvfs.c
@@ -216,7 +1306,7 @@ mod vfs {
         z_name: *const c_char,
         p_file: *mut ffi::sqlite3_file,
         flags: c_int,
-        _p_out_flags: *mut c_int,
+        p_out_flags: *mut c_int,
     ) -> c_int {
         let name = if z_name.is_null() {
             None
@@ -231,34 +1321,134 @@ mod vfs {
         };
         state.last_error.take();
 
-        let path = CStr::from_ptr(z_name);
-        // TODO: any way to use OsStr instead?
-        let path = path.to_string_lossy().to_string();
+        let path = if z_name.is_null() {
+            state.vfs.temporary_path()
+        } else {
+            path_from_bytes(CStr::from_ptr(z_name).to_bytes())
+        };
+        let name = match path_to_cstring(&path) {
+            Ok(name) => name,
+            Err(err) => {
+                state.last_error.set(Some(err.into()));
+                return ffi::SQLITE_CANTOPEN;
+            }
+        };
 
         let opts = match OpenOptions::from_flags(flags) {
             Some(opts) => opts,
             None => {
-                state.last_error.set(Some(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "invalid open flags",
-                )));
+                state
+                    .last_error
+                    .set(Some(std::io::Error::other("invalid open flags")));
                 return ffi::SQLITE_CANTOPEN;
             }
         };
 
-        if let Err(err) = state.vfs.open(path.as_ref(), opts).and_then(|f| {
+        let mut opts = opts;
+        let mut result = state.vfs.open(&path, opts.clone());
+        if let Err(err) = &result {
+            // retry without write access if the storage is read-only (like the unix VFS does)
+            if state.options.read_only_fallback
+                && matches!(opts.access, OpenAccess::Write | OpenAccess::Create)
+                && matches!(
+                    err.kind(),
+                    ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem
+                )
+            {
+                log::trace!("open falls back to read-only access");
+                opts.access = OpenAccess::Read;
+                result = state.vfs.open(&path, opts.clone());
+            }
+        }
+
+        // SQLite only opens a main journal without creating it to roll it back (as a hot journal)
+        let recovering = opts.kind == OpenKind::MainJournal && opts.access == OpenAccess::Write;
+        if recovering {
+            result = result.and_then(|f| {
+                state
+                    .vfs
+                    .on_recovery(&database_path(&name), RecoveryPhase::Start)?;
+                Ok(f)
+            });
+        }
+
+        if matches!(&result, Ok(f) if f.read_only()) {
+            log::trace!("open returned a read-only file");
+            opts.access = OpenAccess::Read;
+        }
+
+        // a file that cannot change cannot be written either (and the VFS need not be asked)
+        let reports_immutable = matches!(
+            &result,
+            Ok(f) if f
+                .device_characteristics(opts.kind)
+                .contains(DeviceCharacteristics::empty().immutable())
+        );
+        if reports_immutable {
+            log::trace!("open returned an immutable file");
+            opts.access = OpenAccess::Read;
+        }
+
+        let immutable = reports_immutable
+            || opts.access == OpenAccess::Read
+                && state.options.immutable_when_read_only
+                && matches!(state.vfs.access(&path, true), Ok(false));
+
+        // like the unix VFS, `psow=0` or `psow=1` in the URI of a database overrides what the file
+        // reports
+        let psow = c"psow".as_ptr();
+        let powersafe_overwrite = (flags & ffi::SQLITE_OPEN_URI != 0
+            && !z_name.is_null()
+            && !ffi::sqlite3_uri_parameter(z_name, psow).is_null())
+        .then(|| ffi::sqlite3_uri_boolean(z_name, psow, 0) != 0);
+
+        if let Err(err) = result.and_then(|f| {
             let out_file = (p_file as *mut FileState<F>)
                 .as_mut()
                 .ok_or_else(null_ptr_error)?;
+            let data_version = f.data_version();
             out_file.base.pMethods = &state.io_methods;
-            // TODO: unwrap
-            out_file.name = CString::new(name.unwrap().to_string()).unwrap().into_raw();
+            out_file.name = name.into_raw();
             out_file.file = Box::into_raw(Box::new(f));
-            out_file.last_error = Rc::into_raw(Rc::clone(&state.last_error));
+            out_file.last_error = Arc::into_raw(Arc::clone(&state.last_error));
+            out_file.vfs = p_vfs;
+            out_file.immutable = immutable;
+            out_file.recovering = recovering;
+            out_file.temporary = z_name.is_null();
+            out_file.persist_wal = false;
+            out_file.powersafe_overwrite = powersafe_overwrite;
+            out_file.mmap_size = 0;
+            out_file.lock = LockKind::None;
+            out_file.data_version = data_version;
+            out_file.validate_writes =
+                state.options.validate_writes && opts.kind == OpenKind::MainDb;
+            out_file.kind = opts.kind;
+            track!(state, allocated, FileState);
+            track!(state, allocated, Name);
+            track!(state, allocated, File);
+            state.open_files.fetch_add(1, Ordering::SeqCst);
             Ok(())
         }) {
+            // like the unix VFS, report files that may not be opened as files that cannot be opened
+            let code = match err.kind() {
+                ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem
+                    if vfs_error(&err).is_none() =>
+                {
+                    ffi::SQLITE_CANTOPEN
+                }
+                _ => error_code(&err, ffi::SQLITE_CANTOPEN),
+            };
             state.last_error.set(Some(err));
-            return ffi::SQLITE_CANTOPEN;
+            return code;
+        }
+
+        if let Some(out_flags) = p_out_flags.as_mut() {
+            *out_flags = if opts.access == OpenAccess::Read {
+                (flags & !(ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE))
+                    | ffi::SQLITE_OPEN_READONLY
+            } else {
+                flags
+            };
         }
 
         ffi::SQLITE_OK
@@ -280,22 +1470,21 @@ mod vfs {
 
         let state = match vfs_state::<V>(p_vfs) {
             Ok(state) => state,
-            Err(_) => return ffi::SQLITE_DELETE,
+            Err(_) => return ffi::SQLITE_IOERR_DELETE,
         };
         state.last_error.take();
 
-        let path = CStr::from_ptr(z_path);
-        // TODO: any way to use OsStr instead?
-        let path = path.to_string_lossy().to_string();
+        let path = path_from_bytes(CStr::from_ptr(z_path).to_bytes());
 
-        match state.vfs.delete(path.as_ref()) {
+        match state.vfs.delete(&path) {
             Ok(_) => ffi::SQLITE_OK,
             Err(err) => {
                 if err.kind() == ErrorKind::NotFound {
                     ffi::SQLITE_OK
                 } else {
+                    let code = error_code(&err, ffi::SQLITE_IOERR_DELETE);
                     state.last_error.set(Some(err));
-                    ffi::SQLITE_DELETE
+                    code
                 }
             }
         }
@@ -322,14 +1511,12 @@ mod vfs {
         };
         state.last_error.take();
 
-        let path = CStr::from_ptr(z_path);
-        // TODO: any way to use OsStr instead?
-        let path = path.to_string_lossy().to_string();
+        let path = path_from_bytes(CStr::from_ptr(z_path).to_bytes());
 
         let result = match flags {
-            ffi::SQLITE_ACCESS_EXISTS => state.vfs.exists(path.as_ref()),
-            ffi::SQLITE_ACCESS_READ => state.vfs.access(path.as_ref(), false),
-            ffi::SQLITE_ACCESS_READWRITE => state.vfs.access(path.as_ref(), true),
+            ffi::SQLITE_ACCESS_EXISTS => state.vfs.exists(&path),
+            ffi::SQLITE_ACCESS_READ => state.vfs.access(&path, false),
+            ffi::SQLITE_ACCESS_READWRITE => state.vfs.access(&path, true),
             _ => return ffi::SQLITE_IOERR_ACCESS,
         };
 
@@ -338,8 +1525,9 @@ mod vfs {
             *p_res_out = ok as i32;
             Ok(())
         }) {
+            let code = error_code(&err, ffi::SQLITE_IOERR_ACCESS);
             state.last_error.set(Some(err));
-            return ffi::SQLITE_IOERR_ACCESS;
+            return code;
         }
 
         ffi::SQLITE_OK
@@ -348,7 +1536,7 @@ mod vfs {
     /// Populate buffer `z_out` with the full canonical pathname corresponding to the pathname in
     /// `z_path`. `z_out` is guaranteed to point to a buffer of at least (INST_MAX_PATHNAME+1)
     /// bytes.
-    pub unsafe extern "C" fn full_pathname(
+    pub unsafe extern "C" fn full_pathname<V: Vfs>(
         p_vfs: *mut ffi::sqlite3_vfs,
         z_path: *const c_char,
         n_out: c_int,
@@ -357,12 +1545,24 @@ mod vfs {
         let name = CStr::from_ptr(z_path);
         log::trace!("full_pathname name={}", name.to_string_lossy());
 
-        let state = match vfs_state::<()>(p_vfs) {
+        let state = match vfs_state::<V>(p_vfs) {
             Ok(state) => state,
             Err(_) => return ffi::SQLITE_ERROR,
         };
         state.last_error.take();
 
+        let path = path_from_bytes(name.to_bytes());
+        let name = match state
+            .vfs
+            .full_pathname(&path)
+            .and_then(|path| path_to_cstring(&path).map_err(std::io::Error::from))
+        {
+            Ok(name) => name,
+            Err(err) => {
+                state.last_error.set(Some(err));
+                return ffi::SQLITE_CANTOPEN_FULLPATH;
+            }
+        };
         let name = name.to_bytes_with_nul();
         if name.len() > n_out as usize || name.len() > MAX_PATH_LENGTH {
             return ffi::SQLITE_ERROR;
@@ -374,6 +1574,7 @@ mod vfs {
     }
 
     /// Open the dynamic library located at `z_path` and return a handle.
+    #[cfg(not(feature = "loadext"))]
     pub unsafe extern "C" fn dlopen(
         _p_vfs: *mut ffi::sqlite3_vfs,
         _z_path: *const c_char,
@@ -385,6 +1586,7 @@ mod vfs {
 
     /// Populate the buffer `z_err_msg` (size `n_byte` bytes) with a human readable utf-8 string
     /// describing the most recent error encountered associated with dynamic libraries.
+    #[cfg(not(feature = "loadext"))]
     pub unsafe extern "C" fn dlerror(
         _p_vfs: *mut ffi::sqlite3_vfs,
         n_byte: c_int,
@@ -397,6 +1599,7 @@ mod vfs {
     }
 
     /// Return a pointer to the symbol `z_sym` in the dynamic library pHandle.
+    #[cfg(not(feature = "loadext"))]
     pub unsafe extern "C" fn dlsym(
         _p_vfs: *mut ffi::sqlite3_vfs,
         _p: *mut c_void,
@@ -408,52 +1611,157 @@ mod vfs {
     }
 
     /// Close the dynamic library handle `p_handle`.
+    #[cfg(not(feature = "loadext"))]
     pub unsafe extern "C" fn dlclose(_p_vfs: *mut ffi::sqlite3_vfs, _p_handle: *mut c_void) {
         log::trace!("dlclose");
     }
 
+    /// Open the dynamic library located at `z_path` and return a handle.
+    #[cfg(feature = "loadext")]
+    pub unsafe extern "C" fn dlopen(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        z_path: *const c_char,
+    ) -> *mut c_void {
+        log::trace!("dlopen");
+
+        let state = match vfs_state::<()>(p_vfs) {
+            Ok(state) => state,
+            Err(_) => return null_mut(),
+        };
+        let path = match z_path.as_ref() {
+            Some(_) => path_from_bytes(CStr::from_ptr(z_path).to_bytes()),
+            None => return null_mut(),
+        };
+        match libloading::Library::new(&path) {
+            Ok(library) => Box::into_raw(Box::new(library)) as *mut c_void,
+            Err(err) => {
+                state.last_error.set(Some(std::io::Error::other(err)));
+                null_mut()
+            }
+        }
+    }
+
+    /// Populate the buffer `z_err_msg` (size `n_byte` bytes) with a human readable utf-8 string
+    /// describing the most recent error encountered associated with dynamic libraries.
+    #[cfg(feature = "loadext")]
+    pub unsafe extern "C" fn dlerror(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        n_byte: c_int,
+        z_err_msg: *mut c_char,
+    ) {
+        log::trace!("dlerror");
+
+        // SQLite already put a message into the buffer, which is kept if there is no better one
+        let err = match vfs_state::<()>(p_vfs).map(|state| state.last_error.take()) {
+            Ok(Some(err)) => err,
+            _ => return,
+        };
+        if let Ok(msg) = CString::new(err.to_string()) {
+            ffi::sqlite3_snprintf(n_byte, z_err_msg, c"%s".as_ptr(), msg.as_ptr());
+        }
+    }
+
+    /// Return a pointer to the symbol `z_sym` in the dynamic library pHandle.
+    #[cfg(feature = "loadext")]
+    pub unsafe extern "C" fn dlsym(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        p: *mut c_void,
+        z_sym: *const c_char,
+    ) -> Option<unsafe extern "C" fn(*mut ffi::sqlite3_vfs, *mut c_void, *const i8)> {
+        log::trace!("dlsym");
+
+        let state = vfs_state::<()>(p_vfs).ok()?;
+        let library = (p as *const libloading::Library).as_ref()?;
+        if z_sym.is_null() {
+            return None;
+        }
+        let name = CStr::from_ptr(z_sym).to_bytes_with_nul();
+        let symbol = match library.get::<unsafe extern "C" fn()>(name) {
+            Ok(symbol) => symbol,
+            Err(err) => {
+                state.last_error.set(Some(std::io::Error::other(err)));
+                return None;
+            }
+        };
+        // SQLite casts the symbol to the actual signature of the entry point before calling it
+        Some(std::mem::transmute::<
+            unsafe extern "C" fn(),
+            unsafe extern "C" fn(*mut ffi::sqlite3_vfs, *mut c_void, *const i8),
+        >(*symbol))
+    }
+
+    /// Close the dynamic library handle `p_handle`.
+    #[cfg(feature = "loadext")]
+    pub unsafe extern "C" fn dlclose(_p_vfs: *mut ffi::sqlite3_vfs, p_handle: *mut c_void) {
+        log::trace!("dlclose");
+
+        if !p_handle.is_null() {
+            drop(Box::from_raw(p_handle as *mut libloading::Library));
+        }
+    }
+
     /// Populate the buffer pointed to by `z_buf_out` with `n_byte` bytes of random data.
-    pub unsafe extern "C" fn randomness(
-        _p_vfs: *mut ffi::sqlite3_vfs,
+    pub unsafe extern "C" fn randomness<V: Vfs>(
+        p_vfs: *mut ffi::sqlite3_vfs,
         n_byte: c_int,
         z_buf_out: *mut c_char,
     ) -> c_int {
         log::trace!("randomness");
 
-        use rand::Rng;
+        let state = match vfs_state::<V>(p_vfs) {
+            Ok(state) => state,
+            Err(_) => return 0,
+        };
 
-        let bytes = slice::from_raw_parts_mut(z_buf_out, n_byte as usize);
-        rand::thread_rng().fill(bytes);
+        let bytes = slice::from_raw_parts_mut(z_buf_out as *mut u8, n_byte.max(0) as usize);
+        state.vfs.random(bytes);
         bytes.len() as c_int
     }
 
     /// Sleep for `n_micro` microseconds. Return the number of microseconds actually slept.
-    pub unsafe extern "C" fn sleep(_p_vfs: *mut ffi::sqlite3_vfs, n_micro: c_int) -> c_int {
+    pub unsafe extern "C" fn sleep<V: Vfs>(p_vfs: *mut ffi::sqlite3_vfs, n_micro: c_int) -> c_int {
         log::trace!("sleep");
 
-        let instant = Instant::now();
-        thread::sleep(Duration::from_micros(n_micro as u64));
-        instant.elapsed().as_micros() as c_int
+        let state = match vfs_state::<V>(p_vfs) {
+            Ok(state) => state,
+            Err(_) => return 0,
+        };
+
+        let slept = state
+            .vfs
+            .sleep(Duration::from_micros(n_micro.max(0) as u64));
+        c_int::try_from(slept.as_micros()).unwrap_or(c_int::MAX)
     }
 
     /// Return the current time as a Julian Day number in `p_time_out`.
-    pub unsafe extern "C" fn current_time(
+    pub unsafe extern "C" fn current_time<V: Vfs>(
         p_vfs: *mut ffi::sqlite3_vfs,
         p_time_out: *mut f64,
     ) -> c_int {
         log::trace!("current_time");
 
-        let state = match vfs_state::<()>(p_vfs) {
+        let state = match vfs_state::<V>(p_vfs) {
             Ok(state) => state,
             Err(_) => return ffi::SQLITE_ERROR,
         };
         state.last_error.take();
 
-        let now = time::OffsetDateTime::now_utc().unix_timestamp() as f64;
-        *p_time_out = 2440587.5 + now / 864.0e5;
+        *p_time_out = julian_day_millis(state.vfs.current_time()) as f64 / 864.0e5;
         ffi::SQLITE_OK
     }
 
+    /// Convert `time` to the milliseconds since noon (in Greenwich) on November 24, 4714 BC, which
+    /// is the start of Julian Day numbers.
+    fn julian_day_millis(time: SystemTime) -> i64 {
+        /// The Julian Day number of the Unix epoch, in milliseconds.
+        const UNIX_EPOCH: i64 = 210_866_760_000_000;
+
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => UNIX_EPOCH + since.as_millis() as i64,
+            Err(err) => UNIX_EPOCH - err.duration().as_millis() as i64,
+        }
+    }
+
     pub unsafe extern "C" fn get_last_error(
         p_vfs: *mut ffi::sqlite3_vfs,
         n_byte: c_int,
@@ -464,60 +1772,180 @@ mod vfs {
             Err(_) => return ffi::SQLITE_ERROR,
         };
         if let Some(err) = state.last_error.take() {
-            let msg = match CString::new(err.to_string()) {
-                Ok(msg) => msg,
-                Err(_) => return ffi::SQLITE_ERROR,
-            };
-
-            let msg = msg.to_bytes_with_nul();
-            if msg.len() > n_byte as usize {
+            if n_byte <= 0 || z_err_msg.is_null() {
                 return ffi::SQLITE_ERROR;
             }
-            let out = slice::from_raw_parts_mut(z_err_msg as *mut u8, msg.len());
-            out.copy_from_slice(msg);
+
+            // cut the message at interior nul bytes and to the size of the buffer (leaving room
+            // for the nul terminator)
+            let msg = error_message(&err, state.options.error_sources);
+            let msg = msg.as_bytes();
+            let mut len = msg
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(msg.len())
+                .min(n_byte as usize - 1);
+            // don't split a UTF-8 sequence
+            while len < msg.len() && len > 0 && (msg[len] & 0b1100_0000) == 0b1000_0000 {
+                len -= 1;
+            }
+
+            let out = slice::from_raw_parts_mut(z_err_msg as *mut u8, len + 1);
+            out[..len].copy_from_slice(&msg[..len]);
+            out[len] = 0;
         }
         ffi::SQLITE_OK
     }
 
-    pub unsafe extern "C" fn current_time_int64(p_vfs: *mut ffi::sqlite3_vfs, p: *mut i64) -> i32 {
+    pub unsafe extern "C" fn current_time_int64<V: Vfs>(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        p: *mut i64,
+    ) -> i32 {
         log::trace!("current_time_int64");
 
-        let state = match vfs_state::<()>(p_vfs) {
+        let state = match vfs_state::<V>(p_vfs) {
             Ok(state) => state,
             Err(_) => return ffi::SQLITE_ERROR,
         };
         state.last_error.take();
 
-        let now = time::OffsetDateTime::now_utc().unix_timestamp() as f64;
-        *p = ((2440587.5 + now / 864.0e5) * 864.0e5) as i64;
+        *p = julian_day_millis(state.vfs.current_time());
         ffi::SQLITE_OK
     }
+
+    /// Replace the system call `z_name` with `p_new_func`, or restore it if `p_new_func` is null.
+    /// Restore all system calls if `z_name` is null.
+    pub unsafe extern "C" fn set_system_call<V: Vfs>(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        z_name: *const c_char,
+        p_new_func: ffi::sqlite3_syscall_ptr,
+    ) -> c_int {
+        log::trace!("set_system_call");
+
+        let calls = match vfs_state::<V>(p_vfs).map(|state| state.vfs.system_calls()) {
+            Ok(Some(calls)) => calls,
+            Ok(None) => return ffi::SQLITE_NOTFOUND,
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
+        if z_name.is_null() {
+            for name in calls.names() {
+                calls.set(name, None);
+            }
+            return ffi::SQLITE_OK;
+        }
+        if calls.set(CStr::from_ptr(z_name), p_new_func) {
+            ffi::SQLITE_OK
+        } else {
+            ffi::SQLITE_NOTFOUND
+        }
+    }
+
+    /// Return the current implementation of the system call `z_name`.
+    pub unsafe extern "C" fn get_system_call<V: Vfs>(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        z_name: *const c_char,
+    ) -> ffi::sqlite3_syscall_ptr {
+        log::trace!("get_system_call");
+
+        let calls = vfs_state::<V>(p_vfs).ok()?.vfs.system_calls()?;
+        if z_name.is_null() {
+            return None;
+        }
+        calls.get(CStr::from_ptr(z_name))
+    }
+
+    /// Return the name of the system call after `z_name`, or of the first one if `z_name` is null.
+    pub unsafe extern "C" fn next_system_call<V: Vfs>(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        z_name: *const c_char,
+    ) -> *const c_char {
+        log::trace!("next_system_call");
+
+        let calls = match vfs_state::<V>(p_vfs).map(|state| state.vfs.system_calls()) {
+            Ok(Some(calls)) => calls,
+            _ => return null(),
+        };
+        let names = calls.names();
+        let next = if z_name.is_null() {
+            names.first()
+        } else {
+            let name = CStr::from_ptr(z_name);
+            names
+                .iter()
+                .position(|n| *n == name)
+                .and_then(|i| names.get(i + 1))
+        };
+        next.map(|name| name.as_ptr()).unwrap_or(null())
+    }
 }
 
 mod io {
     use super::*;
 
     /// Close a file.
-    pub unsafe extern "C" fn close<F>(p_file: *mut ffi::sqlite3_file) -> c_int {
+    pub unsafe extern "C" fn close<V: Vfs>(p_file: *mut ffi::sqlite3_file) -> c_int {
         log::trace!("close");
 
-        let state = match file_state::<F>(p_file, true) {
+        let state = match file_state::<V::File>(p_file, true) {
             Ok(f) => f,
             Err(_) => return ffi::SQLITE_IOERR_CLOSE,
         };
+        let vfs = match vfs_state::<V>(state.vfs) {
+            Ok(vfs) => vfs,
+            Err(_) => return ffi::SQLITE_IOERR_CLOSE,
+        };
         log::trace!("close ({})", CStr::from_ptr(state.name).to_string_lossy());
 
         // TODO: only when free on close is set?
-        drop(CString::from_raw(state.name));
+        let name = CString::from_raw(state.name);
         state.name = null_mut();
+        track!(vfs, freed, Name);
+        let path = path_from_bytes(name.to_bytes());
+        let recovered = if state.recovering {
+            Some(database_path(&name))
+        } else {
+            None
+        };
 
-        Box::from_raw(state.file);
+        let mut file = Box::from_raw(state.file);
         state.file = null_mut();
+        if state.lock != LockKind::None {
+            // SQLite releases all locks before closing a file, but make sure they are not leaked
+            if let Err(err) = file.unlock(LockKind::None) {
+                log::warn!("failed to release the locks of a closed file: {}", err);
+            }
+            state.lock = LockKind::None;
+        }
+        drop(file);
+        track!(vfs, freed, File);
+
+        if state.temporary {
+            match vfs.vfs.delete(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    log::warn!(
+                        "failed to delete the closed file {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        let mut code = ffi::SQLITE_OK;
+        if let Some(path) = recovered {
+            if let Err(err) = vfs.vfs.on_recovery(&path, RecoveryPhase::Done) {
+                state.set_last_error(err);
+                code = ffi::SQLITE_IOERR_CLOSE;
+            }
+        }
 
-        Rc::from_raw(state.last_error);
+        Arc::from_raw(state.last_error);
         state.last_error = null();
+        track!(vfs, freed, FileState);
+        vfs.open_files.fetch_sub(1, Ordering::SeqCst);
 
-        ffi::SQLITE_OK
+        code
     }
 
     /// Read data from a file.
@@ -539,30 +1967,41 @@ mod io {
             Err(_) => return ffi::SQLITE_IOERR_CLOSE,
         };
 
-        match file.seek(SeekFrom::Start(i_ofst as u64)) {
-            Ok(o) => {
-                if o != i_ofst as u64 {
-                    return ffi::SQLITE_IOERR_READ;
-                }
-            }
+        let (offset, len) = match to_u64(i_ofst).and_then(|o| Ok((o, to_usize(i_amt)?))) {
+            Ok(v) => v,
             Err(err) => {
                 state.set_last_error(err);
                 return ffi::SQLITE_IOERR_READ;
             }
-        }
+        };
 
-        let out = slice::from_raw_parts_mut(z_buf as *mut u8, i_amt as usize);
-        if let Err(err) = file.read_exact(out) {
-            let kind = err.kind();
-            if kind == ErrorKind::UnexpectedEof {
-                return ffi::SQLITE_IOERR_SHORT_READ;
-            } else {
+        let out = slice::from_raw_parts_mut(z_buf as *mut u8, len);
+        let code = match file.read_at(out, offset) {
+            Ok(n) if n < len => {
+                // SQLite expects the rest of the buffer to be zeroed after a short read
+                out[n..].fill(0);
+                ffi::SQLITE_IOERR_SHORT_READ
+            }
+            Ok(_) => ffi::SQLITE_OK,
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR_READ);
                 state.set_last_error(err);
-                return ffi::SQLITE_IOERR_READ;
+                return code;
             }
+        };
+
+        if offset == 0 && len >= DATA_VERSION_BYTES.end {
+            state.data_version = file.data_version();
+        } else if offset == DATA_VERSION_BYTES.start as u64
+            && len == DATA_VERSION_BYTES.len()
+            && file.data_version() != state.data_version
+        {
+            // SQLite compares these bytes of the header with the ones it read last to find out
+            // whether the database changed, so alter them to make it discard its cache
+            out[0] ^= 0xff;
         }
 
-        ffi::SQLITE_OK
+        code
     }
 
     /// Write data to a file.
@@ -587,27 +2026,79 @@ mod io {
             }
         };
 
-        match file.seek(SeekFrom::Start(i_ofst as u64)) {
-            Ok(o) => {
-                if o != i_ofst as u64 {
-                    return ffi::SQLITE_IOERR_WRITE;
-                }
-            }
+        let (offset, len) = match to_u64(i_ofst).and_then(|o| Ok((o, to_usize(i_amt)?))) {
+            Ok(v) => v,
             Err(err) => {
                 state.set_last_error(err);
                 return ffi::SQLITE_IOERR_WRITE;
             }
-        }
+        };
 
-        let data = slice::from_raw_parts(z as *mut u8, i_amt as usize);
-        if let Err(err) = file.write_all(data) {
+        let data = slice::from_raw_parts(z as *mut u8, len);
+        if state.validate_writes {
+            if let Err(err) = check_page_write(file, data, offset) {
+                log::error!(
+                    "invalid write to {}: {}",
+                    CStr::from_ptr(state.name).to_string_lossy(),
+                    err
+                );
+                state.set_last_error(err);
+                return ffi::SQLITE_IOERR_WRITE;
+            }
+        }
+        if let Err(err) = file.write_all_at(data, offset) {
+            let code = error_code(&err, ffi::SQLITE_IOERR_WRITE);
             state.set_last_error(err);
-            return ffi::SQLITE_IOERR_WRITE;
+            return code;
+        }
+        if offset == 0 && len >= DATA_VERSION_BYTES.end {
+            state.data_version = file.data_version();
         }
 
         ffi::SQLITE_OK
     }
 
+    /// Check that writing `data` at `offset` to the main database `file` replaces whole pages.
+    /// Usually that is a single page, but a `VACUUM` that changes the page size writes pages of
+    /// the old size, each holding several new ones. The page size is taken from `data` itself when
+    /// it starts with the header (which is how it changes), and from the file otherwise.
+    fn check_page_write<F: File>(
+        file: &mut F,
+        data: &[u8],
+        offset: u64,
+    ) -> Result<(), std::io::Error> {
+        let page_size = if offset == 0 && data.len() >= header::HEADER_SIZE {
+            header::DatabaseHeader::parse(data)?.page_size
+        } else {
+            match header::DatabaseHeader::read(file)? {
+                Some(header) => header.page_size,
+                None => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "{} bytes written at {} before the header",
+                            data.len(),
+                            offset
+                        ),
+                    ))
+                }
+            }
+        };
+        let page_size = page_size as u64;
+        let len = data.len() as u64;
+        if len == 0 || !len.is_multiple_of(page_size) || !offset.is_multiple_of(page_size) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes written at {}, but pages are {} bytes long",
+                    len, offset, page_size
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Truncate a file.
     pub unsafe extern "C" fn truncate<F: File>(
         p_file: *mut ffi::sqlite3_file,
@@ -631,16 +2122,34 @@ mod io {
             }
         };
 
-        if let Err(err) = file.truncate(size as u64) {
+        if let Err(err) = to_u64(size).and_then(|size| file.truncate(size)) {
+            let code = error_code(&err, ffi::SQLITE_IOERR_TRUNCATE);
             state.set_last_error(err);
-            return ffi::SQLITE_IOERR_TRUNCATE;
+            return code;
         }
 
         ffi::SQLITE_OK
     }
 
+    /// Convert an offset or size received from SQLite, which must not be negative.
+    fn to_u64(n: ffi::sqlite3_int64) -> Result<u64, std::io::Error> {
+        u64::try_from(n).map_err(|_| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("negative offset or size: {}", n),
+            )
+        })
+    }
+
+    /// Convert a buffer length received from SQLite, which must not be negative.
+    fn to_usize(n: c_int) -> Result<usize, std::io::Error> {
+        usize::try_from(n).map_err(|_| {
+            std::io::Error::new(ErrorKind::InvalidInput, format!("negative length: {}", n))
+        })
+    }
+
     /// Persist changes to a file.
-    pub unsafe extern "C" fn sync<F: File>(p_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+    pub unsafe extern "C" fn sync<F: File>(p_file: *mut ffi::sqlite3_file, flags: c_int) -> c_int {
         log::trace!("sync");
 
         let state = match file_state::<F>(p_file, true) {
@@ -656,201 +2165,706 @@ mod io {
             }
         };
 
-        if let Err(err) = file.flush() {
+        if let Err(err) = file.sync(SyncOptions::from_flags(flags)) {
+            let code = error_code(&err, ffi::SQLITE_IOERR_FSYNC);
+            state.set_last_error(err);
+            return code;
+        }
+
+        ffi::SQLITE_OK
+    }
+
+    /// Return the current file-size of a file.
+    pub unsafe extern "C" fn file_size<F: File>(
+        p_file: *mut ffi::sqlite3_file,
+        p_size: *mut ffi::sqlite3_int64,
+    ) -> c_int {
+        log::trace!("file_size");
+
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_FSTAT,
+        };
+        log::trace!(
+            "file_size ({})",
+            CStr::from_ptr(state.name).to_string_lossy()
+        );
+        let file = match file::<F>(state.file) {
+            Ok(f) => f,
+            Err(err) => {
+                state.set_last_error(err);
+                return ffi::SQLITE_IOERR_FSTAT;
+            }
+        };
+
+        if let Err(err) = file.file_size().and_then(|n| {
+            let p_size: &mut ffi::sqlite3_int64 = p_size.as_mut().ok_or_else(null_ptr_error)?;
+            *p_size = ffi::sqlite3_int64::try_from(n).map_err(|_| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("file size too large: {}", n),
+                )
+            })?;
+            Ok(())
+        }) {
+            let code = error_code(&err, ffi::SQLITE_IOERR_FSTAT);
+            state.set_last_error(err);
+            return code;
+        }
+
+        ffi::SQLITE_OK
+    }
+
+    /// Lock a file.
+    pub unsafe extern "C" fn lock<F: File>(p_file: *mut ffi::sqlite3_file, e_lock: c_int) -> c_int {
+        log::trace!("lock {}", e_lock);
+
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_LOCK,
+        };
+        let lock = match LockKind::from_i32(e_lock) {
+            Some(lock) => lock,
+            None => return ffi::SQLITE_IOERR_LOCK,
+        };
+        if state.lock >= lock {
+            return ffi::SQLITE_OK;
+        }
+
+        let valid = match lock {
+            LockKind::Shared => state.lock == LockKind::None,
+            LockKind::Reserved => state.lock == LockKind::Shared,
+            LockKind::Exclusive => state.lock != LockKind::None,
+            LockKind::None | LockKind::Pending => false,
+        };
+        if !valid {
+            state.set_last_error(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid lock transition from {:?} to {:?}",
+                    state.lock, lock
+                ),
+            ));
+            return ffi::SQLITE_IOERR_LOCK;
+        }
+
+        let file = match file::<F>(state.file) {
+            Ok(f) => f,
+            Err(err) => {
+                state.set_last_error(err);
+                return ffi::SQLITE_IOERR_LOCK;
+            }
+        };
+
+        // an exclusive lock is acquired through a pending lock (like the unix VFS does), which
+        // keeps new readers out while waiting for the existing ones to finish
+        let steps: &[LockKind] = if lock == LockKind::Exclusive && state.lock < LockKind::Pending {
+            &[LockKind::Pending, LockKind::Exclusive]
+        } else {
+            &[lock]
+        };
+        for step in steps {
+            match file.lock(*step) {
+                Ok(true) => state.lock = *step,
+                Ok(false) => return ffi::SQLITE_BUSY,
+                Err(err) => {
+                    let code = error_code(&err, ffi::SQLITE_IOERR_LOCK);
+                    state.set_last_error(err);
+                    return code;
+                }
+            }
+        }
+
+        ffi::SQLITE_OK
+    }
+
+    /// Unlock a file.
+    pub unsafe extern "C" fn unlock<F: File>(
+        p_file: *mut ffi::sqlite3_file,
+        e_lock: c_int,
+    ) -> c_int {
+        log::trace!("unlock {}", e_lock);
+
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_UNLOCK,
+        };
+        let lock = match LockKind::from_i32(e_lock) {
+            Some(lock @ (LockKind::None | LockKind::Shared)) => lock,
+            _ => return ffi::SQLITE_IOERR_UNLOCK,
+        };
+        if state.lock <= lock {
+            return ffi::SQLITE_OK;
+        }
+
+        let file = match file::<F>(state.file) {
+            Ok(f) => f,
+            Err(err) => {
+                state.set_last_error(err);
+                return ffi::SQLITE_IOERR_UNLOCK;
+            }
+        };
+        if let Err(err) = file.unlock(lock) {
+            let code = error_code(&err, ffi::SQLITE_IOERR_UNLOCK);
             state.set_last_error(err);
-            return ffi::SQLITE_IOERR_FSYNC;
+            return code;
+        }
+        state.lock = lock;
+
+        ffi::SQLITE_OK
+    }
+
+    /// Check if another file-handle holds a RESERVED lock on a file.
+    pub unsafe extern "C" fn check_reserved_lock<F: File>(
+        p_file: *mut ffi::sqlite3_file,
+        p_res_out: *mut c_int,
+    ) -> c_int {
+        log::trace!("check_reserved_lock");
+
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_CHECKRESERVEDLOCK,
+        };
+        let p_res_out = match p_res_out.as_mut() {
+            Some(p_res_out) => p_res_out,
+            None => {
+                state.set_last_error(null_ptr_error());
+                return ffi::SQLITE_IOERR_CHECKRESERVEDLOCK;
+            }
+        };
+
+        let reserved = if state.lock > LockKind::Shared {
+            Ok(true)
+        } else {
+            file::<F>(state.file).and_then(|file| file.check_reserved_lock())
+        };
+        match reserved {
+            Ok(reserved) => {
+                *p_res_out = reserved as c_int;
+                ffi::SQLITE_OK
+            }
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR_CHECKRESERVEDLOCK);
+                state.set_last_error(err);
+                code
+            }
+        }
+    }
+
+    /// File control method. For custom operations on an mem-file.
+    pub unsafe extern "C" fn file_control<V: Vfs>(
+        p_file: *mut ffi::sqlite3_file,
+        op: c_int,
+        p_arg: *mut c_void,
+    ) -> c_int {
+        log::trace!("file_control op={}", op);
+
+        let state = match file_state::<V::File>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
+
+        let code = match op {
+            ffi::SQLITE_FCNTL_PRAGMA => pragma::<V>(state, p_arg as *mut *mut c_char),
+            ffi::SQLITE_FCNTL_VFSNAME => vfs_name(state, p_arg as *mut *mut c_char),
+            ffi::SQLITE_FCNTL_PERSIST_WAL => persist_wal(state, p_arg as *mut c_int),
+            ffi::SQLITE_FCNTL_POWERSAFE_OVERWRITE => {
+                powersafe_overwrite(state, p_arg as *mut c_int)
+            }
+            ffi::SQLITE_FCNTL_MMAP_SIZE => mmap_size(state, p_arg as *mut i64),
+            ffi::SQLITE_FCNTL_LOCKSTATE => match (p_arg as *mut c_int).as_mut() {
+                Some(arg) => {
+                    *arg = state.lock.to_i32();
+                    ffi::SQLITE_OK
+                }
+                None => ffi::SQLITE_ERROR,
+            },
+            ffi::SQLITE_FCNTL_HAS_MOVED => has_moved(state, p_arg as *mut c_int),
+            ffi::SQLITE_FCNTL_EXTERNAL_READER => external_reader(state, p_arg as *mut c_int),
+            ffi::SQLITE_FCNTL_TEMPFILENAME => temp_file_name::<V>(state, p_arg as *mut *mut c_char),
+            ffi::SQLITE_FCNTL_CKPT_START | ffi::SQLITE_FCNTL_CKPT_DONE => {
+                checkpoint::<V>(state, op == ffi::SQLITE_FCNTL_CKPT_START)
+            }
+            ffi::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE
+            | ffi::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE
+            | ffi::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => batch_atomic_write::<V::File>(state, op),
+            ffi::SQLITE_FCNTL_SYNC | ffi::SQLITE_FCNTL_COMMIT_PHASETWO => {
+                commit::<V::File>(state, op == ffi::SQLITE_FCNTL_SYNC)
+            }
+            ffi::SQLITE_FCNTL_OVERWRITE => overwrite::<V::File>(state, p_arg as *const i64),
+            _ => ffi::SQLITE_NOTFOUND,
+        };
+        if code != ffi::SQLITE_NOTFOUND {
+            return code;
+        }
+
+        // everything not handled by the VFS itself is left to the file
+        let result = file::<V::File>(state.file)
+            .and_then(|file| file.file_control(FileControl::from_raw(op, p_arg)));
+        match result {
+            Ok(true) => ffi::SQLITE_OK,
+            Ok(false) => ffi::SQLITE_NOTFOUND,
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR);
+                state.set_last_error(err);
+                code
+            }
+        }
+    }
+
+    /// Notify the [CheckpointCoordinator] of the [Vfs] (if any) that a checkpoint starts or is done.
+    unsafe fn checkpoint<V: Vfs>(state: &mut FileState<V::File>, start: bool) -> c_int {
+        let vfs = match vfs_state::<V>(state.vfs) {
+            Ok(vfs) => vfs,
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
+        let coordinator = match vfs.vfs.checkpoint_coordinator() {
+            Some(coordinator) => coordinator,
+            None => return ffi::SQLITE_NOTFOUND,
+        };
+        let path = path_from_bytes(CStr::from_ptr(state.name).to_bytes());
+
+        if start {
+            coordinator.checkpoint_start(&path);
+        } else {
+            coordinator.checkpoint_done(&path);
+        }
+        ffi::SQLITE_OK
+    }
+
+    /// Report the name the VFS is registered as. Shims stacked on top of it (see
+    /// [VfsHandle::as_raw]) add their own name to it.
+    unsafe fn vfs_name<F>(state: &FileState<F>, out: *mut *mut c_char) -> c_int {
+        let vfs = match state.vfs.as_ref() {
+            Some(vfs) if !out.is_null() => vfs,
+            _ => return ffi::SQLITE_ERROR,
+        };
+        let name = CStr::from_ptr(vfs.zName).to_string_lossy().into_owned();
+        match sqlite_string(name) {
+            Ok(name) => {
+                *out = name;
+                ffi::SQLITE_OK
+            }
+            Err(code) => code,
+        }
+    }
+
+    /// Report a flag of the file if `arg` is negative, or set (`arg` is positive) or clear it
+    /// (`arg` is `0`) otherwise.
+    unsafe fn mode_flag(flag: &mut bool, arg: *mut c_int) -> c_int {
+        let arg = match arg.as_mut() {
+            Some(arg) => arg,
+            None => return ffi::SQLITE_ERROR,
+        };
+        if *arg < 0 {
+            *arg = *flag as c_int;
+        } else {
+            *flag = *arg > 0;
+        }
+        ffi::SQLITE_OK
+    }
+
+    /// Report or change whether the WAL file of the database is kept ([File::persist_wal]).
+    unsafe fn persist_wal<F: File>(state: &mut FileState<F>, arg: *mut c_int) -> c_int {
+        let file = match file::<F>(state.file) {
+            Ok(file) => file,
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
+        let set = matches!(arg.as_ref(), Some(arg) if *arg >= 0);
+        let mut flag = file.persist_wal().unwrap_or(state.persist_wal);
+        let code = mode_flag(&mut flag, arg);
+        if code == ffi::SQLITE_OK && set {
+            state.persist_wal = flag;
+            file.set_persist_wal(flag);
+        }
+        code
+    }
+
+    /// Report or override whether the file reports [DeviceCharacteristics::powersafe_overwrite].
+    unsafe fn powersafe_overwrite<F: File>(state: &mut FileState<F>, arg: *mut c_int) -> c_int {
+        let mut flag = match state.powersafe_overwrite {
+            Some(flag) => flag,
+            None => match file::<F>(state.file) {
+                Ok(file) => file
+                    .device_characteristics(state.kind)
+                    .contains(DeviceCharacteristics::empty().powersafe_overwrite()),
+                Err(_) => return ffi::SQLITE_ERROR,
+            },
+        };
+        let code = mode_flag(&mut flag, arg);
+        if code == ffi::SQLITE_OK {
+            state.powersafe_overwrite = Some(flag);
+        }
+        code
+    }
+
+    /// Report how many bytes of the file may be memory-mapped, and change it to `arg` unless it is
+    /// negative (as far as [MemoryMapped::set_size_limit] allows).
+    unsafe fn mmap_size<F: File>(state: &mut FileState<F>, arg: *mut i64) -> c_int {
+        let arg = match arg.as_mut() {
+            Some(arg) => arg,
+            None => return ffi::SQLITE_ERROR,
+        };
+        let requested = u64::try_from(*arg).ok();
+        *arg = i64::try_from(state.mmap_size).unwrap_or(i64::MAX);
+        if let Some(requested) = requested {
+            state.mmap_size = match file::<F>(state.file) {
+                Ok(file) => match file.memory_mapped() {
+                    Some(mmap) => mmap.set_size_limit(requested),
+                    None => 0,
+                },
+                Err(_) => return ffi::SQLITE_ERROR,
+            };
+        }
+        ffi::SQLITE_OK
+    }
+
+    /// Report whether the database file has moved, as returned by [File::moved].
+    unsafe fn has_moved<F: File>(state: &mut FileState<F>, out: *mut c_int) -> c_int {
+        if out.is_null() {
+            return ffi::SQLITE_ERROR;
+        }
+        match file::<F>(state.file).and_then(|file| file.moved()) {
+            Ok(moved) => {
+                *out = moved as c_int;
+                ffi::SQLITE_OK
+            }
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR_FSTAT);
+                state.set_last_error(err);
+                code
+            }
+        }
+    }
+
+    /// Report whether another connection reads the database in WAL mode, as the
+    /// [SharedMemory::external_reader] of the file tells. Left to the file if it provides no shared
+    /// memory.
+    unsafe fn external_reader<F: File>(state: &mut FileState<F>, out: *mut c_int) -> c_int {
+        if out.is_null() {
+            return ffi::SQLITE_ERROR;
+        }
+        let shm = match file::<F>(state.file) {
+            Ok(file) => match file.shared_memory() {
+                Some(shm) => shm,
+                None => return ffi::SQLITE_NOTFOUND,
+            },
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
+        match shm.external_reader() {
+            Ok(reader) => {
+                *out = reader as c_int;
+                ffi::SQLITE_OK
+            }
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR_SHMLOCK);
+                state.set_last_error(err);
+                code
+            }
         }
-
-        ffi::SQLITE_OK
     }
 
-    /// Return the current file-size of a file.
-    pub unsafe extern "C" fn file_size<F: File>(
-        p_file: *mut ffi::sqlite3_file,
-        p_size: *mut ffi::sqlite3_int64,
-    ) -> c_int {
-        log::trace!("file_size");
+    /// Report the path of a new temporary file, as returned by [Vfs::temporary_path].
+    unsafe fn temp_file_name<V: Vfs>(state: &FileState<V::File>, out: *mut *mut c_char) -> c_int {
+        let vfs = match vfs_state::<V>(state.vfs) {
+            Ok(vfs) if !out.is_null() => vfs,
+            _ => return ffi::SQLITE_ERROR,
+        };
+        let path = match path_to_cstring(&vfs.vfs.temporary_path()) {
+            Ok(path) => path,
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
+        match sqlite_string(path) {
+            Ok(path) => {
+                *out = path;
+                ffi::SQLITE_OK
+            }
+            Err(code) => code,
+        }
+    }
 
-        let state = match file_state::<F>(p_file, true) {
-            Ok(f) => f,
-            Err(_) => return ffi::SQLITE_IOERR_FSTAT,
+    /// Begin, commit or roll back a batch of atomic writes, if the file supports them.
+    unsafe fn batch_atomic_write<F: File>(state: &mut FileState<F>, op: c_int) -> c_int {
+        let batch = match file::<F>(state.file) {
+            Ok(file) => match file.batch_atomic_write() {
+                Some(batch) => batch,
+                None => return ffi::SQLITE_NOTFOUND,
+            },
+            Err(_) => return ffi::SQLITE_ERROR,
         };
-        log::trace!(
-            "file_size ({})",
-            CStr::from_ptr(state.name).to_string_lossy()
-        );
-        let file = match file::<F>(state.file) {
-            Ok(f) => f,
+        let result = match op {
+            ffi::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => batch.begin(),
+            ffi::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => batch.commit(),
+            _ => batch.rollback(),
+        };
+        match result {
+            Ok(()) => ffi::SQLITE_OK,
             Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR);
                 state.set_last_error(err);
-                return ffi::SQLITE_IOERR_FSTAT;
+                code
             }
-        };
-
-        if let Err(err) = file.file_size().and_then(|n| {
-            let p_size: &mut ffi::sqlite3_int64 = p_size.as_mut().ok_or_else(null_ptr_error)?;
-            *p_size = n as ffi::sqlite3_int64;
-            Ok(())
-        }) {
-            state.set_last_error(err);
-            return ffi::SQLITE_IOERR_FSTAT;
         }
-
-        ffi::SQLITE_OK
     }
 
-    /// Lock a file.
-    pub unsafe extern "C" fn lock(p_file: *mut ffi::sqlite3_file, _e_lock: c_int) -> c_int {
-        log::trace!("lock");
-
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_LOCK;
+    /// Tell the file that a transaction is about to be synced ([File::pre_commit]) or was committed
+    /// ([File::post_commit]).
+    unsafe fn commit<F: File>(state: &mut FileState<F>, pre: bool) -> c_int {
+        let result = file::<F>(state.file).and_then(|file| {
+            if pre {
+                file.pre_commit()
+            } else {
+                file.post_commit()
+            }
+        });
+        match result {
+            Ok(()) => ffi::SQLITE_OK,
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR);
+                state.set_last_error(err);
+                code
+            }
         }
-
-        // TODO: implement locking
-        ffi::SQLITE_OK
     }
 
-    /// Unlock a file.
-    pub unsafe extern "C" fn unlock(p_file: *mut ffi::sqlite3_file, _e_lock: c_int) -> c_int {
-        log::trace!("unlock");
-
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_UNLOCK;
+    /// Tell the file that the whole database is about to be rewritten ([File::begin_overwrite]).
+    unsafe fn overwrite<F: File>(state: &mut FileState<F>, size: *const i64) -> c_int {
+        let size = match size.as_ref() {
+            Some(size) => (*size).max(0) as u64,
+            None => return ffi::SQLITE_ERROR,
+        };
+        match file::<F>(state.file).and_then(|file| file.begin_overwrite(size)) {
+            Ok(()) => ffi::SQLITE_OK,
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR);
+                state.set_last_error(err);
+                code
+            }
         }
-
-        // TODO: implement locking
-        ffi::SQLITE_OK
     }
 
-    /// Check if another file-handle holds a RESERVED lock on a file.
-    pub unsafe extern "C" fn check_reserved_lock(
-        p_file: *mut ffi::sqlite3_file,
-        p_res_out: *mut c_int,
-    ) -> c_int {
-        log::trace!("check_reserved_lock");
-
-        let state = match file_state::<()>(p_file, true) {
-            Ok(f) => f,
-            Err(_) => return ffi::SQLITE_IOERR_CHECKRESERVEDLOCK,
+    /// Handle the pragmas provided by the VFS and its files. `args` points to an array of the
+    /// result or error message (out), the pragma name and its argument (if any).
+    unsafe fn pragma<V: Vfs>(state: &mut FileState<V::File>, args: *mut *mut c_char) -> c_int {
+        let name = match args.add(1).as_ref() {
+            Some(name) if !name.is_null() => CStr::from_ptr(*name),
+            _ => return ffi::SQLITE_NOTFOUND,
         };
 
-        match p_res_out.as_mut() {
-            Some(p_res_out) => {
-                *p_res_out = false as i32;
+        let result = if name.to_bytes().eq_ignore_ascii_case(b"vfs_health") {
+            match vfs_state::<V>(state.vfs) {
+                Ok(vfs) => Some(Ok(Some(vfs.vfs.health().to_string()))),
+                Err(_) => return ffi::SQLITE_ERROR,
             }
-            None => {
-                state.set_last_error(null_ptr_error());
-                return ffi::SQLITE_IOERR_CHECKRESERVEDLOCK;
+        } else {
+            let name = match name.to_str() {
+                Ok(name) => name,
+                Err(_) => return ffi::SQLITE_NOTFOUND,
+            };
+            let value = match args.add(2).as_ref() {
+                Some(value) if !value.is_null() => match CStr::from_ptr(*value).to_str() {
+                    Ok(value) => Some(value),
+                    Err(_) => return ffi::SQLITE_NOTFOUND,
+                },
+                _ => None,
+            };
+            match file::<V::File>(state.file) {
+                Ok(file) => file.pragma(name, value),
+                Err(_) => return ffi::SQLITE_ERROR,
             }
-        }
+        };
 
-        // TODO: implement locking
-        ffi::SQLITE_OK
+        match result {
+            None => ffi::SQLITE_NOTFOUND,
+            Some(Ok(None)) => ffi::SQLITE_OK,
+            Some(Ok(Some(text))) => match sqlite_string(text) {
+                Ok(text) => {
+                    *args = text;
+                    ffi::SQLITE_OK
+                }
+                Err(code) => code,
+            },
+            Some(Err(err)) => {
+                let code = error_code(&err, ffi::SQLITE_ERROR);
+                *args = sqlite_string(err.to_string()).unwrap_or(null_mut());
+                state.set_last_error(err);
+                code
+            }
+        }
     }
 
-    /// File control method. For custom operations on an mem-file.
-    pub unsafe extern "C" fn file_control(
-        p_file: *mut ffi::sqlite3_file,
-        op: c_int,
-        _p_arg: *mut c_void,
-    ) -> c_int {
-        log::trace!("file_control op={}", op);
-
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_ERROR;
+    /// Copy `text` into memory allocated by SQLite, for results that SQLite frees once it is done
+    /// with them.
+    unsafe fn sqlite_string(text: impl Into<Vec<u8>>) -> Result<*mut c_char, c_int> {
+        let text = CString::new(text).map_err(|_| ffi::SQLITE_ERROR)?;
+        let text = text.as_bytes_with_nul();
+        let out = ffi::sqlite3_malloc(text.len() as c_int) as *mut u8;
+        if out.is_null() {
+            return Err(ffi::SQLITE_NOMEM);
         }
-
-        ffi::SQLITE_NOTFOUND
+        std::ptr::copy_nonoverlapping(text.as_ptr(), out, text.len());
+        Ok(out as *mut c_char)
     }
 
     /// Return the sector-size in bytes for a file.
-    pub unsafe extern "C" fn sector_size(p_file: *mut ffi::sqlite3_file) -> c_int {
+    pub unsafe extern "C" fn sector_size<F: File>(p_file: *mut ffi::sqlite3_file) -> c_int {
         log::trace!("sector_size");
 
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_ERROR;
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
+        match file::<F>(state.file) {
+            Ok(file) => c_int::try_from(file.sector_size(state.kind)).unwrap_or(c_int::MAX),
+            Err(_) => ffi::SQLITE_ERROR,
         }
-
-        1024
     }
 
     /// Return the device characteristic flags supported by a file.
-    pub unsafe extern "C" fn device_characteristics(p_file: *mut ffi::sqlite3_file) -> c_int {
+    pub unsafe extern "C" fn device_characteristics<F: File>(
+        p_file: *mut ffi::sqlite3_file,
+    ) -> c_int {
         log::trace!("device_characteristics");
 
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_ERROR;
-        }
-
-        // For now, simply copied from [memfs] without putting in a lot of thought.
-        // [memfs]: (https://github.com/sqlite/sqlite/blob/a959bf53110bfada67a3a52187acd57aa2f34e19/ext/misc/memvfs.c#L271-L276)
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
+        let mut characteristics = match file::<F>(state.file) {
+            Ok(file) => match file.batch_atomic_write() {
+                Some(_) => file.device_characteristics(state.kind).batch_atomic(),
+                None => file.device_characteristics(state.kind),
+            },
+            Err(_) => return ffi::SQLITE_ERROR,
+        };
 
-        // writes of any size are atomic
-        ffi::SQLITE_IOCAP_ATOMIC |
-        // after reboot following a crash or power loss, the only bytes in a file that were written
-        // at the application level might have changed and that adjacent bytes, even bytes within
-        // the same sector are guaranteed to be unchanged
-        ffi::SQLITE_IOCAP_POWERSAFE_OVERWRITE |
-        // when data is appended to a file, the data is appended first then the size of the file is
-        // extended, never the other way around
-        ffi::SQLITE_IOCAP_SAFE_APPEND |
-        // information is written to disk in the same order as calls to xWrite()
-        ffi::SQLITE_IOCAP_SEQUENTIAL
+        // the file cannot change while it is open
+        if state.immutable {
+            characteristics = characteristics.immutable();
+        }
+        match state.powersafe_overwrite {
+            Some(true) => characteristics = characteristics.powersafe_overwrite(),
+            Some(false) => characteristics.0 &= !ffi::SQLITE_IOCAP_POWERSAFE_OVERWRITE,
+            None => {}
+        }
+        characteristics.bits()
     }
 
     /// Create a shared memory file mapping.
-    pub unsafe extern "C" fn shm_map(
+    pub unsafe extern "C" fn shm_map<F: File>(
         p_file: *mut ffi::sqlite3_file,
         i_pg: i32,
         pgsz: i32,
         b_extend: i32,
-        _pp: *mut *mut c_void,
+        pp: *mut *mut c_void,
     ) -> i32 {
         log::trace!("shm_map pg={} sz={} extend={}", i_pg, pgsz, b_extend);
 
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_SHMMAP;
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_SHMMAP,
+        };
+        let result = file::<F>(state.file).and_then(|file| {
+            let shm = file.shared_memory().ok_or_else(|| {
+                std::io::Error::new(ErrorKind::Unsupported, "shared memory is not supported")
+            })?;
+            let index = u32::try_from(i_pg).map_err(|_| {
+                std::io::Error::new(ErrorKind::InvalidInput, "negative region index")
+            })?;
+            shm.map(index, to_usize(pgsz)?, b_extend != 0)
+        });
+        match result {
+            Ok(region) => {
+                *pp = region.map_or(null_mut(), |region| region.as_ptr() as *mut c_void);
+                ffi::SQLITE_OK
+            }
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR_SHMMAP);
+                state.set_last_error(err);
+                code
+            }
         }
-
-        ffi::SQLITE_IOERR_SHMMAP
     }
 
     /// Perform locking on a shared-memory segment.
-    pub unsafe extern "C" fn shm_lock(
+    pub unsafe extern "C" fn shm_lock<F: File>(
         p_file: *mut ffi::sqlite3_file,
-        _offset: i32,
-        _n: i32,
-        _flags: i32,
+        offset: i32,
+        n: i32,
+        flags: i32,
     ) -> i32 {
-        log::trace!("shm_lock");
+        log::trace!("shm_lock offset={} n={} flags={}", offset, n, flags);
 
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_SHMMAP;
-        }
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_SHMLOCK,
+        };
+        let slots = match (u8::try_from(offset), u8::try_from(n)) {
+            (Ok(offset), Ok(n)) if offset.saturating_add(n) <= ffi::SQLITE_SHM_NLOCK as u8 => {
+                offset..offset + n
+            }
+            _ => return ffi::SQLITE_IOERR_SHMLOCK,
+        };
+        let lock = if flags & ffi::SQLITE_SHM_EXCLUSIVE != 0 {
+            ShmLock::Exclusive
+        } else {
+            ShmLock::Shared
+        };
 
-        ffi::SQLITE_IOERR_SHMLOCK
+        let result = file::<F>(state.file).and_then(|file| {
+            let shm = file.shared_memory().ok_or_else(|| {
+                std::io::Error::new(ErrorKind::Unsupported, "shared memory is not supported")
+            })?;
+            if flags & ffi::SQLITE_SHM_UNLOCK != 0 {
+                shm.unlock(slots, lock).map(|_| true)
+            } else {
+                shm.lock(slots, lock)
+            }
+        });
+        match result {
+            Ok(true) => ffi::SQLITE_OK,
+            Ok(false) => ffi::SQLITE_BUSY,
+            Err(err) => {
+                let code = error_code(&err, ffi::SQLITE_IOERR_SHMLOCK);
+                state.set_last_error(err);
+                code
+            }
+        }
     }
 
     /// Memory barrier operation on shared memory.
-    pub unsafe extern "C" fn shm_barrier(_p_file: *mut ffi::sqlite3_file) {
+    pub unsafe extern "C" fn shm_barrier<F: File>(p_file: *mut ffi::sqlite3_file) {
         log::trace!("shm_barrier");
+
+        let shm = file_state::<F>(p_file, false)
+            .and_then(|state| file::<F>(state.file))
+            .ok()
+            .and_then(|file| file.shared_memory());
+        match shm {
+            Some(shm) => shm.barrier(),
+            None => std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst),
+        }
     }
 
     /// Unmap a shared memory segment.
-    pub unsafe extern "C" fn shm_unmap(p_file: *mut ffi::sqlite3_file, _delete_flags: i32) -> i32 {
-        log::trace!("shm_unmap");
+    pub unsafe extern "C" fn shm_unmap<F: File>(
+        p_file: *mut ffi::sqlite3_file,
+        delete_flags: i32,
+    ) -> i32 {
+        log::trace!("shm_unmap delete={}", delete_flags);
 
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_SHMMAP;
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_SHMMAP,
+        };
+        let result = file::<F>(state.file).and_then(|file| match file.shared_memory() {
+            Some(shm) => shm.unmap(delete_flags != 0),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            let code = error_code(&err, ffi::SQLITE_IOERR_SHMMAP);
+            state.set_last_error(err);
+            return code;
         }
 
         ffi::SQLITE_OK
@@ -861,57 +2875,188 @@ mod io {
         p_file: *mut ffi::sqlite3_file,
         i_ofst: i64,
         i_amt: i32,
-        _pp: *mut *mut c_void,
+        pp: *mut *mut c_void,
     ) -> i32 {
         log::trace!("mem_fetch offset={} len={}", i_ofst, i_amt);
 
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_ERROR;
+        *pp = null_mut();
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_MMAP,
+        };
+        let (offset, len) = match (u64::try_from(i_ofst), usize::try_from(i_amt)) {
+            (Ok(offset), Ok(len)) if offset.saturating_add(len as u64) <= state.mmap_size => {
+                (offset, len)
+            }
+            _ => return ffi::SQLITE_OK,
+        };
+        let file = match file::<F>(state.file) {
+            Ok(file) => file,
+            Err(err) => {
+                state.set_last_error(err);
+                return ffi::SQLITE_IOERR_MMAP;
+            }
+        };
+        let data_version = file.data_version();
+        let mmap = match file.memory_mapped() {
+            Some(mmap) => mmap,
+            None => return ffi::SQLITE_OK,
+        };
+        match mmap
+            .fetch(offset, len)
+            .map(|bytes| (bytes.len(), bytes.as_ptr()))
+        {
+            Some((n, ptr)) if n == len => {
+                *pp = ptr as *mut c_void;
+                if offset == 0 && len >= DATA_VERSION_BYTES.end {
+                    state.data_version = data_version;
+                }
+            }
+            // SQLite reads the page with xRead instead
+            Some(_) => mmap.unfetch(offset),
+            None => {}
         }
 
-        ffi::SQLITE_ERROR
+        ffi::SQLITE_OK
     }
 
     /// Release a memory-mapped page.
-    pub unsafe extern "C" fn mem_unfetch(
+    pub unsafe extern "C" fn mem_unfetch<F: File>(
         p_file: *mut ffi::sqlite3_file,
         i_ofst: i64,
-        _p_page: *mut c_void,
+        p_page: *mut c_void,
     ) -> i32 {
         log::trace!("mem_unfetch offset={}", i_ofst);
 
-        // reset last error
-        if file_state::<()>(p_file, true).is_err() {
-            return ffi::SQLITE_ERROR;
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_MMAP,
+        };
+        // without a page, SQLite only hints that the mapping could be released as a whole (while no
+        // pages are fetched)
+        if p_page.is_null() {
+            return ffi::SQLITE_OK;
+        }
+        let offset = match u64::try_from(i_ofst) {
+            Ok(offset) => offset,
+            Err(_) => return ffi::SQLITE_IOERR_MMAP,
+        };
+        match file::<F>(state.file) {
+            Ok(file) => {
+                if let Some(mmap) = file.memory_mapped() {
+                    mmap.unfetch(offset);
+                }
+                ffi::SQLITE_OK
+            }
+            Err(err) => {
+                state.set_last_error(err);
+                ffi::SQLITE_IOERR_MMAP
+            }
         }
-
-        ffi::SQLITE_OK
     }
 }
 
 impl<F> FileState<F> {
     unsafe fn unset_last_error(&mut self) {
-        let last_error = Rc::from_raw(self.last_error);
-        last_error.take();
-        self.last_error = Rc::into_raw(last_error);
+        (*self.last_error).take();
     }
 
     unsafe fn set_last_error(&mut self, err: std::io::Error) {
-        let last_error = Rc::from_raw(self.last_error);
-        last_error.set(Some(err));
-        self.last_error = Rc::into_raw(last_error);
+        (*self.last_error).set(Some(err));
+    }
+}
+
+/// Render `err` followed by up to `max_sources` of its sources, separated by `: `.
+fn error_message(err: &dyn std::error::Error, max_sources: usize) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+    for _ in 0..max_sources {
+        let err = match source {
+            Some(err) => err,
+            None => break,
+        };
+        msg.push_str(": ");
+        msg.push_str(&err.to_string());
+        source = err.source();
+    }
+    msg
+}
+
+/// Return the SQLite result code for `err`: the code of a wrapped [VfsError], the code that
+/// corresponds to its [ErrorKind] (e.g. `SQLITE_BUSY` for contention or `SQLITE_FULL` if the
+/// storage is exhausted), or `code` for all other errors.
+///
+/// A denied permission is reported as `SQLITE_READONLY` if `code` is that of a write (like the
+/// unix VFS does for files it may not write), and as `SQLITE_IOERR_ACCESS` otherwise.
+fn error_code(err: &std::io::Error, code: c_int) -> c_int {
+    if let Some(err) = vfs_error(err) {
+        return err.code();
+    }
+    match err.kind() {
+        ErrorKind::WouldBlock => ffi::SQLITE_BUSY,
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge => {
+            ffi::SQLITE_FULL
+        }
+        ErrorKind::ReadOnlyFilesystem => ffi::SQLITE_READONLY,
+        ErrorKind::PermissionDenied => match code {
+            ffi::SQLITE_IOERR_WRITE | ffi::SQLITE_IOERR_TRUNCATE | ffi::SQLITE_IOERR_FSYNC => {
+                ffi::SQLITE_READONLY
+            }
+            _ => ffi::SQLITE_IOERR_ACCESS,
+        },
+        ErrorKind::OutOfMemory => ffi::SQLITE_IOERR_NOMEM,
+        _ => code,
+    }
+}
+
+/// The [VfsError] wrapped in `err`, if any.
+fn vfs_error(err: &std::io::Error) -> Option<&VfsError> {
+    err.get_ref().and_then(|err| err.downcast_ref::<VfsError>())
+}
+
+/// The path of the database a main journal at `journal` belongs to.
+fn database_path(journal: &CStr) -> PathBuf {
+    let journal = journal.to_bytes();
+    path_from_bytes(journal.strip_suffix(b"-journal").unwrap_or(journal))
+}
+
+/// The path SQLite refers to with `bytes`. On unix, paths are arbitrary bytes and are kept as they
+/// are. Elsewhere, SQLite encodes paths as UTF-8.
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// Encode `path` to pass it to SQLite (the reverse of [path_from_bytes]).
+fn path_to_cstring(path: &Path) -> Result<CString, std::ffi::NulError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        CString::new(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        CString::new(path.to_string_lossy().into_owned())
     }
 }
 
 fn null_ptr_error() -> std::io::Error {
-    std::io::Error::new(ErrorKind::Other, "received null pointer")
+    std::io::Error::other("received null pointer")
 }
 
-unsafe fn vfs_state<'a, V>(ptr: *mut ffi::sqlite3_vfs) -> Result<&'a mut State<V>, std::io::Error> {
-    let vfs: &mut ffi::sqlite3_vfs = ptr.as_mut().ok_or_else(null_ptr_error)?;
-    let state = (vfs.pAppData as *mut State<V>)
-        .as_mut()
+unsafe fn vfs_state<'a, V>(ptr: *mut ffi::sqlite3_vfs) -> Result<&'a State<V>, std::io::Error> {
+    let vfs: &ffi::sqlite3_vfs = ptr.as_ref().ok_or_else(null_ptr_error)?;
+    let state = (vfs.pAppData as *const State<V>)
+        .as_ref()
         .ok_or_else(null_ptr_error)?;
     Ok(state)
 }
@@ -938,20 +3083,136 @@ impl<F> Drop for FileState<F> {
     fn drop(&mut self) {
         unsafe {
             drop(CString::from_raw(self.name));
-            Box::from_raw(self.file);
-            Rc::from_raw(self.last_error);
+            drop(Box::from_raw(self.file));
+            Arc::from_raw(self.last_error);
         };
     }
 }
 
-impl File for std::fs::File {
+/// Check that the database stored in `file` reserves at least `required` bytes at the end of each
+/// page.
+///
+/// SQLite handles `SQLITE_FCNTL_RESERVE_BYTES` itself and never forwards it to the VFS, so a VFS
+/// that needs per-page space (e.g. for checksums or encryption tags) cannot request it on its own.
+/// The application has to set it before the database is created (see [set_reserve_bytes]). Use
+/// this to fail loudly instead of corrupting pages when that did not happen. An empty file is
+/// accepted, as it does not contain a database yet.
+pub fn require_reserve_bytes<F: File>(file: &mut F, required: u8) -> Result<(), std::io::Error> {
+    let reserved = match header::DatabaseHeader::read(file)? {
+        Some(header) => header.reserve_bytes,
+        None => return Ok(()),
+    };
+    if reserved < required {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "database reserves {} bytes per page, but {} are required",
+                reserved, required
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The number of bytes the database `schema` (e.g. `c"main"`) of the connection `db` reserves at
+/// the end of each page (`SQLITE_FCNTL_RESERVE_BYTES`).
+///
+/// # Safety
+/// `db` must be an open connection.
+pub unsafe fn reserve_bytes(db: *mut ffi::sqlite3, schema: &CStr) -> Result<u8, std::io::Error> {
+    reserve_bytes_control(db, schema, -1)
+}
+
+/// Reserve `bytes` at the end of each page of the database `schema` (e.g. `c"main"`) of the
+/// connection `db`, e.g. for a VFS that stores checksums or encryption tags there. Returns the
+/// number of bytes reserved before. SQLite only applies it when the database is created, or rebuilt
+/// with `VACUUM`.
+///
+/// # Safety
+/// `db` must be an open connection.
+pub unsafe fn set_reserve_bytes(
+    db: *mut ffi::sqlite3,
+    schema: &CStr,
+    bytes: u8,
+) -> Result<u8, std::io::Error> {
+    reserve_bytes_control(db, schema, c_int::from(bytes))
+}
+
+unsafe fn reserve_bytes_control(
+    db: *mut ffi::sqlite3,
+    schema: &CStr,
+    mut arg: c_int,
+) -> Result<u8, std::io::Error> {
+    let code = ffi::sqlite3_file_control(
+        db,
+        schema.as_ptr(),
+        ffi::SQLITE_FCNTL_RESERVE_BYTES,
+        &mut arg as *mut c_int as *mut c_void,
+    );
+    if code != ffi::SQLITE_OK {
+        return Err(VfsError::Code(code).into());
+    }
+    Ok(arg as u8)
+}
+
+impl StreamFile for std::fs::File {
     fn file_size(&self) -> Result<u64, std::io::Error> {
-        Ok(self.metadata()?.len())
+        Ok(std::fs::File::metadata(self)?.len())
     }
 
     fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
         self.set_len(size)
     }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        let meta = std::fs::File::metadata(self)?;
+        Ok(VfsMetadata {
+            modified: meta.modified().ok(),
+            created: meta.created().ok(),
+            generation: None,
+            allocated: allocated(&meta),
+        })
+    }
+}
+
+/// The bytes of storage allocated to a file with the metadata `meta` (only known on unix).
+pub(crate) fn allocated(meta: &std::fs::Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        // counted in blocks of 512 bytes, whatever the block size of the file system
+        Some(meta.blocks() * 512)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        None
+    }
+}
+
+impl LockKind {
+    fn from_i32(lock: i32) -> Option<Self> {
+        Some(match lock {
+            ffi::SQLITE_LOCK_NONE => Self::None,
+            ffi::SQLITE_LOCK_SHARED => Self::Shared,
+            ffi::SQLITE_LOCK_RESERVED => Self::Reserved,
+            ffi::SQLITE_LOCK_PENDING => Self::Pending,
+            ffi::SQLITE_LOCK_EXCLUSIVE => Self::Exclusive,
+            _ => return None,
+        })
+    }
+
+    fn to_i32(self) -> i32 {
+        match self {
+            Self::None => ffi::SQLITE_LOCK_NONE,
+            Self::Shared => ffi::SQLITE_LOCK_SHARED,
+            Self::Reserved => ffi::SQLITE_LOCK_RESERVED,
+            Self::Pending => ffi::SQLITE_LOCK_PENDING,
+            Self::Exclusive => ffi::SQLITE_LOCK_EXCLUSIVE,
+        }
+    }
 }
 
 impl OpenOptions {
@@ -962,6 +3223,42 @@ impl OpenOptions {
             delete_on_close: flags & ffi::SQLITE_OPEN_DELETEONCLOSE > 0,
         })
     }
+
+    fn to_flags(&self) -> i32 {
+        let kind = match self.kind {
+            OpenKind::MainDb => ffi::SQLITE_OPEN_MAIN_DB,
+            OpenKind::MainJournal => ffi::SQLITE_OPEN_MAIN_JOURNAL,
+            OpenKind::TempDb => ffi::SQLITE_OPEN_TEMP_DB,
+            OpenKind::TempJournal => ffi::SQLITE_OPEN_TEMP_JOURNAL,
+            OpenKind::TransientDb => ffi::SQLITE_OPEN_TRANSIENT_DB,
+            OpenKind::SubJournal => ffi::SQLITE_OPEN_SUBJOURNAL,
+            OpenKind::SuperJournal => ffi::SQLITE_OPEN_SUPER_JOURNAL,
+            OpenKind::Wal => ffi::SQLITE_OPEN_WAL,
+        };
+        let access = match self.access {
+            OpenAccess::Read => ffi::SQLITE_OPEN_READONLY,
+            OpenAccess::Write => ffi::SQLITE_OPEN_READWRITE,
+            OpenAccess::Create => ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+            OpenAccess::CreateNew => {
+                ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE | ffi::SQLITE_OPEN_EXCLUSIVE
+            }
+        };
+        let delete_on_close = if self.delete_on_close {
+            ffi::SQLITE_OPEN_DELETEONCLOSE
+        } else {
+            0
+        };
+        kind | access | delete_on_close
+    }
+}
+
+impl SyncOptions {
+    fn from_flags(flags: i32) -> Self {
+        SyncOptions {
+            full: flags & 0x0f == ffi::SQLITE_SYNC_FULL,
+            data_only: flags & ffi::SQLITE_SYNC_DATAONLY > 0,
+        }
+    }
 }
 
 impl OpenKind {
@@ -997,6 +3294,81 @@ impl OpenAccess {
     }
 }
 
+/// An error with a specific meaning to SQLite. Return it from a [Vfs] or [File] method wrapped in a
+/// [std::io::Error] (using `.into()`) to have SQLite report the corresponding result code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum VfsError {
+    /// Someone else holds a conflicting lock or has a conflicting write in progress
+    /// (`SQLITE_BUSY`). The operation may succeed when retried later.
+    Busy,
+    /// The database changed since the current read transaction started, so it cannot be upgraded
+    /// to a write transaction (`SQLITE_BUSY_SNAPSHOT`). Retrying only helps after starting a new
+    /// transaction.
+    BusySnapshot,
+    /// Another writer changed the file since it was last read, so writing to it would overwrite
+    /// their changes (`SQLITE_IOERR_VNODE`). Being an I/O error, it also makes SQLite discard its
+    /// cached pages.
+    Fenced,
+    /// The storage is full or a quota is exceeded (`SQLITE_FULL`).
+    Full,
+    /// The storage cannot be written (`SQLITE_READONLY`).
+    ReadOnly,
+    /// Access to the storage was denied, e.g. because credentials expired (`SQLITE_AUTH`).
+    Auth,
+    /// Any other SQLite result code, e.g. a more specific `SQLITE_IOERR_*` code than the one the
+    /// glue reports for the failed operation by default.
+    Code(i32),
+}
+
+impl VfsError {
+    /// The SQLite result code for this error.
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::Busy => ffi::SQLITE_BUSY,
+            Self::BusySnapshot => ffi::SQLITE_BUSY_SNAPSHOT,
+            Self::Fenced => ffi::SQLITE_IOERR_VNODE,
+            Self::Full => ffi::SQLITE_FULL,
+            Self::ReadOnly => ffi::SQLITE_READONLY,
+            Self::Auth => ffi::SQLITE_AUTH,
+            Self::Code(code) => *code,
+        }
+    }
+
+    /// The [ErrorKind] of the [std::io::Error] this error is wrapped in.
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Busy | Self::BusySnapshot => ErrorKind::WouldBlock,
+            Self::Full => ErrorKind::StorageFull,
+            Self::ReadOnly => ErrorKind::ReadOnlyFilesystem,
+            Self::Auth => ErrorKind::PermissionDenied,
+            Self::Fenced | Self::Code(_) => ErrorKind::Other,
+        }
+    }
+}
+
+impl From<VfsError> for std::io::Error {
+    fn from(err: VfsError) -> Self {
+        std::io::Error::new(err.kind(), err)
+    }
+}
+
+impl std::error::Error for VfsError {}
+
+impl std::fmt::Display for VfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Busy => f.write_str("file is busy"),
+            Self::BusySnapshot => f.write_str("database changed since the transaction started"),
+            Self::Fenced => f.write_str("file was changed by another writer"),
+            Self::Full => f.write_str("storage is full"),
+            Self::ReadOnly => f.write_str("storage is read-only"),
+            Self::Auth => f.write_str("access to the storage was denied"),
+            Self::Code(code) => write!(f, "sqlite error code {}", code),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RegisterError {
     Nul(std::ffi::NulError),
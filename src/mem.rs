@@ -0,0 +1,544 @@
+//! Keep databases in the memory of the process, like SQLite's `memvfs`.
+//!
+//! A [MemVfs] stores each file in a buffer that lives as long as the [MemVfs] (or an open file that
+//! refers to it). All connections opened through the same [MemVfs] share its files, and lock them
+//! like a file system would, so any number of connections can use a database at the same time.
+//! Temporary files (e.g. of `VACUUM` or large sorts) are kept in memory as well and are deleted once
+//! they are closed.
+//!
+//! The files do not provide [SharedMemory](crate::SharedMemory). To use WAL mode, wrap the [MemVfs]
+//! in a [ShmVfs](crate::shm::ShmVfs):
+//!
+//! ```
+//! use sqlite_vfs::mem::MemVfs;
+//! use sqlite_vfs::shm::ShmVfs;
+//!
+//! let _vfs = sqlite_vfs::register("mem", ShmVfs::new(MemVfs::new())).unwrap();
+//! ```
+//!
+//! Each file is a single contiguous allocation from SQLite's allocator (a [MemBuffer]), laid out
+//! like the databases SQLite keeps in memory itself. [MemVfs::export] and [MemVfs::import] hand
+//! databases to and take them from `sqlite3_deserialize` and `sqlite3_serialize` without copying
+//! them.
+//!
+//! The files of all [MemVfs] created with [MemVfs::with_budget] together cannot grow beyond the
+//! limit of a [MemoryBudget], for which they evict cached data of other components.
+//!
+//! Being a complete implementation of [Vfs] without any I/O, [MemVfs] is a good starting point for
+//! new backends and a convenient backend for tests.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use libsqlite3_sys as ffi;
+
+use crate::budget::{Charge, MemoryBudget};
+use crate::{
+    DeviceCharacteristics, File, LockKind, OpenAccess, OpenKind, OpenOptions, SyncOptions, Vfs,
+    VfsEntries, VfsEntry, VfsMetadata,
+};
+
+/// A [Vfs] that keeps all files in memory. Clones share the same files, e.g. to inspect them after
+/// registering a clone.
+#[derive(Default, Clone)]
+pub struct MemVfs {
+    files: Arc<Mutex<HashMap<PathBuf, Arc<Mutex<Node>>>>>,
+    max_size: Option<u64>,
+    budget: Option<MemoryBudget>,
+}
+
+/// A file opened by [MemVfs].
+pub struct MemFile {
+    node: Arc<Mutex<Node>>,
+    max_size: Option<u64>,
+    writable: bool,
+    held: HeldLock,
+}
+
+/// The contents of a file, allocated with `sqlite3_malloc64` like the databases SQLite keeps in
+/// memory itself (e.g. those returned by `sqlite3_serialize`), so that SQLite can take ownership of
+/// them.
+#[derive(Default)]
+pub struct MemBuffer {
+    /// The allocation, whose size SQLite keeps track of (`None` while nothing is allocated).
+    ptr: Option<NonNull<u8>>,
+    len: usize,
+}
+
+/// The contents of a file and the locks held on it by all connections.
+struct Node {
+    data: MemBuffer,
+    /// The memory the buffer draws from the budget of the [MemVfs] (if it has one).
+    charge: Option<Charge>,
+    created: SystemTime,
+    modified: SystemTime,
+    locks: Locks,
+}
+
+/// The locks held on a file by all connections of the process.
+#[derive(Default)]
+pub(crate) struct Locks {
+    shared: usize,
+    reserved: bool,
+    pending: bool,
+    exclusive: bool,
+}
+
+/// The locks held on a file by one connection.
+pub(crate) struct HeldLock {
+    lock: LockKind,
+    reserved: bool,
+}
+
+impl MemVfs {
+    /// Create an empty [MemVfs] whose files can grow as long as there is memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty [MemVfs] whose files cannot grow beyond `max_size` bytes. Writes beyond it
+    /// fail with [ErrorKind::StorageFull] (`SQLITE_FULL`).
+    pub fn with_max_size(max_size: u64) -> Self {
+        MemVfs {
+            files: Default::default(),
+            max_size: Some(max_size),
+            budget: None,
+        }
+    }
+
+    /// Create an empty [MemVfs] whose files draw their memory from `budget`. Writes that would
+    /// exceed it (after evicting cached data) fail with [ErrorKind::StorageFull] (`SQLITE_FULL`).
+    pub fn with_budget(budget: MemoryBudget) -> Self {
+        MemVfs {
+            files: Default::default(),
+            max_size: None,
+            budget: Some(budget),
+        }
+    }
+
+    /// Remove the file at `path` and return its contents without copying them, e.g. to hand a
+    /// database to `sqlite3_deserialize` (see [MemBuffer::into_raw]). Fails with
+    /// [ErrorKind::WouldBlock] if the file is still open.
+    pub fn export(&self, path: impl AsRef<Path>) -> Result<MemBuffer, std::io::Error> {
+        let mut files = self.files();
+        let node = files.remove(path.as_ref()).ok_or(ErrorKind::NotFound)?;
+        match Arc::try_unwrap(node) {
+            Ok(node) => Ok(node
+                .into_inner()
+                .unwrap_or_else(|err| err.into_inner())
+                .data),
+            Err(node) => {
+                files.insert(path.as_ref().to_path_buf(), node);
+                Err(still_open())
+            }
+        }
+    }
+
+    /// Store `data` as the file at `path` without copying it, e.g. a database returned by
+    /// `sqlite3_serialize` (see [MemBuffer::from_raw]). A file that already exists at `path` is
+    /// replaced, unless it is still open, in which case this fails with [ErrorKind::WouldBlock].
+    pub fn import(&self, path: impl AsRef<Path>, data: MemBuffer) -> Result<(), std::io::Error> {
+        if let Some(max_size) = self.max_size {
+            if data.len() as u64 > max_size {
+                return Err(std::io::Error::new(
+                    ErrorKind::StorageFull,
+                    format!("file exceeds the maximum size of {} bytes", max_size),
+                ));
+            }
+        }
+        let mut files = self.files();
+        if matches!(files.get(path.as_ref()), Some(node) if Arc::strong_count(node) > 1) {
+            return Err(still_open());
+        }
+        let mut charge = self.charge();
+        if let Some(charge) = &mut charge {
+            charge.resize(data.capacity() as u64)?;
+        }
+        let now = SystemTime::now();
+        let node = Node {
+            data,
+            charge,
+            created: now,
+            modified: now,
+            locks: Locks::default(),
+        };
+        files.insert(path.as_ref().to_path_buf(), Arc::new(Mutex::new(node)));
+        Ok(())
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<PathBuf, Arc<Mutex<Node>>>> {
+        self.files.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn charge(&self) -> Option<Charge> {
+        self.budget.as_ref().map(MemoryBudget::charge)
+    }
+}
+
+impl MemBuffer {
+    /// Take ownership of `len` bytes at `ptr`, allocated by SQLite (with `sqlite3_malloc64`, or
+    /// returned by `sqlite3_serialize`). Any bytes allocated beyond `len` are used when the file
+    /// grows.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null (if `len` is zero) or point to an allocation of SQLite of at least `len`
+    /// bytes that nothing else uses or frees.
+    pub unsafe fn from_raw(ptr: *mut u8, len: usize) -> Self {
+        let ptr = NonNull::new(ptr);
+        assert!(ptr.is_some() || len == 0, "null pointer to {} bytes", len);
+        MemBuffer { ptr, len }
+    }
+
+    /// Give up ownership of the buffer. Returns a pointer to it (null if nothing is allocated), the
+    /// size of the file and the size of the allocation, e.g. to pass them to `sqlite3_deserialize`
+    /// along with `SQLITE_DESERIALIZE_FREEONCLOSE` (and `SQLITE_DESERIALIZE_RESIZEABLE`), or to
+    /// free the buffer with `sqlite3_free`.
+    pub fn into_raw(self) -> (*mut u8, usize, usize) {
+        let raw = (
+            self.ptr.map_or(std::ptr::null_mut(), NonNull::as_ptr),
+            self.len,
+            self.capacity(),
+        );
+        std::mem::forget(self);
+        raw
+    }
+
+    /// The size of the file in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn capacity(&self) -> usize {
+        self.ptr
+            .map_or(0, |ptr| unsafe { ffi::sqlite3_msize(ptr.as_ptr().cast()) }
+                as usize)
+    }
+
+    /// The size of the allocation after the file is grown to `len` bytes.
+    fn grown_capacity(&self, len: usize) -> usize {
+        let capacity = self.capacity();
+        if len > capacity {
+            // grow exponentially, like a Vec, to not copy the file on every write that appends
+            len.max(capacity.saturating_mul(2))
+        } else {
+            capacity
+        }
+    }
+
+    /// Grow or shrink the file to `len` bytes. Grown files are filled with zeros.
+    fn resize(&mut self, len: usize) -> Result<(), std::io::Error> {
+        let capacity = self.grown_capacity(len);
+        if capacity > self.capacity() {
+            let ptr = self.ptr.map_or(std::ptr::null_mut(), NonNull::as_ptr);
+            let ptr = unsafe { ffi::sqlite3_realloc64(ptr.cast(), capacity as u64) };
+            self.ptr = Some(NonNull::new(ptr.cast()).ok_or(ErrorKind::OutOfMemory)?);
+        }
+        let start = self.len;
+        self.len = len;
+        if len > start {
+            self.as_mut()[start..].fill(0);
+        }
+        Ok(())
+    }
+}
+
+impl AsRef<[u8]> for MemBuffer {
+    fn as_ref(&self) -> &[u8] {
+        match self.ptr {
+            Some(ptr) => unsafe { std::slice::from_raw_parts(ptr.as_ptr(), self.len) },
+            None => &[],
+        }
+    }
+}
+
+impl AsMut<[u8]> for MemBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        match self.ptr {
+            Some(ptr) => unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr(), self.len) },
+            None => &mut [],
+        }
+    }
+}
+
+impl std::fmt::Debug for MemBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemBuffer").field("len", &self.len).finish()
+    }
+}
+
+impl Drop for MemBuffer {
+    fn drop(&mut self) {
+        if let Some(ptr) = self.ptr {
+            unsafe { ffi::sqlite3_free(ptr.as_ptr().cast()) };
+        }
+    }
+}
+
+// the buffer exclusively owns its allocation
+unsafe impl Send for MemBuffer {}
+unsafe impl Sync for MemBuffer {}
+
+impl Vfs for MemVfs {
+    type File = MemFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let mut files = self.files();
+        let node = match (files.get(path), opts.access) {
+            (Some(_), OpenAccess::CreateNew) => return Err(ErrorKind::AlreadyExists.into()),
+            (Some(node), _) => Arc::clone(node),
+            (None, OpenAccess::Create | OpenAccess::CreateNew) => {
+                let now = SystemTime::now();
+                let node = Arc::new(Mutex::new(Node {
+                    data: MemBuffer::default(),
+                    charge: self.charge(),
+                    created: now,
+                    modified: now,
+                    locks: Locks::default(),
+                }));
+                files.insert(path.to_path_buf(), Arc::clone(&node));
+                node
+            }
+            (None, _) => return Err(ErrorKind::NotFound.into()),
+        };
+        Ok(MemFile {
+            node,
+            max_size: self.max_size,
+            writable: opts.access != OpenAccess::Read,
+            held: HeldLock::new(),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        match self.files().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        Ok(self.files().contains_key(path))
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        let prefix = prefix.to_string_lossy();
+        let mut entries = self
+            .files()
+            .iter()
+            .filter(|(path, _)| path.to_string_lossy().starts_with(&*prefix))
+            .map(|(path, node)| VfsEntry {
+                path: path.clone(),
+                size: lock_node(node).data.len() as u64,
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        let mut files = self.files();
+        let node = files.remove(from).ok_or(ErrorKind::NotFound)?;
+        files.insert(to.to_path_buf(), node);
+        Ok(())
+    }
+}
+
+impl MemFile {
+    fn node(&self) -> MutexGuard<'_, Node> {
+        lock_node(&self.node)
+    }
+
+    /// Fail if the file is read-only or would grow beyond the size limit.
+    fn check_write(&self, end: u64) -> Result<(), std::io::Error> {
+        if !self.writable {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "file is opened read-only",
+            ));
+        }
+        match self.max_size {
+            Some(max_size) if end > max_size => Err(std::io::Error::new(
+                ErrorKind::StorageFull,
+                format!("file would exceed the maximum size of {} bytes", max_size),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl File for MemFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let node = self.node();
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(node.data.len());
+        let n = buf.len().min(node.data.len() - start);
+        buf[..n].copy_from_slice(&node.data.as_ref()[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let end = offset.checked_add(buf.len() as u64).ok_or_else(too_large)?;
+        self.check_write(end)?;
+        let mut node = self.node();
+        let (start, end) = (to_usize(offset)?, to_usize(end)?);
+        if node.data.len() < end {
+            node.resize(end)?;
+        }
+        node.data.as_mut()[start..end].copy_from_slice(buf);
+        node.modified = SystemTime::now();
+        Ok(())
+    }
+
+    fn sync(&mut self, _options: SyncOptions) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.node().data.len() as u64)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.check_write(size)?;
+        let mut node = self.node();
+        node.resize(to_usize(size)?)?;
+        node.modified = SystemTime::now();
+        Ok(())
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        let node = self.node();
+        Ok(VfsMetadata {
+            modified: Some(node.modified),
+            created: Some(node.created),
+            generation: None,
+            allocated: Some(node.data.capacity() as u64),
+        })
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        Ok(self.held.lock(&mut lock_node(&self.node).locks, lock))
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.held.unlock(&mut lock_node(&self.node).locks, lock);
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        Ok(self.node().locks.reserved())
+    }
+
+    fn device_characteristics(&self, _kind: OpenKind) -> DeviceCharacteristics {
+        // nothing survives a crash anyway
+        DeviceCharacteristics::empty()
+            .atomic()
+            .safe_append()
+            .sequential()
+            .powersafe_overwrite()
+    }
+}
+
+impl Drop for MemFile {
+    fn drop(&mut self) {
+        // release the locks of a connection that is closed without unlocking first
+        let _ = self.unlock(LockKind::None);
+    }
+}
+
+impl Node {
+    /// Grow or shrink the file to `len` bytes, drawing the memory it grows by from the budget.
+    fn resize(&mut self, len: usize) -> Result<(), std::io::Error> {
+        if let Some(charge) = &mut self.charge {
+            charge.resize(self.data.grown_capacity(len) as u64)?;
+        }
+        let result = self.data.resize(len);
+        if let Some(charge) = &mut self.charge {
+            // give back what could not be allocated
+            charge.resize(self.data.capacity() as u64)?;
+        }
+        result
+    }
+}
+
+impl Locks {
+    /// Whether any connection holds a [LockKind::Reserved] lock or above.
+    pub(crate) fn reserved(&self) -> bool {
+        self.reserved || self.pending
+    }
+}
+
+impl HeldLock {
+    pub(crate) fn new() -> Self {
+        HeldLock {
+            lock: LockKind::None,
+            reserved: false,
+        }
+    }
+
+    /// Acquire `lock`, or return `false` if it conflicts with the `locks` of other connections.
+    pub(crate) fn lock(&mut self, locks: &mut Locks, lock: LockKind) -> bool {
+        match lock {
+            LockKind::Shared if locks.pending || locks.exclusive => return false,
+            LockKind::Shared => locks.shared += 1,
+            LockKind::Reserved if locks.reserved => return false,
+            LockKind::Reserved => {
+                locks.reserved = true;
+                self.reserved = true;
+            }
+            LockKind::Pending if locks.pending => return false,
+            LockKind::Pending => locks.pending = true,
+            // wait until all other connections released their shared locks
+            LockKind::Exclusive if locks.shared > 1 => return false,
+            LockKind::Exclusive => locks.exclusive = true,
+            LockKind::None => {}
+        }
+        self.lock = lock;
+        true
+    }
+
+    /// Release all locks above `lock`.
+    pub(crate) fn unlock(&mut self, locks: &mut Locks, lock: LockKind) {
+        if self.lock >= LockKind::Pending && lock < LockKind::Pending {
+            locks.pending = false;
+            locks.exclusive = false;
+        }
+        if self.reserved && lock < LockKind::Reserved {
+            locks.reserved = false;
+            self.reserved = false;
+        }
+        if self.lock >= LockKind::Shared && lock == LockKind::None {
+            locks.shared -= 1;
+        }
+        self.lock = lock;
+    }
+}
+
+fn still_open() -> std::io::Error {
+    std::io::Error::new(ErrorKind::WouldBlock, "file is still open")
+}
+
+/// `size` as an offset into a buffer, or an error if it does not fit into the address space.
+fn to_usize(size: u64) -> Result<usize, std::io::Error> {
+    usize::try_from(size).map_err(|_| too_large())
+}
+
+fn too_large() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::FileTooLarge,
+        "file would exceed the address space",
+    )
+}
+
+fn lock_node(node: &Mutex<Node>) -> MutexGuard<'_, Node> {
+    node.lock().unwrap_or_else(|err| err.into_inner())
+}
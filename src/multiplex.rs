@@ -0,0 +1,370 @@
+//! Split large files into shards of a fixed size, like SQLite's multiplexor shim.
+//!
+//! A [MultiplexVfs] stores each file in as many files of the [Vfs] it wraps as needed for none of
+//! them to exceed its chunk size. The first chunk is stored under the name of the file, and each
+//! further one under the name followed by its number in three digits (`main.db`, `main.db001`,
+//! `main.db002`, ...), which is the naming scheme of the multiplexor shim, so databases can be
+//! moved between both. This allows storing databases beyond the size limits of a backend, e.g.
+//! object stores with a maximum object size or file systems like FAT32.
+//!
+//! Locks, shared memory and file controls all apply to the first chunk.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenAccess, OpenKind, OpenOptions, RecoveryPhase,
+    SharedMemory, SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// Chunk sizes are rounded up to a multiple of the largest page size, so that no page is split
+/// across two chunks.
+const MAX_PAGE_SIZE: u64 = 65536;
+
+/// The number of chunks the naming scheme supports.
+const MAX_CHUNKS: u64 = 1000;
+
+/// A [Vfs] that splits the files of the [Vfs] it wraps into chunks.
+pub struct MultiplexVfs<V> {
+    vfs: Arc<V>,
+    chunk_size: u64,
+}
+
+/// A file opened by [MultiplexVfs].
+pub struct MultiplexFile<V: Vfs> {
+    vfs: Arc<V>,
+    path: PathBuf,
+    opts: OpenOptions,
+    chunk_size: u64,
+    /// The chunks opened so far, starting with the first one (which is always open).
+    chunks: Vec<Option<V::File>>,
+}
+
+impl<V> MultiplexVfs<V> {
+    /// Wrap `vfs` and split its files into chunks of `chunk_size` bytes (rounded up to a multiple
+    /// of 64KiB).
+    pub fn new(vfs: V, chunk_size: u64) -> Self {
+        MultiplexVfs {
+            vfs: Arc::new(vfs),
+            chunk_size: chunk_size.max(1).div_ceil(MAX_PAGE_SIZE) * MAX_PAGE_SIZE,
+        }
+    }
+}
+
+/// The path of the chunk `index` of the file at `path`.
+fn chunk_path(path: &Path, index: u64) -> PathBuf {
+    match index {
+        0 => path.to_path_buf(),
+        index => {
+            let mut path = path.as_os_str().to_os_string();
+            path.push(format!("{:03}", index));
+            path.into()
+        }
+    }
+}
+
+impl<V: Vfs> Vfs for MultiplexVfs<V> {
+    type File = MultiplexFile<V>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let file = self.vfs.open(path, opts.clone())?;
+        // later chunks are created as needed
+        let opts = OpenOptions {
+            access: match opts.access {
+                OpenAccess::Read => OpenAccess::Read,
+                _ => OpenAccess::Create,
+            },
+            ..opts
+        };
+        Ok(MultiplexFile {
+            vfs: Arc::clone(&self.vfs),
+            path: path.to_path_buf(),
+            opts,
+            chunk_size: self.chunk_size,
+            chunks: vec![Some(file)],
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)?;
+        for index in 1..MAX_CHUNKS {
+            let path = chunk_path(path, index);
+            if !self.vfs.exists(&path)? {
+                break;
+            }
+            self.vfs.delete(&path)?;
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.vfs.rename(from, to)?;
+        for index in 1..MAX_CHUNKS {
+            let chunk = chunk_path(from, index);
+            if !self.vfs.exists(&chunk)? {
+                break;
+            }
+            self.vfs.rename(&chunk, &chunk_path(to, index))?;
+        }
+        Ok(())
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.vfs.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl<V: Vfs> MultiplexFile<V> {
+    fn first(&self) -> &V::File {
+        self.chunks[0].as_ref().unwrap()
+    }
+
+    fn first_mut(&mut self) -> &mut V::File {
+        self.chunks[0].as_mut().unwrap()
+    }
+
+    /// The chunk `index`, opening (or with `create`, creating) it if necessary. Returns `None` if
+    /// it does not exist.
+    fn chunk(&mut self, index: u64, create: bool) -> Result<Option<&mut V::File>, std::io::Error> {
+        if index >= MAX_CHUNKS {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "file would exceed the maximum number of chunks",
+            ));
+        }
+        let i = index as usize;
+        if self.chunks.len() <= i {
+            self.chunks.resize_with(i + 1, || None);
+        }
+        if self.chunks[i].is_none() {
+            let path = chunk_path(&self.path, index);
+            if !create && !self.vfs.exists(&path)? {
+                return Ok(None);
+            }
+            self.chunks[i] = Some(self.vfs.open(&path, self.opts.clone())?);
+        }
+        Ok(self.chunks[i].as_mut())
+    }
+
+    /// The index of the last chunk.
+    fn last_chunk(&self) -> Result<u64, std::io::Error> {
+        let mut last = 0;
+        while last + 1 < MAX_CHUNKS
+            && (self
+                .chunks
+                .get(last as usize + 1)
+                .is_some_and(Option::is_some)
+                || self.vfs.exists(&chunk_path(&self.path, last + 1))?)
+        {
+            last += 1;
+        }
+        Ok(last)
+    }
+}
+
+impl<V: Vfs> File for MultiplexFile<V> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let mut read = 0;
+        while read < buf.len() {
+            let pos = offset + read as u64;
+            let (index, start) = (pos / self.chunk_size, pos % self.chunk_size);
+            let len = (buf.len() - read).min((self.chunk_size - start) as usize);
+            let Some(chunk) = self.chunk(index, false)? else {
+                break;
+            };
+            let n = chunk.read_at(&mut buf[read..read + len], start)?;
+            read += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(read)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let mut written = 0;
+        while written < buf.len() {
+            let pos = offset + written as u64;
+            let (index, start) = (pos / self.chunk_size, pos % self.chunk_size);
+            let len = (buf.len() - written).min((self.chunk_size - start) as usize);
+            let chunk = self.chunk(index, true)?.unwrap();
+            chunk.write_all_at(&buf[written..written + len], start)?;
+            written += len;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        for chunk in self.chunks.iter_mut().flatten() {
+            chunk.sync(options)?;
+        }
+        Ok(())
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        let last = self.last_chunk()?;
+        let size = match self.chunks.get(last as usize).and_then(Option::as_ref) {
+            Some(chunk) => chunk.file_size()?,
+            None => {
+                let path = chunk_path(&self.path, last);
+                self.vfs.open(&path, self.opts.clone())?.file_size()?
+            }
+        };
+        Ok(last * self.chunk_size + size)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        // the chunks that are still needed, of which the last one may be partial
+        let needed = size.div_ceil(self.chunk_size).max(1);
+        let last = self.last_chunk()?;
+        for index in (needed..=last).rev() {
+            if let Some(chunk) = self.chunks.get_mut(index as usize) {
+                chunk.take();
+            }
+            self.vfs.delete(&chunk_path(&self.path, index))?;
+        }
+        self.chunks.truncate(needed as usize);
+        let len = size - (needed - 1) * self.chunk_size;
+        match self.chunk(needed - 1, false)? {
+            Some(chunk) => chunk.truncate(len),
+            None => Ok(()),
+        }
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.first().metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.first_mut().lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.first_mut().unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.first().check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.first_mut().shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        // a mapping would only cover the first chunk
+        None
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        // a batch could span several chunks
+        None
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.first_mut().file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.first().sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        // writes are not atomic (nor appends safe) across chunks
+        let inner = self.first().device_characteristics(kind);
+        let mut characteristics = DeviceCharacteristics::empty();
+        if inner.contains(DeviceCharacteristics::empty().powersafe_overwrite()) {
+            characteristics = characteristics.powersafe_overwrite();
+        }
+        if inner.contains(DeviceCharacteristics::empty().immutable()) {
+            characteristics = characteristics.immutable();
+        }
+        characteristics
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.first_mut().pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.first().read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.first().moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.first_mut().pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.first_mut().post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.first_mut().begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.first().data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.first_mut().set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.first().persist_wal()
+    }
+}
@@ -0,0 +1,51 @@
+//! Classify the pages of a database file by their contents, e.g. so a caching shim can keep
+//! interior b-tree pages in memory and skip caching overflow pages of large blobs.
+//!
+//! Only b-tree pages start with a type flag. Overflow, freelist and pointer map pages cannot be told
+//! apart by their contents alone (and free pages may still contain a stale b-tree page), so they
+//! are reported as [PageKind::Other]. As their first bytes are not a type flag, but the number of
+//! the next page, such pages of databases with tens of millions of pages can also be mistaken for
+//! b-tree pages, so the classification should only be used as a hint.
+
+use crate::header::HEADER_SIZE;
+
+/// The kind of a database page, as returned by [classify].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageKind {
+    /// An interior page of a table b-tree (only contains keys and child pointers).
+    TableInterior,
+
+    /// A leaf page of a table b-tree (contains rows).
+    TableLeaf,
+
+    /// An interior page of an index b-tree.
+    IndexInterior,
+
+    /// A leaf page of an index b-tree.
+    IndexLeaf,
+
+    /// Any other page (overflow, freelist, pointer map or the lock-byte page).
+    Other,
+}
+
+impl PageKind {
+    /// Whether the page is an interior page of a b-tree, which is read by every lookup that goes
+    /// through it.
+    pub fn is_interior(&self) -> bool {
+        matches!(self, PageKind::TableInterior | PageKind::IndexInterior)
+    }
+}
+
+/// Classify `page`, where `index` is the page number minus one (as in
+/// [PageLocation](crate::transform::PageLocation)). The b-tree page header of the first page comes
+/// after the database header.
+pub fn classify(page: &[u8], index: u64) -> PageKind {
+    let offset = if index == 0 { HEADER_SIZE } else { 0 };
+    match page.get(offset) {
+        Some(0x05) => PageKind::TableInterior,
+        Some(0x0d) => PageKind::TableLeaf,
+        Some(0x02) => PageKind::IndexInterior,
+        Some(0x0a) => PageKind::IndexLeaf,
+        _ => PageKind::Other,
+    }
+}
@@ -0,0 +1,442 @@
+//! Store databases in a page-addressed backend, e.g. a key-value store, by implementing
+//! [PageStore] instead of [Vfs].
+//!
+//! A [PagedVfs] turns the byte ranges SQLite reads and writes into whole pages of a [PageStore]:
+//! reads of part of a page (like the database header, which SQLite reads before it knows the page
+//! size) fetch the complete page, and writes of part of a page read, modify and write it back.
+//! Only main databases are stored in the [PageStore]. Journals, WAL files and temporary files are
+//! stored in a [Vfs] the [PagedVfs] wraps, e.g. a [MemVfs](crate::mem::MemVfs):
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use std::path::{Path, PathBuf};
+//! use std::sync::Mutex;
+//!
+//! use sqlite_vfs::mem::MemVfs;
+//! use sqlite_vfs::paged::{PageStore, PagedVfs};
+//!
+//! #[derive(Default)]
+//! struct MapStore(Mutex<HashMap<PathBuf, Vec<Vec<u8>>>>);
+//!
+//! impl PageStore for MapStore {
+//!     fn get_page(&self, db: &Path, no: u32, page: &mut [u8]) -> Result<(), std::io::Error> {
+//!         let dbs = self.0.lock().unwrap();
+//!         page.copy_from_slice(&dbs[db][no as usize - 1]);
+//!         Ok(())
+//!     }
+//!
+//!     fn put_page(&self, db: &Path, no: u32, data: &[u8]) -> Result<(), std::io::Error> {
+//!         let mut dbs = self.0.lock().unwrap();
+//!         let pages = dbs.entry(db.to_path_buf()).or_default();
+//!         if pages.len() < no as usize {
+//!             pages.resize(no as usize, vec![0; data.len()]);
+//!         }
+//!         pages[no as usize - 1] = data.to_vec();
+//!         Ok(())
+//!     }
+//!
+//!     fn page_count(&self, db: &Path) -> Result<u32, std::io::Error> {
+//!         Ok(self.0.lock().unwrap().get(db).map_or(0, |pages| pages.len() as u32))
+//!     }
+//!
+//!     fn truncate(&self, db: &Path, count: u32) -> Result<(), std::io::Error> {
+//!         let mut dbs = self.0.lock().unwrap();
+//!         if let Some(pages) = dbs.get_mut(db) {
+//!             pages.truncate(count as usize);
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let vfs = PagedVfs::new(MapStore::default(), MemVfs::new());
+//! let _vfs = sqlite_vfs::register("paged", vfs).unwrap();
+//! ```
+//!
+//! The page size of the store is reported as the sector size of databases, which makes SQLite use
+//! it for new databases (for page sizes up to 8192). Databases with a different page size still
+//! work, but each write of a page then reads a page of the store as well.
+//!
+//! Locks are only held within the process, and a transaction is only as durable as the [Vfs] that
+//! keeps its journal: with a [MemVfs](crate::mem::MemVfs), a crash in the middle of a commit can
+//! leave a database that only has part of the pages of the transaction.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, SystemTime};
+
+use crate::mem::{HeldLock, Locks};
+use crate::{
+    DeviceCharacteristics, File, HealthReport, LockKind, OpenAccess, OpenKind, OpenOptions,
+    SyncOptions, Vfs, VfsMetadata,
+};
+
+/// A page-addressed storage for databases.
+///
+/// Pages are numbered from 1, like SQLite numbers the pages of a database, and are all
+/// [PageStore::page_size] bytes long. All methods are called concurrently for different databases,
+/// but SQLite's locking makes sure that a database is not read while it is written.
+pub trait PageStore: Send + Sync {
+    /// The size of all pages in bytes, a power of two between 512 and 65536. The default
+    /// implementation returns 4096, the default page size of SQLite.
+    fn page_size(&self) -> usize {
+        4096
+    }
+
+    /// Read page `no` of the database `db` into `page`. Only called for pages up to
+    /// [PageStore::page_count]; pages that were never put (but are below a page that was) have to
+    /// be read as zeros.
+    fn get_page(&self, db: &Path, no: u32, page: &mut [u8]) -> Result<(), std::io::Error>;
+
+    /// Store `data` as page `no` of the database `db`, which creates the database if it does not
+    /// exist yet. Pages are not necessarily put in order, so `no` can be beyond the page after the
+    /// last one.
+    fn put_page(&self, db: &Path, no: u32, data: &[u8]) -> Result<(), std::io::Error>;
+
+    /// The number of pages of the database `db`, i.e. the highest page number that was put. Zero
+    /// if the database does not exist.
+    fn page_count(&self, db: &Path) -> Result<u32, std::io::Error>;
+
+    /// Remove all pages of the database `db` after page `count`. Removing all of them (a `count` of
+    /// zero) deletes the database.
+    fn truncate(&self, db: &Path, count: u32) -> Result<(), std::io::Error>;
+
+    /// Make all pages put to the database `db` durable. The default implementation does nothing.
+    fn sync(&self, _db: &Path) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+/// A [Vfs] that stores main databases in a [PageStore], and all other files in the [Vfs] it wraps.
+pub struct PagedVfs<S, V> {
+    store: Arc<S>,
+    vfs: V,
+    /// The locks of all databases that are currently open.
+    locks: Mutex<HashMap<PathBuf, Weak<Mutex<Locks>>>>,
+}
+
+/// A file opened by [PagedVfs].
+pub struct PagedFile<S, F>(Inner<S, F>);
+
+enum Inner<S, F> {
+    /// A main database, stored in the [PageStore].
+    Db(PagedDb<S>),
+    /// Any other file, stored in the wrapped [Vfs].
+    File(F),
+}
+
+struct PagedDb<S> {
+    store: Arc<S>,
+    path: PathBuf,
+    page_size: u64,
+    writable: bool,
+    locks: Arc<Mutex<Locks>>,
+    held: HeldLock,
+}
+
+impl<S: PageStore, V> PagedVfs<S, V> {
+    /// Store main databases in `store`, and all other files in `vfs`.
+    pub fn new(store: S, vfs: V) -> Self {
+        let page_size = store.page_size();
+        assert!(
+            page_size.is_power_of_two() && (512..=65536).contains(&page_size),
+            "invalid page size {}",
+            page_size
+        );
+        PagedVfs {
+            store: Arc::new(store),
+            vfs,
+            locks: Default::default(),
+        }
+    }
+
+    /// The locks of the database at `path`, shared by all connections that opened it.
+    fn locks(&self, path: &Path) -> Arc<Mutex<Locks>> {
+        let mut locks = self.locks.lock().unwrap_or_else(|err| err.into_inner());
+        locks.retain(|_, locks| locks.strong_count() > 0);
+        match locks.get(path).and_then(Weak::upgrade) {
+            Some(locks) => locks,
+            None => {
+                let new = Arc::new(Mutex::new(Locks::default()));
+                locks.insert(path.to_path_buf(), Arc::downgrade(&new));
+                new
+            }
+        }
+    }
+}
+
+impl<S: PageStore, V: Vfs> Vfs for PagedVfs<S, V> {
+    type File = PagedFile<S, V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        if opts.kind != OpenKind::MainDb {
+            return Ok(PagedFile(Inner::File(self.vfs.open(path, opts)?)));
+        }
+        let exists = self.store.page_count(path)? > 0;
+        match (exists, opts.access) {
+            (true, OpenAccess::CreateNew) => return Err(ErrorKind::AlreadyExists.into()),
+            (false, OpenAccess::Read | OpenAccess::Write) => return Err(ErrorKind::NotFound.into()),
+            _ => {}
+        }
+        Ok(PagedFile(Inner::Db(PagedDb {
+            store: Arc::clone(&self.store),
+            path: path.to_path_buf(),
+            page_size: self.store.page_size() as u64,
+            writable: opts.access != OpenAccess::Read,
+            locks: self.locks(path),
+            held: HeldLock::new(),
+        })))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        if self.vfs.exists(path)? {
+            return self.vfs.delete(path);
+        }
+        if self.store.page_count(path)? == 0 {
+            return Err(ErrorKind::NotFound.into());
+        }
+        self.store.truncate(path, 0)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        Ok(self.vfs.exists(path)? || self.store.page_count(path)? > 0)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        if self.store.page_count(path)? > 0 {
+            return Ok(true);
+        }
+        self.vfs.access(path, write)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        if self.vfs.exists(from)? {
+            return self.vfs.rename(from, to);
+        }
+        let count = self.store.page_count(from)?;
+        if count == 0 {
+            return Err(ErrorKind::NotFound.into());
+        }
+        let mut page = vec![0; self.store.page_size()];
+        for no in 1..=count {
+            self.store.get_page(from, no, &mut page)?;
+            self.store.put_page(to, no, &page)?;
+        }
+        self.store.truncate(to, count)?;
+        self.store.sync(to)?;
+        self.store.truncate(from, 0)
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+}
+
+impl<S: PageStore> PagedDb<S> {
+    fn check_writable(&self) -> Result<(), std::io::Error> {
+        if !self.writable {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "file is opened read-only",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The page number of the page that contains `offset`, and the position of `offset` in it.
+    fn locate(&self, offset: u64) -> Result<(u32, usize), std::io::Error> {
+        let no = u32::try_from(offset / self.page_size + 1).map_err(|_| {
+            std::io::Error::new(ErrorKind::StorageFull, "database exceeds the page store")
+        })?;
+        Ok((no, (offset % self.page_size) as usize))
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let count = self.store.page_count(&self.path)?;
+        let page_size = self.page_size as usize;
+        let mut page = Vec::new();
+        let mut read = 0;
+        while read < buf.len() {
+            let (no, start) = self.locate(offset + read as u64)?;
+            if no > count {
+                break;
+            }
+            let len = (buf.len() - read).min(page_size - start);
+            if len == page_size {
+                self.store
+                    .get_page(&self.path, no, &mut buf[read..read + len])?;
+            } else {
+                page.resize(page_size, 0);
+                self.store.get_page(&self.path, no, &mut page)?;
+                buf[read..read + len].copy_from_slice(&page[start..start + len]);
+            }
+            read += len;
+        }
+        Ok(read)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.check_writable()?;
+        let count = self.store.page_count(&self.path)?;
+        let page_size = self.page_size as usize;
+        let mut page = Vec::new();
+        let mut written = 0;
+        while written < buf.len() {
+            let (no, start) = self.locate(offset + written as u64)?;
+            let len = (buf.len() - written).min(page_size - start);
+            if len == page_size {
+                self.store
+                    .put_page(&self.path, no, &buf[written..written + len])?;
+            } else {
+                page.clear();
+                page.resize(page_size, 0);
+                if no <= count {
+                    self.store.get_page(&self.path, no, &mut page)?;
+                }
+                page[start..start + len].copy_from_slice(&buf[written..written + len]);
+                self.store.put_page(&self.path, no, &page)?;
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.check_writable()?;
+        let count = u32::try_from(size.div_ceil(self.page_size)).unwrap_or(u32::MAX);
+        if count < self.store.page_count(&self.path)? {
+            self.store.truncate(&self.path, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: PageStore, F: File> File for PagedFile<S, F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        match &mut self.0 {
+            Inner::Db(db) => db.read_at(buf, offset),
+            Inner::File(file) => file.read_at(buf, offset),
+        }
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.0 {
+            Inner::Db(db) => db.write_all_at(buf, offset),
+            Inner::File(file) => file.write_all_at(buf, offset),
+        }
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        match &mut self.0 {
+            Inner::Db(db) => db.store.sync(&db.path),
+            Inner::File(file) => file.sync(options),
+        }
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        match &self.0 {
+            Inner::Db(db) => Ok(u64::from(db.store.page_count(&db.path)?) * db.page_size),
+            Inner::File(file) => file.file_size(),
+        }
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        match &mut self.0 {
+            Inner::Db(db) => db.truncate(size),
+            Inner::File(file) => file.truncate(size),
+        }
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        match &self.0 {
+            Inner::Db(_) => Ok(VfsMetadata::default()),
+            Inner::File(file) => file.metadata(),
+        }
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        match &mut self.0 {
+            Inner::Db(db) => Ok(db.held.lock(&mut lock_locks(&db.locks), lock)),
+            Inner::File(file) => file.lock(lock),
+        }
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        match &mut self.0 {
+            Inner::Db(db) => {
+                db.held.unlock(&mut lock_locks(&db.locks), lock);
+                Ok(())
+            }
+            Inner::File(file) => file.unlock(lock),
+        }
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        match &self.0 {
+            Inner::Db(db) => Ok(lock_locks(&db.locks).reserved()),
+            Inner::File(file) => file.check_reserved_lock(),
+        }
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        match &self.0 {
+            // makes SQLite pick the page size of the store for new databases
+            Inner::Db(db) => db.page_size as u32,
+            Inner::File(file) => file.sector_size(kind),
+        }
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        match &self.0 {
+            Inner::Db(_) => DeviceCharacteristics::empty(),
+            Inner::File(file) => file.device_characteristics(kind),
+        }
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        if let Inner::File(file) = &mut self.0 {
+            file.set_persist_wal(persist)
+        }
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        match &self.0 {
+            Inner::Db(_) => None,
+            Inner::File(file) => file.persist_wal(),
+        }
+    }
+}
+
+impl<S, F> Drop for PagedFile<S, F> {
+    fn drop(&mut self) {
+        // release the locks of a connection that is closed without unlocking first
+        if let Inner::Db(db) = &mut self.0 {
+            db.held.unlock(&mut lock_locks(&db.locks), LockKind::None);
+        }
+    }
+}
+
+fn lock_locks(locks: &Mutex<Locks>) -> MutexGuard<'_, Locks> {
+    locks.lock().unwrap_or_else(|err| err.into_inner())
+}
@@ -0,0 +1,399 @@
+//! Register a set of named VFS stacks declared in a configuration, e.g. a file of a service, with
+//! [VfsRegistry::from_config].
+//!
+//! Each [StackConfig] names a backend and the shims that wrap it, in order (the first one wraps
+//! the backend directly). The stack is put together as a [DynVfs] and registered under the name of
+//! the stack. With the `serde` feature, the configuration can be deserialized (the backends and
+//! shims are tagged with a `type` field):
+//!
+//! ```
+//! # #[cfg(feature = "serde")]
+//! # fn main() {
+//! use sqlite_vfs::registry::{RegistryConfig, VfsRegistry};
+//!
+//! let config: RegistryConfig = serde_json::from_str(
+//!     r#"{
+//!         "vfs": [
+//!             {
+//!                 "name": "cache",
+//!                 "backend": { "type": "memory", "max_size": 104857600 },
+//!                 "shims": [{ "type": "shm" }, { "type": "stats" }]
+//!             }
+//!         ]
+//!     }"#,
+//! )
+//! .unwrap();
+//! let registry = VfsRegistry::from_config(&config).unwrap();
+//! let stats = registry.stats("cache").unwrap();
+//! # }
+//! # #[cfg(not(feature = "serde"))]
+//! # fn main() {}
+//! ```
+//!
+//! Backends implemented by the application are looked up by name in [Backends], which build them
+//! from the string parameters of the configuration (see [BackendConfig::Custom]).
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::checksum::{Checksum, ChecksumVfs};
+use crate::dynamic::{boxed, DynVfs};
+use crate::fencing::FencedVfs;
+use crate::mem::MemVfs;
+use crate::multiplex::MultiplexVfs;
+use crate::shm::ShmVfs;
+use crate::stats::{Stats, StatsVfs};
+use crate::throttle::{Priority, ThrottleOptions, ThrottleVfs};
+use crate::{register_with_options, RegisterError, RegisterOptions, VfsHandle};
+
+/// The VFS stacks to register with [VfsRegistry::from_config].
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct RegistryConfig {
+    /// The stacks, registered in this order.
+    pub vfs: Vec<StackConfig>,
+}
+
+/// A backend and the shims that wrap it, registered under `name`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct StackConfig {
+    /// The name the stack is registered as.
+    pub name: String,
+
+    /// The [Vfs](crate::Vfs) that stores the files.
+    pub backend: BackendConfig,
+
+    /// The shims that wrap the backend, starting with the innermost one.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub shims: Vec<ShimConfig>,
+
+    /// Make the stack the default VFS of the process (see [RegisterOptions::make_default]).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub default: bool,
+}
+
+/// The backend of a [StackConfig].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)
+)]
+pub enum BackendConfig {
+    /// A [MemVfs], whose files cannot grow beyond `max_size` bytes if it is set.
+    Memory {
+        #[cfg_attr(feature = "serde", serde(default))]
+        max_size: Option<u64>,
+    },
+
+    /// A backend of the application, built by the factory added to [Backends] as `name`.
+    Custom {
+        name: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        params: BTreeMap<String, String>,
+    },
+}
+
+/// A shim of a [StackConfig].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)
+)]
+#[non_exhaustive]
+pub enum ShimConfig {
+    /// A [ShmVfs], to use WAL mode.
+    Shm,
+
+    /// A [ChecksumVfs].
+    Checksum,
+
+    /// A [FencedVfs].
+    Fencing,
+
+    /// A [MultiplexVfs] that splits files into chunks of `chunk_size` bytes.
+    Multiplex { chunk_size: u64 },
+
+    /// A [ThrottleVfs] (see [ThrottleOptions] for the limits, with the burst in milliseconds, and
+    /// the priorities by the paths of the databases).
+    Throttle {
+        #[cfg_attr(feature = "serde", serde(default))]
+        read_bandwidth: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        write_bandwidth: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        iops: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        burst_ms: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        per_file: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        priorities: BTreeMap<String, Priority>,
+    },
+
+    /// A [StatsVfs], whose statistics are read with [VfsRegistry::stats].
+    Stats,
+
+    /// A [TraceVfs](crate::trace::TraceVfs) that records operations taking at least `slow_ops_ms`
+    /// milliseconds at the `INFO` level (only with the `tracing` feature).
+    #[cfg(feature = "tracing")]
+    Trace {
+        #[cfg_attr(feature = "serde", serde(default))]
+        slow_ops_ms: Option<u64>,
+    },
+
+    /// A [CompressedVfs](crate::compress::CompressedVfs) with zstd at `level` (only with the `zstd`
+    /// feature).
+    #[cfg(feature = "zstd")]
+    Zstd {
+        #[cfg_attr(feature = "serde", serde(default))]
+        level: Option<i32>,
+    },
+
+    /// A [CompressedVfs](crate::compress::CompressedVfs) with LZ4 (only with the `lz4` feature).
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Builds a backend of the application from the parameters of [BackendConfig::Custom].
+pub type BackendFactory =
+    Box<dyn Fn(&BTreeMap<String, String>) -> Result<DynVfs, std::io::Error> + Send + Sync>;
+
+/// The backends of the application that a [RegistryConfig] can name.
+#[derive(Default)]
+pub struct Backends {
+    factories: HashMap<String, BackendFactory>,
+}
+
+/// The VFS stacks registered by [VfsRegistry::from_config]. Dropping it unregisters them all.
+#[must_use = "the VFS stacks are unregistered as soon as the registry is dropped"]
+pub struct VfsRegistry {
+    handles: Vec<VfsHandle>,
+    stats: HashMap<String, Stats>,
+}
+
+/// Why [VfsRegistry::from_config] failed. The stacks registered until then are unregistered again.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// Two stacks have the same name.
+    Duplicate(String),
+    /// A stack names a custom backend that was not added to the [Backends].
+    UnknownBackend { stack: String, backend: String },
+    /// Building the backend of a stack failed.
+    Backend {
+        stack: String,
+        error: std::io::Error,
+    },
+    /// Registering a stack failed.
+    Register { stack: String, error: RegisterError },
+}
+
+impl Backends {
+    /// No backends of the application.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the backend `name`, built by `factory` from the parameters of the configuration.
+    pub fn with(
+        mut self,
+        name: impl Into<String>,
+        factory: impl Fn(&BTreeMap<String, String>) -> Result<DynVfs, std::io::Error>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.factories.insert(name.into(), Box::new(factory));
+        self
+    }
+}
+
+impl VfsRegistry {
+    /// Build and register the stacks of `config`, which can only use the backends of this crate.
+    pub fn from_config(config: &RegistryConfig) -> Result<Self, ConfigError> {
+        Self::from_config_with(config, &Backends::new())
+    }
+
+    /// Build and register the stacks of `config`, whose custom backends are built by `backends`.
+    pub fn from_config_with(
+        config: &RegistryConfig,
+        backends: &Backends,
+    ) -> Result<Self, ConfigError> {
+        let mut registry = VfsRegistry {
+            handles: Vec::with_capacity(config.vfs.len()),
+            stats: HashMap::new(),
+        };
+        for stack in &config.vfs {
+            if registry.get(&stack.name).is_some() {
+                return Err(ConfigError::Duplicate(stack.name.clone()));
+            }
+            let vfs = registry.build(stack, backends)?;
+            let options = RegisterOptions {
+                make_default: stack.default,
+                ..Default::default()
+            };
+            let handle = register_with_options(&stack.name, vfs, options).map_err(|error| {
+                ConfigError::Register {
+                    stack: stack.name.clone(),
+                    error,
+                }
+            })?;
+            log::debug!("registered the vfs stack {}", stack.name);
+            registry.handles.push(handle);
+        }
+        Ok(registry)
+    }
+
+    /// Put the backend and shims of `stack` together.
+    fn build(&mut self, stack: &StackConfig, backends: &Backends) -> Result<DynVfs, ConfigError> {
+        let mut vfs =
+            match &stack.backend {
+                BackendConfig::Memory { max_size: None } => boxed(MemVfs::new()),
+                BackendConfig::Memory {
+                    max_size: Some(max_size),
+                } => boxed(MemVfs::with_max_size(*max_size)),
+                BackendConfig::Custom { name, params } => {
+                    let factory = backends.factories.get(name).ok_or_else(|| {
+                        ConfigError::UnknownBackend {
+                            stack: stack.name.clone(),
+                            backend: name.clone(),
+                        }
+                    })?;
+                    factory(params).map_err(|error| ConfigError::Backend {
+                        stack: stack.name.clone(),
+                        error,
+                    })?
+                }
+            };
+        for shim in &stack.shims {
+            vfs = match shim {
+                ShimConfig::Shm => boxed(ShmVfs::new(vfs)),
+                ShimConfig::Checksum => boxed(ChecksumVfs::new(vfs, Checksum)),
+                ShimConfig::Fencing => boxed(FencedVfs::new(vfs)),
+                ShimConfig::Multiplex { chunk_size } => boxed(MultiplexVfs::new(vfs, *chunk_size)),
+                ShimConfig::Throttle {
+                    read_bandwidth,
+                    write_bandwidth,
+                    iops,
+                    burst_ms,
+                    per_file,
+                    priorities,
+                } => {
+                    let mut options = ThrottleOptions {
+                        read_bandwidth: *read_bandwidth,
+                        write_bandwidth: *write_bandwidth,
+                        iops: *iops,
+                        per_file: *per_file,
+                        priorities: priorities
+                            .iter()
+                            .map(|(path, priority)| (path.into(), *priority))
+                            .collect(),
+                        ..Default::default()
+                    };
+                    if let Some(burst_ms) = burst_ms {
+                        options.burst = Duration::from_millis(*burst_ms);
+                    }
+                    boxed(ThrottleVfs::new(vfs, options))
+                }
+                ShimConfig::Stats => {
+                    let vfs = StatsVfs::new(vfs);
+                    self.stats.insert(stack.name.clone(), vfs.stats());
+                    boxed(vfs)
+                }
+                #[cfg(feature = "tracing")]
+                ShimConfig::Trace { slow_ops_ms } => {
+                    let options = crate::trace::TraceOptions {
+                        slow_ops: slow_ops_ms.map(Duration::from_millis),
+                        ..Default::default()
+                    };
+                    boxed(crate::trace::TraceVfs::with_options(vfs, options))
+                }
+                #[cfg(feature = "zstd")]
+                ShimConfig::Zstd { level } => {
+                    let mut codec = crate::compress::Zstd::default();
+                    if let Some(level) = level {
+                        codec.level = *level;
+                    }
+                    boxed(crate::compress::CompressedVfs::new(vfs, codec))
+                }
+                #[cfg(feature = "lz4")]
+                ShimConfig::Lz4 => boxed(crate::compress::CompressedVfs::new(
+                    vfs,
+                    crate::compress::Lz4,
+                )),
+            };
+        }
+        Ok(vfs)
+    }
+
+    /// The handle of the stack registered as `name`.
+    pub fn get(&self, name: &str) -> Option<&VfsHandle> {
+        self.handles.iter().find(|handle| handle.name() == name)
+    }
+
+    /// The names of the registered stacks, in the order of the configuration.
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.handles.iter().map(VfsHandle::name)
+    }
+
+    /// The statistics of the stack registered as `name`, if it has a [ShimConfig::Stats] shim.
+    pub fn stats(&self, name: &str) -> Option<Stats> {
+        self.stats.get(name).cloned()
+    }
+
+    /// Unregister all stacks (see [VfsHandle::unregister]). If files are still open through some
+    /// of them, those are kept registered and returned in a registry of their own.
+    pub fn unregister(self) -> Result<(), Self> {
+        let mut kept = Vec::new();
+        for handle in self.handles {
+            if let Err(handle) = handle.unregister() {
+                kept.push(handle);
+            }
+        }
+        if kept.is_empty() {
+            return Ok(());
+        }
+        let mut stats = self.stats;
+        stats.retain(|name, _| kept.iter().any(|handle| handle.name() == name));
+        Err(VfsRegistry {
+            handles: kept,
+            stats,
+        })
+    }
+}
+
+impl std::fmt::Debug for VfsRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Backend { error, .. } => Some(error),
+            Self::Register { error, .. } => Some(error),
+            Self::Duplicate(_) | Self::UnknownBackend { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Duplicate(stack) => write!(f, "vfs stack {} is configured twice", stack),
+            Self::UnknownBackend { stack, backend } => {
+                write!(f, "vfs stack {} uses unknown backend {}", stack, backend)
+            }
+            Self::Backend { stack, .. } => {
+                write!(f, "building the backend of vfs stack {} failed", stack)
+            }
+            Self::Register { stack, .. } => write!(f, "registering vfs stack {} failed", stack),
+        }
+    }
+}
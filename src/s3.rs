@@ -0,0 +1,646 @@
+//! Store databases in an S3-compatible bucket (only with the `s3` feature).
+//!
+//! An [S3Vfs] splits each file into chunks of [S3Options::chunk_size] bytes and stores every chunk
+//! as an object (`<prefix>/<path>/<index>`), so a write only has to upload the chunks it changed.
+//! Changed chunks are kept in memory until the file is synced, which uploads them (with a multipart
+//! upload if they are larger than [S3Options::part_size]). The most recently read chunks are cached
+//! as well, optionally within a [MemoryBudget] shared with other caches ([S3Options::budget]).
+//!
+//! [S3Vfs] is an [AsyncVfs], which is registered through a
+//! [BlockingVfs](crate::async_vfs::BlockingVfs). It works with any [ObjectStore], e.g. with the
+//! in-memory store of `object_store` for tests:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use object_store::memory::InMemory;
+//! use sqlite_vfs::async_vfs::{BlockingVfs, CurrentThread};
+//! use sqlite_vfs::s3::S3Vfs;
+//!
+//! let vfs = S3Vfs::new(Arc::new(InMemory::new()));
+//! let _vfs = sqlite_vfs::register("s3", BlockingVfs::new(vfs, CurrentThread)).unwrap();
+//! ```
+//!
+//! The S3 client of `object_store` (as created by [S3Vfs::from_env]) sends its requests with
+//! `reqwest`, which needs a tokio runtime, so its [Bridge](crate::async_vfs::Bridge) has to block on
+//! one (e.g. with `tokio::runtime::Handle::block_on`) instead of using
+//! [CurrentThread](crate::async_vfs::CurrentThread).
+//!
+//! Locks are only held within the process: connections of other processes (or hosts) must not use
+//! the same database at the same time. Temporary files are kept in memory and never uploaded.
+//!
+//! Requests that are in flight are aborted when the operation is cancelled with a
+//! [CancellationToken](crate::async_vfs::CancellationToken). The chunks of a cancelled sync are
+//! uploaded again with the next one, but the parts of a cancelled multipart upload are left in the
+//! bucket until the upload is aborted (e.g. by a lifecycle rule of the bucket).
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError, Weak};
+
+use futures::TryStreamExt;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+
+use crate::async_vfs::{AsyncFile, AsyncVfs};
+use crate::budget::{Charge, Evict, MemoryBudget};
+use crate::mem::{HeldLock, Locks};
+use crate::{LockKind, OpenAccess, OpenKind, OpenOptions, SyncOptions};
+
+/// Options of an [S3Vfs].
+#[derive(Debug, Clone)]
+pub struct S3Options {
+    /// The prefix of the keys of all objects (default: none).
+    pub prefix: ObjectPath,
+
+    /// The size of the chunks files are split into (default: 8 MiB). Each write uploads the
+    /// complete chunks it changed, so smaller chunks upload less for small transactions, while
+    /// larger chunks need fewer requests to read a database.
+    pub chunk_size: usize,
+
+    /// Chunks larger than this are uploaded in parts of this size with a multipart upload
+    /// (default: 5 MiB, the minimum part size of S3).
+    pub part_size: usize,
+
+    /// How many unchanged chunks to keep in memory per file (default: 8).
+    pub cache_chunks: usize,
+
+    /// The budget the cached unchanged chunks draw their memory from, in addition to the limit of
+    /// [S3Options::cache_chunks] (default: none). They are evicted when other components need the
+    /// memory, and chunks are not cached while there is no room for them.
+    pub budget: Option<MemoryBudget>,
+}
+
+impl Default for S3Options {
+    fn default() -> Self {
+        S3Options {
+            prefix: ObjectPath::default(),
+            chunk_size: 8 * 1024 * 1024,
+            part_size: 5 * 1024 * 1024,
+            cache_chunks: 8,
+            budget: None,
+        }
+    }
+}
+
+/// An [AsyncVfs] that stores files as chunks in an S3-compatible bucket (or any other
+/// [ObjectStore]).
+pub struct S3Vfs {
+    store: Arc<dyn ObjectStore>,
+    options: Arc<S3Options>,
+    /// The state of all files that are currently open.
+    objects: Mutex<HashMap<PathBuf, Weak<Mutex<Object>>>>,
+}
+
+/// A file opened by [S3Vfs].
+pub struct S3File {
+    store: Arc<dyn ObjectStore>,
+    options: Arc<S3Options>,
+    key: ObjectPath,
+    object: Arc<Mutex<Object>>,
+    writable: bool,
+    held: HeldLock,
+}
+
+/// The state of a file, shared by all connections that opened it.
+struct Object {
+    size: u64,
+    /// Chunks at this index and above are not in the store (or outdated).
+    stored: u64,
+    /// Chunks that have to be deleted from the store with the next sync.
+    deleted: BTreeSet<u64>,
+    chunks: HashMap<u64, Chunk>,
+    /// The unchanged chunks in the order they were last used.
+    recent: VecDeque<u64>,
+    /// Temporary files are never uploaded.
+    upload: bool,
+    locks: Locks,
+}
+
+struct Chunk {
+    data: Arc<Vec<u8>>,
+    dirty: bool,
+    /// Incremented with each write, to find out whether a chunk changed while it was uploaded.
+    version: u64,
+    /// The memory an unchanged chunk draws from [S3Options::budget].
+    charge: Option<Charge>,
+}
+
+impl S3Vfs {
+    /// Store files in `store` with the default [S3Options].
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self::with_options(store, S3Options::default())
+    }
+
+    /// Store files in `store` with `options`.
+    pub fn with_options(store: Arc<dyn ObjectStore>, options: S3Options) -> Self {
+        S3Vfs {
+            store,
+            options: Arc::new(options),
+            objects: Default::default(),
+        }
+    }
+
+    /// Store files in `bucket`, configured by the `AWS_*` environment variables (see
+    /// [AmazonS3Builder::from_env](object_store::aws::AmazonS3Builder::from_env)).
+    pub fn from_env(bucket: &str) -> Result<Self, std::io::Error> {
+        let store = object_store::aws::AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()
+            .map_err(io_error)?;
+        Ok(Self::new(Arc::new(store)))
+    }
+
+    /// The key of the file at `path`, under which its chunks are stored.
+    fn key(&self, path: &Path) -> ObjectPath {
+        path.components()
+            .filter_map(|component| match component {
+                Component::Normal(part) => Some(part.to_string_lossy()),
+                _ => None,
+            })
+            .fold(self.options.prefix.clone(), |key, part| {
+                key.child(part.as_ref())
+            })
+    }
+
+    fn objects(&self) -> MutexGuard<'_, HashMap<PathBuf, Weak<Mutex<Object>>>> {
+        self.objects.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn open_object(&self, path: &Path) -> Option<Arc<Mutex<Object>>> {
+        self.objects().get(path).and_then(Weak::upgrade)
+    }
+
+    /// Find the stored chunks of the file at `key`, and return their number and the size of the
+    /// file, or `None` if there are none.
+    async fn stored(&self, key: &ObjectPath) -> Result<Option<(u64, u64)>, std::io::Error> {
+        let chunks = self
+            .store
+            .list(Some(key))
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(io_error)?;
+        let last = chunks
+            .iter()
+            .filter_map(|meta| Some((chunk_index(key, &meta.location)?, meta.size)))
+            .max();
+        Ok(last.map(|(index, len)| {
+            let size = index * self.options.chunk_size as u64 + len as u64;
+            (index + 1, size)
+        }))
+    }
+}
+
+impl AsyncVfs for S3Vfs {
+    type File = S3File;
+
+    async fn open(&self, path: &Path, opts: OpenOptions) -> Result<S3File, std::io::Error> {
+        let key = self.key(path);
+        let upload = matches!(
+            opts.kind,
+            OpenKind::MainDb | OpenKind::MainJournal | OpenKind::SuperJournal | OpenKind::Wal
+        );
+        let object = match self.open_object(path) {
+            Some(_) if opts.access == OpenAccess::CreateNew => {
+                return Err(ErrorKind::AlreadyExists.into())
+            }
+            Some(object) => object,
+            None => {
+                let stored = if upload {
+                    self.stored(&key).await?
+                } else {
+                    None
+                };
+                match (stored, opts.access) {
+                    (Some(_), OpenAccess::CreateNew) => return Err(ErrorKind::AlreadyExists.into()),
+                    (None, OpenAccess::Read | OpenAccess::Write) => {
+                        return Err(ErrorKind::NotFound.into())
+                    }
+                    _ => {}
+                }
+                let (stored, size) = stored.unwrap_or_default();
+                let object = Arc::new(Mutex::new(Object {
+                    size,
+                    stored,
+                    deleted: BTreeSet::new(),
+                    chunks: HashMap::new(),
+                    recent: VecDeque::new(),
+                    upload,
+                    locks: Locks::default(),
+                }));
+                // another connection might have opened the file in the meantime
+                let mut objects = self.objects();
+                match objects.get(path).and_then(Weak::upgrade) {
+                    Some(object) => object,
+                    None => {
+                        objects.insert(path.to_path_buf(), Arc::downgrade(&object));
+                        object
+                    }
+                }
+            }
+        };
+        Ok(S3File {
+            store: Arc::clone(&self.store),
+            options: Arc::clone(&self.options),
+            key,
+            object,
+            writable: opts.access != OpenAccess::Read,
+            held: HeldLock::new(),
+        })
+    }
+
+    async fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        let object = self
+            .objects()
+            .remove(path)
+            .and_then(|object| object.upgrade());
+        if let Some(object) = &object {
+            // connections that still have the file open keep using it, but never upload it again
+            let mut object = lock_object(object);
+            object.size = 0;
+            object.stored = 0;
+            object.deleted.clear();
+            object.chunks.clear();
+            object.recent.clear();
+            object.upload = false;
+        }
+
+        let key = self.key(path);
+        let chunks = self
+            .store
+            .list(Some(&key))
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(io_error)?;
+        if object.is_none() && chunks.is_empty() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        for meta in chunks {
+            if chunk_index(&key, &meta.location).is_some() {
+                delete_chunk(&*self.store, &meta.location).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        if self.open_object(path).is_some() {
+            return Ok(true);
+        }
+        Ok(self.stored(&self.key(path)).await?.is_some())
+    }
+}
+
+impl S3File {
+    fn object(&self) -> MutexGuard<'_, Object> {
+        lock_object(&self.object)
+    }
+
+    fn chunk_key(&self, index: u64) -> ObjectPath {
+        self.key.child(format!("{:010}", index))
+    }
+
+    /// Draw the memory of the unchanged chunk at `index` from the budget (if any). Returns `Err` if
+    /// there is no room for it, in which case the chunk must not be cached.
+    fn charge(&self, index: u64, len: usize) -> Result<Option<Charge>, ()> {
+        match &self.options.budget {
+            Some(budget) => {
+                let owner: Weak<dyn Evict> = Arc::downgrade(&self.object) as Weak<Mutex<Object>>;
+                budget.cache(len as u64, owner, index).map(Some).ok_or(())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The contents of the chunk at `index`, from the cache or from the store. Chunks that are not
+    /// stored are empty.
+    async fn chunk(&self, index: u64) -> Result<Arc<Vec<u8>>, std::io::Error> {
+        {
+            let mut object = self.object();
+            if object.chunks.contains_key(&index) {
+                object.touch(index);
+                return Ok(Arc::clone(&object.chunks[&index].data));
+            }
+            if index >= object.stored {
+                return Ok(Default::default());
+            }
+        }
+
+        let data = match self.store.get(&self.chunk_key(index)).await {
+            Ok(result) => result.bytes().await.map_err(io_error)?.to_vec(),
+            Err(object_store::Error::NotFound { .. }) => Vec::new(),
+            Err(err) => return Err(io_error(err)),
+        };
+        let Ok(charge) = self.charge(index, data.len()) else {
+            return Ok(Arc::new(data));
+        };
+        let mut object = self.object();
+        let data = Arc::clone(
+            &object
+                .chunks
+                .entry(index)
+                .or_insert_with(|| Chunk {
+                    data: Arc::new(data),
+                    dirty: false,
+                    version: 0,
+                    charge,
+                })
+                .data,
+        );
+        object.touch(index);
+        object.evict(self.options.cache_chunks);
+        Ok(data)
+    }
+
+    /// Upload `data` as the chunk at `index`.
+    async fn upload(&self, index: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        let key = self.chunk_key(index);
+        if data.len() <= self.options.part_size {
+            self.store
+                .put(&key, data.to_vec().into())
+                .await
+                .map_err(io_error)?;
+            return Ok(());
+        }
+
+        let mut upload = self.store.put_multipart(&key).await.map_err(io_error)?;
+        for part in data.chunks(self.options.part_size) {
+            if let Err(err) = upload.put_part(part.to_vec().into()).await {
+                let _ = upload.abort().await;
+                return Err(io_error(err));
+            }
+        }
+        upload.complete().await.map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Upload the changed chunks and delete the chunks beyond the end of the file.
+    async fn flush(&mut self) -> Result<(), std::io::Error> {
+        let chunk_size = self.options.chunk_size as u64;
+        let (dirty, deleted, count) = {
+            let object = self.object();
+            if !object.upload {
+                return Ok(());
+            }
+            let size = object.size;
+            let dirty = object
+                .chunks
+                .iter()
+                .filter(|(_, chunk)| chunk.dirty)
+                .map(|(index, chunk)| {
+                    // all but the last chunk are complete
+                    let len = (size - index * chunk_size).min(chunk_size) as usize;
+                    let mut data = chunk.data.to_vec();
+                    data.resize(len, 0);
+                    (*index, data, chunk.version)
+                })
+                .collect::<Vec<_>>();
+            // the chunks stay marked as deleted until they are, in case the flush fails or is
+            // cancelled (which drops this future at an `.await`)
+            let deleted = object.deleted.clone();
+            (dirty, deleted, size.div_ceil(chunk_size))
+        };
+
+        for (index, data, _) in &dirty {
+            self.upload(*index, data).await?;
+        }
+        for index in &deleted {
+            if !dirty.iter().any(|(i, _, _)| i == index) {
+                delete_chunk(&*self.store, &self.chunk_key(*index)).await?;
+            }
+        }
+
+        let mut object = self.object();
+        object.deleted.retain(|index| !deleted.contains(index));
+        for (index, data, version) in dirty {
+            if !matches!(object.chunks.get(&index), Some(chunk) if chunk.version == version) {
+                continue;
+            }
+            // keep the uploaded chunk cached if there is room for it
+            match self.charge(index, data.len()) {
+                Ok(charge) => {
+                    let chunk = object.chunks.get_mut(&index).unwrap();
+                    chunk.dirty = false;
+                    chunk.charge = charge;
+                    object.recent.push_back(index);
+                }
+                Err(()) => {
+                    object.chunks.remove(&index);
+                }
+            }
+        }
+        object.stored = count;
+        object.evict(self.options.cache_chunks);
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), std::io::Error> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "file is opened read-only",
+            ))
+        }
+    }
+}
+
+impl AsyncFile for S3File {
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let chunk_size = self.options.chunk_size as u64;
+        let end = (offset + buf.len() as u64).min(self.object().size);
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / chunk_size;
+            let start = (pos - index * chunk_size) as usize;
+            let n = (end - pos).min(chunk_size - start as u64) as usize;
+            let data = self.chunk(index).await?;
+            let out = &mut buf[(pos - offset) as usize..][..n];
+            // chunks may be shorter than the file, e.g. after a write beyond its end
+            let available = data.len().saturating_sub(start).min(n);
+            out[..available].copy_from_slice(&data[start..start + available]);
+            out[available..].fill(0);
+            pos += n as u64;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    async fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.check_writable()?;
+        let chunk_size = self.options.chunk_size as u64;
+        let end = offset + buf.len() as u64;
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / chunk_size;
+            let start = (pos - index * chunk_size) as usize;
+            let n = (end - pos).min(chunk_size - start as u64) as usize;
+            // the previous contents of a chunk do not matter if they are overwritten completely
+            let size = self.object().size;
+            let data = if start == 0 && (n as u64 == chunk_size || pos + n as u64 >= size) {
+                Default::default()
+            } else {
+                self.chunk(index).await?
+            };
+
+            let mut object = self.object();
+            let chunk = object.chunks.entry(index).or_insert_with(|| Chunk {
+                data,
+                dirty: false,
+                version: 0,
+                charge: None,
+            });
+            let data = Arc::make_mut(&mut chunk.data);
+            if data.len() < start + n {
+                data.resize(start + n, 0);
+            }
+            data[start..start + n].copy_from_slice(&buf[(pos - offset) as usize..][..n]);
+            chunk.dirty = true;
+            chunk.version += 1;
+            chunk.charge = None;
+            object.recent.retain(|i| *i != index);
+            pos += n as u64;
+            object.size = object.size.max(pos);
+        }
+        Ok(())
+    }
+
+    async fn sync(&mut self, _options: SyncOptions) -> Result<(), std::io::Error> {
+        self.flush().await
+    }
+
+    async fn file_size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.object().size)
+    }
+
+    async fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.check_writable()?;
+        let chunk_size = self.options.chunk_size as u64;
+        let count = size.div_ceil(chunk_size);
+        // the last chunk that is kept has to be cut off as well
+        let (index, len) = (size / chunk_size, (size % chunk_size) as usize);
+        let data = if len > 0 && size < self.object().size {
+            Some(self.chunk(index).await?)
+        } else {
+            None
+        };
+
+        {
+            let mut object = self.object();
+            if let Some(data) = data {
+                let chunk = object.chunks.entry(index).or_insert_with(|| Chunk {
+                    data,
+                    dirty: false,
+                    version: 0,
+                    charge: None,
+                });
+                if chunk.data.len() > len {
+                    Arc::make_mut(&mut chunk.data).truncate(len);
+                    chunk.dirty = true;
+                    chunk.version += 1;
+                    chunk.charge = None;
+                    object.recent.retain(|i| *i != index);
+                }
+            }
+            object.chunks.retain(|index, _| *index < count);
+            object.recent.retain(|index| *index < count);
+            let stored = object.stored;
+            object.deleted.extend(count..stored);
+            object.stored = stored.min(count);
+            object.size = size;
+        }
+
+        // the size of a file is derived from its last stored chunk, so remove the chunks beyond it
+        // right away (SQLite does not necessarily sync the file after truncating it)
+        self.flush().await
+    }
+
+    async fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        Ok(self.held.lock(&mut lock_object(&self.object).locks, lock))
+    }
+
+    async fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.held.unlock(&mut lock_object(&self.object).locks, lock);
+        Ok(())
+    }
+
+    async fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        Ok(self.object().locks.reserved())
+    }
+}
+
+impl Drop for S3File {
+    fn drop(&mut self) {
+        // release the locks of a connection that is closed without unlocking first
+        self.held
+            .unlock(&mut lock_object(&self.object).locks, LockKind::None);
+    }
+}
+
+impl Object {
+    /// Mark the unchanged chunk at `index` as the most recently used one.
+    fn touch(&mut self, index: u64) {
+        let chunk = self.chunks.get_mut(&index).unwrap();
+        if !chunk.dirty {
+            if let Some(charge) = &mut chunk.charge {
+                charge.touch();
+            }
+            self.recent.retain(|i| *i != index);
+            self.recent.push_back(index);
+        }
+    }
+
+    /// Drop the least recently used unchanged chunks until at most `capacity` are left.
+    fn evict(&mut self, capacity: usize) {
+        while self.recent.len() > capacity {
+            if let Some(index) = self.recent.pop_front() {
+                self.chunks.remove(&index);
+            }
+        }
+    }
+}
+
+impl Evict for Mutex<Object> {
+    fn evict(&self, index: u64) -> bool {
+        let mut object = match self.try_lock() {
+            Ok(object) => object,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return false,
+        };
+        if !matches!(object.chunks.get(&index), Some(chunk) if !chunk.dirty) {
+            return false;
+        }
+        object.chunks.remove(&index);
+        object.recent.retain(|i| *i != index);
+        true
+    }
+}
+
+/// The index of the chunk stored at `location`, if it is a chunk of the file at `key`.
+fn chunk_index(key: &ObjectPath, location: &ObjectPath) -> Option<u64> {
+    let mut parts = location.prefix_match(key)?;
+    let index = parts.next()?.as_ref().parse().ok()?;
+    match parts.next() {
+        Some(_) => None,
+        None => Some(index),
+    }
+}
+
+async fn delete_chunk(store: &dyn ObjectStore, key: &ObjectPath) -> Result<(), std::io::Error> {
+    match store.delete(key).await {
+        Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+        Err(err) => Err(io_error(err)),
+    }
+}
+
+fn io_error(err: object_store::Error) -> std::io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => std::io::Error::new(ErrorKind::NotFound, err),
+        err => std::io::Error::other(err),
+    }
+}
+
+fn lock_object(object: &Mutex<Object>) -> MutexGuard<'_, Object> {
+    object.lock().unwrap_or_else(|err| err.into_inner())
+}
@@ -0,0 +1,486 @@
+//! Shared memory for WAL mode, kept in the memory of the process.
+//!
+//! A [ShmVfs] wraps a [Vfs] whose files do not provide [SharedMemory] themselves, and shares the
+//! WAL index of each main database between all connections opened through the same [ShmVfs]. This
+//! makes WAL mode work for any number of connections within one process, but not across processes:
+//! connections of other processes (or of another [ShmVfs] over the same files) do not see the WAL
+//! index, and would corrupt the database if they wrote to it at the same time.
+//!
+//! The shared memory of a database is released once the last connection to it closes. The next
+//! connection rebuilds it from the WAL file. Until then, its regions are kept even when all
+//! connections unmap it (e.g. when they leave WAL mode), and are zeroed and reused when it is
+//! mapped again instead of being allocated anew. [ShmVfs::pool] tells how many regions are
+//! allocated, in use and kept for reuse.
+
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenKind, OpenOptions, RecoveryPhase, SharedMemory,
+    ShmLock, SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// Number of lock slots of the shared memory.
+const SLOTS: usize = 8;
+
+/// The first of the lock slots readers hold a shared lock on while their read transaction is open.
+const FIRST_READ_SLOT: usize = 3;
+
+/// A [Vfs] that provides in-process [SharedMemory] to the main databases opened through it.
+pub struct ShmVfs<V> {
+    vfs: V,
+    pool: ShmPool,
+}
+
+/// Reads the occupancy of the regions of a [ShmVfs].
+#[derive(Clone, Default)]
+pub struct ShmPool {
+    inner: Arc<Pool>,
+}
+
+/// The occupancy of the regions of a [ShmVfs] at one point in time.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct PoolOccupancy {
+    /// The number of databases with shared memory, i.e. with open connections that used it.
+    pub databases: usize,
+    /// The number of regions allocated for all databases.
+    pub regions: usize,
+    /// The number of regions of databases that are mapped by at least one connection.
+    pub mapped: usize,
+    /// The number of regions kept for reuse, of databases that no connection maps anymore.
+    pub pooled: usize,
+    /// The size of all regions, in bytes.
+    pub bytes: usize,
+    /// The number of regions that were reused when the shared memory of a database was mapped
+    /// again, instead of being allocated.
+    pub reused: u64,
+}
+
+#[derive(Default)]
+struct Pool {
+    segments: Mutex<HashMap<PathBuf, Weak<Mutex<Segment>>>>,
+    reused: AtomicU64,
+}
+
+/// A file opened by [ShmVfs].
+pub struct ShmFile<F> {
+    file: F,
+    shm: Option<Connection>,
+}
+
+/// The shared memory of a main database.
+#[derive(Default)]
+struct Segment {
+    /// Regions are allocated as `u64` to be aligned to 8 bytes, and are written by SQLite through
+    /// the pointers returned by [SharedMemory::map].
+    regions: Vec<Box<[UnsafeCell<u64>]>>,
+    /// Number of connections holding a shared lock on each slot.
+    shared: [u32; SLOTS],
+    /// Whether a connection holds an exclusive lock on each slot.
+    exclusive: [bool; SLOTS],
+    /// Number of connections that mapped the shared memory (and did not unmap it yet).
+    mapped: usize,
+}
+
+/// The view of one connection on the shared memory of its main database.
+struct Connection {
+    path: PathBuf,
+    pool: ShmPool,
+    /// Attached on the first use and kept until the connection closes.
+    segment: Option<Arc<Mutex<Segment>>>,
+    /// Whether the connection mapped the shared memory (and did not unmap it yet).
+    mapped: bool,
+    shared: [bool; SLOTS],
+    exclusive: [bool; SLOTS],
+}
+
+impl<V> ShmVfs<V> {
+    /// Wrap `vfs` and share the WAL index of all main databases opened through it.
+    pub fn new(vfs: V) -> Self {
+        ShmVfs {
+            vfs,
+            pool: ShmPool::default(),
+        }
+    }
+
+    /// The [ShmPool] of this [ShmVfs] (also after it has been registered).
+    pub fn pool(&self) -> ShmPool {
+        self.pool.clone()
+    }
+}
+
+impl ShmPool {
+    /// The current occupancy of the regions.
+    pub fn occupancy(&self) -> PoolOccupancy {
+        let mut occupancy = PoolOccupancy {
+            reused: self.inner.reused.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        let segments = self.inner.segments.lock().unwrap();
+        for segment in segments.values().filter_map(Weak::upgrade) {
+            let segment = segment.lock().unwrap();
+            occupancy.databases += 1;
+            occupancy.regions += segment.regions.len();
+            match segment.mapped {
+                0 => occupancy.pooled += segment.regions.len(),
+                _ => occupancy.mapped += segment.regions.len(),
+            }
+            occupancy.bytes += segment
+                .regions
+                .iter()
+                .map(|region| region.len() * 8)
+                .sum::<usize>();
+        }
+        occupancy
+    }
+}
+
+impl<V: Vfs> Vfs for ShmVfs<V> {
+    type File = ShmFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let shm = (opts.kind == OpenKind::MainDb).then(|| Connection {
+            path: path.to_path_buf(),
+            pool: self.pool.clone(),
+            segment: None,
+            mapped: false,
+            shared: [false; SLOTS],
+            exclusive: [false; SLOTS],
+        });
+        let file = self.vfs.open(path, opts)?;
+        Ok(ShmFile { file, shm })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.vfs.rename(from, to)
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.vfs.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl<F: File> File for ShmFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.file.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.file.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.file.unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.shm.as_mut().map(|shm| shm as &mut dyn SharedMemory)
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        self.file.memory_mapped()
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        self.file.batch_atomic_write()
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.file.file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        self.file.device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
+
+impl Connection {
+    /// The shared memory of the database, which is attached to (and mapped) if necessary. When it
+    /// is mapped again after all connections unmapped it, its regions are zeroed for reuse, as
+    /// they would be if they were allocated anew.
+    fn segment(&mut self) -> MutexGuard<'_, Segment> {
+        let segment = self.segment.get_or_insert_with(|| {
+            let mut segments = self.pool.inner.segments.lock().unwrap();
+            match segments.get(&self.path).and_then(Weak::upgrade) {
+                Some(segment) => segment,
+                None => {
+                    let segment = Arc::new(Mutex::new(Segment::default()));
+                    segments.insert(self.path.clone(), Arc::downgrade(&segment));
+                    segment
+                }
+            }
+        });
+        let mut segment = segment.lock().unwrap();
+        if !self.mapped {
+            self.mapped = true;
+            segment.mapped += 1;
+            if segment.mapped == 1 && !segment.regions.is_empty() {
+                for region in &mut segment.regions {
+                    region.iter_mut().for_each(|word| *word.get_mut() = 0);
+                }
+                let reused = segment.regions.len() as u64;
+                self.pool.inner.reused.fetch_add(reused, Ordering::Relaxed);
+            }
+        }
+        segment
+    }
+
+    /// Release all locks and unmap the shared memory, which keeps its regions for reuse.
+    fn unmap(&mut self) {
+        let segment = match &self.segment {
+            Some(segment) if self.mapped => segment,
+            _ => return,
+        };
+        let mut segment = segment.lock().unwrap();
+        for slot in 0..SLOTS {
+            if std::mem::take(&mut self.shared[slot]) {
+                segment.shared[slot] -= 1;
+            }
+            if std::mem::take(&mut self.exclusive[slot]) {
+                segment.exclusive[slot] = false;
+            }
+        }
+        segment.mapped -= 1;
+        self.mapped = false;
+    }
+
+    /// Unmap and detach from the shared memory, which is dropped once the last connection
+    /// detached.
+    fn detach(&mut self) {
+        self.unmap();
+        let segment = match self.segment.take() {
+            Some(segment) => segment,
+            None => return,
+        };
+
+        let mut segments = self.pool.inner.segments.lock().unwrap();
+        drop(segment);
+        if segments
+            .get(&self.path)
+            .is_some_and(|segment| segment.strong_count() == 0)
+        {
+            segments.remove(&self.path);
+        }
+    }
+}
+
+unsafe impl SharedMemory for Connection {
+    fn map(
+        &mut self,
+        index: u32,
+        size: usize,
+        extend: bool,
+    ) -> Result<Option<NonNull<u8>>, std::io::Error> {
+        let mut segment = self.segment();
+        let index = index as usize;
+        if segment.regions.len() <= index {
+            if !extend {
+                return Ok(None);
+            }
+            segment.regions.resize_with(index + 1, || {
+                (0..size.div_ceil(8)).map(|_| UnsafeCell::new(0)).collect()
+            });
+        }
+        Ok(NonNull::new(segment.regions[index].as_ptr() as *mut u8))
+    }
+
+    fn lock(&mut self, slots: Range<u8>, lock: ShmLock) -> Result<bool, std::io::Error> {
+        let (shared, exclusive) = (self.shared, self.exclusive);
+        let mut segment = self.segment();
+        let slots = slots.start as usize..slots.end as usize;
+        let available = slots.clone().all(|slot| {
+            let others_exclusive = segment.exclusive[slot] && !exclusive[slot];
+            match lock {
+                ShmLock::Shared => !others_exclusive,
+                ShmLock::Exclusive => {
+                    !others_exclusive && segment.shared[slot] == u32::from(shared[slot])
+                }
+            }
+        });
+        if !available {
+            return Ok(false);
+        }
+
+        for slot in slots.clone() {
+            match lock {
+                ShmLock::Shared if !shared[slot] => segment.shared[slot] += 1,
+                ShmLock::Shared => {}
+                ShmLock::Exclusive => segment.exclusive[slot] = true,
+            }
+        }
+        drop(segment);
+        for slot in slots {
+            match lock {
+                ShmLock::Shared => self.shared[slot] = true,
+                ShmLock::Exclusive => self.exclusive[slot] = true,
+            }
+        }
+        Ok(true)
+    }
+
+    fn unlock(&mut self, slots: Range<u8>, _lock: ShmLock) -> Result<(), std::io::Error> {
+        let (shared, exclusive) = (self.shared, self.exclusive);
+        let mut segment = self.segment();
+        let slots = slots.start as usize..slots.end as usize;
+        for slot in slots.clone() {
+            if shared[slot] {
+                segment.shared[slot] -= 1;
+            }
+            if exclusive[slot] {
+                segment.exclusive[slot] = false;
+            }
+        }
+        drop(segment);
+        for slot in slots {
+            self.shared[slot] = false;
+            self.exclusive[slot] = false;
+        }
+        Ok(())
+    }
+
+    fn external_reader(&mut self) -> Result<bool, std::io::Error> {
+        let segment = match &self.segment {
+            Some(segment) if self.mapped => segment.lock().unwrap(),
+            _ => return Ok(false),
+        };
+        Ok(
+            (FIRST_READ_SLOT..SLOTS)
+                .any(|slot| segment.shared[slot] > u32::from(self.shared[slot])),
+        )
+    }
+
+    fn unmap(&mut self, _delete: bool) -> Result<(), std::io::Error> {
+        Connection::unmap(self);
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
@@ -0,0 +1,379 @@
+//! Copy databases while connections keep using them, with [VfsHandle::snapshot] and
+//! [VfsHandle::snapshot_to], and replace them with such a copy, with [VfsHandle::restore].
+//!
+//! Copies written to the VFS are first written to a temporary file next to their destination,
+//! which is then moved into place with [Vfs::rename]. Whether that is atomic depends on the
+//! backend (see [Vfs::rename]). Backends that cannot rename (whose `rename` fails with
+//! [ErrorKind::Unsupported](std::io::ErrorKind::Unsupported)) get the copy written to the
+//! destination directly, with SQLite's backup API, in a single transaction.
+
+use std::ffi::{CStr, OsString};
+use std::io::{ErrorKind, Read};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr::{null_mut, NonNull};
+
+use libsqlite3_sys as ffi;
+
+use crate::{gc, RawFile, State, Vfs, VfsError, VfsHandle};
+
+/// How long [VfsHandle::snapshot] and [VfsHandle::restore] wait for a lock on the database, in
+/// milliseconds.
+const BUSY_TIMEOUT_MS: c_int = 5000;
+
+/// A consistent copy of a database, made by [VfsHandle::snapshot]. It is read with [Read], or
+/// accessed as a whole with [AsRef].
+pub struct Snapshot {
+    /// The serialized database, allocated by SQLite (`None` if it is empty).
+    data: Option<NonNull<u8>>,
+    len: usize,
+    pos: usize,
+}
+
+impl VfsHandle {
+    /// Copy the database at `path`, opened through this VFS, while other connections keep using
+    /// it. The copy is made with SQLite's backup API in a single read transaction, so it holds all
+    /// transactions committed before the copy started and none after. Writers are not blocked in
+    /// WAL mode; in rollback journal modes, they have to wait until the copy is done, and the copy
+    /// has to wait (for up to five seconds) for a write in progress. The copy is kept in memory.
+    ///
+    /// Fails with [ErrorKind::WouldBlock](std::io::ErrorKind::WouldBlock) if the database stays
+    /// locked.
+    pub fn snapshot(&self, path: impl AsRef<Path>) -> Result<Snapshot, std::io::Error> {
+        let src = self.connect(path.as_ref(), ffi::SQLITE_OPEN_READONLY)?;
+        let dest = Connection::open(c":memory:", ffi::SQLITE_OPEN_READWRITE, None)?;
+        backup(&src, &dest)?;
+
+        unsafe {
+            let main = c"main".as_ptr();
+            let mut len = 0;
+            let data = NonNull::new(ffi::sqlite3_serialize(dest.0, main, &mut len, 0));
+            if data.is_none() && len > 0 {
+                return Err(VfsError::Code(ffi::SQLITE_NOMEM).into());
+            }
+            Ok(Snapshot {
+                data,
+                len: len as usize,
+                pos: 0,
+            })
+        }
+    }
+
+    /// Copy the database at `path` like [VfsHandle::snapshot], into the database at `dest` (e.g.
+    /// to rotate backups), both opened through this VFS. The copy is written to a temporary file
+    /// next to `dest` (`dest` with the suffix `-snapshot`) and moved over `dest` with
+    /// [Vfs::rename], so with an atomic rename, `dest` holds either its old content or the whole
+    /// copy, even if the process crashes in between. If the VFS cannot rename, the copy is written
+    /// to `dest` directly in a single transaction. `dest` must not be in use.
+    ///
+    /// Fails with [ErrorKind::WouldBlock](std::io::ErrorKind::WouldBlock) if the database stays
+    /// locked.
+    pub fn snapshot_to(
+        &self,
+        path: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+    ) -> Result<(), std::io::Error> {
+        let (path, dest) = (path.as_ref(), dest.as_ref());
+        let temp = with_suffix(dest, "-snapshot");
+        let result = self
+            .copy(path, &temp)
+            .and_then(|()| unsafe { (self.rename)(self.vfs.as_ptr(), &temp, dest) });
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                let result = self.copy(&temp, dest);
+                let _ = self.delete(&temp);
+                result
+            }
+            Err(err) => {
+                let _ = self.delete(&temp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Copy the database at `from` to the database at `to` with SQLite's backup API.
+    fn copy(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        let src = self.connect(from, ffi::SQLITE_OPEN_READONLY)?;
+        let dest = self.connect(to, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE)?;
+        backup(&src, &dest)
+    }
+
+    /// Replace the database at `path`, opened through this VFS, with the database read from
+    /// `reader` (e.g. a [Snapshot]), while other connections keep using it. The database is
+    /// created if it does not exist. The copy is written with SQLite's backup API in a single write
+    /// transaction that holds an exclusive lock on the database, so other connections see either
+    /// the old or the new database: it waits (for up to five seconds) for readers and writers in
+    /// progress, and afterwards, connections notice the change like any other commit, drop their
+    /// page caches and see a new `PRAGMA data_version`.
+    ///
+    /// If no file is open through this VFS (so no connection of this process uses the database),
+    /// and the database has no journal or WAL, the copy is instead written to a temporary file
+    /// next to the database (`path` with the suffix `-restore`) and moved over it with
+    /// [Vfs::rename], while holding an exclusive lock on the database. Connections of other
+    /// processes that keep the old database open find out through [File::moved](crate::File::moved)
+    /// (if the backend implements it) and fail with `SQLITE_READONLY_DBMOVED` when writing.
+    ///
+    /// Fails with [ErrorKind::WouldBlock](std::io::ErrorKind::WouldBlock) if the database stays
+    /// locked, and without changing the database if `reader` does not hold a database, or if the
+    /// database is in WAL mode and its page size differs from that of the copy.
+    pub fn restore(
+        &self,
+        path: impl AsRef<Path>,
+        mut reader: impl Read,
+    ) -> Result<(), std::io::Error> {
+        let path = path.as_ref();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        // an in-memory database cannot have a WAL, so a copy of a database in WAL mode is read in
+        // rollback journal mode (a destination in WAL mode stays in WAL mode)
+        if data.len() >= 20 && data[18] == 2 && data[19] == 2 {
+            data[18] = 1;
+            data[19] = 1;
+        }
+        // the copy is only read, from the buffer, which outlives the connection
+        let src = Connection::open(c":memory:", ffi::SQLITE_OPEN_READWRITE, None)?;
+        let code = unsafe {
+            ffi::sqlite3_deserialize(
+                src.0,
+                c"main".as_ptr(),
+                data.as_mut_ptr(),
+                data.len() as _,
+                data.len() as _,
+                ffi::SQLITE_DESERIALIZE_READONLY as _,
+            )
+        };
+        if code != ffi::SQLITE_OK {
+            return Err(src.error());
+        }
+
+        if self.open_files() == 0 {
+            match self.restore_by_rename(path, &src) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(err) if err.kind() == ErrorKind::Unsupported => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let dest = self.connect(path, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE)?;
+        backup(&src, &dest)
+    }
+
+    /// Write the database of `src` to a temporary file and move it over the database at `path`
+    /// while holding an exclusive lock on it. Returns `false`, without changing the database, if
+    /// it is in use or has a journal or WAL (which SQLite would apply to the copy).
+    fn restore_by_rename(&self, path: &Path, src: &Connection) -> Result<bool, std::io::Error> {
+        for suffix in ["-journal", "-wal"] {
+            if self.access(&with_suffix(path, suffix))? {
+                return Ok(false);
+            }
+        }
+        let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+        let mut db = unsafe { self.open_raw(path, flags)? };
+        if unsafe { gc::lock_exclusive(&mut db)? }.is_none() {
+            return Ok(false);
+        }
+        let wal = unsafe { in_wal_mode(&mut db)? };
+
+        let temp = with_suffix(path, "-restore");
+        let result = (|| {
+            let copy = self.connect(&temp, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE)?;
+            backup(src, &copy)?;
+            // a database in WAL mode stays in WAL mode
+            if wal {
+                copy.execute(c"PRAGMA journal_mode = WAL")?;
+            }
+            drop(copy);
+            // another connection opened the database in the meantime, and would keep the old one
+            if self.open_files() > 1 {
+                return Ok(false);
+            }
+            unsafe { (self.rename)(self.vfs.as_ptr(), &temp, path)? };
+            Ok(true)
+        })();
+        if !matches!(result, Ok(true)) {
+            let _ = self.delete(&temp);
+        }
+        // closing the database releases the lock
+        drop(db);
+        result
+    }
+
+    /// Open a connection to the database at `path` through this VFS.
+    fn connect(&self, path: &Path, flags: c_int) -> Result<Connection, std::io::Error> {
+        // elsewhere than on unix, SQLite expects UTF-8 paths, so any other path would be replaced
+        #[cfg(not(unix))]
+        if path.to_str().is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "path is not valid UTF-8",
+            ));
+        }
+        let path = crate::path_to_cstring(path)?;
+        let vfs = unsafe { CStr::from_ptr(self.vfs.as_ref().zName) };
+        let conn = Connection::open(&path, flags, Some(vfs))?;
+        unsafe { ffi::sqlite3_busy_timeout(conn.0, BUSY_TIMEOUT_MS) };
+        Ok(conn)
+    }
+}
+
+/// `path` with `suffix` appended.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+/// Whether the header of the database `file` marks it as being in WAL mode.
+unsafe fn in_wal_mode(file: &mut RawFile) -> Result<bool, std::io::Error> {
+    let file = file.as_ptr();
+    let read = (*(*file).pMethods)
+        .xRead
+        .ok_or(VfsError::Code(ffi::SQLITE_MISUSE))?;
+    let mut header = [0u8; 20];
+    match read(file, header.as_mut_ptr().cast(), header.len() as c_int, 0) {
+        // the rest of a short read is filled with zeros
+        ffi::SQLITE_OK | ffi::SQLITE_IOERR_SHORT_READ => Ok(header[18] == 2),
+        code => Err(VfsError::Code(code).into()),
+    }
+}
+
+/// Move the object at `from` to `to` with [Vfs::rename]. Set as the `rename` of the
+/// [VfsHandle], which does not know the type of the VFS.
+pub(crate) unsafe fn rename<V: Vfs>(
+    ptr: *mut ffi::sqlite3_vfs,
+    from: &Path,
+    to: &Path,
+) -> Result<(), std::io::Error> {
+    let state = &*((*ptr).pAppData as *const State<V>);
+    state.vfs.rename(from, to)
+}
+
+/// Copy the main database of `src` to `dest`.
+fn backup(src: &Connection, dest: &Connection) -> Result<(), std::io::Error> {
+    unsafe {
+        let main = c"main".as_ptr();
+        let backup = ffi::sqlite3_backup_init(dest.0, main, src.0, main);
+        if backup.is_null() {
+            return Err(dest.error());
+        }
+        // copy all pages in one step, so that the database cannot change in between
+        let code = ffi::sqlite3_backup_step(backup, -1);
+        ffi::sqlite3_backup_finish(backup);
+        match code & 0xff {
+            ffi::SQLITE_DONE => Ok(()),
+            ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => Err(VfsError::Busy.into()),
+            // finishing the backup reports its error on the destination
+            _ => Err(dest.error()),
+        }
+    }
+}
+
+impl Snapshot {
+    /// The size of the copy in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the copy is empty (which it is for a database without any tables).
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl AsRef<[u8]> for Snapshot {
+    fn as_ref(&self) -> &[u8] {
+        match self.data {
+            Some(data) => unsafe { std::slice::from_raw_parts(data.as_ptr(), self.len) },
+            None => &[],
+        }
+    }
+}
+
+impl Read for Snapshot {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let mut rest = &self.as_ref()[self.pos..];
+        let n = rest.read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("len", &self.len)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Some(data) = self.data {
+            unsafe { ffi::sqlite3_free(data.as_ptr().cast()) };
+        }
+    }
+}
+
+// the snapshot exclusively owns its buffer
+unsafe impl Send for Snapshot {}
+unsafe impl Sync for Snapshot {}
+
+/// A connection that is closed when dropped.
+pub(crate) struct Connection(pub(crate) *mut ffi::sqlite3);
+
+impl Connection {
+    pub(crate) fn open(
+        path: &CStr,
+        flags: c_int,
+        vfs: Option<&CStr>,
+    ) -> Result<Self, std::io::Error> {
+        let mut db = null_mut();
+        let code = unsafe {
+            ffi::sqlite3_open_v2(
+                path.as_ptr(),
+                &mut db,
+                flags,
+                vfs.map(|vfs| vfs.as_ptr()).unwrap_or(null_mut()),
+            )
+        };
+        // a connection is returned even if opening failed (unless out of memory)
+        let conn = Connection(db);
+        if code != ffi::SQLITE_OK {
+            return Err(conn.error());
+        }
+        unsafe { ffi::sqlite3_extended_result_codes(db, 1) };
+        Ok(conn)
+    }
+
+    /// Run the `sql` statements, discarding any rows.
+    pub(crate) fn execute(&self, sql: &CStr) -> Result<(), std::io::Error> {
+        let code = unsafe { ffi::sqlite3_exec(self.0, sql.as_ptr(), None, null_mut(), null_mut()) };
+        match code {
+            ffi::SQLITE_OK => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    /// The last error of the connection.
+    pub(crate) fn error(&self) -> std::io::Error {
+        if self.0.is_null() {
+            return VfsError::Code(ffi::SQLITE_NOMEM).into();
+        }
+        let (code, message) = unsafe {
+            let message = CStr::from_ptr(ffi::sqlite3_errmsg(self.0));
+            (
+                ffi::sqlite3_extended_errcode(self.0),
+                message.to_string_lossy().into_owned(),
+            )
+        };
+        std::io::Error::other(format!("{}: {}", message, VfsError::Code(code)))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_close(self.0) };
+    }
+}
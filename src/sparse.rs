@@ -0,0 +1,176 @@
+//! Keep databases in sparse files on disk, with a [SparseFile] (only on unix, with the `sparse`
+//! feature).
+//!
+//! A [SparseFile] is a [File] for a [std::fs::File] that gives the storage of ranges SQLite fills
+//! with zeros back to the file system, by punching holes into the file (`fallocate` with
+//! `FALLOC_FL_PUNCH_HOLE`, only on Linux) instead of writing the zeros. SQLite zeroes the pages it
+//! frees with `PRAGMA secure_delete = ON`, and it zeroes the headers of journals it is done with.
+//! Files are extended with zeros, and shrunk, by changing their length, which leaves a hole at the
+//! end or frees the storage beyond it (e.g. when auto-vacuum or `VACUUM` truncates the database).
+//! On file systems that cannot punch holes, the zeros are written.
+//!
+//! [VfsMetadata::allocated] (and the statistics of a [StatsVfs](crate::stats::StatsVfs)) report
+//! how much storage the files still take up:
+//!
+//! ```no_run
+//! use std::fs;
+//! use std::path::Path;
+//!
+//! use sqlite_vfs::sparse::SparseFile;
+//! use sqlite_vfs::{OpenAccess, OpenOptions, Vfs};
+//!
+//! struct SparseFsVfs;
+//!
+//! impl Vfs for SparseFsVfs {
+//!     type File = SparseFile;
+//!
+//!     fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+//!         let file = fs::OpenOptions::new()
+//!             .read(true)
+//!             .write(opts.access != OpenAccess::Read)
+//!             .create(opts.access == OpenAccess::Create)
+//!             .open(path)?;
+//!         SparseFile::new(file)
+//!     }
+//!
+//!     fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+//!         fs::remove_file(path)
+//!     }
+//!
+//!     fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+//!         Ok(path.is_file())
+//!     }
+//! }
+//! ```
+
+use std::os::unix::fs::{FileExt, MetadataExt};
+
+use crate::{allocated, File, SyncOptions, VfsMetadata};
+
+/// A [File] for a [std::fs::File] that punches holes into the ranges SQLite fills with zeros.
+pub struct SparseFile {
+    file: std::fs::File,
+    /// The block size of the file system, the unit in which holes are punched.
+    block_size: u64,
+    /// Whether the file system can punch holes (until it failed to).
+    punch: bool,
+}
+
+impl SparseFile {
+    /// Access `file`, punching holes into it where zeros are written.
+    pub fn new(file: std::fs::File) -> Result<Self, std::io::Error> {
+        let block_size = file.metadata()?.blksize().max(512);
+        Ok(SparseFile {
+            file,
+            block_size,
+            punch: cfg!(target_os = "linux"),
+        })
+    }
+
+    /// The file this [SparseFile] accesses.
+    pub fn get_ref(&self) -> &std::fs::File {
+        &self.file
+    }
+
+    /// Deallocate the whole blocks between `start` and `end` (which must not extend the file), and
+    /// return the range that was deallocated, which reads as zeros afterwards (`None` if no block
+    /// was deallocated).
+    fn punch_hole(&mut self, start: u64, end: u64) -> Result<Option<(u64, u64)>, std::io::Error> {
+        let start = start.next_multiple_of(self.block_size);
+        let end = end - end % self.block_size;
+        if !self.punch || start >= end {
+            return Ok(None);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            let code = unsafe {
+                libc::fallocate(
+                    self.file.as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    start as libc::off_t,
+                    (end - start) as libc::off_t,
+                )
+            };
+            if code == 0 {
+                return Ok(Some((start, end)));
+            }
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err);
+            }
+            log::debug!("the file system cannot punch holes, writing zeros instead");
+            self.punch = false;
+        }
+        Ok(None)
+    }
+}
+
+impl File for SparseFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.file.read_at(&mut buf[n..], offset + n as u64) {
+                Ok(0) => break,
+                Ok(read) => n += read,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(n)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        if buf.iter().any(|byte| *byte != 0) {
+            return self.file.write_all_at(buf, offset);
+        }
+
+        // zeros beyond the end of the file become a hole when the file is extended
+        let end = offset + buf.len() as u64;
+        let size = self.file.metadata()?.len();
+        let inside = end.min(size);
+        if offset < inside {
+            let zeros = |from: u64, to: u64| &buf[(from - offset) as usize..(to - offset) as usize];
+            match self.punch_hole(offset, inside)? {
+                // the partial blocks at both ends are written
+                Some((start, stop)) => {
+                    self.file.write_all_at(zeros(offset, start), offset)?;
+                    self.file.write_all_at(zeros(stop, inside), stop)?;
+                }
+                None => self.file.write_all_at(zeros(offset, inside), offset)?,
+            }
+        }
+        if end > size {
+            self.file.set_len(end)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        if options.data_only {
+            self.file.sync_data()
+        } else {
+            self.file.sync_all()
+        }
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        // frees the storage beyond `size`, or leaves a hole up to it
+        self.file.set_len(size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        let meta = self.file.metadata()?;
+        Ok(VfsMetadata {
+            modified: meta.modified().ok(),
+            created: meta.created().ok(),
+            generation: None,
+            allocated: allocated(&meta),
+        })
+    }
+}
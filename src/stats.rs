@@ -0,0 +1,509 @@
+//! Collect statistics about the I/O of a [Vfs].
+//!
+//! A [StatsVfs] counts the reads, writes, syncs and locks of all files of the [Vfs] it wraps, along
+//! with the bytes they transferred, the errors they failed with and a histogram of how long they
+//! took. The same statistics are also kept for each [OpenKind], e.g. to tell the syncs of the WAL
+//! from those of the database. The statistics are read through a [Stats] handle, which can be kept
+//! after the [StatsVfs] is registered, e.g. to export them to a monitoring system:
+//!
+//! ```
+//! use sqlite_vfs::mem::MemVfs;
+//! use sqlite_vfs::stats::StatsVfs;
+//! use sqlite_vfs::OpenKind;
+//!
+//! let vfs = StatsVfs::new(MemVfs::new());
+//! let stats = vfs.stats();
+//! let _vfs = sqlite_vfs::register("monitored", vfs).unwrap();
+//!
+//! // ... later
+//! let snapshot = stats.snapshot();
+//! println!(
+//!     "{} writes ({} bytes), p99 {:?}",
+//!     snapshot.writes.count,
+//!     snapshot.writes.bytes,
+//!     snapshot.writes.latency.quantile(0.99),
+//! );
+//! if let Some(wal) = snapshot.kinds.get(&OpenKind::Wal) {
+//!     println!("WAL syncs p99 {:?}", wal.syncs.latency.quantile(0.99));
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenKind, OpenOptions, RecoveryPhase, SharedMemory,
+    SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// The number of buckets of a [Histogram]. The last one holds everything that took longer than
+/// 2^(BUCKETS - 2) microseconds (about 17 seconds).
+const BUCKETS: usize = 26;
+
+/// A [Vfs] that collects statistics about the I/O of the [Vfs] it wraps.
+pub struct StatsVfs<V> {
+    vfs: V,
+    stats: Stats,
+}
+
+/// A file opened by [StatsVfs].
+pub struct StatsFile<F> {
+    file: F,
+    kind: OpenKind,
+    stats: Stats,
+    /// The bytes allocated to the file when they were last counted in the statistics.
+    allocated: u64,
+}
+
+/// Reads the statistics of a [StatsVfs].
+#[derive(Clone, Default)]
+pub struct Stats {
+    snapshot: Arc<Mutex<StatsSnapshot>>,
+}
+
+/// The statistics of a [StatsVfs] at one point in time.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct StatsSnapshot {
+    /// The number of files opened.
+    pub opens: u64,
+    /// Reads of all files.
+    pub reads: OpStats,
+    /// Writes to all files.
+    pub writes: OpStats,
+    /// Syncs of all files.
+    pub syncs: OpStats,
+    /// Lock requests of all files (including those that found the file locked by another
+    /// connection).
+    pub locks: OpStats,
+    /// The bytes of storage allocated to the files that are currently open (see
+    /// [VfsMetadata::allocated]), as of when each was opened, last synced or last truncated. Files
+    /// whose backend does not report it are not counted. This is kept by [Stats::reset].
+    pub allocated: u64,
+    /// The same statistics for the files of each kind (only for the kinds opened so far).
+    pub kinds: HashMap<OpenKind, KindStats>,
+}
+
+/// The statistics of the files of one [OpenKind].
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct KindStats {
+    /// The number of files opened.
+    pub opens: u64,
+    /// Reads of the files.
+    pub reads: OpStats,
+    /// Writes to the files.
+    pub writes: OpStats,
+    /// Syncs of the files.
+    pub syncs: OpStats,
+    /// Lock requests of the files.
+    pub locks: OpStats,
+    /// The bytes of storage allocated to the files that are currently open.
+    pub allocated: u64,
+}
+
+/// Statistics about one kind of operation.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct OpStats {
+    /// The number of operations, including failed ones.
+    pub count: u64,
+    /// The number of operations that failed.
+    pub errors: u64,
+    /// The number of bytes read or written (always zero for syncs and locks).
+    pub bytes: u64,
+    /// The time spent in all operations.
+    pub total: Duration,
+    /// How long each operation took.
+    pub latency: Histogram,
+}
+
+/// A histogram of durations, in buckets of powers of two microseconds: the first bucket counts
+/// durations of up to 1µs, the second of up to 2µs, the third of up to 4µs, and so on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+}
+
+/// The operations counted in an [OpStats].
+#[derive(Clone, Copy)]
+enum Op {
+    Read,
+    Write,
+    Sync,
+    Lock,
+}
+
+impl<V> StatsVfs<V> {
+    /// Wrap `vfs` and collect statistics about its I/O.
+    pub fn new(vfs: V) -> Self {
+        StatsVfs {
+            vfs,
+            stats: Stats::default(),
+        }
+    }
+
+    /// The [Stats] of this [StatsVfs] (also after it has been registered).
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+}
+
+impl Stats {
+    /// The statistics collected so far.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.lock().clone()
+    }
+
+    /// Start over with empty statistics (except for the storage allocated to open files). Returns
+    /// the statistics collected until now.
+    pub fn reset(&self) -> StatsSnapshot {
+        let mut snapshot = self.lock();
+        let previous = std::mem::take(&mut *snapshot);
+        snapshot.allocated = previous.allocated;
+        for (kind, stats) in &previous.kinds {
+            snapshot.kinds.entry(*kind).or_default().allocated = stats.allocated;
+        }
+        previous
+    }
+
+    fn lock(&self) -> MutexGuard<'_, StatsSnapshot> {
+        self.snapshot.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Run `f` and count it as `op` on a file of `kind`, with the bytes returned by `bytes`.
+    fn record<T>(
+        &self,
+        kind: OpenKind,
+        op: Op,
+        f: impl FnOnce() -> Result<T, std::io::Error>,
+        bytes: impl FnOnce(&T) -> u64,
+    ) -> Result<T, std::io::Error> {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+
+        let bytes = result.as_ref().ok().map(bytes);
+        let mut snapshot = self.lock();
+        snapshot.op(op).add(elapsed, bytes);
+        snapshot
+            .kinds
+            .entry(kind)
+            .or_default()
+            .op(op)
+            .add(elapsed, bytes);
+        result
+    }
+}
+
+impl<F: File> StatsFile<F> {
+    /// Count the storage the file now takes up instead of what it took up before.
+    fn update_allocated(&mut self) {
+        let allocated = match self.file.metadata() {
+            Ok(VfsMetadata {
+                allocated: Some(allocated),
+                ..
+            }) => allocated,
+            _ => return,
+        };
+        let previous = std::mem::replace(&mut self.allocated, allocated);
+        let mut snapshot = self.stats.lock();
+        snapshot.allocated = snapshot.allocated.saturating_sub(previous) + allocated;
+        let kind = snapshot.kinds.entry(self.kind).or_default();
+        kind.allocated = kind.allocated.saturating_sub(previous) + allocated;
+    }
+}
+
+impl<F> Drop for StatsFile<F> {
+    fn drop(&mut self) {
+        let mut snapshot = self.stats.lock();
+        snapshot.allocated = snapshot.allocated.saturating_sub(self.allocated);
+        let kind = snapshot.kinds.entry(self.kind).or_default();
+        kind.allocated = kind.allocated.saturating_sub(self.allocated);
+    }
+}
+
+impl StatsSnapshot {
+    fn op(&mut self, op: Op) -> &mut OpStats {
+        match op {
+            Op::Read => &mut self.reads,
+            Op::Write => &mut self.writes,
+            Op::Sync => &mut self.syncs,
+            Op::Lock => &mut self.locks,
+        }
+    }
+}
+
+impl KindStats {
+    fn op(&mut self, op: Op) -> &mut OpStats {
+        match op {
+            Op::Read => &mut self.reads,
+            Op::Write => &mut self.writes,
+            Op::Sync => &mut self.syncs,
+            Op::Lock => &mut self.locks,
+        }
+    }
+}
+
+impl OpStats {
+    /// Count an operation that took `elapsed` and transferred `bytes`, or failed if `None`.
+    fn add(&mut self, elapsed: Duration, bytes: Option<u64>) {
+        self.count += 1;
+        self.total += elapsed;
+        self.latency.record(elapsed);
+        match bytes {
+            Some(bytes) => self.bytes += bytes,
+            None => self.errors += 1,
+        }
+    }
+
+    /// The average duration of an operation.
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.total.as_nanos() / u128::from(count)) as u64),
+        }
+    }
+}
+
+impl Histogram {
+    /// Count `duration`.
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros();
+        let bucket = match micros {
+            0 | 1 => 0,
+            micros => (u128::BITS - (micros - 1).leading_zeros()) as usize,
+        };
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+    }
+
+    /// The number of durations counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The upper bound of each bucket, and the number of durations counted in it. The upper bound
+    /// of the last bucket is [Duration::MAX].
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, count)| {
+            let bound = match i {
+                i if i == BUCKETS - 1 => Duration::MAX,
+                i => Duration::from_micros(1 << i),
+            };
+            (bound, *count)
+        })
+    }
+
+    /// An upper bound for the duration that a fraction of `q` (between 0 and 1) of all durations
+    /// did not exceed, e.g. `quantile(0.99)` for the 99th percentile. Zero if nothing was counted.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = (q.clamp(0.0, 1.0) * self.count() as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= rank {
+                return bound;
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+impl<V: Vfs> Vfs for StatsVfs<V> {
+    type File = StatsFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let kind = opts.kind;
+        let file = self.vfs.open(path, opts)?;
+        let mut snapshot = self.stats.lock();
+        snapshot.opens += 1;
+        snapshot.kinds.entry(kind).or_default().opens += 1;
+        drop(snapshot);
+        let mut file = StatsFile {
+            file,
+            kind,
+            stats: self.stats.clone(),
+            allocated: 0,
+        };
+        file.update_allocated();
+        Ok(file)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.vfs.rename(from, to)
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.vfs.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl<F: File> File for StatsFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.stats.record(
+            self.kind,
+            Op::Read,
+            || self.file.read_at(buf, offset),
+            |n| *n as u64,
+        )
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.stats.record(
+            self.kind,
+            Op::Write,
+            || self.file.write_all_at(buf, offset),
+            |()| buf.len() as u64,
+        )
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.stats
+            .record(self.kind, Op::Sync, || self.file.sync(options), |()| 0)?;
+        self.update_allocated();
+        Ok(())
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)?;
+        self.update_allocated();
+        Ok(())
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.stats
+            .record(self.kind, Op::Lock, || self.file.lock(lock), |_| 0)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.file.unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.file.shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        // reads from mapped memory are not counted
+        self.file.memory_mapped()
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        self.file.batch_atomic_write()
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.file.file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        self.file.device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
@@ -0,0 +1,578 @@
+//! Limit the bandwidth and the number of operations of the files of a [Vfs].
+//!
+//! A [ThrottleVfs] delays reads and writes so that they stay below the limits of its
+//! [ThrottleOptions], e.g. to keep a background job that uses SQLite from starving the foreground
+//! traffic on a shared disk. Each limit is a token bucket: after a pause, a burst of up to
+//! [ThrottleOptions::burst] worth of operations runs at full speed, and everything after it at the
+//! configured rate.
+//!
+//! The limits apply to all files of the [ThrottleVfs] together, or with
+//! [ThrottleOptions::per_file], to each opened file on its own. Register a [ThrottleVfs] next to an
+//! unthrottled VFS to only throttle the connections that open databases through it:
+//!
+//! ```
+//! use sqlite_vfs::mem::MemVfs;
+//! use sqlite_vfs::throttle::{ThrottleOptions, ThrottleVfs};
+//!
+//! let options = ThrottleOptions {
+//!     write_bandwidth: Some(4 * 1024 * 1024),
+//!     iops: Some(500),
+//!     ..Default::default()
+//! };
+//! let _vfs = sqlite_vfs::register("background", ThrottleVfs::new(MemVfs::new(), options));
+//! ```
+//!
+//! The databases of a [ThrottleVfs] (e.g. those of different tenants) can be given a [Priority]
+//! with [ThrottleOptions::priorities], so that a bulk job on one database does not add latency to
+//! the interactive queries on another. The reads, writes and syncs of a database wait while those
+//! of databases with a higher priority are in flight (for at most [ThrottleOptions::max_wait] per
+//! operation, so they are not starved), and only use the capacity of the limits that is left over
+//! by them:
+//!
+//! ```
+//! use std::path::PathBuf;
+//!
+//! use sqlite_vfs::mem::MemVfs;
+//! use sqlite_vfs::throttle::{Priority, ThrottleOptions, ThrottleVfs};
+//!
+//! let options = ThrottleOptions {
+//!     write_bandwidth: Some(64 * 1024 * 1024),
+//!     priorities: [
+//!         (PathBuf::from("/tenants/reports.db"), Priority::Background),
+//!         (PathBuf::from("/tenants/archive.db"), Priority::Maintenance),
+//!     ]
+//!     .into(),
+//!     ..Default::default()
+//! };
+//! let _vfs = sqlite_vfs::register("tenants", ThrottleVfs::new(MemVfs::new(), options));
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenKind, OpenOptions, RecoveryPhase, SharedMemory,
+    SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// Limits of a [ThrottleVfs]. Limits that are `None` are not enforced.
+#[derive(Debug, Clone)]
+pub struct ThrottleOptions {
+    /// The number of bytes that can be read per second.
+    pub read_bandwidth: Option<u64>,
+
+    /// The number of bytes that can be written per second.
+    pub write_bandwidth: Option<u64>,
+
+    /// The number of reads and writes per second.
+    pub iops: Option<u64>,
+
+    /// How long the limits can be exceeded after a pause (default: one second). With a burst of
+    /// zero, every operation waits for its share of the rate.
+    pub burst: Duration,
+
+    /// Apply the limits to each opened file on its own, instead of to all files together (default:
+    /// `false`).
+    pub per_file: bool,
+
+    /// The priority of the databases at these paths (and of their journals and WAL files). All
+    /// other files have [Priority::Foreground] (default: none).
+    pub priorities: HashMap<PathBuf, Priority>,
+
+    /// How long an operation waits at most for the operations of higher priorities to finish
+    /// (default: 100 ms).
+    pub max_wait: Duration,
+}
+
+/// The priority class of the I/O of a database of a [ThrottleVfs]. Under contention, the
+/// operations of higher classes go first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Priority {
+    /// Interactive queries, which never wait for other operations.
+    #[default]
+    Foreground,
+    /// Jobs that should not slow down interactive queries, e.g. reports or exports.
+    Background,
+    /// Bulk work that yields to everything else, e.g. `VACUUM`, backups or migrations.
+    Maintenance,
+}
+
+impl Default for ThrottleOptions {
+    fn default() -> Self {
+        ThrottleOptions {
+            read_bandwidth: None,
+            write_bandwidth: None,
+            iops: None,
+            burst: Duration::from_secs(1),
+            per_file: false,
+            priorities: HashMap::new(),
+            max_wait: Duration::from_millis(100),
+        }
+    }
+}
+
+/// A [Vfs] that throttles the reads and writes of the files of the [Vfs] it wraps.
+pub struct ThrottleVfs<V> {
+    vfs: V,
+    options: ThrottleOptions,
+    limits: Arc<Mutex<Limits>>,
+    /// Orders the operations of all files by their priority (`None` without any priorities).
+    scheduler: Option<Arc<Scheduler>>,
+}
+
+/// A file opened by [ThrottleVfs].
+pub struct ThrottleFile<F> {
+    file: F,
+    limits: Arc<Mutex<Limits>>,
+    priority: Priority,
+    scheduler: Option<Arc<Scheduler>>,
+    max_wait: Duration,
+}
+
+/// An operation counts as in flight for a moment after it finished, as the next operation of the
+/// same statement usually follows right after it.
+const IDLE: Duration = Duration::from_millis(2);
+
+/// Keeps track of the operations in flight, by priority.
+#[derive(Default)]
+struct Scheduler {
+    activity: Mutex<Activity>,
+    /// Notified whenever an operation finishes.
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct Activity {
+    /// The number of operations in flight.
+    active: [usize; 3],
+    /// When the last operation finished.
+    finished: [Option<Instant>; 3],
+}
+
+/// Counts an operation as in flight until it is dropped.
+struct Active {
+    scheduler: Arc<Scheduler>,
+    priority: Priority,
+    /// Until when the operation yields to those of higher priorities.
+    deadline: Instant,
+}
+
+/// The token buckets of the limits that are enforced.
+struct Limits {
+    read: Option<Bucket>,
+    write: Option<Bucket>,
+    ops: Option<Bucket>,
+}
+
+struct Bucket {
+    /// Tokens added per second.
+    rate: f64,
+    /// The most tokens the bucket can hold.
+    capacity: f64,
+    /// The available tokens, negative if operations have to wait for them.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<V> ThrottleVfs<V> {
+    /// Wrap `vfs` and throttle its files to the limits of `options`.
+    pub fn new(vfs: V, options: ThrottleOptions) -> Self {
+        ThrottleVfs {
+            vfs,
+            limits: Arc::new(Mutex::new(Limits::new(&options))),
+            scheduler: (!options.priorities.is_empty()).then(Default::default),
+            options,
+        }
+    }
+}
+
+impl<V> ThrottleVfs<V> {
+    /// The priority of the file at `path`, which is the one of its database.
+    fn priority(&self, path: &Path) -> Priority {
+        let name = path.as_os_str().to_string_lossy();
+        let database = ["-journal", "-wal"]
+            .iter()
+            .find_map(|suffix| name.strip_suffix(suffix))
+            .map(PathBuf::from);
+        let priorities = &self.options.priorities;
+        database
+            .and_then(|database| priorities.get(&database))
+            .or_else(|| priorities.get(path))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl<V: Vfs> Vfs for ThrottleVfs<V> {
+    type File = ThrottleFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let file = self.vfs.open(path, opts)?;
+        let limits = match self.options.per_file {
+            true => Arc::new(Mutex::new(Limits::new(&self.options))),
+            false => Arc::clone(&self.limits),
+        };
+        Ok(ThrottleFile {
+            file,
+            limits,
+            priority: self.priority(path),
+            scheduler: self.scheduler.clone(),
+            max_wait: self.options.max_wait,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.vfs.rename(from, to)
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.vfs.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl Limits {
+    fn new(options: &ThrottleOptions) -> Self {
+        let bucket = |rate: Option<u64>| rate.map(|rate| Bucket::new(rate, options.burst));
+        Limits {
+            read: bucket(options.read_bandwidth),
+            write: bucket(options.write_bandwidth),
+            ops: bucket(options.iops),
+        }
+    }
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: Duration) -> Self {
+        let rate = rate.max(1) as f64;
+        let capacity = rate * burst.as_secs_f64();
+        Bucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Take `n` tokens, and return how long to wait until they would have been available.
+    fn take(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        // later operations wait for the tokens taken by earlier ones
+        self.tokens -= n as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// How long to wait until `n` tokens (or a full bucket, for more than it holds) are available,
+    /// without taking them, so that waiting does not delay other operations.
+    fn available(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        let needed = (n as f64).min(self.capacity);
+        match self.tokens < needed {
+            true => Duration::from_secs_f64((needed - self.tokens) / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+impl Scheduler {
+    fn activity(&self) -> MutexGuard<'_, Activity> {
+        self.activity.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Count an operation of `priority` as in flight until the returned [Active] is dropped.
+    fn start(self: &Arc<Self>, priority: Priority, max_wait: Duration) -> Active {
+        self.activity().active[priority as usize] += 1;
+        Active {
+            scheduler: Arc::clone(self),
+            priority,
+            deadline: Instant::now() + max_wait,
+        }
+    }
+}
+
+impl Active {
+    /// Wait until no operation of a higher priority is in flight, or until the deadline.
+    fn yield_to_higher(&self) {
+        let mut activity = self.scheduler.activity();
+        loop {
+            let now = Instant::now();
+            if now >= self.deadline {
+                return;
+            }
+            let mut timeout = None;
+            for higher in 0..self.priority as usize {
+                if activity.active[higher] > 0 {
+                    timeout = Some(self.deadline - now);
+                    break;
+                }
+                if let Some(idle) = activity.finished[higher].map(|finished| finished + IDLE) {
+                    if idle > now {
+                        timeout = timeout.max(Some(idle - now));
+                    }
+                }
+            }
+            let Some(timeout) = timeout else {
+                return;
+            };
+            activity = self
+                .scheduler
+                .finished
+                .wait_timeout(activity, timeout.min(self.deadline - now))
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        let mut activity = self.scheduler.activity();
+        activity.active[self.priority as usize] -= 1;
+        activity.finished[self.priority as usize] = Some(Instant::now());
+        drop(activity);
+        self.scheduler.finished.notify_all();
+    }
+}
+
+impl<F> ThrottleFile<F> {
+    /// Wait for the operations of higher priorities, and count the operation as in flight until
+    /// the returned [Active] is dropped.
+    fn schedule(&self) -> Option<Active> {
+        let active = self.scheduler.as_ref()?.start(self.priority, self.max_wait);
+        active.yield_to_higher();
+        Some(active)
+    }
+
+    /// Wait until an operation on `len` bytes is within the limits.
+    fn throttle(&self, len: usize, write: bool, active: Option<&Active>) {
+        if let Some(active) = active.filter(|_| self.priority != Priority::Foreground) {
+            return self.throttle_behind(len, write, active);
+        }
+        let wait = {
+            let mut limits = self.limits.lock().unwrap_or_else(|err| err.into_inner());
+            let now = Instant::now();
+            let bandwidth = match write {
+                true => &mut limits.write,
+                false => &mut limits.read,
+            };
+            let bandwidth = bandwidth
+                .as_mut()
+                .map(|bucket| bucket.take(len as u64, now));
+            let ops = limits.ops.as_mut().map(|bucket| bucket.take(1, now));
+            bandwidth.max(ops).unwrap_or_default()
+        };
+        if !wait.is_zero() {
+            log::trace!("throttle for {:?}", wait);
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Wait until the tokens for an operation on `len` bytes are left over by the operations of
+    /// higher priorities, which take them first (and may take them in advance).
+    fn throttle_behind(&self, len: usize, write: bool, active: &Active) {
+        let wait = loop {
+            active.yield_to_higher();
+            let wait = {
+                let mut limits = self.limits.lock().unwrap_or_else(|err| err.into_inner());
+                let now = Instant::now();
+                let Limits {
+                    read,
+                    write: written,
+                    ops,
+                } = &mut *limits;
+                let bandwidth = match write {
+                    true => written,
+                    false => read,
+                };
+                let wait = bandwidth
+                    .as_mut()
+                    .map(|bucket| bucket.available(len as u64, now))
+                    .max(ops.as_mut().map(|bucket| bucket.available(1, now)))
+                    .unwrap_or_default();
+                if wait.is_zero() {
+                    // more tokens than the bucket holds are still waited for after taking them
+                    let bandwidth = bandwidth
+                        .as_mut()
+                        .map(|bucket| bucket.take(len as u64, now));
+                    let ops = ops.as_mut().map(|bucket| bucket.take(1, now));
+                    break bandwidth.max(ops).unwrap_or_default();
+                }
+                wait
+            };
+            log::trace!("throttle {:?} for {:?}", self.priority, wait);
+            std::thread::sleep(wait);
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+impl<F: File> File for ThrottleFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let active = self.schedule();
+        self.throttle(buf.len(), false, active.as_ref());
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let active = self.schedule();
+        self.throttle(buf.len(), true, active.as_ref());
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        let _active = self.schedule();
+        self.file.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.file.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.file.unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.file.shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        // reads from mapped memory could not be throttled
+        None
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        self.file.batch_atomic_write()
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.file.file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        self.file.device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
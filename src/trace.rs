@@ -0,0 +1,841 @@
+//! Emit a [tracing] event for each operation on a [Vfs] and its files (only with the `tracing`
+//! feature).
+//!
+//! A [TraceVfs] wraps any [Vfs] and records, for every operation, an event with the fields
+//!
+//! - `op`: the name of the operation (e.g. `"read"`, `"write"`, `"sync"` or `"lock"`),
+//! - `path`: the file it applies to,
+//! - `offset` and `len`: the accessed range of reads and writes (and the new size of truncates),
+//! - `duration_us`: how long the wrapped [Vfs] took, in microseconds, and
+//! - `result`: what it returned, or `error`: the error it failed with.
+//!
+//! Successful operations are recorded at the `TRACE` level, failed ones at the `WARN` level, all
+//! with the target `sqlite_vfs::trace`, so they can be filtered separately from the rest of the
+//! application:
+//!
+//! ```
+//! use sqlite_vfs::mem::MemVfs;
+//! use sqlite_vfs::trace::TraceVfs;
+//!
+//! let _vfs = sqlite_vfs::register("traced", TraceVfs::new(MemVfs::new())).unwrap();
+//! // e.g. with `RUST_LOG=sqlite_vfs::trace=trace` and `tracing_subscriber::fmt::init()`
+//! ```
+//!
+//! Each write transaction on a database is covered by an `INFO` span named `transaction` (with
+//! the `path` of the database), from its first write (or its reserved lock) until SQLite reports
+//! the commit ([File::post_commit]), when the span's `outcome` is set to `"commit"`, or until it
+//! releases the lock without committing (`"rollback"`). Checkpoints are covered by a `checkpoint`
+//! span in the same way, using [CheckpointCoordinator]. The events of all operations on the files
+//! of the database (including its journal and WAL) within these boundaries are children of the
+//! span, and the span itself is a child of the span that was current when it began, i.e. of the
+//! application code that ran the transaction. Exported with e.g. `tracing-opentelemetry`, the
+//! storage activity of a transaction thus appears in the trace of the request that caused it.
+//!
+//! As the operations themselves are usually filtered out at that point, [TraceVfs::with_slow_ops]
+//! records operations taking at least a given time at the `INFO` level instead:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use sqlite_vfs::mem::MemVfs;
+//! use sqlite_vfs::trace::TraceVfs;
+//!
+//! let vfs = TraceVfs::with_slow_ops(MemVfs::new(), Duration::from_millis(10));
+//! let _vfs = sqlite_vfs::register("traced-slow", vfs).unwrap();
+//! ```
+//!
+//! To analyze the operations with SQL instead, a [SqliteSink] writes them into a table of a
+//! separate database (see [TraceOptions::sink]), whether or not a subscriber records the events:
+//!
+//! ```no_run
+//! use sqlite_vfs::mem::MemVfs;
+//! use sqlite_vfs::trace::{SqliteSink, TraceOptions, TraceVfs};
+//!
+//! let sink = SqliteSink::open("trace.db", None).unwrap();
+//! let options = TraceOptions {
+//!     sink: Some(sink),
+//!     ..Default::default()
+//! };
+//! let vfs = TraceVfs::with_options(MemVfs::new(), options);
+//! let _vfs = sqlite_vfs::register("traced-sql", vfs).unwrap();
+//! // SELECT op, count(*), sum(duration_us) FROM operations GROUP BY op
+//! ```
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt::Debug;
+use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libsqlite3_sys as ffi;
+use tracing::field::{display, Empty};
+use tracing::Span;
+
+use crate::snapshot::Connection;
+use crate::{
+    path_to_cstring, BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File,
+    FileControl, HealthReport, LockKind, MemoryMapped, OpenKind, OpenOptions, RecoveryPhase,
+    SharedMemory, SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// A [Vfs] that traces the operations on the [Vfs] it wraps.
+pub struct TraceVfs<V> {
+    vfs: V,
+    options: Arc<TraceOptions>,
+    spans: Arc<Spans>,
+}
+
+/// The options of a [TraceVfs].
+#[derive(Debug, Clone, Default)]
+pub struct TraceOptions {
+    /// Record operations that take at least this long at the `INFO` level instead of `TRACE`.
+    pub slow_ops: Option<Duration>,
+
+    /// Write each operation into a table of a separate database as well.
+    pub sink: Option<SqliteSink>,
+}
+
+/// A file opened by [TraceVfs].
+pub struct TraceFile<F> {
+    file: F,
+    path: String,
+    /// The database the file belongs to, if it is the main database, its journal or its WAL.
+    database: Option<PathBuf>,
+    main: bool,
+    options: Arc<TraceOptions>,
+    spans: Arc<Spans>,
+}
+
+/// The spans of the transactions and checkpoints currently running, by database.
+type Spans = Mutex<HashMap<PathBuf, Running>>;
+
+#[derive(Default)]
+struct Running {
+    transaction: Option<Span>,
+    checkpoint: Option<Span>,
+}
+
+impl<V> TraceVfs<V> {
+    /// Wrap `vfs` and trace all operations on it.
+    pub fn new(vfs: V) -> Self {
+        TraceVfs::with_options(vfs, TraceOptions::default())
+    }
+
+    /// Wrap `vfs` and trace all operations on it, recording those that take at least `threshold`
+    /// at the `INFO` level.
+    pub fn with_slow_ops(vfs: V, threshold: Duration) -> Self {
+        TraceVfs::with_options(
+            vfs,
+            TraceOptions {
+                slow_ops: Some(threshold),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Wrap `vfs` and trace all operations on it with the given `options`.
+    pub fn with_options(vfs: V, options: TraceOptions) -> Self {
+        TraceVfs {
+            vfs,
+            options: Arc::new(options),
+            spans: Default::default(),
+        }
+    }
+}
+
+/// Run `f`, and record an event for it.
+fn traced<T: Debug>(
+    op: &'static str,
+    path: &str,
+    range: Option<(u64, u64)>,
+    options: &TraceOptions,
+    f: impl FnOnce() -> Result<T, std::io::Error>,
+) -> Result<T, std::io::Error> {
+    let start = Instant::now();
+    let result = f();
+    record(op, path, range, start, options, result.as_ref());
+    result
+}
+
+/// Record an event for an operation that started at `start` and returned `result`, at the `INFO`
+/// level if it took at least [TraceOptions::slow_ops], and write it to the [TraceOptions::sink].
+fn record<T: Debug>(
+    op: &'static str,
+    path: &str,
+    range: Option<(u64, u64)>,
+    start: Instant,
+    options: &TraceOptions,
+    result: Result<&T, &std::io::Error>,
+) {
+    let elapsed = start.elapsed();
+    let slow = options.slow_ops;
+    let duration_us = elapsed.as_micros() as u64;
+    let (offset, len) = (range.map(|r| r.0), range.map(|r| r.1));
+    match result {
+        Ok(value) if slow.is_some_and(|slow| elapsed >= slow) => tracing::info!(
+            target: "sqlite_vfs::trace",
+            op,
+            path,
+            offset,
+            len,
+            duration_us,
+            result = ?value,
+        ),
+        Ok(value) => tracing::trace!(
+            target: "sqlite_vfs::trace",
+            op,
+            path,
+            offset,
+            len,
+            duration_us,
+            result = ?value,
+        ),
+        Err(err) => tracing::warn!(
+            target: "sqlite_vfs::trace",
+            op,
+            path,
+            offset,
+            len,
+            duration_us,
+            error = %err,
+        ),
+    }
+
+    if let Some(sink) = &options.sink {
+        let started = SystemTime::now() - elapsed;
+        sink.send(Operation {
+            time_us: started
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_micros() as i64),
+            op,
+            path: path.to_string(),
+            offset: range.map(|r| r.0 as i64),
+            len: range.map(|r| r.1 as i64),
+            duration_us: duration_us as i64,
+            result: result
+                .map(|value| format!("{:?}", value))
+                .map_err(|err| err.to_string()),
+        });
+    }
+}
+
+impl<V: Vfs> Vfs for TraceVfs<V> {
+    type File = TraceFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let (name, access, kind) = (path.display().to_string(), opts.access, opts.kind);
+        let start = Instant::now();
+        let result = self.vfs.open(path, opts);
+        let outcome = result.as_ref().map(|_| &access);
+        record("open", &name, None, start, &self.options, outcome);
+
+        let suffix = match kind {
+            OpenKind::MainDb => Some(""),
+            OpenKind::MainJournal => Some("-journal"),
+            OpenKind::Wal => Some("-wal"),
+            _ => None,
+        };
+        let database = suffix.and_then(|suffix| name.strip_suffix(suffix).map(PathBuf::from));
+        Ok(TraceFile {
+            file: result?,
+            path: name,
+            database,
+            main: kind == OpenKind::MainDb,
+            options: Arc::clone(&self.options),
+            spans: Arc::clone(&self.spans),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        traced(
+            "delete",
+            &path.display().to_string(),
+            None,
+            &self.options,
+            || self.vfs.delete(path),
+        )
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        traced(
+            "exists",
+            &path.display().to_string(),
+            None,
+            &self.options,
+            || self.vfs.exists(path),
+        )
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        traced(
+            "access",
+            &path.display().to_string(),
+            None,
+            &self.options,
+            || self.vfs.access(path, write),
+        )
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        let path = format!("{} -> {}", from.display(), to.display());
+        traced("rename", &path, None, &self.options, || {
+            self.vfs.rename(from, to)
+        })
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        // the checkpoints of the wrapped VFS are coordinated by [TraceVfs] as well, to trace them
+        Some(self)
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        let start = Instant::now();
+        let result = self.vfs.on_recovery(path, phase);
+        let name = path.display().to_string();
+        record(
+            "recovery",
+            &name,
+            None,
+            start,
+            &self.options,
+            result.as_ref().map(|()| &phase),
+        );
+        result
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl<V: Vfs> CheckpointCoordinator for TraceVfs<V> {
+    fn checkpoint_start(&self, path: &Path) {
+        let span = tracing::info_span!(
+            target: "sqlite_vfs::trace",
+            "checkpoint",
+            path = %path.display(),
+        );
+        let mut spans = self.spans.lock().unwrap();
+        spans.entry(path.to_path_buf()).or_default().checkpoint = Some(span);
+        drop(spans);
+
+        if let Some(coordinator) = self.vfs.checkpoint_coordinator() {
+            coordinator.checkpoint_start(path);
+        }
+    }
+
+    fn checkpoint_done(&self, path: &Path) {
+        if let Some(coordinator) = self.vfs.checkpoint_coordinator() {
+            coordinator.checkpoint_done(path);
+        }
+
+        let mut spans = self.spans.lock().unwrap();
+        if let Some(running) = spans.get_mut(path) {
+            running.checkpoint = None;
+            if running.transaction.is_none() {
+                spans.remove(path);
+            }
+        }
+    }
+}
+
+impl<F> TraceFile<F> {
+    /// The span the operations on the file currently belong to (if any), which is that of the
+    /// running checkpoint or transaction. Starts a transaction if `write` is set and neither is
+    /// running.
+    fn span(&self, write: bool) -> Span {
+        let database = match &self.database {
+            Some(database) => database,
+            None => return Span::none(),
+        };
+        let mut spans = self.spans.lock().unwrap();
+        if let Some(running) = spans.get(database) {
+            if let Some(span) = running.checkpoint.as_ref().or(running.transaction.as_ref()) {
+                return span.clone();
+            }
+        }
+        if !write {
+            return Span::none();
+        }
+
+        let span = tracing::info_span!(
+            target: "sqlite_vfs::trace",
+            "transaction",
+            path = %database.display(),
+            outcome = Empty,
+        );
+        spans.entry(database.clone()).or_default().transaction = Some(span.clone());
+        span
+    }
+
+    /// End the transaction running on the database (if any) with `outcome`.
+    fn end_transaction(&self, outcome: &'static str) {
+        let database = match &self.database {
+            Some(database) if self.main => database,
+            _ => return,
+        };
+        let mut spans = self.spans.lock().unwrap();
+        let running = match spans.get_mut(database) {
+            Some(running) => running,
+            None => return,
+        };
+        if let Some(span) = running.transaction.take() {
+            span.record("outcome", outcome);
+        }
+        if running.checkpoint.is_none() {
+            spans.remove(database);
+        }
+    }
+}
+
+impl<F: File> File for TraceFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let range = Some((offset, buf.len() as u64));
+        let _span = self.span(false).entered();
+        traced("read", &self.path, range, &self.options, || {
+            self.file.read_at(buf, offset)
+        })
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let range = Some((offset, buf.len() as u64));
+        let _span = self.span(true).entered();
+        traced("write", &self.path, range, &self.options, || {
+            self.file.write_all_at(buf, offset)
+        })
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        let _span = self.span(false).entered();
+        traced("sync", &self.path, None, &self.options, || {
+            self.file.sync(options)
+        })
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        let _span = self.span(false).entered();
+        traced("file_size", &self.path, None, &self.options, || {
+            self.file.file_size()
+        })
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        // checkpoints truncate the files after they are done, so this doesn't begin a transaction
+        let _span = self.span(false).entered();
+        traced(
+            "truncate",
+            &self.path,
+            Some((size, 0)),
+            &self.options,
+            || self.file.truncate(size),
+        )
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let span = self.span(false);
+        let _span = span.enter();
+        let start = Instant::now();
+        let result = self.file.lock(lock);
+        let outcome = result.as_ref().map(|acquired| (lock, *acquired));
+        let outcome = outcome.as_ref().map_err(|err| *err);
+        record("lock", &self.path, None, start, &self.options, outcome);
+        if self.main && lock == LockKind::Reserved && matches!(result, Ok(true)) {
+            // a reserved lock on the main database begins a write transaction
+            self.span(true);
+        }
+        result
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        let span = self.span(false);
+        let _span = span.enter();
+        let start = Instant::now();
+        let result = self.file.unlock(lock);
+        let outcome = result.as_ref().map(|()| &lock);
+        record("unlock", &self.path, None, start, &self.options, outcome);
+        // a transaction that is still running when its locks are released wasn't committed
+        self.end_transaction("rollback");
+        result
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        let _span = self.span(false).entered();
+        traced(
+            "check_reserved_lock",
+            &self.path,
+            None,
+            &self.options,
+            || self.file.check_reserved_lock(),
+        )
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.file.shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        // reads from mapped memory are not traced
+        self.file.memory_mapped()
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        self.file.batch_atomic_write()
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        let name = format!("{:?}", op);
+        let _span = self.span(false).entered();
+        let start = Instant::now();
+        let result = self.file.file_control(op);
+        let outcome = result.as_ref().map(|handled| (display(&name), *handled));
+        let outcome = outcome.as_ref().map_err(|err| *err);
+        record(
+            "file_control",
+            &self.path,
+            None,
+            start,
+            &self.options,
+            outcome,
+        );
+        result
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        self.file.device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        let _span = self.span(false).entered();
+        traced("pre_commit", &self.path, None, &self.options, || {
+            self.file.pre_commit()
+        })
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        let span = self.span(false);
+        let result = span.in_scope(|| {
+            traced("post_commit", &self.path, None, &self.options, || {
+                self.file.post_commit()
+            })
+        });
+        self.end_transaction("commit");
+        result
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
+
+/// How many operations [SqliteSink] writes in one transaction at most.
+const SINK_BATCH: usize = 1000;
+
+/// Writes the operations traced by a [TraceVfs] into the table `operations` of an SQLite
+/// database, to be analyzed with SQL. Set as [TraceOptions::sink].
+///
+/// The table has a row for each operation, with the columns `time_us` (when it started, in
+/// microseconds since the Unix epoch) and `op`, `path`, `offset`, `len`, `duration_us`, `result`
+/// and `error`, which hold the fields of its event. `op`, `path` and `duration_us` are indexed.
+///
+/// The operations are written by a thread of the sink in the background, in batches, so that
+/// they do not slow down the traced [Vfs]. The database is opened through another VFS than the one
+/// being traced (the default VFS, unless a name is given), as tracing the writes of the sink itself
+/// would never end. Clones of the sink write to the same database, which is closed when the last
+/// of them is dropped.
+#[derive(Clone)]
+pub struct SqliteSink {
+    inner: Arc<SinkInner>,
+}
+
+struct SinkInner {
+    path: PathBuf,
+    sender: Option<mpsc::Sender<Message>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+enum Message {
+    Operation(Operation),
+    Flush(mpsc::Sender<()>),
+}
+
+/// A row of the table `operations`.
+struct Operation {
+    time_us: i64,
+    op: &'static str,
+    path: String,
+    offset: Option<i64>,
+    len: Option<i64>,
+    duration_us: i64,
+    result: Result<String, String>,
+}
+
+impl SqliteSink {
+    /// Open (or create) the database at `path` through the VFS registered as `vfs` (or the default
+    /// VFS), and create the table `operations` in it, unless it already exists.
+    pub fn open(path: impl AsRef<Path>, vfs: Option<&str>) -> Result<Self, std::io::Error> {
+        let name = path_to_cstring(path.as_ref())?;
+        let vfs = vfs.map(CString::new).transpose()?;
+        let (sender, receiver) = mpsc::channel();
+        let (opened, open_result) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("sqlite-vfs-trace-sink".to_string())
+            .spawn(move || match SinkWriter::open(&name, vfs.as_deref()) {
+                Ok(writer) => {
+                    let _ = opened.send(Ok(()));
+                    writer.run(receiver);
+                }
+                Err(err) => {
+                    let _ = opened.send(Err(err));
+                }
+            })?;
+        let result = open_result
+            .recv()
+            .unwrap_or_else(|_| Err(std::io::Error::other("the trace sink thread panicked")));
+        let inner = SinkInner {
+            path: path.as_ref().to_path_buf(),
+            sender: Some(sender),
+            thread: Some(thread),
+        };
+        // on failure, dropping the sink joins the thread
+        result.map(|()| SqliteSink {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Wait until all operations traced so far are written to the database.
+    pub fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        self.send_message(Message::Flush(done));
+        let _ = flushed.recv();
+    }
+
+    fn send(&self, operation: Operation) {
+        self.send_message(Message::Operation(operation));
+    }
+
+    fn send_message(&self, message: Message) {
+        if let Some(sender) = &self.inner.sender {
+            // the thread only stops once the sender is dropped
+            let _ = sender.send(message);
+        }
+    }
+}
+
+impl Debug for SqliteSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteSink")
+            .field("path", &self.inner.path)
+            .finish()
+    }
+}
+
+impl Drop for SinkInner {
+    fn drop(&mut self) {
+        // the thread writes the remaining operations and stops once the channel is closed
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::warn!("the trace sink thread panicked");
+            }
+        }
+    }
+}
+
+/// The connection of a [SqliteSink] to its database, used by its thread.
+struct SinkWriter {
+    // finalized before the connection is closed
+    insert: Statement,
+    conn: Connection,
+}
+
+/// A prepared statement that is finalized when dropped.
+struct Statement(*mut ffi::sqlite3_stmt);
+
+impl SinkWriter {
+    fn open(path: &CStr, vfs: Option<&CStr>) -> Result<Self, std::io::Error> {
+        let flags = ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+        let conn = Connection::open(path, flags, vfs)?;
+        conn.execute(
+            c"CREATE TABLE IF NOT EXISTS operations (
+                id INTEGER PRIMARY KEY,
+                time_us INTEGER NOT NULL,
+                op TEXT NOT NULL,
+                path TEXT NOT NULL,
+                offset INTEGER,
+                len INTEGER,
+                duration_us INTEGER NOT NULL,
+                result TEXT,
+                error TEXT
+            );
+            CREATE INDEX IF NOT EXISTS operations_op ON operations (op);
+            CREATE INDEX IF NOT EXISTS operations_path ON operations (path);
+            CREATE INDEX IF NOT EXISTS operations_duration_us ON operations (duration_us);",
+        )?;
+        let sql = c"INSERT INTO operations
+            (time_us, op, path, offset, len, duration_us, result, error)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)";
+        let mut insert = null_mut();
+        let code =
+            unsafe { ffi::sqlite3_prepare_v2(conn.0, sql.as_ptr(), -1, &mut insert, null_mut()) };
+        if code != ffi::SQLITE_OK {
+            return Err(conn.error());
+        }
+        Ok(SinkWriter {
+            insert: Statement(insert),
+            conn,
+        })
+    }
+
+    /// Write the operations sent through `receiver` until it is closed, batching those that are
+    /// already waiting into one transaction.
+    fn run(self, receiver: mpsc::Receiver<Message>) {
+        while let Ok(message) = receiver.recv() {
+            let mut flushed = Vec::new();
+            let mut message = Some(message);
+            let result = self.conn.execute(c"BEGIN").and_then(|()| {
+                let mut written = 0;
+                while let Some(next) = message.take().or_else(|| receiver.try_recv().ok()) {
+                    match next {
+                        Message::Operation(operation) => self.insert(&operation)?,
+                        Message::Flush(done) => flushed.push(done),
+                    }
+                    written += 1;
+                    if written == SINK_BATCH {
+                        break;
+                    }
+                }
+                self.conn.execute(c"COMMIT")
+            });
+            if let Err(err) = result {
+                log::warn!("failed to write traced operations: {}", err);
+                let _ = self.conn.execute(c"ROLLBACK");
+            }
+            for done in flushed {
+                let _ = done.send(());
+            }
+        }
+    }
+
+    fn insert(&self, operation: &Operation) -> Result<(), std::io::Error> {
+        let (result, error) = match &operation.result {
+            Ok(result) => (Some(result.as_str()), None),
+            Err(error) => (None, Some(error.as_str())),
+        };
+        let stmt = self.insert.0;
+        let code = unsafe {
+            let codes = [
+                ffi::sqlite3_bind_int64(stmt, 1, operation.time_us),
+                bind_text(stmt, 2, Some(operation.op)),
+                bind_text(stmt, 3, Some(&operation.path)),
+                bind_int64(stmt, 4, operation.offset),
+                bind_int64(stmt, 5, operation.len),
+                ffi::sqlite3_bind_int64(stmt, 6, operation.duration_us),
+                bind_text(stmt, 7, result),
+                bind_text(stmt, 8, error),
+            ];
+            match codes.into_iter().find(|code| *code != ffi::SQLITE_OK) {
+                Some(code) => code,
+                None => ffi::sqlite3_step(stmt),
+            }
+        };
+        unsafe { ffi::sqlite3_reset(stmt) };
+        match code {
+            ffi::SQLITE_DONE => Ok(()),
+            _ => Err(self.conn.error()),
+        }
+    }
+}
+
+impl Drop for Statement {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_finalize(self.0) };
+    }
+}
+
+unsafe fn bind_int64(stmt: *mut ffi::sqlite3_stmt, index: c_int, value: Option<i64>) -> c_int {
+    match value {
+        Some(value) => ffi::sqlite3_bind_int64(stmt, index, value),
+        None => ffi::sqlite3_bind_null(stmt, index),
+    }
+}
+
+unsafe fn bind_text(stmt: *mut ffi::sqlite3_stmt, index: c_int, value: Option<&str>) -> c_int {
+    match value {
+        Some(value) => ffi::sqlite3_bind_text(
+            stmt,
+            index,
+            value.as_ptr() as *const c_char,
+            value.len() as c_int,
+            ffi::SQLITE_TRANSIENT(),
+        ),
+        None => ffi::sqlite3_bind_null(stmt, index),
+    }
+}
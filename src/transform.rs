@@ -0,0 +1,597 @@
+//! Transform each page that is written to or read from a [Vfs], using the bytes SQLite reserves at
+//! the end of each page (e.g. to store a checksum, a MAC or an encryption nonce and tag).
+//!
+//! Wrap any [Vfs] in a [TransformVfs] to apply a [PageTransform] to all pages of the main
+//! database, its rollback journal and its WAL. The wrapper knows about the layout of journal and WAL
+//! files and only hands actual page contents to the transform (never journal headers, record
+//! headers or WAL frame headers). Checksums SQLite stores in journal records are computed over the
+//! untransformed pages, and the wrapper computes those of WAL frames again over the pages as they
+//! are read back, so crash recovery (rolling back a hot journal or recovering a WAL) works as
+//! usual.
+//!
+//! The database must be created with enough reserved bytes per page (see
+//! [set_reserve_bytes](crate::set_reserve_bytes)). Reading or writing a database that does not
+//! reserve enough bytes fails instead of corrupting its pages.
+
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    BatchAtomicWrite, CheckpointCoordinator, DeviceCharacteristics, File, FileControl,
+    HealthReport, LockKind, MemoryMapped, OpenKind, OpenOptions, RecoveryPhase, SharedMemory,
+    SyncOptions, SystemCallOverrides, Vfs, VfsEntries, VfsMetadata,
+};
+
+/// Size of the header at the start of a WAL file.
+const WAL_HEADER_SIZE: u64 = 32;
+
+/// Size of the header in front of each page in a WAL file.
+const WAL_FRAME_HEADER_SIZE: u64 = 24;
+
+/// The magic number at the start of a WAL file, with the lowest bit set if the checksums of the WAL
+/// are computed over big-endian words.
+const WAL_MAGIC: u32 = 0x377f0682;
+
+/// Size of the page number in front of each page in a journal file.
+const JOURNAL_PAGE_NO_SIZE: u64 = 4;
+
+/// Size of the database header at the start of page 1.
+const DB_HEADER_SIZE: usize = 100;
+
+/// A reversible transformation applied to each page.
+pub trait PageTransform: Send + Sync {
+    /// The number of bytes this transform uses at the end of each page.
+    fn reserve_bytes(&self) -> u8;
+
+    /// Transform `page` in place before it is written. SQLite does not use the last
+    /// [PageTransform::reserve_bytes] bytes of `page`, so they can be overwritten freely.
+    ///
+    /// The first 100 bytes of page 1 of a database file (the database header) are read by SQLite
+    /// before it knows the page size and must therefore be left unchanged.
+    fn encode(&self, page: &mut [u8], location: PageLocation) -> Result<(), std::io::Error>;
+
+    /// Reverse [PageTransform::encode] after `page` has been read.
+    fn decode(&self, page: &mut [u8], location: PageLocation) -> Result<(), std::io::Error>;
+}
+
+/// Where a page passed to a [PageTransform] is stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageLocation {
+    /// The type of the file the page is stored in.
+    pub kind: OpenKind,
+
+    /// The position of the page in the file. For database files, this is the page number minus one.
+    /// For journal and WAL files, it identifies the record or frame that contains the page and is
+    /// unique within the file.
+    pub index: u64,
+}
+
+/// A [Vfs] that applies a [PageTransform] to all pages stored in the [Vfs] it wraps.
+pub struct TransformVfs<V, T> {
+    vfs: V,
+    transform: Arc<T>,
+}
+
+/// A file opened by [TransformVfs].
+pub struct TransformFile<F, T> {
+    file: F,
+    kind: OpenKind,
+    transform: Arc<T>,
+    /// The format of a WAL, from its header.
+    wal: Option<WalFormat>,
+    /// A page of a WAL that has only been written in part so far.
+    pending: Option<PendingPage>,
+}
+
+/// What the header of a WAL tells about its frames.
+#[derive(Clone, Copy)]
+struct WalFormat {
+    page_size: u64,
+    big_endian_checksums: bool,
+}
+
+/// When SQLite pads a WAL to a sector boundary after a commit, it syncs the WAL at that boundary,
+/// splitting the write of the page that crosses it in two. The parts are collected here and the
+/// page is transformed and written once it is complete. The page is one of the copies of the
+/// commit frame used as padding, so delaying it does not affect what the sync makes durable.
+struct PendingPage {
+    /// The offset of the page in the file.
+    offset: u64,
+    location: PageLocation,
+    data: Vec<u8>,
+    /// The number of bytes written so far (SQLite never writes a part twice).
+    written: usize,
+}
+
+impl<V, T> TransformVfs<V, T> {
+    /// Wrap `vfs` and apply `transform` to all pages.
+    pub fn new(vfs: V, transform: T) -> Self {
+        TransformVfs {
+            vfs,
+            transform: Arc::new(transform),
+        }
+    }
+}
+
+impl<V: Vfs, T: PageTransform> Vfs for TransformVfs<V, T> {
+    type File = TransformFile<V::File, T>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let kind = opts.kind;
+        let file = self.vfs.open(path, opts)?;
+        Ok(TransformFile {
+            file,
+            kind,
+            transform: Arc::clone(&self.transform),
+            wal: None,
+            pending: None,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+
+    fn access(&self, path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(path, write)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.vfs.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.vfs.rename(from, to)
+    }
+
+    fn health(&self) -> HealthReport {
+        self.vfs.health()
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        self.vfs.checkpoint_coordinator()
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        self.vfs.on_recovery(path, phase)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        self.vfs.full_pathname(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        self.vfs.random(buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        self.vfs.temporary_path()
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        self.vfs.system_calls()
+    }
+}
+
+impl<F, T: PageTransform> TransformFile<F, T> {
+    /// Find the page contained in an access of `len` bytes at `offset` of the file. Returns the
+    /// range of the page inside of the accessed bytes, or `None` if the access does not contain a
+    /// page (e.g. because it is a journal or WAL header).
+    fn locate(&self, offset: u64, len: usize) -> Option<(Range<usize>, PageLocation)> {
+        let is_page_size = |n: u64| n.is_power_of_two() && (512..=65536).contains(&n);
+        let page = |range: Range<usize>, index: u64| {
+            Some((
+                range,
+                PageLocation {
+                    kind: self.kind,
+                    index,
+                },
+            ))
+        };
+        let len = len as u64;
+
+        match self.kind {
+            OpenKind::MainDb | OpenKind::TempDb | OpenKind::TransientDb => {
+                if is_page_size(len) && offset.is_multiple_of(len) {
+                    return page(0..len as usize, offset / len);
+                }
+            }
+            OpenKind::Wal => {
+                // A page is either read and written on its own (directly after its frame header) or
+                // read together with its frame header (during recovery).
+                let first_page = WAL_HEADER_SIZE + WAL_FRAME_HEADER_SIZE;
+                if is_page_size(len) && offset >= first_page {
+                    let frame_size = WAL_FRAME_HEADER_SIZE + len;
+                    if (offset - first_page).is_multiple_of(frame_size) {
+                        return page(0..len as usize, (offset - first_page) / frame_size);
+                    }
+                }
+                if len > WAL_FRAME_HEADER_SIZE
+                    && is_page_size(len - WAL_FRAME_HEADER_SIZE)
+                    && offset >= WAL_HEADER_SIZE
+                    && (offset - WAL_HEADER_SIZE).is_multiple_of(len)
+                {
+                    return page(
+                        WAL_FRAME_HEADER_SIZE as usize..len as usize,
+                        (offset - WAL_HEADER_SIZE) / len,
+                    );
+                }
+            }
+            OpenKind::MainJournal | OpenKind::TempJournal => {
+                // Journal headers start at sector boundaries, while each page is preceded by its
+                // 4 byte page number and followed by a 4 byte checksum. Pages thus never start at a
+                // multiple of 8, while headers always do.
+                if is_page_size(len) && offset % 8 == JOURNAL_PAGE_NO_SIZE {
+                    return page(0..len as usize, offset / (len + 2 * JOURNAL_PAGE_NO_SIZE));
+                }
+            }
+            OpenKind::SubJournal => {
+                // A sub-journal has no header and no checksums, only page numbers followed by
+                // pages.
+                if is_page_size(len)
+                    && offset >= JOURNAL_PAGE_NO_SIZE
+                    && (offset - JOURNAL_PAGE_NO_SIZE).is_multiple_of(len + JOURNAL_PAGE_NO_SIZE)
+                {
+                    return page(
+                        0..len as usize,
+                        (offset - JOURNAL_PAGE_NO_SIZE) / (len + JOURNAL_PAGE_NO_SIZE),
+                    );
+                }
+            }
+            OpenKind::SuperJournal => {}
+        }
+
+        None
+    }
+
+    /// Remember the format of a WAL when its header is read or written.
+    fn parse_wal_header(&mut self, buf: &[u8], offset: u64) {
+        if self.kind == OpenKind::Wal && offset == 0 {
+            if let Some(format) = WalFormat::parse(buf) {
+                self.wal = Some(format);
+            }
+        }
+    }
+
+    /// The format of a WAL, read from its header if this file has not seen it yet (which happens
+    /// when another connection wrote it).
+    fn wal_format(&mut self) -> Result<Option<WalFormat>, std::io::Error>
+    where
+        F: File,
+    {
+        if self.wal.is_none() && self.kind == OpenKind::Wal {
+            let mut header = [0; WAL_HEADER_SIZE as usize];
+            let n = self.file.read_at(&mut header, 0)?;
+            self.wal = WalFormat::parse(&header[..n]);
+        }
+        Ok(self.wal)
+    }
+
+    /// SQLite computes the checksum of a WAL frame over the page it wrote, but recovery checks it
+    /// against the page as it is read back, whose reserved bytes the transform may have changed. So
+    /// compute the checksum of the frame again over the page `encoded` decodes to, continuing from
+    /// the checksum of the previous frame (or of the header), like SQLite does.
+    fn update_wal_checksum(
+        &mut self,
+        encoded: &[u8],
+        location: PageLocation,
+    ) -> Result<(), std::io::Error>
+    where
+        F: File,
+    {
+        let format = match self.wal_format()? {
+            Some(format) if format.page_size == encoded.len() as u64 => format,
+            _ => return Ok(()),
+        };
+        let mut page = encoded.to_vec();
+        self.transform.decode(&mut page, location)?;
+
+        let frame_size = WAL_FRAME_HEADER_SIZE + format.page_size;
+        let frame = WAL_HEADER_SIZE + location.index * frame_size;
+        let previous = match location.index {
+            0 => WAL_HEADER_SIZE - 8,
+            _ => frame - frame_size + WAL_FRAME_HEADER_SIZE - 8,
+        };
+        let mut checksum = [0; 8];
+        read_exact_at(&mut self.file, &mut checksum, previous)?;
+        let mut header = [0; 8];
+        read_exact_at(&mut self.file, &mut header, frame)?;
+
+        let checksum = [
+            u32::from_be_bytes(checksum[..4].try_into().unwrap()),
+            u32::from_be_bytes(checksum[4..].try_into().unwrap()),
+        ];
+        let checksum = format.checksum(format.checksum(checksum, &header), &page);
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&checksum[0].to_be_bytes());
+        bytes[4..].copy_from_slice(&checksum[1].to_be_bytes());
+        self.file
+            .write_all_at(&bytes, frame + WAL_FRAME_HEADER_SIZE - 8)
+    }
+
+    /// Find the page of a WAL in `format` that an access of `len` bytes at `offset` covers only in
+    /// part. Returns the offset of the page in the file, the offset of the access inside of the
+    /// page and the location of the page.
+    fn locate_part(
+        &self,
+        format: WalFormat,
+        offset: u64,
+        len: usize,
+    ) -> Option<(u64, usize, PageLocation)> {
+        let page_size = format.page_size;
+        let frame_size = WAL_FRAME_HEADER_SIZE + page_size;
+        let index = offset.checked_sub(WAL_HEADER_SIZE)? / frame_size;
+        let page_offset = WAL_HEADER_SIZE + index * frame_size + WAL_FRAME_HEADER_SIZE;
+        let start = offset.checked_sub(page_offset)?;
+        if start + len as u64 > page_size {
+            return None;
+        }
+        let location = PageLocation {
+            kind: self.kind,
+            index,
+        };
+        Some((page_offset, start as usize, location))
+    }
+
+    /// Collect a part of a page of a WAL, and transform and write the page once it is complete.
+    fn write_part(
+        &mut self,
+        buf: &[u8],
+        page_offset: u64,
+        start: usize,
+        location: PageLocation,
+    ) -> Result<(), std::io::Error>
+    where
+        F: File,
+    {
+        let page_size = self.wal.map_or(0, |wal| wal.page_size) as usize;
+        let pending = match &mut self.pending {
+            Some(pending) if pending.offset == page_offset => pending,
+            pending => pending.insert(PendingPage {
+                offset: page_offset,
+                location,
+                data: vec![0; page_size],
+                written: 0,
+            }),
+        };
+        pending.data[start..start + buf.len()].copy_from_slice(buf);
+        pending.written += buf.len();
+        if pending.written < pending.data.len() {
+            return Ok(());
+        }
+
+        let mut pending = self.pending.take().unwrap();
+        self.transform.encode(&mut pending.data, pending.location)?;
+        self.file.write_all_at(&pending.data, pending.offset)?;
+        self.update_wal_checksum(&pending.data, pending.location)
+    }
+
+    fn is_first_db_page(&self, location: &PageLocation) -> bool {
+        matches!(
+            location.kind,
+            OpenKind::MainDb | OpenKind::TempDb | OpenKind::TransientDb
+        ) && location.index == 0
+    }
+
+    /// Make sure that the database header on page 1 reserves enough bytes for the transform.
+    fn check_reserve_bytes(&self, header: &[u8]) -> Result<(), std::io::Error> {
+        let required = self.transform.reserve_bytes();
+        if header.len() > 20 && header[20] < required {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "database reserves {} bytes per page, but {} are required",
+                    header[20], required
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<F: File, T: PageTransform> File for TransformFile<F, T> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let n = self.file.read_at(buf, offset)?;
+        self.parse_wal_header(&buf[..n], offset);
+
+        // pages are only decoded if they have been read completely
+        if n < buf.len() {
+            return Ok(n);
+        }
+        if let Some((range, location)) = self.locate(offset, n) {
+            let page = &mut buf[range];
+            if self.is_first_db_page(&location) {
+                self.check_reserve_bytes(page)?;
+            }
+            self.transform.decode(page, location)?;
+        }
+
+        Ok(n)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.parse_wal_header(buf, offset);
+        if let Some((range, location)) = self.locate(offset, buf.len()) {
+            let mut data = buf.to_vec();
+            let page = &mut data[range.clone()];
+            if self.is_first_db_page(&location) {
+                self.check_reserve_bytes(page)?;
+                let header = page[..DB_HEADER_SIZE].to_vec();
+                self.transform.encode(page, location)?;
+                if page[..DB_HEADER_SIZE] != header[..] {
+                    return Err(std::io::Error::other(
+                        "page transform must not modify the database header",
+                    ));
+                }
+            } else {
+                self.transform.encode(page, location)?;
+            }
+            self.file.write_all_at(&data, offset)?;
+            if self.kind == OpenKind::Wal {
+                self.update_wal_checksum(&data[range], location)?;
+            }
+            Ok(())
+        } else if let Some((page_offset, start, location)) = self
+            .wal_format()?
+            .and_then(|format| self.locate_part(format, offset, buf.len()))
+        {
+            self.write_part(buf, page_offset, start, location)
+        } else {
+            self.file.write_all_at(buf, offset)
+        }
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.file.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        // the rest of a page that is truncated away is not written anymore
+        self.pending = None;
+        self.file.truncate(size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.file.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.file.unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.file.shared_memory()
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        // the pages are stored transformed
+        None
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        self.file.batch_atomic_write()
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        self.file.file_control(op)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        self.file.sector_size(kind)
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        self.file.device_characteristics(kind)
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.pre_commit()
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.file.post_commit()
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.begin_overwrite(size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.file.data_version()
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.file.set_persist_wal(persist)
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        self.file.persist_wal()
+    }
+}
+
+impl WalFormat {
+    fn parse(header: &[u8]) -> Option<Self> {
+        if header.len() < WAL_HEADER_SIZE as usize {
+            return None;
+        }
+        let magic = u32::from_be_bytes(header[..4].try_into().unwrap());
+        if magic & !1 != WAL_MAGIC {
+            return None;
+        }
+        Some(WalFormat {
+            page_size: u64::from(u32::from_be_bytes(header[8..12].try_into().unwrap())),
+            big_endian_checksums: magic & 1 == 1,
+        })
+    }
+
+    /// Continue the checksum `sum` over `data`, the way SQLite checksums WAL frames.
+    fn checksum(&self, sum: [u32; 2], data: &[u8]) -> [u32; 2] {
+        let word = |bytes: &[u8]| {
+            let bytes = bytes.try_into().unwrap();
+            match self.big_endian_checksums {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            }
+        };
+        let [mut s1, mut s2] = sum;
+        for words in data.chunks_exact(8) {
+            s1 = s1.wrapping_add(word(&words[..4])).wrapping_add(s2);
+            s2 = s2.wrapping_add(word(&words[4..])).wrapping_add(s1);
+        }
+        [s1, s2]
+    }
+}
+
+/// Read exactly `buf.len()` bytes at `offset`.
+fn read_exact_at(file: &mut impl File, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+    if file.read_at(buf, offset)? < buf.len() {
+        return Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "WAL frame is truncated",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! An [ArchiveVfs] opens databases stored in zip and tar archives in place.
+
+mod common;
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use rusqlite::{ffi, Connection, OpenFlags};
+use sqlite_vfs::archive::ArchiveVfs;
+use sqlite_vfs::{register, Vfs};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// A member name that does not fit the name field of a tar header.
+const LONG_NAME: &str = "data/a/directory/with/a/name/that/is/long/enough/to/need/an/extension/\
+    of/the/tar/format/lookup.db";
+
+/// Create a database with some rows and return its contents.
+fn create(dir: &TempDir) -> Vec<u8> {
+    let path = dir.path("lookup.db");
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+    drop(conn);
+    fs::read(path).unwrap()
+}
+
+fn sum(conn: &Connection) -> i64 {
+    conn.query_row("SELECT SUM(id) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+fn open_read_only(path: &Path, vfs: &str) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        vfs,
+    )
+}
+
+#[test]
+fn tar_members() {
+    let dir = TempDir::new("archive-tar");
+    let db = create(&dir);
+    let archive = dir.path("bundle.tar");
+    let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(3);
+    header.set_mode(0o644);
+    builder
+        .append_data(&mut header, "README", &b"hi\n"[..])
+        .unwrap();
+    for name in ["data/lookup.db", LONG_NAME] {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(db.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, name, &db[..]).unwrap();
+    }
+    builder.into_inner().unwrap();
+
+    let _vfs = register("archive-tar", ArchiveVfs::new(FsVfs, &archive).unwrap()).unwrap();
+    for name in ["data/lookup.db", LONG_NAME] {
+        let conn = open_read_only(Path::new(name), "archive-tar").unwrap();
+        integrity_check(&conn);
+        assert_eq!(sum(&conn), 500 * 501 / 2);
+    }
+
+    // temporary tables are kept in memory
+    let conn = open_read_only(Path::new("data/lookup.db"), "archive-tar").unwrap();
+    conn.execute_batch("CREATE TEMP TABLE copy AS SELECT * FROM vals ORDER BY val DESC")
+        .unwrap();
+    let copied: i64 = conn
+        .query_row("SELECT COUNT(*) FROM copy", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(copied, 500);
+
+    assert!(open_read_only(Path::new("data/missing.db"), "archive-tar").is_err());
+}
+
+#[test]
+fn writes_fail() {
+    let dir = TempDir::new("archive-read-only");
+    let db = create(&dir);
+    let archive = dir.path("bundle.tar");
+    let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+    let mut header = tar::Header::new_ustar();
+    header.set_size(db.len() as u64);
+    header.set_mode(0o644);
+    builder
+        .append_data(&mut header, "lookup.db", &db[..])
+        .unwrap();
+    builder.into_inner().unwrap();
+
+    let vfs = ArchiveVfs::new(FsVfs, &archive).unwrap();
+    assert!(vfs.access(Path::new("lookup.db"), false).unwrap());
+    assert!(!vfs.access(Path::new("lookup.db"), true).unwrap());
+    let _vfs = register("archive-read-only", vfs).unwrap();
+
+    // opening for writing falls back to reading
+    let conn = open(Path::new("lookup.db"), "archive-read-only");
+    match conn.execute("DELETE FROM vals", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, rusqlite::ErrorCode::ReadOnly)
+        }
+        result => panic!("expected SQLITE_READONLY, got {:?}", result),
+    }
+    assert_eq!(sum(&conn), 500 * 501 / 2);
+}
+
+#[test]
+fn zip_members() {
+    let dir = TempDir::new("archive-zip");
+    let db = create(&dir);
+    let archive = dir.path("bundle.zip");
+    let mut zip = ZipWriter::new(fs::File::create(&archive).unwrap());
+    zip.add_directory("data/", FileOptions::default()).unwrap();
+    for (name, method) in [
+        ("data/stored.db", CompressionMethod::Stored),
+        ("data/deflated.db", CompressionMethod::Deflated),
+    ] {
+        zip.start_file(name, FileOptions::default().compression_method(method))
+            .unwrap();
+        zip.write_all(&db).unwrap();
+    }
+    zip.set_comment("lookup databases");
+    zip.finish().unwrap();
+
+    let vfs = ArchiveVfs::new(FsVfs, &archive).unwrap();
+    let members = vfs
+        .list(Path::new("data/"))
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .map(|entry| (entry.path.display().to_string(), entry.size))
+        .collect::<Vec<_>>();
+    let len = db.len() as u64;
+    assert_eq!(
+        members,
+        [
+            ("data/deflated.db".to_string(), len),
+            ("data/stored.db".to_string(), len)
+        ]
+    );
+    let _vfs = register("archive-zip", vfs).unwrap();
+
+    let conn = open_read_only(Path::new("data/stored.db"), "archive-zip").unwrap();
+    integrity_check(&conn);
+    assert_eq!(sum(&conn), 500 * 501 / 2);
+
+    match open_read_only(Path::new("data/deflated.db"), "archive-zip") {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.extended_code, ffi::SQLITE_CANTOPEN)
+        }
+        result => panic!("expected SQLITE_CANTOPEN, got {:?}", result.map(|_| ())),
+    }
+}
+
+#[test]
+fn not_an_archive() {
+    let dir = TempDir::new("archive-invalid");
+    create(&dir);
+
+    let err = ArchiveVfs::new(FsVfs, dir.path("lookup.db")).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
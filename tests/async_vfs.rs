@@ -0,0 +1,243 @@
+//! Async backends are registered through a [BlockingVfs], which blocks on their futures with a
+//! pluggable [Bridge].
+
+mod common;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{integrity_check, open};
+use sqlite_vfs::async_vfs::{
+    cancellation_token, AsyncFile, AsyncVfs, BlockingVfs, Bridge, CancellationToken, CurrentThread,
+};
+use sqlite_vfs::{register, OpenAccess, OpenOptions, SyncOptions};
+
+type Data = Arc<Mutex<Vec<u8>>>;
+
+/// Keeps files in memory, but answers like a remote service: every operation completes on
+/// another thread.
+#[derive(Default)]
+struct RemoteVfs {
+    files: Mutex<HashMap<PathBuf, Data>>,
+    /// Reads never complete while set.
+    stalled: Arc<AtomicBool>,
+}
+
+struct RemoteFile {
+    data: Data,
+    stalled: Arc<AtomicBool>,
+}
+
+/// A future that completes after a short delay, woken from another thread.
+struct Delay(Option<Arc<Mutex<bool>>>);
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match &self.0 {
+            Some(done) if *done.lock().unwrap() => Poll::Ready(()),
+            Some(_) => Poll::Pending,
+            None => {
+                let done = Arc::new(Mutex::new(false));
+                let waker = cx.waker().clone();
+                let flag = Arc::clone(&done);
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_micros(10));
+                    *flag.lock().unwrap() = true;
+                    waker.wake();
+                });
+                self.0 = Some(done);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn remote() -> Delay {
+    Delay(None)
+}
+
+impl AsyncVfs for RemoteVfs {
+    type File = RemoteFile;
+
+    async fn open(&self, path: &Path, opts: OpenOptions) -> Result<RemoteFile, std::io::Error> {
+        remote().await;
+        let mut files = self.files.lock().unwrap();
+        let data = match (files.get(path), opts.access) {
+            (Some(data), _) => Arc::clone(data),
+            (None, OpenAccess::Create | OpenAccess::CreateNew) => {
+                Arc::clone(files.entry(path.to_path_buf()).or_default())
+            }
+            (None, _) => return Err(ErrorKind::NotFound.into()),
+        };
+        Ok(RemoteFile {
+            data,
+            stalled: Arc::clone(&self.stalled),
+        })
+    }
+
+    async fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        remote().await;
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        remote().await;
+        Ok(self.files.lock().unwrap().contains_key(path))
+    }
+}
+
+impl AsyncFile for RemoteFile {
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        remote().await;
+        if self.stalled.load(Ordering::SeqCst) {
+            std::future::pending::<()>().await;
+        }
+        let data = self.data.lock().unwrap();
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    async fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        remote().await;
+        let mut data = self.data.lock().unwrap();
+        let end = offset as usize + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    async fn sync(&mut self, _options: SyncOptions) -> Result<(), std::io::Error> {
+        remote().await;
+        Ok(())
+    }
+
+    async fn file_size(&self) -> Result<u64, std::io::Error> {
+        remote().await;
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    async fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        remote().await;
+        self.data.lock().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+/// Counts the futures it blocks on.
+#[derive(Default)]
+struct CountingBridge(Arc<AtomicUsize>);
+
+impl Bridge for CountingBridge {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        CurrentThread.block_on(future)
+    }
+}
+
+fn round_trip(vfs: &str) {
+    let path = Path::new("/async/main.db");
+    let conn = open(path, vfs);
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT); INSERT INTO vals VALUES (1, 'a');",
+    )
+    .unwrap();
+    drop(conn);
+
+    let conn = open(path, vfs);
+    let val: String = conn
+        .query_row("SELECT val FROM vals WHERE id = 1", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(val, "a");
+    integrity_check(&conn);
+}
+
+#[test]
+fn current_thread() {
+    let vfs = BlockingVfs::new(RemoteVfs::default(), CurrentThread);
+    let _vfs = register("async-current-thread", vfs).unwrap();
+    round_trip("async-current-thread");
+}
+
+#[test]
+fn custom_bridge() {
+    let bridge = CountingBridge::default();
+    let calls = Arc::clone(&bridge.0);
+    let _vfs = register(
+        "async-custom-bridge",
+        BlockingVfs::new(RemoteVfs::default(), bridge),
+    )
+    .unwrap();
+    round_trip("async-custom-bridge");
+    assert!(calls.load(Ordering::SeqCst) > 0);
+}
+
+/// Cancel `token` while a query waits for a stalled read, and check that the query fails promptly
+/// and that the connection works again once the token is reset.
+fn cancel_stalled_read(
+    conn: &rusqlite::Connection,
+    stalled: &AtomicBool,
+    token: &CancellationToken,
+) {
+    stalled.store(true, Ordering::SeqCst);
+    let canceller = {
+        let token = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        })
+    };
+    let start = Instant::now();
+    // each read transaction reads the header of the database again
+    let result = conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get::<_, i64>(0));
+    assert!(result.is_err());
+    assert!(start.elapsed() < Duration::from_secs(10));
+    canceller.join().unwrap();
+
+    stalled.store(false, Ordering::SeqCst);
+    token.reset();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn cancellation() {
+    let remote = RemoteVfs::default();
+    let stalled = Arc::clone(&remote.stalled);
+    let vfs = BlockingVfs::new(remote, CurrentThread);
+    let vfs_token = vfs.cancellation_token().clone();
+    let _vfs = register("async-cancellation", vfs).unwrap();
+
+    let path = Path::new("/async/cancel.db");
+    let conn = open(path, "async-cancellation");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT); INSERT INTO vals VALUES (1, 'a');",
+    )
+    .unwrap();
+
+    // the token of the database file only cancels its operations
+    let token = unsafe { cancellation_token(conn.handle(), c"main") }.unwrap();
+    cancel_stalled_read(&conn, &stalled, &token);
+
+    // the token of the VFS cancels the operations of all files
+    cancel_stalled_read(&conn, &stalled, &vfs_token);
+    integrity_check(&conn);
+}
@@ -0,0 +1,158 @@
+//! Files that implement [BatchAtomicWrite] report `SQLITE_IOCAP_BATCH_ATOMIC` and receive the
+//! atomic write file controls.
+
+mod common;
+
+use std::path::Path;
+use std::ptr::null_mut;
+
+use common::{FsVfs, RawFile, TempDir};
+use rusqlite::ffi;
+use sqlite_vfs::{register, BatchAtomicWrite, File, OpenOptions, SyncOptions, Vfs};
+
+/// Keeps the writes of a batch in memory and only applies them when it is committed.
+struct BatchVfs;
+
+struct BatchFile {
+    file: std::fs::File,
+    batch: Option<Vec<(u64, Vec<u8>)>>,
+}
+
+impl Vfs for BatchVfs {
+    type File = BatchFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(BatchFile {
+            file: FsVfs.open(path, opts)?,
+            batch: None,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for BatchFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.batch {
+            Some(batch) => {
+                batch.push((offset, buf.to_vec()));
+                Ok(())
+            }
+            None => self.file.write_all_at(buf, offset),
+        }
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn batch_atomic_write(&mut self) -> Option<&mut dyn BatchAtomicWrite> {
+        Some(self)
+    }
+}
+
+impl BatchAtomicWrite for BatchFile {
+    fn begin(&mut self) -> Result<(), std::io::Error> {
+        self.batch = Some(Vec::new());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), std::io::Error> {
+        for (offset, buf) in self.batch.take().unwrap_or_default() {
+            self.file.write_all_at(&buf, offset)?;
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), std::io::Error> {
+        self.batch = None;
+        Ok(())
+    }
+}
+
+fn file_control(file: &mut RawFile, op: i32) -> i32 {
+    file.file_control(op, null_mut())
+}
+
+#[test]
+fn batches() {
+    let _vfs = register("batch-atomic-write", BatchVfs).unwrap();
+    let dir = TempDir::new("batch-atomic-write");
+    let path = dir.path("main.db");
+    let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+    let mut file = RawFile::open("batch-atomic-write", &path, flags).unwrap();
+    let mut other = RawFile::open("batch-atomic-write", &path, flags).unwrap();
+    assert_eq!(
+        file.device_characteristics() & ffi::SQLITE_IOCAP_BATCH_ATOMIC,
+        ffi::SQLITE_IOCAP_BATCH_ATOMIC
+    );
+
+    // the writes of a batch are applied at once when it is committed ...
+    assert_eq!(
+        file_control(&mut file, ffi::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE),
+        ffi::SQLITE_OK
+    );
+    assert_eq!(file.write(b"hello", 0), ffi::SQLITE_OK);
+    assert_eq!(file.write(b" world", 5), ffi::SQLITE_OK);
+    assert_eq!(other.file_size(), Ok(0));
+    assert_eq!(
+        file_control(&mut file, ffi::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE),
+        ffi::SQLITE_OK
+    );
+    let mut buf = [0; 11];
+    assert_eq!(other.read(&mut buf, 0), ffi::SQLITE_OK);
+    assert_eq!(&buf, b"hello world");
+
+    // ... or not at all when it is rolled back
+    assert_eq!(
+        file_control(&mut file, ffi::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE),
+        ffi::SQLITE_OK
+    );
+    assert_eq!(file.write(b"HELLO", 0), ffi::SQLITE_OK);
+    assert_eq!(
+        file_control(&mut file, ffi::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE),
+        ffi::SQLITE_OK
+    );
+    assert_eq!(other.read(&mut buf, 0), ffi::SQLITE_OK);
+    assert_eq!(&buf, b"hello world");
+}
+
+#[test]
+fn unsupported() {
+    let _vfs = common::register_fs("batch-atomic-write-unsupported");
+    let dir = TempDir::new("batch-atomic-write-unsupported");
+    let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+    let mut file = RawFile::open(
+        "batch-atomic-write-unsupported",
+        &dir.path("main.db"),
+        flags,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file.device_characteristics() & ffi::SQLITE_IOCAP_BATCH_ATOMIC,
+        0
+    );
+    assert_eq!(
+        file_control(&mut file, ffi::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE),
+        ffi::SQLITE_NOTFOUND
+    );
+}
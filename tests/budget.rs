@@ -0,0 +1,135 @@
+//! A [MemoryBudget] bounds the memory of the files of [MemVfs] and of the caches of other VFS
+//! together, evicting cached data to make room.
+
+mod common;
+
+use std::path::Path;
+
+use common::{integrity_check, open};
+use rusqlite::{Connection, ErrorCode};
+use sqlite_vfs::budget::MemoryBudget;
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::{register, Vfs};
+
+const LIMIT: u64 = 512 * 1024;
+
+fn create(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT hex(randomblob(50)) FROM n;",
+    )
+    .unwrap();
+}
+
+fn count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+/// Insert rows until the database is full.
+fn fill(conn: &Connection) {
+    let result = (0..1000).try_for_each(|_| {
+        conn.execute(
+            "INSERT INTO vals (val) SELECT hex(randomblob(1000)) FROM vals LIMIT 10",
+            [],
+        )
+        .map(|_| ())
+    });
+    match result {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => assert_eq!(err.code, ErrorCode::DiskFull),
+        result => panic!("expected the database to be full, got {:?}", result),
+    }
+}
+
+#[test]
+fn files_share_budget() {
+    let budget = MemoryBudget::new(LIMIT);
+    let a = MemVfs::with_budget(budget.clone());
+    let b = MemVfs::with_budget(budget.clone());
+    let _a = register("budget-files-a", a.clone()).unwrap();
+    let _b = register("budget-files-b", b.clone()).unwrap();
+
+    let path = Path::new("/budget/main.db");
+    let conn_a = open(path, "budget-files-a");
+    create(&conn_a);
+    let used = budget.stats().used;
+    assert!(used > 0);
+
+    // the files of both VFS count towards the budget
+    let conn_b = open(path, "budget-files-b");
+    create(&conn_b);
+    assert!(budget.stats().used > used);
+    fill(&conn_b);
+    let stats = budget.stats();
+    assert!(stats.used <= LIMIT);
+    assert_eq!(stats.limit, LIMIT);
+    assert!(stats.denied > 0);
+    integrity_check(&conn_b);
+
+    // deleting files gives their memory back
+    drop(conn_a);
+    drop(conn_b);
+    a.delete(path).unwrap();
+    b.delete(path).unwrap();
+    assert_eq!(budget.stats().used, 0);
+}
+
+#[test]
+fn import_within_budget() {
+    let budget = MemoryBudget::new(LIMIT);
+    let vfs = MemVfs::with_budget(budget.clone());
+    let _vfs = register("budget-import", vfs.clone()).unwrap();
+
+    let path = Path::new("/budget/main.db");
+    create(&open(path, "budget-import"));
+    let data = vfs.export(path).unwrap();
+    assert_eq!(budget.stats().used, 0);
+
+    // the imported buffer draws from the budget again
+    vfs.import(path, data).unwrap();
+    assert!(budget.stats().used > 0);
+    assert_eq!(count(&open(path, "budget-import")), 500);
+}
+
+#[cfg(feature = "s3")]
+#[test]
+fn evicts_cached_chunks() {
+    use std::sync::Arc;
+
+    use object_store::memory::InMemory;
+    use sqlite_vfs::async_vfs::{BlockingVfs, CurrentThread};
+    use sqlite_vfs::s3::{S3Options, S3Vfs};
+
+    let budget = MemoryBudget::new(LIMIT);
+    let options = S3Options {
+        chunk_size: 16 * 1024,
+        cache_chunks: 64,
+        budget: Some(budget.clone()),
+        ..Default::default()
+    };
+    let s3 = S3Vfs::with_options(Arc::new(InMemory::new()), options);
+    let _s3 = register("budget-s3", BlockingVfs::new(s3, CurrentThread)).unwrap();
+    let _mem = register("budget-mem", MemVfs::with_budget(budget.clone())).unwrap();
+
+    let path = Path::new("/budget/main.db");
+    let s3_conn = open(path, "budget-s3");
+    create(&s3_conn);
+    assert_eq!(count(&s3_conn), 500);
+    let cached = budget.stats().cached;
+    assert!(cached > 0);
+
+    // the files of the MemVfs take the memory of the cached chunks
+    let mem_conn = open(path, "budget-mem");
+    create(&mem_conn);
+    fill(&mem_conn);
+    let stats = budget.stats();
+    assert!(stats.evictions > 0);
+    assert!(stats.evicted > 0);
+    assert!(stats.cached < cached);
+    assert!(stats.used <= LIMIT);
+
+    // the evicted chunks are read from the store again
+    assert_eq!(count(&s3_conn), 500);
+    integrity_check(&s3_conn);
+}
@@ -0,0 +1,98 @@
+//! The [CheckpointCoordinator] of a [Vfs] is notified around checkpoints.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use sqlite_vfs::{register, CheckpointCoordinator, OpenOptions, Vfs};
+
+type Events = Arc<Mutex<Vec<(&'static str, PathBuf)>>>;
+
+#[derive(Default)]
+struct CoordinatedVfs {
+    events: Events,
+}
+
+impl Vfs for CoordinatedVfs {
+    type File = std::fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        FsVfs.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+
+    fn checkpoint_coordinator(&self) -> Option<&dyn CheckpointCoordinator> {
+        Some(self)
+    }
+}
+
+impl CheckpointCoordinator for CoordinatedVfs {
+    fn checkpoint_start(&self, path: &Path) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(("start", path.to_path_buf()));
+    }
+
+    fn checkpoint_done(&self, path: &Path) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(("done", path.to_path_buf()));
+    }
+}
+
+#[test]
+fn coordinated_checkpoint() {
+    let vfs = CoordinatedVfs::default();
+    let events = Arc::clone(&vfs.events);
+    let _vfs = register("checkpoint-coordinated", vfs).unwrap();
+    let dir = TempDir::new("checkpoint-coordinated");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "checkpoint-coordinated");
+    // without shared memory support, WAL mode only works with an exclusive lock
+    conn.execute_batch(
+        "PRAGMA locking_mode = EXCLUSIVE;
+        PRAGMA journal_mode = WAL;
+        PRAGMA wal_autocheckpoint = 0;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY);
+        INSERT INTO vals VALUES (1);",
+    )
+    .unwrap();
+    assert!(events.lock().unwrap().is_empty());
+
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![("start", path.clone()), ("done", path.clone())]
+    );
+    integrity_check(&conn);
+}
+
+#[test]
+fn rollback_journal() {
+    let vfs = CoordinatedVfs::default();
+    let events = Arc::clone(&vfs.events);
+    let _vfs = register("checkpoint-rollback", vfs).unwrap();
+    let dir = TempDir::new("checkpoint-rollback");
+
+    let conn = open(&dir.path("main.db"), "checkpoint-rollback");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY);
+        INSERT INTO vals VALUES (1);
+        PRAGMA wal_checkpoint;",
+    )
+    .unwrap();
+    assert!(events.lock().unwrap().is_empty());
+}
@@ -0,0 +1,89 @@
+//! A [ChecksumVfs] detects pages that were changed behind its back.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use rusqlite::{ffi, Connection};
+use sqlite_vfs::checksum::{Checksum, ChecksumVfs, RESERVE_BYTES};
+use sqlite_vfs::register;
+
+fn create(path: &Path, vfs: &str) -> Connection {
+    let conn = open(path, vfs);
+    unsafe { sqlite_vfs::set_reserve_bytes(conn.handle(), c"main", RESERVE_BYTES) }.unwrap();
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+    conn
+}
+
+fn sum(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT SUM(length(val)) FROM vals", [], |row| row.get(0))
+}
+
+#[test]
+fn valid_pages() {
+    let _vfs = register("cksum", ChecksumVfs::new(FsVfs, Checksum)).unwrap();
+    let dir = TempDir::new("cksum");
+    let path = dir.path("main.db");
+
+    let conn = create(&path, "cksum");
+    conn.execute("UPDATE vals SET val = 'changed' WHERE id % 7 = 0", [])
+        .unwrap();
+    drop(conn);
+
+    let conn = open(&path, "cksum");
+    integrity_check(&conn);
+    assert!(sum(&conn).unwrap() > 0);
+
+    // the database stays readable without the checksums being verified
+    let conn = Connection::open(&path).unwrap();
+    integrity_check(&conn);
+}
+
+#[test]
+fn corrupted_page() {
+    let _vfs = register("cksum-corrupt", ChecksumVfs::new(FsVfs, Checksum)).unwrap();
+    let dir = TempDir::new("cksum-corrupt");
+    let path = dir.path("main.db");
+
+    let conn = create(&path, "cksum-corrupt");
+    let page_size: usize = conn
+        .query_row("PRAGMA page_size", [], |row| row.get(0))
+        .unwrap();
+    drop(conn);
+
+    // flip a bit in the middle of the last page, which still is a valid page to SQLite
+    let mut data = fs::read(&path).unwrap();
+    let offset = data.len() - page_size / 2;
+    data[offset] ^= 0x01;
+    fs::write(&path, data).unwrap();
+
+    let conn = open(&path, "cksum-corrupt");
+    match sum(&conn) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.extended_code, ffi::SQLITE_IOERR_DATA)
+        }
+        result => panic!("expected SQLITE_IOERR_DATA, got {:?}", result),
+    }
+}
+
+#[test]
+fn missing_reserve_bytes() {
+    let _vfs = register("cksum-unreserved", ChecksumVfs::new(FsVfs, Checksum)).unwrap();
+    let dir = TempDir::new("cksum-unreserved");
+    let path = dir.path("main.db");
+
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT)")
+        .unwrap();
+    drop(conn);
+
+    let conn = open(&path, "cksum-unreserved");
+    assert!(sum(&conn).is_err());
+}
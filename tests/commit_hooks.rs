@@ -0,0 +1,201 @@
+//! [File::pre_commit] and [File::post_commit] mark the boundaries of each committed transaction,
+//! and [File::begin_overwrite] announces a `VACUUM`.
+
+mod common;
+
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use sqlite_vfs::{register, File, OpenKind, OpenOptions, SyncOptions, Vfs};
+
+type Events = Arc<Mutex<Vec<&'static str>>>;
+
+/// Records the commits of the main database, like a replicating VFS would ship them.
+#[derive(Default)]
+struct CommitVfs {
+    events: Events,
+    fail: Arc<AtomicBool>,
+    overwrites: Arc<Mutex<Vec<u64>>>,
+}
+
+struct CommitFile {
+    file: std::fs::File,
+    events: Option<Events>,
+    fail: Arc<AtomicBool>,
+    overwrites: Arc<Mutex<Vec<u64>>>,
+}
+
+impl CommitFile {
+    fn record(&self, event: &'static str) {
+        if let Some(events) = &self.events {
+            events.lock().unwrap().push(event);
+        }
+    }
+}
+
+impl Vfs for CommitVfs {
+    type File = CommitFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let events = (opts.kind == OpenKind::MainDb).then(|| Arc::clone(&self.events));
+        Ok(CommitFile {
+            file: FsVfs.open(path, opts)?,
+            events,
+            fail: Arc::clone(&self.fail),
+            overwrites: Arc::clone(&self.overwrites),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for CommitFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.record("write");
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn pre_commit(&mut self) -> Result<(), std::io::Error> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(ErrorKind::Other.into());
+        }
+        self.record("pre_commit");
+        Ok(())
+    }
+
+    fn post_commit(&mut self) -> Result<(), std::io::Error> {
+        self.record("post_commit");
+        Ok(())
+    }
+
+    fn begin_overwrite(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.record("begin_overwrite");
+        self.overwrites.lock().unwrap().push(size);
+        Ok(())
+    }
+}
+
+#[test]
+fn commit_boundaries() {
+    let vfs = CommitVfs::default();
+    let events = Arc::clone(&vfs.events);
+    let _vfs = register("commit-hooks", vfs).unwrap();
+    let dir = TempDir::new("commit-hooks");
+    let conn = open(&dir.path("main.db"), "commit-hooks");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    events.lock().unwrap().clear();
+
+    conn.execute("INSERT INTO vals VALUES (1)", []).unwrap();
+    let recorded = events.lock().unwrap().clone();
+    assert_eq!(recorded.first(), Some(&"write"));
+    assert_eq!(
+        &recorded[recorded.len() - 2..],
+        ["pre_commit", "post_commit"]
+    );
+
+    // reads do not commit anything
+    events.lock().unwrap().clear();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+    assert!(events.lock().unwrap().is_empty());
+}
+
+#[test]
+fn failed_pre_commit_aborts() {
+    let vfs = CommitVfs::default();
+    let fail = Arc::clone(&vfs.fail);
+    let _vfs = register("commit-hooks-fail", vfs).unwrap();
+    let dir = TempDir::new("commit-hooks-fail");
+    let conn = open(&dir.path("main.db"), "commit-hooks-fail");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+
+    fail.store(true, Ordering::SeqCst);
+    assert!(conn.execute("INSERT INTO vals VALUES (1)", []).is_err());
+    fail.store(false, Ordering::SeqCst);
+
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 0);
+    integrity_check(&conn);
+}
+
+#[test]
+fn wal_mode() {
+    let vfs = CommitVfs::default();
+    let events = Arc::clone(&vfs.events);
+    let _vfs = register("commit-hooks-wal", vfs).unwrap();
+    let dir = TempDir::new("commit-hooks-wal");
+    let conn = open(&dir.path("main.db"), "commit-hooks-wal");
+    conn.execute_batch(
+        "PRAGMA locking_mode = EXCLUSIVE; PRAGMA journal_mode = WAL;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY);",
+    )
+    .unwrap();
+    events.lock().unwrap().clear();
+
+    conn.execute("INSERT INTO vals VALUES (1)", []).unwrap();
+    assert_eq!(*events.lock().unwrap(), ["post_commit"]);
+}
+
+#[test]
+fn vacuum_overwrites() {
+    let vfs = CommitVfs::default();
+    let events = Arc::clone(&vfs.events);
+    let overwrites = Arc::clone(&vfs.overwrites);
+    let _vfs = register("commit-hooks-vacuum", vfs).unwrap();
+    let dir = TempDir::new("commit-hooks-vacuum");
+    let conn = open(&dir.path("main.db"), "commit-hooks-vacuum");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val BLOB);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+        INSERT INTO vals SELECT i, randomblob(1000) FROM n;
+        DELETE FROM vals WHERE id > 10;",
+    )
+    .unwrap();
+    events.lock().unwrap().clear();
+    assert!(overwrites.lock().unwrap().is_empty());
+
+    conn.execute_batch("VACUUM").unwrap();
+    let size: u64 = conn
+        .query_row(
+            "SELECT page_count * page_size FROM pragma_page_count, pragma_page_size",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(*overwrites.lock().unwrap(), [size]);
+    let recorded = events.lock().unwrap().clone();
+    assert_eq!(recorded.first(), Some(&"begin_overwrite"));
+    assert_eq!(recorded.last(), Some(&"post_commit"));
+    integrity_check(&conn);
+}
@@ -0,0 +1,383 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::ffi::{c_void, CString};
+use std::fs;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use rusqlite::{ffi, Connection, OpenFlags};
+use sqlite_vfs::shm::ShmVfs;
+use sqlite_vfs::{
+    register, File, LockKind, OpenAccess, OpenOptions, SyncOptions, Vfs, VfsEntries, VfsEntry,
+    VfsHandle, VfsMetadata,
+};
+
+/// The VFS from the `fs` example.
+pub struct FsVfs;
+
+impl Vfs for FsVfs {
+    type File = fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let mut o = fs::OpenOptions::new();
+        o.read(true).write(opts.access != OpenAccess::Read);
+        match opts.access {
+            OpenAccess::Create => {
+                o.create(true);
+            }
+            OpenAccess::CreateNew => {
+                o.create_new(true);
+            }
+            _ => {}
+        }
+        let f = o.open(path)?;
+        Ok(f)
+    }
+
+    fn delete(&self, path: &std::path::Path) -> Result<(), std::io::Error> {
+        std::fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        Ok(path.is_file())
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        // list a directory, or the files in the parent directory starting with the file name
+        let (dir, name) = if prefix.is_dir() {
+            (prefix, String::new())
+        } else {
+            let dir = prefix.parent().filter(|p| !p.as_os_str().is_empty());
+            let name = prefix.file_name().unwrap_or_default();
+            (
+                dir.unwrap_or(Path::new(".")),
+                name.to_string_lossy().into_owned(),
+            )
+        };
+        let entries = fs::read_dir(dir)?.filter_map(move |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            if !entry.file_name().to_string_lossy().starts_with(&name) {
+                return None;
+            }
+            match entry.metadata() {
+                Ok(meta) if meta.is_file() => Some(Ok(VfsEntry {
+                    path: entry.path(),
+                    size: meta.len(),
+                })),
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            }
+        });
+        Ok(Box::new(entries))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        fs::rename(from, to)
+    }
+}
+
+/// Adds locking between the connections of this process to the [Vfs] it wraps.
+pub struct LockingVfs<V>(pub V);
+
+pub struct LockingFile<F> {
+    file: F,
+    path: PathBuf,
+    lock: LockKind,
+    reserved: bool,
+}
+
+/// The locks held on a file by all connections of this process.
+#[derive(Default)]
+struct Locks {
+    shared: usize,
+    reserved: bool,
+    pending: bool,
+    exclusive: bool,
+}
+
+fn locks() -> MutexGuard<'static, HashMap<PathBuf, Locks>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Locks>>> = OnceLock::new();
+    LOCKS.get_or_init(Default::default).lock().unwrap()
+}
+
+impl<V: Vfs> Vfs for LockingVfs<V> {
+    type File = LockingFile<V::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(LockingFile {
+            file: self.0.open(path, opts)?,
+            path: path.to_path_buf(),
+            lock: LockKind::None,
+            reserved: false,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.0.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.0.exists(path)
+    }
+
+    fn list(&self, prefix: &Path) -> Result<VfsEntries<'_>, std::io::Error> {
+        self.0.list(prefix)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+        self.0.rename(from, to)
+    }
+}
+
+impl<F: File> File for LockingFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.file.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn metadata(&self) -> Result<VfsMetadata, std::io::Error> {
+        self.file.metadata()
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let mut locks = locks();
+        let locks = locks.entry(self.path.clone()).or_default();
+        match lock {
+            LockKind::Shared if locks.pending || locks.exclusive => return Ok(false),
+            LockKind::Shared => locks.shared += 1,
+            LockKind::Reserved if locks.reserved => return Ok(false),
+            LockKind::Reserved => {
+                locks.reserved = true;
+                self.reserved = true;
+            }
+            LockKind::Pending if locks.pending => return Ok(false),
+            LockKind::Pending => locks.pending = true,
+            // wait until all other connections released their shared locks
+            LockKind::Exclusive if locks.shared > 1 => return Ok(false),
+            LockKind::Exclusive => locks.exclusive = true,
+            LockKind::None => {}
+        }
+        self.lock = lock;
+        Ok(true)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        let mut locks = locks();
+        let locks = locks.entry(self.path.clone()).or_default();
+        if self.lock >= LockKind::Pending && lock < LockKind::Pending {
+            locks.pending = false;
+            locks.exclusive = false;
+        }
+        if self.reserved && lock < LockKind::Reserved {
+            locks.reserved = false;
+            self.reserved = false;
+        }
+        if self.lock >= LockKind::Shared && lock == LockKind::None {
+            locks.shared -= 1;
+        }
+        self.lock = lock;
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        let locks = locks();
+        Ok(locks
+            .get(&self.path)
+            .map(|locks| locks.reserved || locks.pending)
+            .unwrap_or(false))
+    }
+}
+
+/// A temporary directory that is removed once dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-vfs-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Register the `fs` example VFS (with locking and shared memory between the connections of this
+/// process) under `name`.
+pub fn register_fs(name: &str) -> VfsHandle {
+    register(name, ShmVfs::new(LockingVfs(FsVfs))).unwrap()
+}
+
+/// Open (or create) the database at `path` through the VFS registered as `vfs`.
+pub fn open(path: &Path, vfs: &str) -> Connection {
+    Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        vfs,
+    )
+    .unwrap()
+}
+
+pub fn integrity_check(conn: &Connection) {
+    let result: String = conn
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(result, "ok");
+}
+
+/// A file opened by calling into the VFS registered as `vfs` directly, the same way SQLite does.
+/// Each method returns the SQLite result code of the call.
+pub struct RawFile {
+    vfs: *mut ffi::sqlite3_vfs,
+    file: Vec<u64>,
+}
+
+impl RawFile {
+    pub fn open(vfs: &str, path: &Path, flags: c_int) -> Result<Self, c_int> {
+        let vfs = CString::new(vfs).unwrap();
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let vfs = ffi::sqlite3_vfs_find(vfs.as_ptr());
+            assert!(!vfs.is_null(), "vfs not registered");
+            let mut file = RawFile {
+                vfs,
+                file: vec![0; ((*vfs).szOsFile as usize).div_ceil(8)],
+            };
+            let mut out_flags = 0;
+            let code =
+                ((*vfs).xOpen.unwrap())(vfs, path.as_ptr(), file.as_ptr(), flags, &mut out_flags);
+            if code == ffi::SQLITE_OK {
+                Ok(file)
+            } else {
+                // a file that failed to open must not be closed
+                file.vfs = std::ptr::null_mut();
+                Err(code)
+            }
+        }
+    }
+
+    fn as_ptr(&mut self) -> *mut ffi::sqlite3_file {
+        self.file.as_mut_ptr() as *mut ffi::sqlite3_file
+    }
+
+    fn methods(&mut self) -> &ffi::sqlite3_io_methods {
+        unsafe { &*(*self.as_ptr()).pMethods }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8], offset: i64) -> c_int {
+        let read = self.methods().xRead.unwrap();
+        unsafe {
+            read(
+                self.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as c_int,
+                offset,
+            )
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8], offset: i64) -> c_int {
+        let write = self.methods().xWrite.unwrap();
+        unsafe {
+            write(
+                self.as_ptr(),
+                data.as_ptr() as *const _,
+                data.len() as c_int,
+                offset,
+            )
+        }
+    }
+
+    pub fn truncate(&mut self, size: i64) -> c_int {
+        let truncate = self.methods().xTruncate.unwrap();
+        unsafe { truncate(self.as_ptr(), size) }
+    }
+
+    pub fn lock(&mut self, lock: c_int) -> c_int {
+        let xlock = self.methods().xLock.unwrap();
+        unsafe { xlock(self.as_ptr(), lock) }
+    }
+
+    pub fn unlock(&mut self, lock: c_int) -> c_int {
+        let unlock = self.methods().xUnlock.unwrap();
+        unsafe { unlock(self.as_ptr(), lock) }
+    }
+
+    pub fn file_size(&mut self) -> Result<i64, c_int> {
+        let file_size = self.methods().xFileSize.unwrap();
+        let mut size = 0;
+        match unsafe { file_size(self.as_ptr(), &mut size) } {
+            ffi::SQLITE_OK => Ok(size),
+            code => Err(code),
+        }
+    }
+
+    pub fn sector_size(&mut self) -> c_int {
+        let sector_size = self.methods().xSectorSize.unwrap();
+        unsafe { sector_size(self.as_ptr()) }
+    }
+
+    pub fn device_characteristics(&mut self) -> c_int {
+        let device_characteristics = self.methods().xDeviceCharacteristics.unwrap();
+        unsafe { device_characteristics(self.as_ptr()) }
+    }
+
+    pub fn file_control(&mut self, op: c_int, arg: *mut c_void) -> c_int {
+        let file_control = self.methods().xFileControl.unwrap();
+        unsafe { file_control(self.as_ptr(), op, arg) }
+    }
+
+    /// The last error message of the VFS, as reported into a buffer of `n_byte` bytes.
+    pub fn last_error(&mut self, n_byte: usize) -> String {
+        let mut buf = vec![0u8; n_byte];
+        unsafe {
+            let get_last_error = (*self.vfs).xGetLastError.unwrap();
+            assert_eq!(
+                get_last_error(self.vfs, n_byte as c_int, buf.as_mut_ptr() as *mut _),
+                ffi::SQLITE_OK
+            );
+        }
+        let len = buf.iter().position(|b| *b == 0).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+}
+
+impl Drop for RawFile {
+    fn drop(&mut self) {
+        if !self.vfs.is_null() {
+            let close = self.methods().xClose.unwrap();
+            unsafe { close(self.as_ptr()) };
+        }
+    }
+}
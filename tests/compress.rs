@@ -0,0 +1,390 @@
+//! A [CompressedVfs] stores compressed pages, which are read back through it, and produces
+//! compressed archives with `VACUUM INTO`. Pages can be compressed with per-database dictionaries.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{integrity_check, open, FsVfs, LockingVfs, TempDir};
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::compress::{Codec, CompressedVfs};
+use sqlite_vfs::shm::ShmVfs;
+use sqlite_vfs::{register, VfsHandle};
+
+/// Run-length encoding, which shrinks the long runs of zeros in pages with free space.
+struct Rle;
+
+impl Codec for Rle {
+    fn compress(&self, block: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut data = Vec::new();
+        for run in block.chunk_by(|a, b| a == b) {
+            for part in run.chunks(255) {
+                data.extend_from_slice(&[part.len() as u8, part[0]]);
+            }
+        }
+        Ok(data)
+    }
+
+    fn decompress(&self, data: &[u8], block: &mut [u8]) -> Result<(), std::io::Error> {
+        let mut pos = 0;
+        for pair in data.chunks_exact(2) {
+            block[pos..pos + pair[0] as usize].fill(pair[1]);
+            pos += pair[0] as usize;
+        }
+        assert_eq!(pos, block.len());
+        Ok(())
+    }
+}
+
+/// [Rle] that prefixes blocks compressed with a dictionary with the sum of its bytes, so that
+/// decompressing them with any other dictionary fails.
+struct DictionaryRle;
+
+fn checksum(dictionary: &[u8]) -> u8 {
+    dictionary.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+impl Codec for DictionaryRle {
+    fn compress(&self, block: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        Rle.compress(block)
+    }
+
+    fn decompress(&self, data: &[u8], block: &mut [u8]) -> Result<(), std::io::Error> {
+        Rle.decompress(data, block)
+    }
+
+    fn compress_with_dictionary(
+        &self,
+        block: &[u8],
+        dictionary: &[u8],
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let mut data = vec![checksum(dictionary)];
+        data.extend(Rle.compress(block)?);
+        Ok(data)
+    }
+
+    fn decompress_with_dictionary(
+        &self,
+        data: &[u8],
+        block: &mut [u8],
+        dictionary: &[u8],
+    ) -> Result<(), std::io::Error> {
+        assert_eq!(data[0], checksum(dictionary), "wrong dictionary");
+        Rle.decompress(&data[1..], block)
+    }
+}
+
+fn register_compressed<C: Codec + 'static>(name: &str, codec: C) -> VfsHandle {
+    let vfs = CompressedVfs::new(LockingVfs(FsVfs), codec);
+    register(name, ShmVfs::new(vfs)).unwrap()
+}
+
+fn fill(conn: &Connection, rows: usize) {
+    conn.execute_batch(&format!(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {})
+        INSERT INTO vals (val) SELECT replace(hex(zeroblob(200)), '0', 'x') || i FROM n;",
+        rows
+    ))
+    .unwrap();
+}
+
+fn count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+/// The size of the database as seen by SQLite.
+fn logical_size(conn: &Connection) -> u64 {
+    let pages: u64 = conn
+        .query_row("PRAGMA page_count", [], |row| row.get(0))
+        .unwrap();
+    let page_size: u64 = conn
+        .query_row("PRAGMA page_size", [], |row| row.get(0))
+        .unwrap();
+    pages * page_size
+}
+
+fn stored_size(path: &Path) -> u64 {
+    fs::metadata(path).unwrap().len()
+}
+
+/// Set the dictionary of the database, returning the id of the dictionary now in use.
+fn set_dictionary(conn: &Connection, dictionary: &[u8]) -> rusqlite::Result<String> {
+    let hex = dictionary
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    conn.query_row(
+        &format!("PRAGMA compression_dictionary = '{}'", hex),
+        [],
+        |row| row.get(0),
+    )
+}
+
+fn magic(path: &Path) -> Vec<u8> {
+    fs::read(path).unwrap()[..8].to_vec()
+}
+
+#[test]
+fn pages_are_compressed() {
+    let _vfs = register_compressed("compress", Rle);
+    let dir = TempDir::new("compress");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "compress");
+    fill(&conn, 1000);
+    let size = logical_size(&conn);
+    drop(conn);
+
+    // the rows are not stored in plain text
+    let stored = fs::read(&path).unwrap();
+    assert!(stored.len() < size as usize / 4);
+    assert!(!stored.windows(20).any(|w| w == [b'x'; 20]));
+    assert!(Connection::open(&path)
+        .and_then(|conn| conn.query_row("SELECT COUNT(*) FROM vals", [], |_| Ok(())))
+        .is_err());
+
+    let conn = open(&path, "compress");
+    assert_eq!(count(&conn), 1000);
+    assert_eq!(logical_size(&conn), size);
+    integrity_check(&conn);
+}
+
+#[test]
+fn rewrites_and_shrinks() {
+    let _vfs = register_compressed("compress-rewrite", Rle);
+    let dir = TempDir::new("compress-rewrite");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "compress-rewrite");
+    fill(&conn, 2000);
+
+    // rewritten pages compress worse (or not at all), and have to move
+    conn.execute(
+        "UPDATE vals SET val = hex(randomblob(100)) WHERE id % 3 = 0",
+        [],
+    )
+    .unwrap();
+    integrity_check(&conn);
+    let updated = stored_size(&path);
+    conn.execute("DELETE FROM vals WHERE id > 500", []).unwrap();
+    conn.execute_batch("VACUUM").unwrap();
+    integrity_check(&conn);
+    assert_eq!(count(&conn), 500);
+    assert!(stored_size(&path) < updated / 2);
+    assert!(stored_size(&path) < logical_size(&conn));
+    drop(conn);
+
+    let conn = open(&path, "compress-rewrite");
+    assert_eq!(count(&conn), 500);
+    integrity_check(&conn);
+}
+
+#[test]
+fn connections_share_the_index() {
+    let _vfs = register_compressed("compress-shared", Rle);
+    let dir = TempDir::new("compress-shared");
+    let path = dir.path("main.db");
+
+    let conn1 = open(&path, "compress-shared");
+    conn1.execute_batch("PRAGMA journal_mode = WAL").unwrap();
+    fill(&conn1, 100);
+    let conn2 = open(&path, "compress-shared");
+    assert_eq!(count(&conn2), 100);
+
+    conn2
+        .execute("DELETE FROM vals WHERE id <= 50", [])
+        .unwrap();
+    conn2
+        .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    assert_eq!(count(&conn1), 50);
+    conn1
+        .execute("DELETE FROM vals WHERE id <= 60", [])
+        .unwrap();
+    assert_eq!(count(&conn2), 40);
+    integrity_check(&conn2);
+}
+
+#[test]
+fn read_only_archive() {
+    let _vfs = register_compressed("compress-archive", Rle);
+    let dir = TempDir::new("compress-archive");
+    let path = dir.path("main.db");
+    let archive = dir.path("archive.db");
+
+    let conn = open(&path, "compress-archive");
+    fill(&conn, 1000);
+    conn.execute("DELETE FROM vals WHERE id % 2 = 0", [])
+        .unwrap();
+    conn.execute("VACUUM INTO ?", [archive.to_str().unwrap()])
+        .unwrap();
+    drop(conn);
+    // the archive has no gaps
+    assert!(stored_size(&archive) <= stored_size(&path));
+
+    let mut perms = fs::metadata(&archive).unwrap().permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(&archive, perms).unwrap();
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &archive,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        "compress-archive",
+    )
+    .unwrap();
+    assert_eq!(count(&conn), 500);
+    integrity_check(&conn);
+    assert!(conn.execute("DELETE FROM vals", []).is_err());
+}
+
+#[test]
+fn not_compressed() {
+    let _vfs = register_compressed("compress-plain", Rle);
+    let dir = TempDir::new("compress-plain");
+    let path = dir.path("main.db");
+
+    let conn = Connection::open(&path).unwrap();
+    fill(&conn, 10);
+    drop(conn);
+
+    let result = Connection::open_with_flags_and_vfs(
+        &path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        "compress-plain",
+    )
+    .and_then(|conn| conn.query_row("SELECT COUNT(*) FROM vals", [], |_| Ok(())));
+    assert!(result.is_err());
+}
+
+#[test]
+fn dictionaries() {
+    let _vfs = register_compressed("compress-dictionaries", DictionaryRle);
+    let dir = TempDir::new("compress-dictionaries");
+    let path = dir.path("main.db");
+
+    // a dictionary can be set before anything is written
+    let conn = open(&path, "compress-dictionaries");
+    assert_eq!(set_dictionary(&conn, b"first").unwrap(), "1");
+    assert_eq!(magic(&path), b"SQLVFSZ2");
+    fill(&conn, 1000);
+    drop(conn);
+
+    let conn = open(&path, "compress-dictionaries");
+    assert_eq!(count(&conn), 1000);
+    integrity_check(&conn);
+    let id: String = conn
+        .query_row("PRAGMA compression_dictionary", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(id, "1");
+
+    // rotate: rewritten pages use the new dictionary, the others keep the first one
+    assert_eq!(set_dictionary(&conn, b"second").unwrap(), "2");
+    conn.execute("UPDATE vals SET val = 'changed' WHERE id % 2 = 0", [])
+        .unwrap();
+    integrity_check(&conn);
+    drop(conn);
+
+    let conn = open(&path, "compress-dictionaries");
+    assert_eq!(count(&conn), 1000);
+    integrity_check(&conn);
+
+    // stop using dictionaries, and rewrite all pages, which drops the unused dictionaries
+    assert_eq!(set_dictionary(&conn, b"").unwrap(), "0");
+    conn.execute_batch("VACUUM").unwrap();
+    assert_eq!(magic(&path), b"SQLVFSZ1");
+    drop(conn);
+
+    let conn = open(&path, "compress-dictionaries");
+    assert_eq!(count(&conn), 1000);
+    integrity_check(&conn);
+    let err = conn
+        .query_row("PRAGMA compression_dictionary = 'xyz'", [], |_| Ok(()))
+        .unwrap_err();
+    assert!(err.to_string().contains("not hex"), "{}", err);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd() {
+    let _vfs = register_compressed("compress-zstd", sqlite_vfs::compress::Zstd::default());
+    let dir = TempDir::new("compress-zstd");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "compress-zstd");
+    fill(&conn, 1000);
+    let size = logical_size(&conn);
+    drop(conn);
+    assert!(stored_size(&path) < size / 4);
+
+    let conn = open(&path, "compress-zstd");
+    assert_eq!(count(&conn), 1000);
+    integrity_check(&conn);
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn lz4() {
+    let _vfs = register_compressed("compress-lz4", sqlite_vfs::compress::Lz4);
+    let dir = TempDir::new("compress-lz4");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "compress-lz4");
+    fill(&conn, 1000);
+    let size = logical_size(&conn);
+    drop(conn);
+    assert!(stored_size(&path) < size / 4);
+
+    let conn = open(&path, "compress-lz4");
+    assert_eq!(count(&conn), 1000);
+    integrity_check(&conn);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_dictionary() {
+    use sqlite_vfs::compress::{train_dictionary, Zstd};
+
+    /// Rows that have little in common within a page, but a lot across pages.
+    fn fill_records(conn: &Connection) {
+        conn.execute_batch(
+            "CREATE TABLE records (id INTEGER PRIMARY KEY, record TEXT);
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20000)
+            INSERT INTO records (record) SELECT json_object(
+                'customer', 'customer-' || (i * 7919 % 100003),
+                'email', 'user' || (i * 104729 % 1000003) || '@example.com',
+                'status', CASE i % 3 WHEN 0 THEN 'active' WHEN 1 THEN 'suspended' ELSE 'closed' END,
+                'created_at', datetime(1600000000 + i * 86413, 'unixepoch')
+            ) FROM n;",
+        )
+        .unwrap();
+    }
+
+    let _vfs = register_compressed("compress-zstd-dictionary", Zstd::default());
+    let dir = TempDir::new("compress-zstd-dictionary");
+
+    // train on a plain database with similar content
+    let sample = dir.path("sample.db");
+    fill_records(&Connection::open(&sample).unwrap());
+    let dictionary = train_dictionary(fs::File::open(&sample).unwrap(), 16 * 1024).unwrap();
+    assert!(!dictionary.is_empty() && dictionary.len() <= 16 * 1024);
+
+    let plain = dir.path("plain.db");
+    fill_records(&open(&plain, "compress-zstd-dictionary"));
+    let path = dir.path("main.db");
+    let conn = open(&path, "compress-zstd-dictionary");
+    assert_eq!(set_dictionary(&conn, &dictionary).unwrap(), "1");
+    fill_records(&conn);
+    drop(conn);
+    // even with the dictionary stored in it
+    assert!(stored_size(&path) < stored_size(&plain));
+
+    let conn = open(&path, "compress-zstd-dictionary");
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM records", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 20000);
+    integrity_check(&conn);
+}
@@ -0,0 +1,244 @@
+//! Many threads locking, unlocking, closing and unregistering through the same VFS at once.
+//!
+//! The threads interleave at random (with a seed per thread), check the invariants of the lock
+//! manager while they hold their locks, and are watched for deadlocks.
+
+mod common;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use common::{register_fs, RawFile, TempDir};
+use rusqlite::ffi;
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::register;
+
+const THREADS: usize = 8;
+const ROUNDS: usize = 300;
+
+/// A xorshift generator, so that the tests need no dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// The locks held on the database by all threads, counted after acquiring a lock and before
+/// releasing it.
+#[derive(Default)]
+struct Held {
+    shared: AtomicUsize,
+    reserved: AtomicUsize,
+    exclusive: AtomicUsize,
+}
+
+/// Wait for the threads to finish, failing if they don't (which would be a deadlock).
+fn join_all(handles: Vec<thread::JoinHandle<()>>, done: mpsc::Receiver<()>) {
+    for _ in 0..handles.len() {
+        if let Err(err) = done.recv_timeout(Duration::from_secs(60)) {
+            panic!("threads did not finish: {}", err);
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn open(vfs: &str, path: &Path) -> RawFile {
+    let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+    RawFile::open(vfs, path, flags).unwrap()
+}
+
+/// Take a write lock the way SQLite does (giving up when another thread holds the reserved lock)
+/// and write a page.
+fn write(file: &mut RawFile, held: &Held, id: u8) {
+    match file.lock(ffi::SQLITE_LOCK_RESERVED) {
+        ffi::SQLITE_OK => {}
+        ffi::SQLITE_BUSY => return,
+        code => panic!("reserved lock failed with {}", code),
+    }
+    assert_eq!(held.reserved.fetch_add(1, Ordering::SeqCst), 0);
+
+    // readers finish on their own, so waiting for the exclusive lock can't deadlock
+    while file.lock(ffi::SQLITE_LOCK_EXCLUSIVE) == ffi::SQLITE_BUSY {
+        thread::yield_now();
+    }
+    assert_eq!(held.exclusive.fetch_add(1, Ordering::SeqCst), 0);
+    assert_eq!(
+        held.shared.load(Ordering::SeqCst),
+        1,
+        "readers during a write"
+    );
+
+    assert_eq!(file.write(&[id; 512], 0), ffi::SQLITE_OK);
+    let mut page = [0; 512];
+    assert_eq!(file.read(&mut page, 0), ffi::SQLITE_OK);
+    assert_eq!(page, [id; 512], "write was not isolated");
+
+    held.exclusive.fetch_sub(1, Ordering::SeqCst);
+    held.reserved.fetch_sub(1, Ordering::SeqCst);
+    assert_eq!(file.unlock(ffi::SQLITE_LOCK_SHARED), ffi::SQLITE_OK);
+}
+
+fn lock_interleavings(vfs: &str, path: &Path) {
+    assert_eq!(open(vfs, path).write(&[0; 512], 0), ffi::SQLITE_OK);
+    let held = Arc::new(Held::default());
+    let (done, finished) = mpsc::channel();
+    let handles = (0..THREADS)
+        .map(|t| {
+            let (vfs, path, held, done) = (
+                vfs.to_string(),
+                path.to_path_buf(),
+                held.clone(),
+                done.clone(),
+            );
+            thread::spawn(move || {
+                let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (t as u64 + 1));
+                let mut file = Some(open(&vfs, &path));
+                for _ in 0..ROUNDS {
+                    let f = file.get_or_insert_with(|| open(&vfs, &path));
+                    match f.lock(ffi::SQLITE_LOCK_SHARED) {
+                        ffi::SQLITE_OK => {}
+                        ffi::SQLITE_BUSY => {
+                            thread::yield_now();
+                            continue;
+                        }
+                        code => panic!("shared lock failed with {}", code),
+                    }
+                    held.shared.fetch_add(1, Ordering::SeqCst);
+                    assert_eq!(
+                        held.exclusive.load(Ordering::SeqCst),
+                        0,
+                        "read during a write"
+                    );
+
+                    match rng.below(3) {
+                        0 => {
+                            let mut page = [0; 512];
+                            assert_eq!(f.read(&mut page, 0), ffi::SQLITE_OK);
+                            assert!(page.iter().all(|b| *b == page[0]), "torn page");
+                        }
+                        1 => write(f, &held, t as u8 + 1),
+                        _ => thread::yield_now(),
+                    }
+
+                    held.shared.fetch_sub(1, Ordering::SeqCst);
+                    if rng.below(4) == 0 {
+                        // closing a file releases its locks
+                        file = None;
+                    } else {
+                        assert_eq!(f.unlock(ffi::SQLITE_LOCK_NONE), ffi::SQLITE_OK);
+                    }
+                }
+                done.send(()).unwrap();
+            })
+        })
+        .collect();
+    join_all(handles, finished);
+
+    // all locks were released, so a single file can lock the database exclusively
+    let mut file = open(vfs, path);
+    assert_eq!(file.lock(ffi::SQLITE_LOCK_SHARED), ffi::SQLITE_OK);
+    assert_eq!(file.lock(ffi::SQLITE_LOCK_EXCLUSIVE), ffi::SQLITE_OK);
+}
+
+#[test]
+fn lock_interleavings_mem() {
+    let _vfs = register("concurrency-locks-mem", MemVfs::new()).unwrap();
+    lock_interleavings("concurrency-locks-mem", Path::new("/main.db"));
+}
+
+#[test]
+fn lock_interleavings_fs() {
+    let _vfs = register_fs("concurrency-locks-fs");
+    let dir = TempDir::new("concurrency-locks-fs");
+    lock_interleavings("concurrency-locks-fs", &dir.path("main.db"));
+}
+
+#[test]
+fn unregister_while_closing() {
+    let mut vfs = register("concurrency-unregister", MemVfs::new()).unwrap();
+    let barrier = Arc::new(Barrier::new(THREADS + 1));
+    let (done, finished) = mpsc::channel();
+    let handles = (0..THREADS)
+        .map(|t| {
+            let (barrier, done) = (barrier.clone(), done.clone());
+            thread::spawn(move || {
+                let mut rng = Rng(0x2545_f491_4f6c_dd1d ^ (t as u64 + 1));
+                let mut files = (0..4)
+                    .map(|i| {
+                        open(
+                            "concurrency-unregister",
+                            Path::new(&format!("/{}-{}.db", t, i)),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                barrier.wait();
+                barrier.wait();
+
+                // the VFS is being unregistered from now on, which must wait for these files
+                while !files.is_empty() {
+                    let i = rng.below(files.len() as u64) as usize;
+                    let file = &mut files[i];
+                    assert_eq!(file.lock(ffi::SQLITE_LOCK_SHARED), ffi::SQLITE_OK);
+                    assert_eq!(file.lock(ffi::SQLITE_LOCK_EXCLUSIVE), ffi::SQLITE_OK);
+                    assert_eq!(file.write(&[t as u8; 512], 0), ffi::SQLITE_OK);
+                    if rng.below(2) == 0 {
+                        assert_eq!(file.unlock(ffi::SQLITE_LOCK_NONE), ffi::SQLITE_OK);
+                    }
+                    if rng.below(8) == 0 {
+                        files.swap_remove(i);
+                    }
+                }
+                done.send(()).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+
+    barrier.wait();
+    vfs = vfs.unregister().unwrap_err();
+    assert_eq!(vfs.open_files(), THREADS * 4);
+    barrier.wait();
+    while let Err(handle) = vfs.unregister() {
+        vfs = handle;
+        thread::yield_now();
+    }
+    join_all(handles, finished);
+}
+
+#[test]
+fn last_error_per_thread() {
+    let _vfs = register("concurrency-last-error", MemVfs::new()).unwrap();
+    let (done, finished) = mpsc::channel();
+    let handles = (0..THREADS)
+        .map(|t| {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut file = open("concurrency-last-error", Path::new("/main.db"));
+                // threads holding a shared lock fail with a different message than the others
+                let (held, expected) = if t % 2 == 0 {
+                    (ffi::SQLITE_LOCK_NONE, "from None to Pending")
+                } else {
+                    assert_eq!(file.lock(ffi::SQLITE_LOCK_SHARED), ffi::SQLITE_OK);
+                    (ffi::SQLITE_LOCK_SHARED, "from Shared to Pending")
+                };
+                for _ in 0..ROUNDS {
+                    assert_eq!(file.lock(ffi::SQLITE_LOCK_PENDING), ffi::SQLITE_IOERR_LOCK);
+                    thread::yield_now();
+                    let msg = file.last_error(256);
+                    assert!(msg.contains(expected), "{} (holding {})", msg, held);
+                }
+                done.send(()).unwrap();
+            })
+        })
+        .collect();
+    join_all(handles, finished);
+}
@@ -0,0 +1,118 @@
+//! Files bump [File::data_version] when the database changed behind SQLite's back, which makes it
+//! discard its page cache and change `PRAGMA data_version`.
+
+mod common;
+
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use common::{open, FsVfs, TempDir};
+use rusqlite::Connection;
+use sqlite_vfs::{register, File, OpenOptions, SyncOptions, Vfs};
+
+/// Reports the version of the data set by the test, like a backend that polls its storage would.
+#[derive(Default)]
+struct VersionedVfs {
+    version: Arc<AtomicU64>,
+}
+
+struct VersionedFile {
+    file: std::fs::File,
+    version: Arc<AtomicU64>,
+}
+
+impl Vfs for VersionedVfs {
+    type File = VersionedFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(VersionedFile {
+            file: FsVfs.open(path, opts)?,
+            version: Arc::clone(&self.version),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for VersionedFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        File::read_at(&mut self.file, buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        File::write_all_at(&mut self.file, buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        File::file_size(&self.file)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        File::truncate(&mut self.file, size)
+    }
+
+    fn data_version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+}
+
+fn val(conn: &Connection) -> String {
+    conn.query_row("SELECT val FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+fn data_version(conn: &Connection) -> i64 {
+    conn.query_row("PRAGMA data_version", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn external_change() {
+    let vfs = VersionedVfs::default();
+    let version = Arc::clone(&vfs.version);
+    let _vfs = register("data-version", vfs).unwrap();
+    let dir = TempDir::new("data-version");
+    let path = dir.path("main.db");
+    let conn = open(&path, "data-version");
+    conn.execute_batch("CREATE TABLE vals (val TEXT); INSERT INTO vals VALUES ('before');")
+        .unwrap();
+    assert_eq!(val(&conn), "before");
+    let before = data_version(&conn);
+
+    // change a row without touching the database header, so SQLite cannot tell
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let contents = std::fs::read(&path).unwrap();
+    let offset = contents
+        .windows(6)
+        .position(|window| window == b"before")
+        .unwrap();
+    file.write_all_at(b"after!", offset as u64).unwrap();
+    assert_eq!(val(&conn), "before");
+    assert_eq!(data_version(&conn), before);
+
+    // until the file reports the change
+    version.fetch_add(1, Ordering::SeqCst);
+    assert_eq!(val(&conn), "after!");
+    let after = data_version(&conn);
+    assert_ne!(after, before);
+
+    // the cache is only discarded once per change
+    assert_eq!(val(&conn), "after!");
+    assert_eq!(data_version(&conn), after);
+}
@@ -0,0 +1,80 @@
+//! A [DelegatingVfs] forwards all operations to a VFS registered to SQLite.
+
+mod common;
+
+use std::io::ErrorKind;
+
+use common::{integrity_check, open, TempDir};
+use rusqlite::ErrorCode;
+use sqlite_vfs::delegate::DelegatingVfs;
+use sqlite_vfs::register;
+
+#[test]
+fn rollback_journal() {
+    let vfs = DelegatingVfs::new().unwrap();
+    assert!(!vfs.name().is_empty());
+    let _vfs = register("delegate-rollback", vfs).unwrap();
+    let dir = TempDir::new("delegate-rollback");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "delegate-rollback");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT); INSERT INTO vals VALUES (1, 'a');",
+    )
+    .unwrap();
+    assert!(path.is_file());
+
+    // the locks of the delegate are shared with other connections
+    let other = open(&path, "delegate-rollback");
+    other.busy_timeout(std::time::Duration::ZERO).unwrap();
+    conn.execute_batch("BEGIN EXCLUSIVE").unwrap();
+    let err = other
+        .query_row("SELECT val FROM vals", [], |row| row.get::<_, String>(0))
+        .unwrap_err();
+    match err {
+        rusqlite::Error::SqliteFailure(err, _) => assert_eq!(err.code, ErrorCode::DatabaseBusy),
+        err => panic!("unexpected error: {}", err),
+    }
+    conn.execute_batch("UPDATE vals SET val = 'b'; COMMIT")
+        .unwrap();
+
+    let val: String = other
+        .query_row("SELECT val FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(val, "b");
+    integrity_check(&other);
+}
+
+#[test]
+fn wal() {
+    let _vfs = register("delegate-wal", DelegatingVfs::new().unwrap()).unwrap();
+    let dir = TempDir::new("delegate-wal");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "delegate-wal");
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT)")
+        .unwrap();
+
+    // readers see the committed writes through the shared memory of the delegate
+    let other = open(&path, "delegate-wal");
+    for i in 0..100 {
+        conn.execute("INSERT INTO vals (val) VALUES (?)", [i.to_string()])
+            .unwrap();
+    }
+    let count: i64 = other
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 100);
+    assert!(dir.path("main.db-wal").is_file());
+    integrity_check(&other);
+}
+
+#[test]
+fn missing_vfs() {
+    let err = DelegatingVfs::find(Some("delegate-missing")).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
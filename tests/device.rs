@@ -0,0 +1,279 @@
+//! Files report the properties of their storage to SQLite, and are told how thoroughly to sync.
+
+mod common;
+
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use common::{open, FsVfs, RawFile, TempDir};
+use rusqlite::{ffi, Connection, OpenFlags};
+use sqlite_vfs::{register, DeviceCharacteristics, File, OpenKind, OpenOptions, SyncOptions, Vfs};
+
+type Syncs = Arc<Mutex<Vec<(OpenKind, SyncOptions)>>>;
+
+/// Databases on a storage with 4 KiB sectors, that appends safely and never damages neighbouring
+/// bytes, and journals on a storage with 512 byte sectors that writes in order. Records the syncs
+/// of all files.
+#[derive(Default)]
+struct DeviceVfs {
+    syncs: Syncs,
+}
+
+struct DeviceFile {
+    file: std::fs::File,
+    kind: OpenKind,
+    syncs: Syncs,
+}
+
+impl Vfs for DeviceVfs {
+    type File = DeviceFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(DeviceFile {
+            kind: opts.kind,
+            file: FsVfs.open(path, opts)?,
+            syncs: Arc::clone(&self.syncs),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for DeviceFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.syncs.lock().unwrap().push((self.kind, options));
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn sector_size(&self, kind: OpenKind) -> u32 {
+        match kind {
+            OpenKind::MainDb => 4096,
+            _ => 512,
+        }
+    }
+
+    fn device_characteristics(&self, kind: OpenKind) -> DeviceCharacteristics {
+        match kind {
+            OpenKind::MainDb => DeviceCharacteristics::empty()
+                .safe_append()
+                .powersafe_overwrite(),
+            _ => DeviceCharacteristics::empty().sequential(),
+        }
+    }
+}
+
+/// The `sqlite3_file` of the main database of `conn`.
+fn main_file(conn: &Connection) -> *mut ffi::sqlite3_file {
+    let mut file: *mut ffi::sqlite3_file = std::ptr::null_mut();
+    let code = unsafe {
+        ffi::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            ffi::SQLITE_FCNTL_FILE_POINTER,
+            &mut file as *mut *mut ffi::sqlite3_file as *mut _,
+        )
+    };
+    assert_eq!(code, ffi::SQLITE_OK);
+    file
+}
+
+fn sector_size(conn: &Connection) -> i32 {
+    unsafe {
+        let file = main_file(conn);
+        ((*(*file).pMethods).xSectorSize.unwrap())(file)
+    }
+}
+
+fn device_characteristics(conn: &Connection) -> i32 {
+    unsafe {
+        let file = main_file(conn);
+        ((*(*file).pMethods).xDeviceCharacteristics.unwrap())(file)
+    }
+}
+
+#[test]
+fn sector_size_of_file() {
+    let _vfs = register("device-sector-size", DeviceVfs::default()).unwrap();
+    let _vfs = common::register_fs("device-sector-size-default");
+    let dir = TempDir::new("device-sector-size");
+
+    let conn = open(&dir.path("main.db"), "device-sector-size");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    assert_eq!(sector_size(&conn), 4096);
+
+    let conn = open(&dir.path("main.db"), "device-sector-size-default");
+    assert_eq!(sector_size(&conn), 1024);
+}
+
+#[test]
+fn device_characteristics_of_file() {
+    let _vfs = register("device-characteristics", DeviceVfs::default()).unwrap();
+    let _vfs = common::register_fs("device-characteristics-default");
+    let dir = TempDir::new("device-characteristics");
+
+    let conn = open(&dir.path("main.db"), "device-characteristics");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    assert_eq!(
+        device_characteristics(&conn),
+        ffi::SQLITE_IOCAP_SAFE_APPEND | ffi::SQLITE_IOCAP_POWERSAFE_OVERWRITE
+    );
+    drop(conn);
+
+    let conn = open(&dir.path("main.db"), "device-characteristics-default");
+    assert_eq!(device_characteristics(&conn), 0);
+
+    let characteristics = DeviceCharacteristics::empty().sequential().atomic();
+    assert!(characteristics.contains(DeviceCharacteristics::empty().atomic()));
+    assert!(!characteristics.contains(DeviceCharacteristics::empty().atomic().immutable()));
+}
+
+#[test]
+fn per_kind() {
+    let _vfs = register("device-per-kind", DeviceVfs::default()).unwrap();
+    let dir = TempDir::new("device-per-kind");
+    let create = ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+
+    let mut db = RawFile::open(
+        "device-per-kind",
+        &dir.path("main.db"),
+        ffi::SQLITE_OPEN_MAIN_DB | create,
+    )
+    .unwrap();
+    assert_eq!(db.sector_size(), 4096);
+    assert_eq!(
+        db.device_characteristics(),
+        ffi::SQLITE_IOCAP_SAFE_APPEND | ffi::SQLITE_IOCAP_POWERSAFE_OVERWRITE
+    );
+
+    for (name, flags) in [
+        ("main.db-journal", ffi::SQLITE_OPEN_MAIN_JOURNAL),
+        ("main.db-wal", ffi::SQLITE_OPEN_WAL),
+    ] {
+        let mut file = RawFile::open("device-per-kind", &dir.path(name), flags | create).unwrap();
+        assert_eq!(file.sector_size(), 512, "{}", name);
+        assert_eq!(
+            file.device_characteristics(),
+            ffi::SQLITE_IOCAP_SEQUENTIAL,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn sync_options() {
+    let vfs = DeviceVfs::default();
+    let syncs = Arc::clone(&vfs.syncs);
+    let _vfs = register("device-sync", vfs).unwrap();
+    let dir = TempDir::new("device-sync");
+    let conn = open(&dir.path("main.db"), "device-sync");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+
+    syncs.lock().unwrap().clear();
+    conn.execute("INSERT INTO vals VALUES (1)", []).unwrap();
+    assert_eq!(
+        *syncs.lock().unwrap(),
+        vec![
+            (OpenKind::MainJournal, SyncOptions::default()),
+            (OpenKind::MainDb, SyncOptions::default()),
+        ]
+    );
+
+    conn.execute_batch("PRAGMA fullfsync = ON").unwrap();
+    syncs.lock().unwrap().clear();
+    conn.execute("INSERT INTO vals VALUES (2)", []).unwrap();
+    assert_eq!(
+        *syncs.lock().unwrap(),
+        vec![
+            (
+                OpenKind::MainJournal,
+                SyncOptions {
+                    full: true,
+                    data_only: true
+                }
+            ),
+            (
+                OpenKind::MainDb,
+                SyncOptions {
+                    full: true,
+                    data_only: false
+                }
+            ),
+        ]
+    );
+}
+
+#[test]
+fn toggle_powersafe_overwrite() {
+    let _vfs = register("device-psow", DeviceVfs::default()).unwrap();
+    let _vfs = common::register_fs("device-psow-default");
+    let dir = TempDir::new("device-psow");
+    let psow = ffi::SQLITE_IOCAP_POWERSAFE_OVERWRITE;
+
+    let conn = open(&dir.path("main.db"), "device-psow");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    assert_eq!(powersafe_overwrite(&conn, -1), 1);
+    assert_eq!(powersafe_overwrite(&conn, 0), 0);
+    assert_eq!(powersafe_overwrite(&conn, -1), 0);
+    assert_eq!(device_characteristics(&conn), ffi::SQLITE_IOCAP_SAFE_APPEND);
+    drop(conn);
+
+    let conn = open(&dir.path("main.db"), "device-psow-default");
+    assert_eq!(powersafe_overwrite(&conn, -1), 0);
+    assert_eq!(powersafe_overwrite(&conn, 1), 1);
+    assert_eq!(device_characteristics(&conn), psow);
+    drop(conn);
+
+    // the `psow` URI parameter overrides the file as well
+    let uri = format!("file:{}?psow=0", dir.path("main.db").display());
+    let conn = Connection::open_with_flags_and_vfs(
+        uri,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_URI,
+        "device-psow",
+    )
+    .unwrap();
+    assert_eq!(powersafe_overwrite(&conn, -1), 0);
+    assert_eq!(device_characteristics(&conn), ffi::SQLITE_IOCAP_SAFE_APPEND);
+}
+
+/// Send `SQLITE_FCNTL_POWERSAFE_OVERWRITE` with `arg` to the main database of `conn`, and return
+/// the argument afterwards.
+fn powersafe_overwrite(conn: &Connection, mut arg: c_int) -> c_int {
+    let code = unsafe {
+        ffi::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            ffi::SQLITE_FCNTL_POWERSAFE_OVERWRITE,
+            &mut arg as *mut c_int as *mut _,
+        )
+    };
+    assert_eq!(code, ffi::SQLITE_OK);
+    arg
+}
@@ -0,0 +1,42 @@
+//! With the `diagnostics` feature, [VfsHandle::live_objects] counts the objects the glue allocates
+//! for a VFS and the files open through it.
+#![cfg(feature = "diagnostics")]
+
+mod common;
+
+use common::{open, TempDir};
+use sqlite_vfs::diagnostics::LiveObjects;
+
+#[test]
+fn live_objects_per_vfs() {
+    let dir = TempDir::new("diagnostics");
+    let vfs = common::register_fs("diagnostics");
+    let other = common::register_fs("diagnostics-other");
+    let registered = LiveObjects {
+        vfs_states: 1,
+        names: 1,
+        ..Default::default()
+    };
+    assert_eq!(vfs.live_objects(), registered);
+
+    let conn = open(&dir.path("main.db"), "diagnostics");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY);
+        INSERT INTO vals VALUES (1);",
+    )
+    .unwrap();
+    assert_eq!(
+        vfs.live_objects(),
+        LiveObjects {
+            vfs_states: 1,
+            file_states: 1,
+            files: 1,
+            names: 2,
+        }
+    );
+    // the files of one VFS do not count against another
+    assert_eq!(other.live_objects(), registered);
+
+    drop(conn);
+    assert_eq!(vfs.live_objects(), registered);
+}
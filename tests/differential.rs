@@ -0,0 +1,31 @@
+mod common;
+
+use common::{FsVfs, TempDir};
+use sqlite_vfs::differential;
+
+#[test]
+fn fs_vfs_matches_default_vfs() {
+    let dir = TempDir::new("differential");
+    let path = dir.path("differential.db");
+
+    let divergence = differential::run(
+        FsVfs,
+        &path.to_string_lossy(),
+        &[
+            "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT NOT NULL)",
+            "INSERT INTO vals (val) VALUES ('a'), ('b'), ('c')",
+            "BEGIN; UPDATE vals SET val = 'x'; ROLLBACK",
+            "INSERT INTO vals (val) SELECT hex(zeroblob(2000)) FROM vals",
+            "SELECT id, length(val) FROM vals ORDER BY id",
+            "INSERT INTO vals (val) VALUES (NULL)",
+            "DELETE FROM vals WHERE id > 3",
+            "VACUUM",
+            "SELECT * FROM vals",
+        ],
+    )
+    .unwrap();
+
+    if let Some(divergence) = divergence {
+        panic!("{}", divergence);
+    }
+}
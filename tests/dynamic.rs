@@ -0,0 +1,47 @@
+//! A [Vfs] chosen at runtime can be registered as a [DynVfs].
+
+mod common;
+
+use common::{integrity_check, open, FsVfs, LockingVfs, TempDir};
+use sqlite_vfs::dynamic::{boxed, DynVfs};
+use sqlite_vfs::fencing::FencedVfs;
+use sqlite_vfs::register;
+use sqlite_vfs::shm::ShmVfs;
+
+fn from_config(backend: &str) -> DynVfs {
+    match backend {
+        "shared-memory" => boxed(ShmVfs::new(LockingVfs(FsVfs))),
+        "fenced" => boxed(FencedVfs::new(LockingVfs(FsVfs))),
+        _ => panic!("unknown backend {}", backend),
+    }
+}
+
+#[test]
+fn backends_chosen_at_runtime() {
+    for (backend, journal_mode) in [("shared-memory", "wal"), ("fenced", "delete")] {
+        let name = format!("dynamic-{}", backend);
+        let _vfs = register(&name, from_config(backend)).unwrap();
+        let dir = TempDir::new(&name);
+        let conn = open(&dir.path("main.db"), &name);
+
+        // the boxed files still provide shared memory for WAL mode
+        let mode: String = conn
+            .query_row(
+                &format!("PRAGMA journal_mode = {}", journal_mode),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(mode, journal_mode);
+        conn.execute_batch(
+            "CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+            INSERT INTO vals (text) VALUES ('a'), ('b');",
+        )
+        .unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        integrity_check(&conn);
+    }
+}
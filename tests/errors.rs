@@ -0,0 +1,444 @@
+//! Errors returned by a VFS surface as the right SQLite result codes.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{open, FsVfs, RawFile, TempDir};
+use rusqlite::ErrorCode;
+use sqlite_vfs::{
+    register, register_with_options, File, OpenOptions, RegisterOptions, SyncOptions, Vfs, VfsError,
+};
+
+/// A VFS that fails all writes and truncates of the main database with the returned error.
+struct FailingVfs(fn() -> std::io::Error);
+
+struct FailingFile {
+    file: fs::File,
+    fail: Option<fn() -> std::io::Error>,
+}
+
+impl Vfs for FailingVfs {
+    type File = FailingFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let fail = (opts.kind == sqlite_vfs::OpenKind::MainDb).then_some(self.0);
+        Ok(FailingFile {
+            file: FsVfs.open(path, opts)?,
+            fail,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl FailingFile {
+    fn check(&self) -> std::io::Result<()> {
+        match self.fail {
+            Some(err) => Err(err()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl File for FailingFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.check()?;
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.file.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.check()?;
+        self.file.truncate(size)
+    }
+}
+
+fn sqlite_error(result: rusqlite::Result<()>) -> rusqlite::ffi::Error {
+    match result {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => err,
+        result => panic!("expected an SQLite error, got {:?}", result),
+    }
+}
+
+fn error_code(result: rusqlite::Result<()>) -> ErrorCode {
+    sqlite_error(result).code
+}
+
+#[test]
+fn storage_full() {
+    let _vfs = register(
+        "errors-storage-full",
+        FailingVfs(|| std::io::Error::new(std::io::ErrorKind::StorageFull, "injected failure")),
+    )
+    .unwrap();
+    let dir = TempDir::new("storage-full");
+    let conn = open(&dir.path("main.db"), "errors-storage-full");
+
+    let result = conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)");
+    assert_eq!(error_code(result), ErrorCode::DiskFull);
+}
+
+#[test]
+fn other_write_errors() {
+    let _vfs = register(
+        "errors-other",
+        FailingVfs(|| std::io::Error::other("injected failure")),
+    )
+    .unwrap();
+    let dir = TempDir::new("other-write-errors");
+    let conn = open(&dir.path("main.db"), "errors-other");
+
+    let result = conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)");
+    assert_eq!(error_code(result), ErrorCode::SystemIoFailure);
+}
+
+#[test]
+fn error_kinds() {
+    type Case = (&'static str, fn() -> std::io::Error, i32);
+    let cases: [Case; 5] = [
+        (
+            "errors-kind-too-large",
+            || std::io::ErrorKind::FileTooLarge.into(),
+            rusqlite::ffi::SQLITE_FULL,
+        ),
+        (
+            "errors-kind-read-only",
+            || std::io::ErrorKind::ReadOnlyFilesystem.into(),
+            rusqlite::ffi::SQLITE_READONLY,
+        ),
+        (
+            "errors-kind-permission",
+            || std::io::ErrorKind::PermissionDenied.into(),
+            rusqlite::ffi::SQLITE_READONLY,
+        ),
+        (
+            "errors-kind-memory",
+            || std::io::ErrorKind::OutOfMemory.into(),
+            rusqlite::ffi::SQLITE_IOERR_NOMEM,
+        ),
+        (
+            "errors-kind-other",
+            || std::io::ErrorKind::TimedOut.into(),
+            rusqlite::ffi::SQLITE_IOERR_WRITE,
+        ),
+    ];
+    let dir = TempDir::new("error-kinds");
+    for (name, err, expected) in cases {
+        let _vfs = register(name, FailingVfs(err)).unwrap();
+        let (code, _) = write_error(name, &dir.path(&format!("{}.db", name)), 512);
+        assert_eq!(code, expected, "{}", name);
+    }
+}
+
+/// A VFS whose main databases cannot be read (like files whose permissions changed).
+struct UnreadableVfs;
+
+struct UnreadableFile(fs::File);
+
+impl Vfs for UnreadableVfs {
+    type File = UnreadableFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(UnreadableFile(FsVfs.open(path, opts)?))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for UnreadableFile {
+    fn read_at(&mut self, _buf: &mut [u8], _offset: u64) -> Result<usize, std::io::Error> {
+        Err(std::io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.0.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.0.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.0.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.0.truncate(size)
+    }
+}
+
+#[test]
+fn permission_denied() {
+    let _vfs = register(
+        "errors-permission-write",
+        FailingVfs(|| std::io::ErrorKind::PermissionDenied.into()),
+    )
+    .unwrap();
+    let _vfs = register("errors-permission-read", UnreadableVfs).unwrap();
+    let dir = TempDir::new("errors-permission");
+    let flags = rusqlite::ffi::SQLITE_OPEN_MAIN_DB
+        | rusqlite::ffi::SQLITE_OPEN_READWRITE
+        | rusqlite::ffi::SQLITE_OPEN_CREATE;
+
+    // writes fail as if the file was read-only ...
+    let path = dir.path("write.db");
+    let mut file = RawFile::open("errors-permission-write", &path, flags).unwrap();
+    assert_eq!(file.write(&[0; 512], 0), rusqlite::ffi::SQLITE_READONLY);
+    assert_eq!(file.truncate(0), rusqlite::ffi::SQLITE_READONLY);
+
+    // ... while other operations fail with an I/O error
+    let path = dir.path("read.db");
+    let mut file = RawFile::open("errors-permission-read", &path, flags).unwrap();
+    assert_eq!(file.write(&[1; 512], 0), rusqlite::ffi::SQLITE_OK);
+    assert_eq!(
+        file.read(&mut [0; 512], 0),
+        rusqlite::ffi::SQLITE_IOERR_ACCESS
+    );
+}
+
+#[test]
+fn busy() {
+    let _vfs = register("errors-busy", FailingVfs(|| VfsError::Busy.into())).unwrap();
+    let dir = TempDir::new("busy");
+    let conn = open(&dir.path("main.db"), "errors-busy");
+
+    let result = conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)");
+    assert_eq!(error_code(result), ErrorCode::DatabaseBusy);
+}
+
+#[test]
+fn would_block_is_busy() {
+    let _vfs = register(
+        "errors-would-block",
+        FailingVfs(|| std::io::Error::new(std::io::ErrorKind::WouldBlock, "injected failure")),
+    )
+    .unwrap();
+    let dir = TempDir::new("would-block");
+    let conn = open(&dir.path("main.db"), "errors-would-block");
+
+    let result = conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)");
+    assert_eq!(error_code(result), ErrorCode::DatabaseBusy);
+}
+
+#[test]
+fn busy_snapshot() {
+    let _vfs = register(
+        "errors-busy-snapshot",
+        FailingVfs(|| VfsError::BusySnapshot.into()),
+    )
+    .unwrap();
+    let dir = TempDir::new("busy-snapshot");
+    let conn = open(&dir.path("main.db"), "errors-busy-snapshot");
+
+    let err = sqlite_error(conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)"));
+    assert_eq!(err.code, ErrorCode::DatabaseBusy);
+    assert_eq!(err.extended_code, rusqlite::ffi::SQLITE_BUSY_SNAPSHOT);
+}
+
+#[test]
+fn specific_codes() {
+    let _vfs = register("errors-auth", FailingVfs(|| VfsError::Auth.into())).unwrap();
+    let _vfs = register(
+        "errors-code",
+        FailingVfs(|| VfsError::Code(rusqlite::ffi::SQLITE_IOERR_DATA).into()),
+    )
+    .unwrap();
+    let dir = TempDir::new("specific-codes");
+
+    let conn = open(&dir.path("auth.db"), "errors-auth");
+    let result = conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)");
+    assert_eq!(
+        error_code(result),
+        ErrorCode::AuthorizationForStatementDenied
+    );
+
+    let (code, message) = write_error("errors-code", &dir.path("code.db"), 512);
+    assert_eq!(code, rusqlite::ffi::SQLITE_IOERR_DATA);
+    assert_eq!(
+        message,
+        format!("sqlite error code {}", rusqlite::ffi::SQLITE_IOERR_DATA)
+    );
+}
+
+/// An error caused by another error.
+#[derive(Debug)]
+struct Caused(
+    &'static str,
+    Option<Box<dyn std::error::Error + Send + Sync>>,
+);
+
+impl std::fmt::Display for Caused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Caused {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.1.as_ref().map(|err| err.as_ref() as _)
+    }
+}
+
+/// Write to `path` by calling the VFS registered as `name` directly, and return the result code
+/// together with the last error message the VFS reports in a buffer of `n_byte` bytes.
+fn write_error(name: &str, path: &Path, n_byte: usize) -> (i32, String) {
+    let flags = rusqlite::ffi::SQLITE_OPEN_MAIN_DB
+        | rusqlite::ffi::SQLITE_OPEN_READWRITE
+        | rusqlite::ffi::SQLITE_OPEN_CREATE;
+    let mut file = RawFile::open(name, path, flags).unwrap();
+    let code = file.write(&[0; 512], 0);
+    (code, file.last_error(n_byte))
+}
+
+#[test]
+fn error_sources() {
+    let failing_vfs = || {
+        FailingVfs(|| {
+            std::io::Error::other(Caused(
+                "request failed",
+                Some(Box::new(Caused(
+                    "tls handshake failed",
+                    Some(Box::new(std::io::Error::from(
+                        std::io::ErrorKind::ConnectionReset,
+                    ))),
+                ))),
+            ))
+        })
+    };
+    let _vfs = register("errors-sources", failing_vfs()).unwrap();
+    let _vfs = register_with_options(
+        "errors-sources-limited",
+        failing_vfs(),
+        RegisterOptions {
+            error_sources: 1,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let dir = TempDir::new("error-sources");
+    let path = dir.path("main.db");
+
+    assert_eq!(
+        write_error("errors-sources", &path, 512),
+        (
+            rusqlite::ffi::SQLITE_IOERR_WRITE,
+            "request failed: tls handshake failed: connection reset".to_string()
+        )
+    );
+    assert_eq!(
+        write_error("errors-sources-limited", &path, 512),
+        (
+            rusqlite::ffi::SQLITE_IOERR_WRITE,
+            "request failed: tls handshake failed".to_string()
+        )
+    );
+
+    // messages that do not fit are truncated
+    assert_eq!(
+        write_error("errors-sources-limited", &path, 8),
+        (rusqlite::ffi::SQLITE_IOERR_WRITE, "request".to_string())
+    );
+}
+
+#[test]
+fn error_messages_across_threads() {
+    let _vfs = register(
+        "errors-threads",
+        FailingVfs(|| std::io::Error::other("injected failure")),
+    )
+    .unwrap();
+    let dir = TempDir::new("error-threads");
+
+    // the VFS is registered on this thread, but used from others
+    std::thread::scope(|scope| {
+        for i in 0..4 {
+            let path = dir.path(&format!("main-{}.db", i));
+            scope.spawn(move || {
+                for _ in 0..10 {
+                    assert_eq!(
+                        write_error("errors-threads", &path, 512),
+                        (
+                            rusqlite::ffi::SQLITE_IOERR_WRITE,
+                            "injected failure".to_string()
+                        )
+                    );
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn short_read() {
+    let _vfs = common::register_fs("errors-short-read");
+    let dir = TempDir::new("errors-short-read");
+    let flags = rusqlite::ffi::SQLITE_OPEN_MAIN_DB
+        | rusqlite::ffi::SQLITE_OPEN_READWRITE
+        | rusqlite::ffi::SQLITE_OPEN_CREATE;
+    let mut file = RawFile::open("errors-short-read", &dir.path("main.db"), flags).unwrap();
+    assert_eq!(file.write(&[1; 100], 0), rusqlite::ffi::SQLITE_OK);
+
+    // the part of the buffer beyond the end of the file is zeroed
+    let mut buf = [2; 512];
+    assert_eq!(
+        file.read(&mut buf, 0),
+        rusqlite::ffi::SQLITE_IOERR_SHORT_READ
+    );
+    assert_eq!(buf[..100], [1; 100]);
+    assert_eq!(buf[100..], [0; 412]);
+}
+
+#[test]
+fn last_error_is_cleared() {
+    let _vfs = register(
+        "errors-cleared",
+        FailingVfs(|| std::io::Error::other("injected failure")),
+    )
+    .unwrap();
+    let dir = TempDir::new("errors-cleared");
+    let flags = rusqlite::ffi::SQLITE_OPEN_MAIN_DB
+        | rusqlite::ffi::SQLITE_OPEN_READWRITE
+        | rusqlite::ffi::SQLITE_OPEN_CREATE;
+    let mut file = RawFile::open("errors-cleared", &dir.path("main.db"), flags).unwrap();
+    assert_eq!(file.write(&[0; 512], 0), rusqlite::ffi::SQLITE_IOERR_WRITE);
+
+    // the calls of other threads neither report nor clear the error
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut other = RawFile::open("errors-cleared", &dir.path("other.db"), flags).unwrap();
+            assert_eq!(other.file_size(), Ok(0));
+            assert_eq!(other.last_error(512), "");
+        });
+    });
+    assert_eq!(file.last_error(512), "injected failure");
+
+    // every successful call clears the error of its thread
+    assert_eq!(file.write(&[0; 512], 0), rusqlite::ffi::SQLITE_IOERR_WRITE);
+    assert_eq!(file.file_size(), Ok(0));
+    assert_eq!(file.last_error(512), "");
+}
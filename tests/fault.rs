@@ -0,0 +1,150 @@
+//! A [FaultVfs] fails writes, shortens reads and crashes when told to, and databases survive its
+//! crashes.
+
+mod common;
+
+use std::path::Path;
+
+use common::{integrity_check, open};
+use rusqlite::{ffi, Connection};
+use sqlite_vfs::fault::{FaultVfs, Faults};
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::{register, File, OpenAccess, OpenKind, OpenOptions, Vfs, VfsHandle};
+
+fn register_faulty(name: &str) -> (VfsHandle, Faults) {
+    let vfs = FaultVfs::new(MemVfs::new());
+    let faults = vfs.faults();
+    (register(name, vfs).unwrap(), faults)
+}
+
+fn create(path: &Path, vfs: &str) -> Connection {
+    let conn = open(path, vfs);
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+    conn
+}
+
+/// Change most pages of the database in one transaction.
+fn update(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute("UPDATE vals SET val = 'changed ' || id", [])
+}
+
+fn changed(conn: &Connection) -> i64 {
+    conn.query_row(
+        "SELECT COUNT(*) FROM vals WHERE val LIKE 'changed %'",
+        [],
+        |row| row.get(0),
+    )
+    .unwrap()
+}
+
+#[test]
+fn failed_write() {
+    let (_vfs, faults) = register_faulty("fault-write");
+    let path = Path::new("/fault-write/main.db");
+    let conn = create(path, "fault-write");
+
+    faults.fail_write(2);
+    match update(&conn) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.extended_code, ffi::SQLITE_IOERR_WRITE)
+        }
+        result => panic!("expected SQLITE_IOERR_WRITE, got {:?}", result),
+    }
+    assert_eq!(changed(&conn), 0);
+
+    // only the scheduled write failed
+    update(&conn).unwrap();
+    assert_eq!(changed(&conn), 500);
+    integrity_check(&conn);
+}
+
+#[test]
+fn short_read() {
+    let vfs = FaultVfs::new(MemVfs::new());
+    let faults = vfs.faults();
+    let opts = OpenOptions {
+        kind: OpenKind::MainDb,
+        access: OpenAccess::Create,
+        delete_on_close: false,
+    };
+    let mut file = vfs.open(Path::new("main.db"), opts).unwrap();
+    file.write_all_at(&[1; 100], 0).unwrap();
+
+    faults.short_read(2);
+    let mut buf = [0; 100];
+    assert_eq!(file.read_at(&mut buf, 0).unwrap(), 100);
+    assert_eq!(file.read_at(&mut buf, 0).unwrap(), 50);
+    assert_eq!(file.read_at(&mut buf, 0).unwrap(), 100);
+}
+
+#[test]
+fn crash_during_transaction() {
+    let (_vfs, faults) = register_faulty("fault-crash");
+    let path = Path::new("/fault-crash/main.db");
+    let conn = create(path, "fault-crash");
+
+    faults.crash_at_write(10);
+    assert!(update(&conn).is_err());
+    assert_eq!(faults.crashes(), 1);
+    // the connection is dead
+    assert!(conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |_| Ok(()))
+        .is_err());
+    drop(conn);
+
+    // the hot journal is rolled back
+    let conn = open(path, "fault-crash");
+    integrity_check(&conn);
+    assert_eq!(changed(&conn), 0);
+}
+
+#[test]
+fn crash_at_every_write() {
+    let (_vfs, faults) = register_faulty("fault-sweep");
+
+    // count the writes of the transaction
+    let conn = create(Path::new("/fault-sweep/count.db"), "fault-sweep");
+    let before = faults.writes();
+    update(&conn).unwrap();
+    let writes = faults.writes() - before;
+    assert!(writes > 2);
+
+    for n in 1..=writes {
+        let path = format!("/fault-sweep/{}.db", n);
+        let conn = create(Path::new(&path), "fault-sweep");
+        faults.crash_at_write(n);
+        assert!(update(&conn).is_err());
+        drop(conn);
+
+        // the transaction is either rolled back, or was already committed
+        let conn = open(Path::new(&path), "fault-sweep");
+        integrity_check(&conn);
+        let changed = changed(&conn);
+        assert!(changed == 0 || changed == 500, "{} rows changed", changed);
+    }
+    assert_eq!(faults.crashes(), writes);
+}
+
+#[test]
+fn dropped_syncs() {
+    let (_vfs, faults) = register_faulty("fault-sync");
+    let path = Path::new("/fault-sync/main.db");
+    let conn = create(path, "fault-sync");
+
+    // the transaction commits, but is not durable
+    faults.drop_syncs(true);
+    update(&conn).unwrap();
+    assert_eq!(changed(&conn), 500);
+    faults.crash().unwrap();
+    drop(conn);
+
+    faults.clear();
+    let conn = open(path, "fault-sync");
+    integrity_check(&conn);
+    assert_eq!(changed(&conn), 0);
+}
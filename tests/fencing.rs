@@ -0,0 +1,72 @@
+//! A second writer that changed the database in the meantime is detected instead of overwritten.
+
+mod common;
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use rusqlite::{ffi, ErrorCode};
+use sqlite_vfs::fencing::FencedVfs;
+use sqlite_vfs::register;
+
+fn count(conn: &rusqlite::Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn sequential_writers() {
+    let _vfs = register("fencing-sequential", FencedVfs::new(FsVfs)).unwrap();
+    let dir = TempDir::new("fencing-sequential");
+    let a = open(&dir.path("main.db"), "fencing-sequential");
+    let b = open(&dir.path("main.db"), "fencing-sequential");
+
+    a.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    for i in 0..10 {
+        let conn = if i % 2 == 0 { &a } else { &b };
+        conn.execute("INSERT INTO vals VALUES (?1)", [i]).unwrap();
+    }
+
+    assert_eq!(count(&a), 10);
+    integrity_check(&b);
+}
+
+#[test]
+fn concurrent_writer() {
+    let _vfs = register("fencing-concurrent", FencedVfs::new(FsVfs)).unwrap();
+    let dir = TempDir::new("fencing-concurrent");
+    let a = open(&dir.path("main.db"), "fencing-concurrent");
+    let b = open(&dir.path("main.db"), "fencing-concurrent");
+    a.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT)")
+        .unwrap();
+
+    // The VFS does not lock, so `b` can commit while `a` is in a transaction (like a second writer
+    // after a split brain).
+    a.execute_batch("BEGIN; SELECT COUNT(*) FROM vals;")
+        .unwrap();
+    b.execute("INSERT INTO vals (text) VALUES ('b')", [])
+        .unwrap();
+    a.execute("INSERT INTO vals (text) VALUES ('a')", [])
+        .unwrap();
+    match a.execute_batch("COMMIT") {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, ErrorCode::SystemIoFailure);
+            assert_eq!(err.extended_code, ffi::SQLITE_IOERR_VNODE);
+        }
+        result => panic!("expected SQLITE_IOERR_VNODE, got {:?}", result),
+    }
+    assert!(a.is_autocommit());
+
+    // the changes of `b` survived, and `a` can retry on top of them
+    a.execute("INSERT INTO vals (text) VALUES ('a')", [])
+        .unwrap();
+    let conn = open(&dir.path("main.db"), "fencing-concurrent");
+    integrity_check(&conn);
+    let texts = conn
+        .prepare("SELECT text FROM vals")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<Vec<String>, _>>()
+        .unwrap();
+    assert_eq!(texts, vec!["b".to_string(), "a".to_string()]);
+}
@@ -0,0 +1,259 @@
+//! File controls and pragmas not handled by the VFS itself are passed to [File::file_control]
+//! and [File::pragma].
+
+mod common;
+
+use std::ffi::CStr;
+use std::io::ErrorKind;
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::{open, FsVfs, TempDir};
+use rusqlite::{ffi, Connection};
+use sqlite_vfs::{register, File, FileControl, OpenKind, OpenOptions, SyncOptions, Vfs};
+
+/// An opcode the file answers with the number of operations it has seen.
+const COUNT_OPERATIONS: c_int = 1_000_001;
+
+type SizeHints = Arc<Mutex<Vec<u64>>>;
+type ChunkSizes = Arc<Mutex<Vec<u32>>>;
+
+#[derive(Default)]
+struct ControlledVfs {
+    size_hints: SizeHints,
+    chunk_sizes: ChunkSizes,
+    moved: Arc<AtomicBool>,
+}
+
+struct ControlledFile {
+    file: std::fs::File,
+    size_hints: Option<SizeHints>,
+    chunk_sizes: ChunkSizes,
+    moved: Arc<AtomicBool>,
+    operations: i64,
+    cache_size: u64,
+}
+
+impl Vfs for ControlledVfs {
+    type File = ControlledFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let size_hints = (opts.kind == OpenKind::MainDb).then(|| Arc::clone(&self.size_hints));
+        Ok(ControlledFile {
+            file: FsVfs.open(path, opts)?,
+            size_hints,
+            chunk_sizes: Arc::clone(&self.chunk_sizes),
+            moved: Arc::clone(&self.moved),
+            operations: 0,
+            cache_size: 100,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for ControlledFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.operations += 1;
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.operations += 1;
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn file_control(&mut self, op: FileControl) -> Result<bool, std::io::Error> {
+        match op {
+            FileControl::SizeHint(size) => match &self.size_hints {
+                Some(size_hints) => {
+                    size_hints.lock().unwrap().push(size);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            FileControl::ChunkSize(size) => {
+                self.chunk_sizes.lock().unwrap().push(size);
+                Ok(true)
+            }
+            FileControl::Raw { op, arg } if op == COUNT_OPERATIONS => {
+                unsafe { *(arg as *mut i64) = self.operations };
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        if !name.eq_ignore_ascii_case("my_cache_size") {
+            return None;
+        }
+        Some(match value {
+            None => Ok(Some(self.cache_size.to_string())),
+            Some(value) => match value.parse() {
+                Ok(size) => {
+                    self.cache_size = size;
+                    Ok(None)
+                }
+                Err(_) => Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid cache size: {}", value),
+                )),
+            },
+        })
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        Ok(self.moved.load(Ordering::SeqCst))
+    }
+}
+
+fn file_control<T>(conn: &Connection, op: c_int, arg: &mut T) -> c_int {
+    unsafe {
+        ffi::sqlite3_file_control(conn.handle(), c"main".as_ptr(), op, arg as *mut T as *mut _)
+    }
+}
+
+#[test]
+fn typed_and_raw_operations() {
+    let vfs = ControlledVfs::default();
+    let size_hints = Arc::clone(&vfs.size_hints);
+    let _vfs = register("file-control", vfs).unwrap();
+    let dir = TempDir::new("file-control");
+    let conn = open(&dir.path("main.db"), "file-control");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+
+    // SQLite announces the growth of the database itself ...
+    assert_eq!(*size_hints.lock().unwrap(), vec![8192]);
+
+    // ... and applications can send any file control as well
+    let mut size = 1 << 20;
+    assert_eq!(
+        file_control(&conn, ffi::SQLITE_FCNTL_SIZE_HINT, &mut size),
+        ffi::SQLITE_OK
+    );
+    assert_eq!(*size_hints.lock().unwrap(), vec![8192, 1 << 20]);
+
+    let mut operations = 0;
+    assert_eq!(
+        file_control(&conn, COUNT_OPERATIONS, &mut operations),
+        ffi::SQLITE_OK
+    );
+    assert!(operations > 0);
+
+    assert_eq!(
+        file_control(&conn, COUNT_OPERATIONS + 1, &mut operations),
+        ffi::SQLITE_NOTFOUND
+    );
+}
+
+#[test]
+fn chunk_size() {
+    let vfs = ControlledVfs::default();
+    let chunk_sizes = Arc::clone(&vfs.chunk_sizes);
+    let _vfs = register("file-control-chunk-size", vfs).unwrap();
+    let dir = TempDir::new("file-control-chunk-size");
+    let conn = open(&dir.path("main.db"), "file-control-chunk-size");
+
+    let mut size: c_int = 1 << 16;
+    assert_eq!(
+        file_control(&conn, ffi::SQLITE_FCNTL_CHUNK_SIZE, &mut size),
+        ffi::SQLITE_OK
+    );
+    assert_eq!(*chunk_sizes.lock().unwrap(), vec![1 << 16]);
+}
+
+#[test]
+fn vfs_name() {
+    let _vfs = register("file-control-vfs-name", ControlledVfs::default()).unwrap();
+    let dir = TempDir::new("file-control-vfs-name");
+    let conn = open(&dir.path("main.db"), "file-control-vfs-name");
+
+    let mut name: *mut c_char = std::ptr::null_mut();
+    assert_eq!(
+        file_control(&conn, ffi::SQLITE_FCNTL_VFSNAME, &mut name),
+        ffi::SQLITE_OK
+    );
+    assert_eq!(
+        unsafe { CStr::from_ptr(name) }.to_str().unwrap(),
+        "file-control-vfs-name"
+    );
+    unsafe { ffi::sqlite3_free(name as *mut _) };
+}
+
+#[test]
+fn moved_database() {
+    let vfs = ControlledVfs::default();
+    let moved = Arc::clone(&vfs.moved);
+    let _vfs = register("file-control-moved", vfs).unwrap();
+    let dir = TempDir::new("file-control-moved");
+    let conn = open(&dir.path("main.db"), "file-control-moved");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+
+    moved.store(true, Ordering::SeqCst);
+    match conn.execute("INSERT INTO vals VALUES (1)", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.extended_code, ffi::SQLITE_READONLY_DBMOVED)
+        }
+        result => panic!("expected SQLITE_READONLY_DBMOVED, got {:?}", result),
+    }
+
+    moved.store(false, Ordering::SeqCst);
+    conn.execute("INSERT INTO vals VALUES (1)", []).unwrap();
+}
+
+#[test]
+fn custom_pragma() {
+    let _vfs = register("file-control-pragma", ControlledVfs::default()).unwrap();
+    let dir = TempDir::new("file-control-pragma");
+    let conn = open(&dir.path("main.db"), "file-control-pragma");
+    let cache_size = |conn: &Connection| -> String {
+        conn.query_row("PRAGMA my_cache_size", [], |row| row.get(0))
+            .unwrap()
+    };
+
+    assert_eq!(cache_size(&conn), "100");
+    conn.execute_batch("PRAGMA MY_CACHE_SIZE = '250'").unwrap();
+    assert_eq!(cache_size(&conn), "250");
+
+    match conn.execute_batch("PRAGMA my_cache_size = lots") {
+        Err(rusqlite::Error::SqliteFailure(err, Some(message))) => {
+            assert_eq!(err.code, rusqlite::ErrorCode::Unknown);
+            assert_eq!(message, "invalid cache size: lots");
+        }
+        result => panic!("expected the pragma to fail, got {:?}", result),
+    }
+    assert_eq!(cache_size(&conn), "250");
+
+    // unknown pragmas are still left to SQLite
+    let page_size: i64 = conn
+        .query_row("PRAGMA page_size", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(page_size, 4096);
+}
@@ -0,0 +1,145 @@
+//! [VfsHandle::collect_garbage](sqlite_vfs::VfsHandle::collect_garbage) removes the journals and
+//! WALs left behind by crashed processes, but keeps those still in use or to be recovered.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use common::{open, register_fs, RawFile, TempDir};
+use rusqlite::ffi;
+use sqlite_vfs::gc::{GarbageOptions, GarbageReport};
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::register;
+
+fn paths(report: &GarbageReport) -> [Vec<PathBuf>; 3] {
+    [&report.orphaned, &report.in_use, &report.needs_recovery]
+        .map(|entries| entries.iter().map(|entry| entry.path.clone()).collect())
+}
+
+#[test]
+fn missing_databases() {
+    let vfs = register_fs("gc-missing");
+    let dir = TempDir::new("gc-missing");
+    let (journal, wal) = (dir.path("gone.db-journal"), dir.path("gone.db-wal"));
+    fs::write(&journal, [1; 512]).unwrap();
+    fs::write(&wal, [1; 4096]).unwrap();
+    fs::write(dir.path("other.db-shm"), [1; 64]).unwrap();
+
+    // the side files were just written
+    let options = GarbageOptions {
+        min_age: Duration::from_secs(3600),
+        ..Default::default()
+    };
+    let report = vfs.collect_garbage(dir.path(""), options).unwrap();
+    assert_eq!(report, GarbageReport::default());
+
+    let options = GarbageOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let report = vfs.collect_garbage(dir.path(""), options).unwrap();
+    assert_eq!(
+        paths(&report),
+        [vec![journal.clone(), wal.clone()], vec![], vec![]]
+    );
+    assert!(journal.exists() && wal.exists());
+
+    let report = vfs
+        .collect_garbage(dir.path(""), GarbageOptions::default())
+        .unwrap();
+    assert_eq!(paths(&report)[0], [journal.clone(), wal.clone()]);
+    assert!(!journal.exists() && !wal.exists());
+    assert!(dir.path("other.db-shm").exists());
+}
+
+#[test]
+fn side_files_in_use() {
+    let vfs = register_fs("gc-in-use");
+    let dir = TempDir::new("gc-in-use");
+
+    // connections keep a shared lock on databases in WAL mode
+    let wal = open(&dir.path("wal.db"), "gc-in-use");
+    wal.query_row("PRAGMA journal_mode = wal", [], |_| Ok(()))
+        .unwrap();
+    wal.execute_batch("CREATE TABLE vals (val TEXT); INSERT INTO vals VALUES ('wal');")
+        .unwrap();
+
+    // a writer holds a reserved lock while writing the journal
+    let journal = open(&dir.path("journal.db"), "gc-in-use");
+    journal
+        .execute_batch(
+            "CREATE TABLE vals (val TEXT);
+            BEGIN IMMEDIATE;
+            INSERT INTO vals VALUES ('journal');",
+        )
+        .unwrap();
+
+    let report = vfs
+        .collect_garbage(dir.path(""), GarbageOptions::default())
+        .unwrap();
+    assert_eq!(
+        paths(&report),
+        [
+            vec![],
+            vec![dir.path("journal.db-journal"), dir.path("wal.db-wal")],
+            vec![]
+        ]
+    );
+
+    journal.execute_batch("COMMIT").unwrap();
+    let count: i64 = wal
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn recovery() {
+    let vfs = register_fs("gc-recovery");
+    let dir = TempDir::new("gc-recovery");
+    for name in ["hot.db", "persisted.db", "wal.db", "empty.db"] {
+        open(&dir.path(name), "gc-recovery")
+            .execute_batch("CREATE TABLE vals (val TEXT);")
+            .unwrap();
+    }
+    // a hot journal and a WAL with frames hold changes to recover, while a journal with a zeroed
+    // header (as left by `journal_mode = PERSIST`) and an empty WAL do not
+    fs::write(dir.path("hot.db-journal"), [0xd9; 512]).unwrap();
+    fs::write(dir.path("persisted.db-journal"), [0; 512]).unwrap();
+    fs::write(dir.path("wal.db-wal"), [1; 4096]).unwrap();
+    fs::write(dir.path("empty.db-wal"), []).unwrap();
+
+    let report = vfs
+        .collect_garbage(dir.path(""), GarbageOptions::default())
+        .unwrap();
+    assert_eq!(
+        paths(&report),
+        [
+            vec![dir.path("empty.db-wal"), dir.path("persisted.db-journal")],
+            vec![],
+            vec![dir.path("hot.db-journal"), dir.path("wal.db-wal")]
+        ]
+    );
+    assert!(!dir.path("persisted.db-journal").exists());
+    assert!(dir.path("hot.db-journal").exists() && dir.path("wal.db-wal").exists());
+}
+
+#[test]
+fn mem() {
+    let vfs = register("gc-mem", MemVfs::new()).unwrap();
+    open(Path::new("/main.db"), "gc-mem")
+        .execute_batch("CREATE TABLE vals (val TEXT);")
+        .unwrap();
+    let flags =
+        ffi::SQLITE_OPEN_MAIN_JOURNAL | ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+    drop(RawFile::open("gc-mem", Path::new("/gone.db-journal"), flags).unwrap());
+
+    let report = vfs.collect_garbage("/", GarbageOptions::default()).unwrap();
+    assert_eq!(paths(&report)[0], [PathBuf::from("/gone.db-journal")]);
+    assert_eq!(
+        vfs.collect_garbage("/", GarbageOptions::default()).unwrap(),
+        GarbageReport::default()
+    );
+}
@@ -0,0 +1,92 @@
+//! Decode the database header through a [Vfs] without opening a connection.
+
+mod common;
+
+use std::fs;
+use std::io::ErrorKind;
+
+use common::{open, FsVfs, TempDir};
+use sqlite_vfs::header::{read_header, JournalMode, TextEncoding};
+use sqlite_vfs::{require_reserve_bytes, reserve_bytes, set_reserve_bytes};
+
+#[test]
+fn decode_header() {
+    let _vfs = common::register_fs("header-decode");
+    let dir = TempDir::new("header-decode");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "header-decode");
+    conn.execute_batch(
+        "PRAGMA page_size = 8192;
+        PRAGMA encoding = 'UTF-16le';
+        PRAGMA user_version = 42;
+        PRAGMA application_id = 7;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY);
+        INSERT INTO vals VALUES (1);",
+    )
+    .unwrap();
+
+    let header = read_header(&FsVfs, &path).unwrap().unwrap();
+    assert_eq!(header.page_size, 8192);
+    assert_eq!(header.journal_mode, JournalMode::Rollback);
+    assert_eq!(header.reserve_bytes, 0);
+    assert_eq!(header.version_valid_for, header.change_counter);
+    assert_eq!(header.page_count, 2);
+    assert_eq!(header.freelist_pages, 0);
+    assert_eq!(header.text_encoding, Some(TextEncoding::Utf16Le));
+    assert_eq!(header.user_version, 42);
+    assert_eq!(header.application_id, 7);
+    assert_eq!(header.sqlite_version, rusqlite::version_number() as u32);
+
+    conn.execute("INSERT INTO vals VALUES (2)", []).unwrap();
+    let changed = read_header(&FsVfs, &path).unwrap().unwrap();
+    assert_eq!(changed.change_counter, header.change_counter + 1);
+
+    conn.execute_batch("PRAGMA journal_mode = WAL").unwrap();
+    drop(conn);
+    let header = read_header(&FsVfs, &path).unwrap().unwrap();
+    assert_eq!(header.journal_mode, JournalMode::Wal);
+}
+
+#[test]
+fn no_database() {
+    let dir = TempDir::new("header-no-database");
+
+    fs::write(dir.path("empty.db"), b"").unwrap();
+    assert_eq!(read_header(&FsVfs, &dir.path("empty.db")).unwrap(), None);
+
+    fs::write(dir.path("text.db"), b"not a database").unwrap();
+    let err = read_header(&FsVfs, &dir.path("text.db")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let err = read_header(&FsVfs, &dir.path("missing.db")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn reserved_bytes() {
+    let _vfs = common::register_fs("header-reserve-bytes");
+    let dir = TempDir::new("header-reserve-bytes");
+    let path = dir.path("main.db");
+    let conn = open(&path, "header-reserve-bytes");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    assert_eq!(unsafe { reserve_bytes(conn.handle(), c"main") }.unwrap(), 0);
+    let mut file = fs::File::open(&path).unwrap();
+    let err = require_reserve_bytes(&mut file, 8).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    // applied once the database is rebuilt
+    assert_eq!(
+        unsafe { set_reserve_bytes(conn.handle(), c"main", 8) }.unwrap(),
+        0
+    );
+    conn.execute_batch("VACUUM").unwrap();
+    assert_eq!(unsafe { reserve_bytes(conn.handle(), c"main") }.unwrap(), 8);
+    let header = read_header(&FsVfs, &path).unwrap().unwrap();
+    assert_eq!(header.reserve_bytes, 8);
+    require_reserve_bytes(&mut file, 8).unwrap();
+
+    let err = unsafe { reserve_bytes(conn.handle(), c"missing") }.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+}
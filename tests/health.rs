@@ -0,0 +1,92 @@
+//! `PRAGMA vfs_health` reports the health of the storage of a VFS.
+
+mod common;
+
+use std::path::Path;
+use std::time::Duration;
+
+use common::{open, register_fs, FsVfs, TempDir};
+use sqlite_vfs::{register, HealthReport, OpenOptions, Vfs};
+
+/// A VFS that reports the given health.
+struct ReplicatedVfs(fn() -> HealthReport);
+
+impl Vfs for ReplicatedVfs {
+    type File = std::fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        FsVfs.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+
+    fn health(&self) -> HealthReport {
+        (self.0)()
+    }
+}
+
+fn vfs_health(conn: &rusqlite::Connection) -> String {
+    conn.query_row("PRAGMA vfs_health", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn default_health() {
+    let _vfs = register_fs("health-default");
+    let dir = TempDir::new("health-default");
+    let conn = open(&dir.path("main.db"), "health-default");
+
+    assert_eq!(vfs_health(&conn), "reachable=true");
+}
+
+#[test]
+fn reported_health() {
+    let _vfs = register(
+        "health-replicated",
+        ReplicatedVfs(|| HealthReport {
+            replication_lag: Some(Duration::from_millis(1500)),
+            cache_hit_rate: Some(0.75),
+            details: vec![("region".to_string(), "eu-west".to_string())],
+            ..HealthReport::reachable()
+        }),
+    )
+    .unwrap();
+    let dir = TempDir::new("health-replicated");
+    let conn = open(&dir.path("main.db"), "health-replicated");
+
+    assert_eq!(
+        vfs_health(&conn),
+        "reachable=true replication_lag=1500ms cache_hit_rate=0.750 region=eu-west"
+    );
+}
+
+#[test]
+fn unreachable() {
+    let _vfs = register(
+        "health-unreachable",
+        ReplicatedVfs(HealthReport::unreachable),
+    )
+    .unwrap();
+    let dir = TempDir::new("health-unreachable");
+    let conn = open(&dir.path("main.db"), "health-unreachable");
+
+    assert_eq!(vfs_health(&conn), "reachable=false");
+}
+
+#[test]
+fn other_pragmas_unaffected() {
+    let _vfs = register_fs("health-other-pragmas");
+    let dir = TempDir::new("health-other-pragmas");
+    let conn = open(&dir.path("main.db"), "health-other-pragmas");
+
+    let page_size: i64 = conn
+        .query_row("PRAGMA page_size", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(page_size, 4096);
+}
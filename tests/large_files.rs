@@ -0,0 +1,52 @@
+//! Offsets and sizes beyond 4 GiB survive the round trip through the VFS.
+//!
+//! The test relies on sparse files, so it only runs on Unix.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::{register_fs, RawFile, TempDir};
+use rusqlite::ffi;
+
+const GIB: i64 = 1024 * 1024 * 1024;
+
+fn open_main_db(vfs: &str, dir: &TempDir) -> RawFile {
+    RawFile::open(
+        vfs,
+        &dir.path("main.db"),
+        ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+    )
+    .unwrap()
+}
+
+#[test]
+fn beyond_4_gib() {
+    let _vfs = register_fs("large-files");
+    let dir = TempDir::new("large-files");
+    let mut file = open_main_db("large-files", &dir);
+
+    let offset = 5 * GIB;
+    let page = [42u8; 4096];
+    assert_eq!(file.write(&page, offset), ffi::SQLITE_OK);
+    assert_eq!(file.file_size(), Ok(offset + 4096));
+
+    let mut buf = [0u8; 4096];
+    assert_eq!(file.read(&mut buf, offset), ffi::SQLITE_OK);
+    assert_eq!(buf, page);
+
+    assert_eq!(file.truncate(4 * GIB + 512), ffi::SQLITE_OK);
+    assert_eq!(file.file_size(), Ok(4 * GIB + 512));
+}
+
+#[test]
+fn negative_offsets() {
+    let _vfs = register_fs("large-files-negative");
+    let dir = TempDir::new("large-files-negative");
+    let mut file = open_main_db("large-files-negative", &dir);
+
+    assert_eq!(file.write(&[0; 512], -512), ffi::SQLITE_IOERR_WRITE);
+    assert_eq!(file.read(&mut [0; 512], -512), ffi::SQLITE_IOERR_READ);
+    assert_eq!(file.truncate(-1), ffi::SQLITE_IOERR_TRUNCATE);
+    assert_eq!(file.file_size(), Ok(0));
+}
@@ -0,0 +1,65 @@
+//! `load_extension()` loads libraries through the VFS with the `loadext` feature, and fails
+//! without it.
+
+mod common;
+
+use std::ffi::{CStr, CString};
+use std::ptr::null_mut;
+
+use common::{open, TempDir};
+use rusqlite::{ffi, Connection};
+
+fn load_extension(conn: &Connection, file: &str, entry_point: &str) -> Result<(), String> {
+    let file = CString::new(file).unwrap();
+    let entry_point = CString::new(entry_point).unwrap();
+    let mut message = null_mut();
+    unsafe {
+        assert_eq!(
+            ffi::sqlite3_enable_load_extension(conn.handle(), 1),
+            ffi::SQLITE_OK
+        );
+        let code = ffi::sqlite3_load_extension(
+            conn.handle(),
+            file.as_ptr(),
+            entry_point.as_ptr(),
+            &mut message,
+        );
+        if code == ffi::SQLITE_OK {
+            return Ok(());
+        }
+        let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+        ffi::sqlite3_free(message as *mut _);
+        Err(text)
+    }
+}
+
+#[cfg(not(feature = "loadext"))]
+#[test]
+fn unsupported() {
+    let _vfs = common::register_fs("loadext-unsupported");
+    let dir = TempDir::new("loadext-unsupported");
+    let conn = open(&dir.path("main.db"), "loadext-unsupported");
+    let err = load_extension(&conn, "libm.so.6", "sqlite3_extension_init").unwrap_err();
+    assert_eq!(err, "Loadable extensions are not supported");
+}
+
+#[cfg(feature = "loadext")]
+#[test]
+fn missing_library() {
+    let _vfs = common::register_fs("loadext-missing");
+    let dir = TempDir::new("loadext-missing");
+    let conn = open(&dir.path("main.db"), "loadext-missing");
+    let err = load_extension(&conn, "/nonexistent/ext", "sqlite3_extension_init").unwrap_err();
+    assert!(err.contains("/nonexistent/ext"), "{}", err);
+}
+
+#[cfg(all(feature = "loadext", target_os = "linux"))]
+#[test]
+fn missing_entry_point() {
+    let _vfs = common::register_fs("loadext-entry-point");
+    let dir = TempDir::new("loadext-entry-point");
+    let conn = open(&dir.path("main.db"), "loadext-entry-point");
+    // the library is opened, but does not contain the entry point
+    let err = load_extension(&conn, "libm.so.6", "sqlite3_extension_init").unwrap_err();
+    assert!(err.contains("sqlite3_extension_init"), "{}", err);
+}
@@ -0,0 +1,525 @@
+//! Model checks of the lock manager (behind the glue's `xLock`, `xUnlock` and `xClose`, and
+//! [VfsHandle::unregister](sqlite_vfs::VfsHandle::unregister)) and of the shared-memory locks of
+//! [ShmVfs], which explore every interleaving of a few connections exhaustively.
+//!
+//! Each lock operation runs atomically under the lock of the file (or of the shared memory), so
+//! interleaving whole operations covers everything the threads of real connections can do. The
+//! explorer replays the steps that lead to each state on a fresh VFS, which makes it deterministic
+//! without a model checking crate. It checks the safety invariants in every reachable state, and
+//! that every reachable state can still reach the end of all connections (no deadlock).
+
+mod common;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::os::raw::c_int;
+use std::path::Path;
+
+use common::RawFile;
+use rusqlite::ffi;
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::shm::{ShmPool, ShmVfs};
+use sqlite_vfs::{register, File, OpenAccess, OpenKind, OpenOptions, ShmLock, Vfs, VfsHandle};
+
+/// A set of actors, each running a program whose program counter is advanced by [Model::step].
+trait Model {
+    type World;
+
+    /// A fresh world in which all actors are at the start of their programs.
+    fn world(&self) -> Self::World;
+
+    /// Whether `actor` has finished its program at `pc`.
+    fn done(&self, actor: usize, pc: usize) -> bool;
+
+    /// Run the step of `actor` at `pc` (or close its file if `close` is set) and return its next
+    /// program counter, which is `pc` again if it has to wait.
+    fn step(&self, world: &mut Self::World, actor: usize, pc: usize, close: bool) -> usize;
+
+    /// Check the invariants after a step led from `before` to `after`.
+    fn check(&self, world: &Self::World, before: &[usize], after: &[usize]);
+}
+
+/// Explore all interleavings of the steps of `actors` actors of `model`, and return the number of
+/// states reached.
+fn explore<M: Model>(model: &M, actors: usize) -> usize {
+    let start = vec![0; actors];
+    // the steps that first led to each state, which are replayed to get there again
+    let mut paths = HashMap::from([(start.clone(), Vec::<(usize, bool)>::new())]);
+    let mut edges = HashMap::<Vec<usize>, Vec<Vec<usize>>>::new();
+    let mut queue = VecDeque::from([start]);
+    while let Some(pcs) = queue.pop_front() {
+        let path = paths[&pcs].clone();
+        let mut next_states = Vec::new();
+        for actor in (0..actors).filter(|actor| !model.done(*actor, pcs[*actor])) {
+            for close in [false, true] {
+                let mut world = model.world();
+                let mut replayed = vec![0; actors];
+                for &(actor, close) in &path {
+                    replayed[actor] = model.step(&mut world, actor, replayed[actor], close);
+                }
+                assert_eq!(replayed, pcs, "replaying {:?} is not deterministic", path);
+
+                let mut next = pcs.clone();
+                next[actor] = model.step(&mut world, actor, pcs[actor], close);
+                let mut next_path = path.clone();
+                next_path.push((actor, close));
+                let check = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    model.check(&world, &pcs, &next)
+                }));
+                if let Err(err) = check {
+                    eprintln!("invariant violated after {:?}", next_path);
+                    std::panic::resume_unwind(err);
+                }
+                if next == pcs {
+                    continue;
+                }
+                next_states.push(next.clone());
+                if !paths.contains_key(&next) {
+                    paths.insert(next.clone(), next_path);
+                    queue.push_back(next);
+                }
+            }
+        }
+        edges.insert(pcs, next_states);
+    }
+
+    // every state can reach the state in which all actors are done
+    let mut reverse = HashMap::<&Vec<usize>, Vec<&Vec<usize>>>::new();
+    for (state, next_states) in &edges {
+        for next in next_states {
+            reverse.entry(next).or_default().push(state);
+        }
+    }
+    let mut live = edges
+        .keys()
+        .filter(|pcs| (0..actors).all(|actor| model.done(actor, pcs[actor])))
+        .collect::<HashSet<_>>();
+    assert!(!live.is_empty(), "the actors never finish");
+    let mut queue = live.iter().copied().collect::<VecDeque<_>>();
+    while let Some(state) = queue.pop_front() {
+        for previous in reverse.get(state).into_iter().flatten() {
+            if live.insert(previous) {
+                queue.push_back(previous);
+            }
+        }
+    }
+    for state in edges.keys() {
+        assert!(
+            live.contains(state),
+            "deadlock: {:?} (reached with {:?}) cannot finish",
+            state,
+            paths[state]
+        );
+    }
+    edges.len()
+}
+
+/// The programs of the connections of [LockModel].
+#[derive(Clone, Copy)]
+enum Program {
+    /// Lock `SHARED`, unlock, close.
+    Reader,
+    /// Lock `SHARED`, `RESERVED` (giving up when busy) and `EXCLUSIVE` (waiting on a pending lock
+    /// when busy), then unlock to `SHARED` and `NONE` and close.
+    Writer,
+    /// Unregister the VFS (retrying while files are open).
+    Unregister,
+}
+
+/// The lock each step of a [Program::Writer] holds before it runs.
+const WRITER_LOCKS: [c_int; 8] = [
+    ffi::SQLITE_LOCK_NONE,
+    ffi::SQLITE_LOCK_SHARED,
+    ffi::SQLITE_LOCK_RESERVED,
+    ffi::SQLITE_LOCK_PENDING,
+    ffi::SQLITE_LOCK_EXCLUSIVE,
+    ffi::SQLITE_LOCK_SHARED,
+    ffi::SQLITE_LOCK_NONE,
+    ffi::SQLITE_LOCK_NONE,
+];
+const WRITER_CLOSED: usize = 7;
+const READER_CLOSED: usize = 3;
+
+/// Connections locking the same database through the glue, and a thread unregistering the VFS.
+struct LockModel {
+    name: &'static str,
+    programs: Vec<Program>,
+}
+
+struct LockWorld {
+    // closed before the VFS is unregistered when the world is dropped
+    files: Vec<Option<RawFile>>,
+    vfs: Option<VfsHandle>,
+}
+
+impl LockModel {
+    /// The lock held by `actor` at `pc`.
+    fn held(&self, actor: usize, pc: usize) -> c_int {
+        match self.programs[actor] {
+            Program::Reader if pc == 1 => ffi::SQLITE_LOCK_SHARED,
+            Program::Writer => WRITER_LOCKS[pc],
+            _ => ffi::SQLITE_LOCK_NONE,
+        }
+    }
+
+    fn closed(&self, actor: usize, pc: usize) -> bool {
+        match self.programs[actor] {
+            Program::Reader => pc == READER_CLOSED,
+            Program::Writer => pc == WRITER_CLOSED,
+            Program::Unregister => pc == 1,
+        }
+    }
+}
+
+impl Model for LockModel {
+    type World = LockWorld;
+
+    fn world(&self) -> LockWorld {
+        let vfs = register(self.name, MemVfs::new()).unwrap();
+        let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+        let files = self
+            .programs
+            .iter()
+            .map(|program| match program {
+                Program::Unregister => None,
+                _ => Some(RawFile::open(self.name, Path::new("/main.db"), flags).unwrap()),
+            })
+            .collect();
+        LockWorld {
+            files,
+            vfs: Some(vfs),
+        }
+    }
+
+    fn done(&self, actor: usize, pc: usize) -> bool {
+        self.closed(actor, pc)
+    }
+
+    fn step(&self, world: &mut LockWorld, actor: usize, pc: usize, close: bool) -> usize {
+        let program = self.programs[actor];
+        if let Program::Unregister = program {
+            return match world.vfs.take().unwrap().unregister() {
+                Ok(()) => 1,
+                Err(vfs) => {
+                    world.vfs = Some(vfs);
+                    0
+                }
+            };
+        }
+        let closed = match program {
+            Program::Reader => READER_CLOSED,
+            _ => WRITER_CLOSED,
+        };
+        if close || pc == closed - 1 {
+            // closing a file releases its locks
+            world.files[actor] = None;
+            return closed;
+        }
+
+        let file = world.files[actor].as_mut().unwrap();
+        let ok = |code: c_int| match code {
+            ffi::SQLITE_OK => true,
+            ffi::SQLITE_BUSY => false,
+            code => panic!("lock failed with {}", code),
+        };
+        match (program, pc) {
+            (_, 0) => match ok(file.lock(ffi::SQLITE_LOCK_SHARED)) {
+                true => 1,
+                false => 0,
+            },
+            (Program::Reader, 1) => {
+                assert_eq!(file.unlock(ffi::SQLITE_LOCK_NONE), ffi::SQLITE_OK);
+                2
+            }
+            (Program::Writer, 1) => match ok(file.lock(ffi::SQLITE_LOCK_RESERVED)) {
+                true => 2,
+                false => {
+                    // SQLite returns SQLITE_BUSY to the application, which tries again
+                    assert_eq!(file.unlock(ffi::SQLITE_LOCK_NONE), ffi::SQLITE_OK);
+                    0
+                }
+            },
+            (Program::Writer, 2 | 3) => match ok(file.lock(ffi::SQLITE_LOCK_EXCLUSIVE)) {
+                true => 4,
+                // the pending lock is kept while waiting for the readers
+                false => 3,
+            },
+            (Program::Writer, 4) => {
+                assert_eq!(file.unlock(ffi::SQLITE_LOCK_SHARED), ffi::SQLITE_OK);
+                5
+            }
+            (Program::Writer, 5) => {
+                assert_eq!(file.unlock(ffi::SQLITE_LOCK_NONE), ffi::SQLITE_OK);
+                6
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn check(&self, world: &LockWorld, before: &[usize], after: &[usize]) {
+        let held = |pcs: &[usize]| {
+            (0..pcs.len())
+                .map(|actor| self.held(actor, pcs[actor]))
+                .collect::<Vec<_>>()
+        };
+        let (before, after_held) = (held(before), held(after));
+        let writers = after_held
+            .iter()
+            .filter(|lock| **lock >= ffi::SQLITE_LOCK_RESERVED)
+            .count();
+        assert!(writers <= 1, "several writers: {:?}", after_held);
+        if let Some(exclusive) = after_held
+            .iter()
+            .position(|lock| *lock == ffi::SQLITE_LOCK_EXCLUSIVE)
+        {
+            for (actor, lock) in after_held.iter().enumerate() {
+                assert!(
+                    actor == exclusive || *lock == ffi::SQLITE_LOCK_NONE,
+                    "lock beside an exclusive lock: {:?}",
+                    after_held
+                );
+            }
+        }
+        // a pending lock keeps new readers out
+        for (actor, lock) in after_held.iter().enumerate() {
+            let others_pending = before
+                .iter()
+                .enumerate()
+                .any(|(other, lock)| other != actor && *lock >= ffi::SQLITE_LOCK_PENDING);
+            assert!(
+                !(others_pending
+                    && before[actor] == ffi::SQLITE_LOCK_NONE
+                    && *lock == ffi::SQLITE_LOCK_SHARED),
+                "new reader beside a pending lock: {:?} -> {:?}",
+                before,
+                after_held
+            );
+        }
+
+        // the VFS is only unregistered (and freed) once all files are closed
+        let unregistered = world.vfs.is_none();
+        let open = (0..after.len()).any(|actor| !self.closed(actor, after[actor]));
+        match self
+            .programs
+            .iter()
+            .position(|p| matches!(p, Program::Unregister))
+        {
+            Some(_) if unregistered => assert!(!open, "unregistered with open files"),
+            Some(_) => {}
+            None => assert!(!unregistered),
+        }
+        if let Some(vfs) = &world.vfs {
+            let files = after
+                .iter()
+                .enumerate()
+                .filter(|(actor, pc)| {
+                    !matches!(self.programs[*actor], Program::Unregister)
+                        && !self.closed(*actor, **pc)
+                })
+                .count();
+            assert_eq!(vfs.open_files(), files);
+        }
+    }
+}
+
+#[test]
+fn writers() {
+    let model = LockModel {
+        name: "model-writers",
+        programs: vec![Program::Writer, Program::Writer, Program::Writer],
+    };
+    let states = explore(&model, 3);
+    assert!(states > 100, "{} states", states);
+}
+
+#[test]
+fn readers_and_writers() {
+    let model = LockModel {
+        name: "model-readers-writers",
+        programs: vec![
+            Program::Reader,
+            Program::Writer,
+            Program::Reader,
+            Program::Writer,
+        ],
+    };
+    let states = explore(&model, 4);
+    assert!(states > 100, "{} states", states);
+}
+
+#[test]
+fn unregister() {
+    let model = LockModel {
+        name: "model-unregister",
+        programs: vec![Program::Writer, Program::Reader, Program::Unregister],
+    };
+    let states = explore(&model, 3);
+    assert!(states > 10, "{} states", states);
+}
+
+/// The programs of the connections of [ShmModel], with the slots they lock.
+#[derive(Clone, Copy)]
+enum ShmProgram {
+    /// Lock the read slot shared, unlock, close.
+    Reader,
+    /// Lock the write slot exclusively, unlock, close.
+    Writer,
+    /// Lock the checkpoint slot and then all read slots exclusively (giving up on both when the
+    /// readers are busy), unlock both, close.
+    Checkpointer,
+}
+
+const WRITE_SLOT: Range<u8> = 0..1;
+const CHECKPOINT_SLOT: Range<u8> = 1..2;
+const READ_SLOT: Range<u8> = 3..4;
+const READ_SLOTS: Range<u8> = 3..8;
+
+/// Connections locking the shared memory of the same database in WAL mode.
+struct ShmModel {
+    programs: Vec<ShmProgram>,
+}
+
+type ShmFile = <ShmVfs<MemVfs> as Vfs>::File;
+
+struct ShmWorld {
+    files: Vec<Option<ShmFile>>,
+    pool: ShmPool,
+}
+
+impl ShmModel {
+    fn closed(&self, actor: usize) -> usize {
+        match self.programs[actor] {
+            ShmProgram::Reader | ShmProgram::Writer => 3,
+            ShmProgram::Checkpointer => 5,
+        }
+    }
+
+    /// The slots `actor` holds at `pc`, and whether it holds them exclusively.
+    fn held(&self, actor: usize, pc: usize) -> Vec<(Range<u8>, bool)> {
+        match (self.programs[actor], pc) {
+            (ShmProgram::Reader, 1) => vec![(READ_SLOT, false)],
+            (ShmProgram::Writer, 1) => vec![(WRITE_SLOT, true)],
+            (ShmProgram::Checkpointer, 1 | 3) => vec![(CHECKPOINT_SLOT, true)],
+            (ShmProgram::Checkpointer, 2) => vec![(CHECKPOINT_SLOT, true), (READ_SLOTS, true)],
+            _ => vec![],
+        }
+    }
+}
+
+impl Model for ShmModel {
+    type World = ShmWorld;
+
+    fn world(&self) -> ShmWorld {
+        let vfs = ShmVfs::new(MemVfs::new());
+        let opts = OpenOptions {
+            kind: OpenKind::MainDb,
+            access: OpenAccess::Create,
+            delete_on_close: false,
+        };
+        let files = self
+            .programs
+            .iter()
+            .map(|_| Some(vfs.open(Path::new("/main.db"), opts.clone()).unwrap()))
+            .collect();
+        ShmWorld {
+            files,
+            pool: vfs.pool(),
+        }
+    }
+
+    fn done(&self, actor: usize, pc: usize) -> bool {
+        pc == self.closed(actor)
+    }
+
+    fn step(&self, world: &mut ShmWorld, actor: usize, pc: usize, close: bool) -> usize {
+        let closed = self.closed(actor);
+        if close || pc == closed - 1 {
+            // closing a connection releases its locks
+            world.files[actor] = None;
+            return closed;
+        }
+
+        let file = world.files[actor].as_mut().unwrap();
+        let shm = file.shared_memory().unwrap();
+        let mut lock = |slots, lock| shm.lock(slots, lock).unwrap();
+        match (self.programs[actor], pc) {
+            (ShmProgram::Reader, 0) => match lock(READ_SLOT, ShmLock::Shared) {
+                true => 1,
+                false => 0,
+            },
+            (ShmProgram::Writer, 0) => match lock(WRITE_SLOT, ShmLock::Exclusive) {
+                true => 1,
+                false => 0,
+            },
+            (ShmProgram::Checkpointer, 0) => match lock(CHECKPOINT_SLOT, ShmLock::Exclusive) {
+                true => 1,
+                false => 0,
+            },
+            (ShmProgram::Checkpointer, 1) => match lock(READ_SLOTS, ShmLock::Exclusive) {
+                true => 2,
+                false => {
+                    shm.unlock(CHECKPOINT_SLOT, ShmLock::Exclusive).unwrap();
+                    0
+                }
+            },
+            (ShmProgram::Reader, 1) => {
+                shm.unlock(READ_SLOT, ShmLock::Shared).unwrap();
+                2
+            }
+            (ShmProgram::Writer, 1) => {
+                shm.unlock(WRITE_SLOT, ShmLock::Exclusive).unwrap();
+                2
+            }
+            (ShmProgram::Checkpointer, 2) => {
+                shm.unlock(READ_SLOTS, ShmLock::Exclusive).unwrap();
+                3
+            }
+            (ShmProgram::Checkpointer, 3) => {
+                shm.unlock(CHECKPOINT_SLOT, ShmLock::Exclusive).unwrap();
+                4
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn check(&self, world: &ShmWorld, _before: &[usize], after: &[usize]) {
+        for slot in 0..8u8 {
+            let holders = (0..after.len())
+                .flat_map(|actor| {
+                    self.held(actor, after[actor])
+                        .into_iter()
+                        .filter(|(slots, _)| slots.contains(&slot))
+                        .map(move |(_, exclusive)| (actor, exclusive))
+                })
+                .collect::<Vec<_>>();
+            assert!(
+                holders.len() <= 1 || holders.iter().all(|(_, exclusive)| !exclusive),
+                "slot {} is held by {:?}",
+                slot,
+                holders
+            );
+        }
+
+        // the shared memory is freed once the last connection closed
+        let databases = world.pool.occupancy().databases;
+        if (0..after.len()).all(|actor| self.done(actor, after[actor])) {
+            assert_eq!(databases, 0);
+        } else {
+            assert!(databases <= 1, "{} databases", databases);
+        }
+    }
+}
+
+#[test]
+fn shm_locks() {
+    let model = ShmModel {
+        programs: vec![
+            ShmProgram::Reader,
+            ShmProgram::Reader,
+            ShmProgram::Writer,
+            ShmProgram::Checkpointer,
+        ],
+    };
+    let states = explore(&model, 4);
+    assert!(states > 100, "{} states", states);
+}
@@ -0,0 +1,209 @@
+//! The glue passes valid lock transitions to [File::lock] and [File::unlock], and reports locks
+//! held by other connections as `SQLITE_BUSY`.
+
+mod common;
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{integrity_check, open, FsVfs, LockingVfs, TempDir};
+use rusqlite::{ffi, Connection, ErrorCode};
+use sqlite_vfs::{register, File, LockKind, OpenKind, OpenOptions, SyncOptions, Vfs, VfsHandle};
+
+type Transitions = Arc<Mutex<Vec<(&'static str, LockKind)>>>;
+
+/// Records the lock transitions of the main database.
+struct RecordingVfs {
+    vfs: LockingVfs<FsVfs>,
+    transitions: Transitions,
+}
+
+struct RecordingFile<F> {
+    file: F,
+    transitions: Option<Transitions>,
+}
+
+impl Vfs for RecordingVfs {
+    type File = RecordingFile<<LockingVfs<FsVfs> as Vfs>::File>;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let transitions = (opts.kind == OpenKind::MainDb).then(|| Arc::clone(&self.transitions));
+        Ok(RecordingFile {
+            file: self.vfs.open(path, opts)?,
+            transitions,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.vfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.vfs.exists(path)
+    }
+}
+
+impl<F: File> RecordingFile<F> {
+    fn record(&self, operation: &'static str, lock: LockKind) {
+        if let Some(transitions) = &self.transitions {
+            transitions.lock().unwrap().push((operation, lock));
+        }
+    }
+}
+
+impl<F: File> File for RecordingFile<F> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        self.file.sync(options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let acquired = self.file.lock(lock)?;
+        self.record(if acquired { "lock" } else { "busy" }, lock);
+        Ok(acquired)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<(), std::io::Error> {
+        self.record("unlock", lock);
+        self.file.unlock(lock)
+    }
+
+    fn check_reserved_lock(&self) -> Result<bool, std::io::Error> {
+        self.file.check_reserved_lock()
+    }
+}
+
+fn register_recording(name: &str) -> (VfsHandle, Transitions) {
+    let transitions = Transitions::default();
+    let vfs = register(
+        name,
+        RecordingVfs {
+            vfs: LockingVfs(FsVfs),
+            transitions: Arc::clone(&transitions),
+        },
+    )
+    .unwrap();
+    (vfs, transitions)
+}
+
+#[test]
+fn write_transaction() {
+    let (_vfs, transitions) = register_recording("locking-write");
+    let dir = TempDir::new("locking-write");
+    let conn = open(&dir.path("main.db"), "locking-write");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    transitions.lock().unwrap().clear();
+
+    conn.execute("INSERT INTO vals VALUES (1)", []).unwrap();
+    assert_eq!(
+        *transitions.lock().unwrap(),
+        vec![
+            ("lock", LockKind::Shared),
+            ("lock", LockKind::Reserved),
+            ("lock", LockKind::Pending),
+            ("lock", LockKind::Exclusive),
+            ("unlock", LockKind::Shared),
+            ("unlock", LockKind::None),
+        ]
+    );
+}
+
+#[test]
+fn pending_lock_blocks_readers() {
+    let (_vfs, transitions) = register_recording("locking-pending");
+    let dir = TempDir::new("locking-pending");
+    let writer = open(&dir.path("main.db"), "locking-pending");
+    let reader = open(&dir.path("main.db"), "locking-pending");
+    for conn in [&writer, &reader] {
+        conn.busy_timeout(Duration::ZERO).unwrap();
+    }
+    writer
+        .execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY); INSERT INTO vals VALUES (1);")
+        .unwrap();
+
+    // an open read transaction keeps the writer from committing ...
+    reader
+        .execute_batch("BEGIN; SELECT COUNT(*) FROM vals;")
+        .unwrap();
+    writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+    writer.execute("INSERT INTO vals VALUES (2)", []).unwrap();
+    transitions.lock().unwrap().clear();
+    match writer.execute_batch("COMMIT") {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, ErrorCode::DatabaseBusy)
+        }
+        result => panic!("expected SQLITE_BUSY, got {:?}", result),
+    }
+    // ... but the pending lock it holds now keeps new readers out
+    assert_eq!(
+        *transitions.lock().unwrap(),
+        vec![("lock", LockKind::Pending), ("busy", LockKind::Exclusive)]
+    );
+    reader.execute_batch("COMMIT").unwrap();
+    let other = open(&dir.path("main.db"), "locking-pending");
+    other.busy_timeout(Duration::ZERO).unwrap();
+    match other.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get::<_, i64>(0)) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, ErrorCode::DatabaseBusy)
+        }
+        result => panic!("expected SQLITE_BUSY, got {:?}", result),
+    }
+
+    writer.execute_batch("COMMIT").unwrap();
+    let count: i64 = other
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2);
+    integrity_check(&other);
+}
+
+fn lock_state(conn: &Connection) -> i32 {
+    let mut lock = -1;
+    let rc = unsafe {
+        ffi::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            ffi::SQLITE_FCNTL_LOCKSTATE,
+            &mut lock as *mut i32 as *mut _,
+        )
+    };
+    assert_eq!(rc, ffi::SQLITE_OK);
+    lock
+}
+
+#[test]
+fn lock_state_is_reported() {
+    let _vfs = register("locking-state", LockingVfs(FsVfs)).unwrap();
+    let dir = TempDir::new("locking-state");
+    let conn = open(&dir.path("main.db"), "locking-state");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    assert_eq!(lock_state(&conn), ffi::SQLITE_LOCK_NONE);
+
+    conn.execute_batch("BEGIN; SELECT COUNT(*) FROM vals;")
+        .unwrap();
+    assert_eq!(lock_state(&conn), ffi::SQLITE_LOCK_SHARED);
+    conn.execute_batch("COMMIT").unwrap();
+
+    conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+    assert_eq!(lock_state(&conn), ffi::SQLITE_LOCK_RESERVED);
+    conn.execute_batch("COMMIT").unwrap();
+    assert_eq!(lock_state(&conn), ffi::SQLITE_LOCK_NONE);
+}
@@ -0,0 +1,129 @@
+//! Management operations (listing, renaming, metadata, ...) that tooling can use next to SQLite.
+
+mod common;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use sqlite_vfs::{register, File, OpenAccess, OpenKind, OpenOptions, Vfs, VfsEntry};
+
+/// A VFS that only implements the required methods.
+struct MinimalVfs;
+
+impl Vfs for MinimalVfs {
+    type File = std::fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        FsVfs.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+fn list(vfs: &impl Vfs, prefix: PathBuf) -> Vec<VfsEntry> {
+    let mut entries = vfs
+        .list(&prefix)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
+}
+
+#[test]
+fn list_database_files() {
+    let _vfs = common::register_fs("management-list");
+    let dir = TempDir::new("management-list");
+    let conn = open(&dir.path("main.db"), "management-list");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY); BEGIN; INSERT INTO vals VALUES (1);",
+    )
+    .unwrap();
+    let _other = open(&dir.path("other.db"), "management-list");
+
+    let paths = |entries: Vec<VfsEntry>| entries.into_iter().map(|e| e.path).collect::<Vec<_>>();
+    assert_eq!(
+        paths(list(&FsVfs, dir.path("main.db"))),
+        vec![dir.path("main.db"), dir.path("main.db-journal")]
+    );
+    assert_eq!(
+        paths(list(&FsVfs, dir.path(""))),
+        vec![
+            dir.path("main.db"),
+            dir.path("main.db-journal"),
+            dir.path("other.db")
+        ]
+    );
+    assert!(list(&FsVfs, dir.path("missing")).is_empty());
+
+    let main = &list(&FsVfs, dir.path("main.db"))[0];
+    assert_eq!(
+        main.size,
+        std::fs::metadata(dir.path("main.db")).unwrap().len()
+    );
+}
+
+#[test]
+fn list_unsupported() {
+    let err = MinimalVfs.list("db".as_ref()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+fn rename(vfs: &str, rename: impl Fn(&Path, &Path) -> std::io::Result<()>) {
+    let dir = TempDir::new(vfs);
+    let conn = open(&dir.path("old.db"), vfs);
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY); INSERT INTO vals VALUES (1);")
+        .unwrap();
+    drop(conn);
+    // an existing destination is replaced
+    std::fs::write(dir.path("new.db"), b"outdated").unwrap();
+
+    rename(&dir.path("old.db"), &dir.path("new.db")).unwrap();
+
+    assert!(!dir.path("old.db").exists());
+    let conn = open(&dir.path("new.db"), vfs);
+    integrity_check(&conn);
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn rename_fs() {
+    let _vfs = common::register_fs("management-rename");
+    rename("management-rename", |from, to| FsVfs.rename(from, to));
+}
+
+#[test]
+fn rename_copy_and_delete() {
+    let _vfs = register("management-rename-copy", MinimalVfs).unwrap();
+    rename("management-rename-copy", |from, to| {
+        MinimalVfs.rename(from, to)
+    });
+}
+
+#[test]
+fn file_metadata() {
+    let dir = TempDir::new("management-metadata");
+    let opts = OpenOptions {
+        kind: OpenKind::MainDb,
+        access: OpenAccess::Create,
+        delete_on_close: false,
+    };
+    let before = SystemTime::now() - Duration::from_secs(1);
+    let mut file = FsVfs.open(&dir.path("main.db"), opts).unwrap();
+    file.write_all(b"data").unwrap();
+
+    let meta = File::metadata(&file).unwrap();
+    assert!(meta.modified.unwrap() >= before);
+    assert_eq!(meta.generation, None);
+}
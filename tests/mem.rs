@@ -0,0 +1,207 @@
+//! [MemVfs] keeps databases in memory, shared by all connections opened through it.
+
+mod common;
+
+use std::io::ErrorKind;
+use std::path::Path;
+
+use common::{integrity_check, open};
+use rusqlite::{ffi, Connection, ErrorCode};
+use sqlite_vfs::mem::{MemBuffer, MemVfs};
+use sqlite_vfs::shm::ShmVfs;
+use sqlite_vfs::{register, File, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+fn error_code(result: rusqlite::Result<impl std::fmt::Debug>) -> ErrorCode {
+    match result {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => err.code,
+        result => panic!("expected an SQLite error, got {:?}", result),
+    }
+}
+
+fn count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn shared_between_connections() {
+    let _vfs = register("mem-shared", MemVfs::new()).unwrap();
+    let path = Path::new("/mem/main.db");
+
+    let conn = open(path, "mem-shared");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT); INSERT INTO vals VALUES (1, 'a');",
+    )
+    .unwrap();
+    let other = open(path, "mem-shared");
+    assert_eq!(count(&other), 1);
+
+    // a writer locks out readers until it commits
+    conn.execute_batch("BEGIN EXCLUSIVE; INSERT INTO vals VALUES (2, 'b');")
+        .unwrap();
+    assert_eq!(
+        error_code(other.query_row("SELECT 1 FROM vals", [], |_| Ok(()))),
+        ErrorCode::DatabaseBusy
+    );
+    conn.execute_batch("COMMIT").unwrap();
+    assert_eq!(count(&other), 2);
+    integrity_check(&other);
+
+    // the database outlives its connections
+    drop(conn);
+    drop(other);
+    assert_eq!(count(&open(path, "mem-shared")), 2);
+}
+
+#[test]
+fn wal() {
+    let _vfs = register("mem-wal", ShmVfs::new(MemVfs::new())).unwrap();
+    let path = Path::new("/mem/main.db");
+
+    let conn = open(path, "mem-wal");
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT)")
+        .unwrap();
+    let other = open(path, "mem-wal");
+    for i in 0..100 {
+        conn.execute("INSERT INTO vals (val) VALUES (?)", [i.to_string()])
+            .unwrap();
+    }
+    assert_eq!(count(&other), 100);
+    integrity_check(&other);
+}
+
+#[test]
+fn temp_files() {
+    let vfs = MemVfs::new();
+    let _vfs = register("mem-temp", vfs.clone()).unwrap();
+    let path = Path::new("/mem/main.db");
+
+    let conn = open(path, "mem-temp");
+    conn.execute_batch(
+        "PRAGMA temp_store = FILE;
+        PRAGMA cache_size = 2;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+        INSERT INTO vals (val) SELECT hex(randomblob(100)) FROM n;
+        CREATE TEMP TABLE sorted AS SELECT val FROM vals ORDER BY val;
+        VACUUM;",
+    )
+    .unwrap();
+    assert_eq!(count(&conn), 2000);
+    integrity_check(&conn);
+    drop(conn);
+
+    // all temporary files are deleted once closed
+    let paths = vfs
+        .list(Path::new(""))
+        .unwrap()
+        .map(|entry| entry.unwrap().path)
+        .collect::<Vec<_>>();
+    assert_eq!(paths, [path]);
+}
+
+#[test]
+fn max_size() {
+    let _vfs = register("mem-max-size", MemVfs::with_max_size(64 * 1024)).unwrap();
+    let conn = open(Path::new("/mem/main.db"), "mem-max-size");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val BLOB)")
+        .unwrap();
+
+    let result = conn.execute("INSERT INTO vals (val) VALUES (zeroblob(100000))", []);
+    assert_eq!(error_code(result), ErrorCode::DiskFull);
+
+    // the database is still usable with smaller values
+    conn.execute("INSERT INTO vals (val) VALUES (zeroblob(1000))", [])
+        .unwrap();
+    assert_eq!(count(&conn), 1);
+    integrity_check(&conn);
+}
+
+#[test]
+fn large_offsets() {
+    let opts = OpenOptions {
+        kind: OpenKind::MainDb,
+        access: OpenAccess::Create,
+        delete_on_close: false,
+    };
+
+    // writes beyond 4 GiB are refused by the size limit before anything is allocated
+    let vfs = MemVfs::with_max_size(4 << 30);
+    let mut file = vfs.open(Path::new("main.db"), opts.clone()).unwrap();
+    let err = file.write_all_at(&[1; 512], 5 << 30).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::StorageFull);
+    let err = file.truncate(5 << 30).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::StorageFull);
+    let mut buf = [0; 512];
+    assert_eq!(file.read_at(&mut buf, 5 << 30).unwrap(), 0);
+
+    // offsets that overflow fail instead of wrapping around
+    let vfs = MemVfs::new();
+    let mut file = vfs.open(Path::new("main.db"), opts).unwrap();
+    let err = file.write_all_at(&[1; 512], u64::MAX - 100).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+    assert!(file.truncate(u64::MAX).is_err());
+    assert_eq!(file.file_size().unwrap(), 0);
+
+    // the file is still usable
+    file.write_all_at(&[1; 512], 512).unwrap();
+    assert_eq!(file.file_size().unwrap(), 1024);
+}
+
+#[test]
+fn export_and_import() {
+    let vfs = MemVfs::new();
+    let _vfs = register("mem-export", vfs.clone()).unwrap();
+    let path = Path::new("/mem/main.db");
+
+    let conn = open(path, "mem-export");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+    let err = vfs.export(path).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    drop(conn);
+
+    // SQLite's in-memory database takes over the buffer of the file
+    let data = vfs.export(path).unwrap();
+    assert!(!vfs.exists(path).unwrap());
+    let exported = data.as_ref().as_ptr();
+    let (ptr, len, capacity) = data.into_raw();
+    assert_eq!(ptr as *const u8, exported);
+    assert!(capacity >= len);
+    let mem = Connection::open_in_memory().unwrap();
+    let code = unsafe {
+        ffi::sqlite3_deserialize(
+            mem.handle(),
+            c"main".as_ptr(),
+            ptr,
+            len as i64,
+            capacity as i64,
+            (ffi::SQLITE_DESERIALIZE_FREEONCLOSE | ffi::SQLITE_DESERIALIZE_RESIZEABLE) as u32,
+        )
+    };
+    assert_eq!(code, ffi::SQLITE_OK);
+    assert_eq!(count(&mem), 500);
+    mem.execute("DELETE FROM vals WHERE id > 100", []).unwrap();
+
+    // and hands its buffer back
+    let mut len = 0;
+    let ptr = unsafe { ffi::sqlite3_serialize(mem.handle(), c"main".as_ptr(), &mut len, 0) };
+    let data = unsafe { MemBuffer::from_raw(ptr, len as usize) };
+    vfs.import(path, data).unwrap();
+    let conn = open(path, "mem-export");
+    assert_eq!(count(&conn), 100);
+    conn.execute("INSERT INTO vals (val) VALUES ('new')", [])
+        .unwrap();
+    integrity_check(&conn);
+
+    let err = vfs.import(path, MemBuffer::default()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+}
@@ -0,0 +1,235 @@
+//! With `PRAGMA mmap_size`, SQLite reads pages straight from the memory of files that provide
+//! [MemoryMapped].
+
+mod common;
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use common::{integrity_check, open};
+use rusqlite::Connection;
+use sqlite_vfs::{register, File, MemoryMapped, OpenAccess, OpenOptions, SyncOptions, Vfs};
+
+/// The largest file the [MemoryVfs] can store.
+const CAPACITY: usize = 1 << 20;
+
+#[derive(Default)]
+struct Counters {
+    fetched: usize,
+    outstanding: usize,
+    /// The end of the furthest page fetched.
+    end: u64,
+}
+
+/// Keeps each file in a buffer that is allocated once and never moves, so its pages can be
+/// fetched.
+#[derive(Default)]
+struct MemoryVfs {
+    files: Mutex<HashMap<PathBuf, Arc<Mutex<Contents>>>>,
+    counters: Arc<Mutex<Counters>>,
+}
+
+struct Contents {
+    buf: Box<[u8]>,
+    len: usize,
+}
+
+struct MemoryFile {
+    contents: Arc<Mutex<Contents>>,
+    counters: Arc<Mutex<Counters>>,
+    /// Offsets of the fetched pages.
+    fetched: Vec<u64>,
+}
+
+impl Vfs for MemoryVfs {
+    type File = MemoryFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        if !files.contains_key(path) {
+            if opts.access == OpenAccess::Read || opts.access == OpenAccess::Write {
+                return Err(ErrorKind::NotFound.into());
+            }
+            let contents = Contents {
+                buf: vec![0; CAPACITY].into_boxed_slice(),
+                len: 0,
+            };
+            files.insert(path.to_path_buf(), Arc::new(Mutex::new(contents)));
+        }
+        Ok(MemoryFile {
+            contents: Arc::clone(&files[path]),
+            counters: Arc::clone(&self.counters),
+            fetched: Vec::new(),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        Ok(self.files.lock().unwrap().contains_key(path))
+    }
+}
+
+impl File for MemoryFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let contents = self.contents.lock().unwrap();
+        let start = (offset as usize).min(contents.len);
+        let n = buf.len().min(contents.len - start);
+        buf[..n].copy_from_slice(&contents.buf[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let mut contents = self.contents.lock().unwrap();
+        let end = offset as usize + buf.len();
+        if end > CAPACITY {
+            return Err(ErrorKind::StorageFull.into());
+        }
+        contents.buf[offset as usize..end].copy_from_slice(buf);
+        contents.len = contents.len.max(end);
+        Ok(())
+    }
+
+    fn sync(&mut self, _options: SyncOptions) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.contents.lock().unwrap().len as u64)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let mut contents = self.contents.lock().unwrap();
+        let size = size as usize;
+        if size > CAPACITY {
+            return Err(ErrorKind::StorageFull.into());
+        }
+        if size > contents.len {
+            let len = contents.len;
+            contents.buf[len..size].fill(0);
+        }
+        contents.len = size;
+        Ok(())
+    }
+
+    fn memory_mapped(&mut self) -> Option<&mut dyn MemoryMapped> {
+        Some(self)
+    }
+}
+
+unsafe impl MemoryMapped for MemoryFile {
+    fn fetch(&mut self, offset: u64, len: usize) -> Option<&[u8]> {
+        let contents = self.contents.lock().unwrap();
+        let start = offset as usize;
+        if start + len > contents.len {
+            return None;
+        }
+        let mut counters = self.counters.lock().unwrap();
+        counters.fetched += 1;
+        counters.outstanding += 1;
+        counters.end = counters.end.max(offset + len as u64);
+        self.fetched.push(offset);
+        // the buffer never moves and is kept alive by this file
+        let page = &contents.buf[start..start + len];
+        Some(unsafe { std::slice::from_raw_parts(page.as_ptr(), len) })
+    }
+
+    fn unfetch(&mut self, offset: u64) {
+        let index = self.fetched.iter().position(|o| *o == offset).unwrap();
+        self.fetched.swap_remove(index);
+        self.counters.lock().unwrap().outstanding -= 1;
+    }
+
+    fn set_size_limit(&mut self, limit: u64) -> u64 {
+        limit.min(CAPACITY as u64)
+    }
+}
+
+fn create(path: &Path, vfs: &str) {
+    let conn = open(path, vfs);
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+}
+
+fn mmap_size(conn: &Connection, size: Option<u64>) -> i64 {
+    let sql = match size {
+        Some(size) => format!("PRAGMA mmap_size = {}", size),
+        None => "PRAGMA mmap_size".to_string(),
+    };
+    conn.query_row(&sql, [], |row| row.get(0)).unwrap()
+}
+
+#[test]
+fn pages_are_fetched() {
+    let vfs = MemoryVfs::default();
+    let counters = Arc::clone(&vfs.counters);
+    let _vfs = register("mmap", vfs).unwrap();
+    let path = Path::new("/mmap/main.db");
+
+    create(path, "mmap");
+    assert_eq!(counters.lock().unwrap().fetched, 0);
+
+    let conn = open(path, "mmap");
+    assert_eq!(mmap_size(&conn, None), 0);
+    // the file caps the size to what it can map
+    assert_eq!(mmap_size(&conn, Some(1 << 30)), CAPACITY as i64);
+    let count: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM vals WHERE val LIKE 'value %'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(count, 500);
+    integrity_check(&conn);
+    assert!(counters.lock().unwrap().fetched > 0);
+
+    // writes go through write_all_at, and are seen by the next read
+    conn.execute("UPDATE vals SET val = 'changed' WHERE id = 1", [])
+        .unwrap();
+    let val: String = conn
+        .query_row("SELECT val FROM vals WHERE id = 1", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(val, "changed");
+
+    drop(conn);
+    assert_eq!(counters.lock().unwrap().outstanding, 0);
+}
+
+#[test]
+fn size_limit() {
+    let vfs = MemoryVfs::default();
+    let counters = Arc::clone(&vfs.counters);
+    let _vfs = register("mmap-size-limit", vfs).unwrap();
+    let path = Path::new("/mmap-size-limit/main.db");
+    create(path, "mmap-size-limit");
+
+    let conn = open(path, "mmap-size-limit");
+    assert_eq!(mmap_size(&conn, Some(8192)), 8192);
+    integrity_check(&conn);
+    let counters = counters.lock().unwrap();
+    assert!(counters.fetched > 0);
+    assert!(counters.end <= 8192);
+}
+
+#[test]
+fn not_memory_mapped() {
+    let _vfs = common::register_fs("mmap-unsupported");
+    let dir = common::TempDir::new("mmap-unsupported");
+    let conn = open(&dir.path("main.db"), "mmap-unsupported");
+    assert_eq!(mmap_size(&conn, Some(1 << 20)), 0);
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    integrity_check(&conn);
+}
@@ -0,0 +1,100 @@
+//! A [MultiplexVfs] splits databases into chunks that never exceed its chunk size.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use rusqlite::Connection;
+use sqlite_vfs::multiplex::MultiplexVfs;
+use sqlite_vfs::{register, Vfs};
+
+const CHUNK_SIZE: u64 = 65536;
+
+/// The sizes of the chunks of the file at `path`.
+fn chunks(path: &Path) -> Vec<u64> {
+    let mut sizes = Vec::new();
+    for index in 0.. {
+        let chunk = match index {
+            0 => path.to_path_buf(),
+            index => format!("{}{:03}", path.display(), index).into(),
+        };
+        match fs::metadata(&chunk) {
+            Ok(metadata) => sizes.push(metadata.len()),
+            Err(_) => return sizes,
+        }
+    }
+    unreachable!()
+}
+
+fn sum(conn: &Connection) -> i64 {
+    conn.query_row("SELECT SUM(length(val)) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn chunks_are_split_and_removed() {
+    let _vfs = register("multiplex", MultiplexVfs::new(FsVfs, CHUNK_SIZE)).unwrap();
+    let dir = TempDir::new("multiplex");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "multiplex");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val BLOB);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT randomblob(1000) FROM n;",
+    )
+    .unwrap();
+    integrity_check(&conn);
+    drop(conn);
+
+    let sizes = chunks(&path);
+    assert!(sizes.len() > 4, "{:?}", sizes);
+    assert!(sizes.iter().all(|size| *size <= CHUNK_SIZE), "{:?}", sizes);
+    assert!(sizes[..sizes.len() - 1]
+        .iter()
+        .all(|size| *size == CHUNK_SIZE));
+    // the journal was split as well, and deleted with all of its chunks
+    assert!(chunks(&dir.path("main.db-journal")).is_empty());
+
+    let conn = open(&path, "multiplex");
+    integrity_check(&conn);
+    assert_eq!(sum(&conn), 500 * 1000);
+
+    conn.execute_batch("DELETE FROM vals WHERE id > 10; VACUUM;")
+        .unwrap();
+    integrity_check(&conn);
+    assert_eq!(sum(&conn), 10 * 1000);
+    assert_eq!(chunks(&path).len(), 1);
+}
+
+#[test]
+fn rename_and_delete() {
+    let vfs = MultiplexVfs::new(FsVfs, CHUNK_SIZE);
+    let _vfs = register("multiplex-rename", MultiplexVfs::new(FsVfs, CHUNK_SIZE)).unwrap();
+    let dir = TempDir::new("multiplex-rename");
+    let (from, to) = (dir.path("from.db"), dir.path("to.db"));
+
+    let conn = open(&from, "multiplex-rename");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val BLOB);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+        INSERT INTO vals (val) SELECT randomblob(1000) FROM n;",
+    )
+    .unwrap();
+    drop(conn);
+    let count = chunks(&from).len();
+    assert!(count > 1);
+
+    vfs.rename(&from, &to).unwrap();
+    assert!(chunks(&from).is_empty());
+    assert_eq!(chunks(&to).len(), count);
+    let conn = open(&to, "multiplex-rename");
+    integrity_check(&conn);
+    assert_eq!(sum(&conn), 200 * 1000);
+    drop(conn);
+
+    vfs.delete(&to).unwrap();
+    assert!(chunks(&to).is_empty());
+}
@@ -0,0 +1,54 @@
+//! Classify the pages of a database by their contents.
+
+mod common;
+
+use std::fs;
+
+use common::{open, TempDir};
+use sqlite_vfs::page::{classify, PageKind};
+
+const PAGE_SIZE: usize = 4096;
+
+#[test]
+fn classify_pages() {
+    let _vfs = common::register_fs("page-classify");
+    let dir = TempDir::new("page-classify");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "page-classify");
+    conn.execute_batch(&format!(
+        "PRAGMA page_size = {};
+        CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+        CREATE INDEX vals_text ON vals (text);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+        INSERT INTO vals (text) SELECT 'value ' || i FROM n;
+        CREATE TABLE blobs (data BLOB NOT NULL);
+        INSERT INTO blobs VALUES (zeroblob({}));",
+        PAGE_SIZE,
+        4 * PAGE_SIZE
+    ))
+    .unwrap();
+    drop(conn);
+
+    let data = fs::read(&path).unwrap();
+    let kinds = data
+        .chunks(PAGE_SIZE)
+        .enumerate()
+        .map(|(i, page)| classify(page, i as u64))
+        .collect::<Vec<_>>();
+
+    // the schema table on page 1 is small enough to fit on a single leaf page
+    assert_eq!(kinds[0], PageKind::TableLeaf);
+    for kind in [
+        PageKind::TableInterior,
+        PageKind::TableLeaf,
+        PageKind::IndexInterior,
+        PageKind::IndexLeaf,
+    ] {
+        assert!(kinds.contains(&kind), "no {:?} page", kind);
+    }
+    // the blob does not fit onto a leaf page and overflows to at least 3 other pages
+    assert!(kinds.iter().filter(|k| **k == PageKind::Other).count() >= 3);
+    assert!(PageKind::TableInterior.is_interior());
+    assert!(!PageKind::IndexLeaf.is_interior());
+}
@@ -0,0 +1,162 @@
+//! A [PagedVfs] stores databases in a [PageStore] page by page.
+
+mod common;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use common::{integrity_check, open};
+use rusqlite::Connection;
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::paged::{PageStore, PagedVfs};
+use sqlite_vfs::register;
+
+type Pages = Vec<Option<Vec<u8>>>;
+
+/// Keeps the pages of each database in a map. Clones share the same databases.
+#[derive(Clone)]
+struct MapStore {
+    page_size: usize,
+    dbs: Arc<Mutex<HashMap<PathBuf, Pages>>>,
+}
+
+impl MapStore {
+    fn new(page_size: usize) -> Self {
+        MapStore {
+            page_size,
+            dbs: Default::default(),
+        }
+    }
+
+    fn pages(&self, db: &str) -> u32 {
+        self.page_count(Path::new(db)).unwrap()
+    }
+}
+
+impl PageStore for MapStore {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn get_page(&self, db: &Path, no: u32, page: &mut [u8]) -> Result<(), std::io::Error> {
+        assert_eq!(page.len(), self.page_size);
+        let dbs = self.dbs.lock().unwrap();
+        match &dbs[db][no as usize - 1] {
+            Some(data) => page.copy_from_slice(data),
+            None => page.fill(0),
+        }
+        Ok(())
+    }
+
+    fn put_page(&self, db: &Path, no: u32, data: &[u8]) -> Result<(), std::io::Error> {
+        assert_eq!(data.len(), self.page_size);
+        let mut dbs = self.dbs.lock().unwrap();
+        let pages = dbs.entry(db.to_path_buf()).or_default();
+        if pages.len() < no as usize {
+            pages.resize(no as usize, None);
+        }
+        pages[no as usize - 1] = Some(data.to_vec());
+        Ok(())
+    }
+
+    fn page_count(&self, db: &Path) -> Result<u32, std::io::Error> {
+        let dbs = self.dbs.lock().unwrap();
+        Ok(dbs.get(db).map_or(0, |pages| pages.len() as u32))
+    }
+
+    fn truncate(&self, db: &Path, count: u32) -> Result<(), std::io::Error> {
+        let mut dbs = self.dbs.lock().unwrap();
+        match count {
+            0 => drop(dbs.remove(db)),
+            count => dbs.get_mut(db).unwrap().truncate(count as usize),
+        }
+        Ok(())
+    }
+}
+
+const CREATE: &str = "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+    WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+    INSERT INTO vals (val) SELECT 'value ' || i FROM n;";
+
+fn create(path: &Path, vfs: &str) -> Connection {
+    let conn = open(path, vfs);
+    conn.execute_batch(CREATE).unwrap();
+    conn
+}
+
+fn pragma(conn: &Connection, name: &str) -> u32 {
+    conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn pages_are_stored() {
+    let store = MapStore::new(4096);
+    let _vfs = register("paged", PagedVfs::new(store.clone(), MemVfs::new())).unwrap();
+    let path = Path::new("/paged/main.db");
+
+    let conn = create(path, "paged");
+    assert_eq!(pragma(&conn, "page_size"), 4096);
+    assert_eq!(store.pages("/paged/main.db"), pragma(&conn, "page_count"));
+
+    // another connection reads the pages from the store
+    let other = open(path, "paged");
+    integrity_check(&other);
+    let count: i64 = other
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2000);
+    drop(other);
+
+    let before = store.pages("/paged/main.db");
+    conn.execute_batch("DELETE FROM vals WHERE id > 10; VACUUM;")
+        .unwrap();
+    integrity_check(&conn);
+    let after = store.pages("/paged/main.db");
+    assert!(after < before, "{} pages before, {} after", before, after);
+    assert_eq!(after, pragma(&conn, "page_count"));
+}
+
+#[test]
+fn page_size_of_the_store() {
+    let store = MapStore::new(8192);
+    let _vfs = register("paged-8k", PagedVfs::new(store.clone(), MemVfs::new())).unwrap();
+
+    let conn = create(Path::new("/paged-8k/main.db"), "paged-8k");
+    assert_eq!(pragma(&conn, "page_size"), 8192);
+    assert_eq!(
+        store.pages("/paged-8k/main.db"),
+        pragma(&conn, "page_count")
+    );
+}
+
+#[test]
+fn other_page_size() {
+    let store = MapStore::new(4096);
+    let _vfs = register("paged-1k", PagedVfs::new(store.clone(), MemVfs::new())).unwrap();
+    let path = Path::new("/paged-1k/main.db");
+
+    let conn = open(path, "paged-1k");
+    conn.execute_batch("PRAGMA page_size = 1024").unwrap();
+    conn.execute_batch(CREATE).unwrap();
+    assert_eq!(pragma(&conn, "page_size"), 1024);
+    conn.execute("UPDATE vals SET val = 'changed' WHERE id % 3 = 0", [])
+        .unwrap();
+    drop(conn);
+
+    let conn = open(path, "paged-1k");
+    integrity_check(&conn);
+    let changed: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM vals WHERE val = 'changed'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(changed, 666);
+    assert_eq!(
+        store.pages("/paged-1k/main.db"),
+        pragma(&conn, "page_count").div_ceil(4)
+    );
+}
@@ -0,0 +1,390 @@
+//! Storage that cannot be written is opened as read-only (and immutable).
+
+mod common;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use common::{open, FsVfs, RawFile, TempDir};
+use rusqlite::{ffi, Connection, OpenFlags};
+use sqlite_vfs::{
+    register, register_with_options, DeviceCharacteristics, File, LockKind, OpenAccess, OpenKind,
+    OpenOptions, RegisterOptions, SyncOptions, Vfs, VfsHandle,
+};
+
+/// A VFS that reports all files as not writable (like a read-only snapshot).
+struct ReadOnlyVfs;
+
+impl Vfs for ReadOnlyVfs {
+    type File = std::fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        FsVfs.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+
+    fn access(&self, _path: &Path, write: bool) -> Result<bool, std::io::Error> {
+        Ok(!write)
+    }
+}
+
+fn create_db(dir: &TempDir) -> std::path::PathBuf {
+    let path = dir.path("main.db");
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY); INSERT INTO vals VALUES (1);")
+        .unwrap();
+    path
+}
+
+fn immutable(vfs: &str, path: &Path, flags: i32) -> bool {
+    let mut file = RawFile::open(vfs, path, ffi::SQLITE_OPEN_MAIN_DB | flags).unwrap();
+    file.device_characteristics() & ffi::SQLITE_IOCAP_IMMUTABLE != 0
+}
+
+/// Opts in to [RegisterOptions::immutable_when_read_only].
+fn register_immutable<V: Vfs>(name: &str, vfs: V) -> VfsHandle {
+    register_with_options(
+        name,
+        vfs,
+        RegisterOptions {
+            immutable_when_read_only: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn immutable_when_read_only() {
+    let _vfs = register_immutable("read-only-immutable", ReadOnlyVfs);
+    let dir = TempDir::new("read-only-immutable");
+    let path = create_db(&dir);
+
+    assert!(immutable(
+        "read-only-immutable",
+        &path,
+        ffi::SQLITE_OPEN_READONLY
+    ));
+    // opened for writing, so it is up to SQLite to find out that it cannot write
+    assert!(!immutable(
+        "read-only-immutable",
+        &path,
+        ffi::SQLITE_OPEN_READWRITE
+    ));
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        "read-only-immutable",
+    )
+    .unwrap();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn written_by_others_not_immutable() {
+    // this process may not write the database, but another one does
+    let _vfs = register("read-only-mutable", ReadOnlyVfs).unwrap();
+    let dir = TempDir::new("read-only-mutable");
+    let path = create_db(&dir);
+
+    assert!(!immutable(
+        "read-only-mutable",
+        &path,
+        ffi::SQLITE_OPEN_READONLY
+    ));
+
+    let reader = Connection::open_with_flags_and_vfs(
+        &path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        "read-only-mutable",
+    )
+    .unwrap();
+    let count = |conn: &Connection| -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(count(&reader), 1);
+
+    // the reader notices the change instead of using the pages it cached
+    let writer = Connection::open(&path).unwrap();
+    writer.execute("INSERT INTO vals VALUES (2)", []).unwrap();
+    assert_eq!(count(&reader), 2);
+}
+
+#[test]
+fn writable_not_immutable() {
+    let _vfs = register_immutable("read-only-writable", FsVfs);
+    let dir = TempDir::new("read-only-writable");
+    let path = create_db(&dir);
+
+    assert!(!immutable(
+        "read-only-writable",
+        &path,
+        ffi::SQLITE_OPEN_READONLY
+    ));
+    open(&path, "read-only-writable");
+}
+
+/// A VFS that fails to open files for writing (like storage that is mounted read-only).
+struct ReadOnlyMountVfs;
+
+impl Vfs for ReadOnlyMountVfs {
+    type File = std::fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        if opts.access != OpenAccess::Read {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ReadOnlyFilesystem,
+                "read-only file system",
+            ));
+        }
+        FsVfs.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+#[test]
+fn read_only_fallback() {
+    let _vfs = register("read-only-fallback", ReadOnlyMountVfs).unwrap();
+    let dir = TempDir::new("read-only-fallback");
+    let path = create_db(&dir);
+    let conn = open(&path, "read-only-fallback");
+
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+
+    match conn.execute("INSERT INTO vals VALUES (2)", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, rusqlite::ErrorCode::ReadOnly)
+        }
+        result => panic!("expected SQLITE_READONLY, got {:?}", result),
+    }
+}
+
+#[test]
+fn read_only_fallback_disabled() {
+    let _vfs = register_with_options(
+        "read-only-no-fallback",
+        ReadOnlyMountVfs,
+        RegisterOptions {
+            read_only_fallback: false,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let dir = TempDir::new("read-only-no-fallback");
+    let path = create_db(&dir);
+
+    let result = Connection::open_with_flags_and_vfs(
+        &path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        "read-only-no-fallback",
+    );
+    match result {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, rusqlite::ErrorCode::CannotOpen)
+        }
+        result => panic!("expected SQLITE_CANTOPEN, got {:?}", result.map(|_| ())),
+    }
+}
+
+/// A VFS that always opens main databases from a read-only replica, and reports that to SQLite.
+struct ReplicaVfs;
+
+struct ReplicaFile(std::fs::File);
+
+impl Vfs for ReplicaVfs {
+    type File = ReplicaFile;
+
+    fn open(&self, path: &Path, mut opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        opts.access = OpenAccess::Read;
+        Ok(ReplicaFile(FsVfs.open(path, opts)?))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for ReplicaFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.0.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.0.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.0, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.0.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.0.truncate(size)
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn read_only_file() {
+    let _vfs = register("read-only-file", ReplicaVfs).unwrap();
+    let dir = TempDir::new("read-only-file");
+    let path = create_db(&dir);
+    let conn = open(&path, "read-only-file");
+    assert_eq!(
+        unsafe { ffi::sqlite3_db_readonly(conn.handle(), c"main".as_ptr()) },
+        1
+    );
+
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+    match conn.execute("INSERT INTO vals VALUES (2)", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, rusqlite::ErrorCode::ReadOnly)
+        }
+        result => panic!("expected SQLITE_READONLY, got {:?}", result),
+    }
+}
+
+/// A VFS that serves databases that never change (like a CDN), and counts how often SQLite locks
+/// them or asks for other files.
+#[derive(Default)]
+struct StaticVfs {
+    calls: Arc<AtomicUsize>,
+}
+
+struct StaticFile {
+    file: std::fs::File,
+    calls: Arc<AtomicUsize>,
+}
+
+impl Vfs for StaticVfs {
+    type File = StaticFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(StaticFile {
+            file: FsVfs.open(path, opts)?,
+            calls: Arc::clone(&self.calls),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        FsVfs.exists(path)
+    }
+
+    fn access(&self, _path: &Path, _write: bool) -> Result<bool, std::io::Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(true)
+    }
+}
+
+impl File for StaticFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn lock(&mut self, _lock: LockKind) -> Result<bool, std::io::Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    fn device_characteristics(&self, _kind: OpenKind) -> DeviceCharacteristics {
+        DeviceCharacteristics::empty().immutable()
+    }
+}
+
+#[test]
+fn immutable_file() {
+    let vfs = StaticVfs::default();
+    let calls = Arc::clone(&vfs.calls);
+    let _vfs = register("read-only-immutable-file", vfs).unwrap();
+    let dir = TempDir::new("read-only-immutable-file");
+    let path = create_db(&dir);
+
+    // opened for writing, but SQLite is told that the file is read-only
+    let conn = open(&path, "read-only-immutable-file");
+    assert_eq!(
+        unsafe { ffi::sqlite3_db_readonly(conn.handle(), c"main".as_ptr()) },
+        1
+    );
+    for _ in 0..3 {
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+    match conn.execute("INSERT INTO vals VALUES (2)", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, rusqlite::ErrorCode::ReadOnly)
+        }
+        result => panic!("expected SQLITE_READONLY, got {:?}", result),
+    }
+    let conn = Connection::open_with_flags_and_vfs(
+        &path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        "read-only-immutable-file",
+    )
+    .unwrap();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+
+    // neither locks nor looks for journals (or asks whether the database can be written)
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,131 @@
+//! [Vfs::on_recovery] is called around the rollback of a hot journal.
+
+mod common;
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use rusqlite::ErrorCode;
+use sqlite_vfs::{register, OpenOptions, RecoveryPhase, Vfs};
+
+type Events = Arc<Mutex<Vec<(RecoveryPhase, PathBuf)>>>;
+
+#[derive(Default)]
+struct RecoveringVfs {
+    events: Events,
+    refuse: bool,
+}
+
+impl Vfs for RecoveringVfs {
+    type File = std::fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        FsVfs.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+
+    fn on_recovery(&self, path: &Path, phase: RecoveryPhase) -> Result<(), std::io::Error> {
+        if self.refuse {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "recovery refused",
+            ));
+        }
+        self.events
+            .lock()
+            .unwrap()
+            .push((phase, path.to_path_buf()));
+        Ok(())
+    }
+}
+
+/// Leave a hot journal behind in `crash_dir` and return the number of rows committed before.
+fn crash(vfs: &str, dir: &TempDir, crash_dir: &TempDir) -> i64 {
+    let conn = open(&dir.path("main.db"), vfs);
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (text) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+
+    // Force SQLite to write changed pages to the database before the commit, and copy the files
+    // in the middle of the transaction, as if the process crashed.
+    conn.execute_batch(
+        "PRAGMA cache_size = 1;
+        BEGIN;
+        DELETE FROM vals WHERE id % 2 = 0;",
+    )
+    .unwrap();
+    for name in ["main.db", "main.db-journal"] {
+        fs::copy(dir.path(name), crash_dir.path(name)).unwrap();
+    }
+    conn.execute_batch("ROLLBACK").unwrap();
+    500
+}
+
+fn count(conn: &rusqlite::Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+}
+
+#[test]
+fn hot_journal_rollback() {
+    let vfs = RecoveringVfs::default();
+    let events = Arc::clone(&vfs.events);
+    let _vfs = register("recovery-notified", vfs).unwrap();
+    let dir = TempDir::new("recovery-notified");
+    let crash_dir = TempDir::new("recovery-notified-crashed");
+
+    let committed = crash("recovery-notified", &dir, &crash_dir);
+    assert!(events.lock().unwrap().is_empty());
+
+    let conn = open(&crash_dir.path("main.db"), "recovery-notified");
+    assert_eq!(count(&conn).unwrap(), committed);
+    integrity_check(&conn);
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            (RecoveryPhase::Start, crash_dir.path("main.db")),
+            (RecoveryPhase::Done, crash_dir.path("main.db")),
+        ]
+    );
+    assert!(!crash_dir.path("main.db-journal").exists());
+}
+
+#[test]
+fn refused_recovery() {
+    let _vfs = common::register_fs("recovery-refused-setup");
+    let _vfs = register(
+        "recovery-refused",
+        RecoveringVfs {
+            refuse: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let dir = TempDir::new("recovery-refused");
+    let crash_dir = TempDir::new("recovery-refused-crashed");
+    let committed = crash("recovery-refused-setup", &dir, &crash_dir);
+
+    let conn = open(&crash_dir.path("main.db"), "recovery-refused");
+    match count(&conn) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => assert_eq!(err.code, ErrorCode::CannotOpen),
+        result => panic!("expected the recovery to fail, got {:?}", result),
+    }
+    assert!(crash_dir.path("main.db-journal").exists());
+
+    // the hot journal is still rolled back by the next connection that is allowed to
+    let conn = open(&crash_dir.path("main.db"), "recovery-refused-setup");
+    assert_eq!(count(&conn).unwrap(), committed);
+    integrity_check(&conn);
+}
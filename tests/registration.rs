@@ -0,0 +1,124 @@
+//! A [VfsHandle] keeps its VFS registered until it is dropped, but never frees it while files are
+//! still open through it.
+
+mod common;
+
+use std::ffi::CString;
+
+use common::{integrity_check, open, FsVfs, LockingVfs, TempDir};
+use rusqlite::{ffi, Connection};
+use sqlite_vfs::{register_with_options, RegisterOptions, VfsError};
+
+fn find_vfs(name: &str) -> *mut ffi::sqlite3_vfs {
+    let name = CString::new(name).unwrap();
+    unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }
+}
+
+#[test]
+fn unregister() {
+    let dir = TempDir::new("registration-unregister");
+    let vfs = common::register_fs("registration-unregister");
+    assert_eq!(vfs.name(), "registration-unregister");
+    assert_eq!(find_vfs("registration-unregister"), vfs.as_raw());
+
+    let conn = open(&dir.path("main.db"), "registration-unregister");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY);
+        INSERT INTO vals VALUES (1);",
+    )
+    .unwrap();
+    assert_eq!(vfs.open_files(), 1);
+
+    // the VFS is kept as long as the connection uses it
+    let vfs = vfs.unregister().unwrap_err();
+    assert_eq!(find_vfs("registration-unregister"), vfs.as_raw());
+    conn.execute("INSERT INTO vals VALUES (2)", []).unwrap();
+
+    drop(conn);
+    assert_eq!(vfs.open_files(), 0);
+    vfs.unregister().unwrap();
+    assert!(find_vfs("registration-unregister").is_null());
+
+    // the name is free to be registered again
+    let _vfs = common::register_fs("registration-unregister");
+    let conn = open(&dir.path("main.db"), "registration-unregister");
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2);
+    integrity_check(&conn);
+}
+
+#[test]
+fn drop_with_open_files() {
+    let dir = TempDir::new("registration-drop");
+    let vfs = common::register_fs("registration-drop");
+    let conn = open(&dir.path("main.db"), "registration-drop");
+
+    // the VFS is unregistered, but the open connection keeps working
+    drop(vfs);
+    assert!(find_vfs("registration-drop").is_null());
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    integrity_check(&conn);
+}
+
+#[test]
+fn make_default() {
+    let dir = TempDir::new("registration-default");
+    let vfs = register_with_options(
+        "registration-default",
+        LockingVfs(FsVfs),
+        RegisterOptions {
+            make_default: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        unsafe { ffi::sqlite3_vfs_find(std::ptr::null()) },
+        vfs.as_raw()
+    );
+
+    // connections that do not name a VFS use it
+    let conn = Connection::open(dir.path("main.db")).unwrap();
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+    assert_eq!(vfs.open_files(), 1);
+    drop(conn);
+    drop(vfs);
+    assert_ne!(
+        unsafe { ffi::sqlite3_vfs_find(std::ptr::null()) },
+        std::ptr::null_mut()
+    );
+}
+
+#[test]
+fn open_raw() {
+    let dir = TempDir::new("registration-raw");
+    let vfs = common::register_fs("registration-raw");
+    let path = dir.path("main.db");
+    open(&path, "registration-raw")
+        .execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+
+    let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READONLY;
+    let mut file = unsafe { vfs.open_raw(&path, flags) }.unwrap();
+    assert_eq!(vfs.open_files(), 1);
+    let mut header = [0; 16];
+    let code = unsafe {
+        let ptr = file.as_ptr();
+        ((*(*ptr).pMethods).xRead.unwrap())(ptr, header.as_mut_ptr().cast(), 16, 0)
+    };
+    assert_eq!(code, ffi::SQLITE_OK);
+    assert_eq!(&header, b"SQLite format 3\0");
+
+    drop(file);
+    assert_eq!(vfs.open_files(), 0);
+
+    let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE;
+    let err = unsafe { vfs.open_raw(dir.path("missing.db"), flags) }.unwrap_err();
+    let err = err.into_inner().unwrap().downcast::<VfsError>().unwrap();
+    assert_eq!(*err, VfsError::Code(ffi::SQLITE_CANTOPEN));
+    assert_eq!(vfs.open_files(), 0);
+}
@@ -0,0 +1,257 @@
+//! [VfsRegistry::from_config] registers the VFS stacks of a configuration.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::path::Path;
+
+use common::{integrity_check, open, FsVfs, LockingVfs, TempDir};
+use rusqlite::ffi;
+use sqlite_vfs::dynamic::boxed;
+use sqlite_vfs::registry::{
+    BackendConfig, Backends, ConfigError, RegistryConfig, ShimConfig, StackConfig, VfsRegistry,
+};
+
+fn registered(name: &str) -> bool {
+    let name = CString::new(name).unwrap();
+    !unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }.is_null()
+}
+
+fn stack(name: &str, backend: BackendConfig, shims: Vec<ShimConfig>) -> StackConfig {
+    StackConfig {
+        name: name.to_string(),
+        backend,
+        shims,
+        default: false,
+    }
+}
+
+fn memory() -> BackendConfig {
+    BackendConfig::Memory { max_size: None }
+}
+
+fn write(path: &Path, vfs: &str) -> rusqlite::Connection {
+    let conn = open(path, vfs);
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        INSERT INTO vals (val) VALUES ('a'), ('b');",
+    )
+    .unwrap();
+    integrity_check(&conn);
+    conn
+}
+
+#[test]
+fn stacks() {
+    let config = RegistryConfig {
+        vfs: vec![
+            stack(
+                "registry-wal",
+                memory(),
+                vec![ShimConfig::Shm, ShimConfig::Stats],
+            ),
+            stack(
+                "registry-chunks",
+                BackendConfig::Memory {
+                    max_size: Some(1 << 20),
+                },
+                vec![ShimConfig::Fencing],
+            ),
+        ],
+    };
+    let registry = VfsRegistry::from_config(&config).unwrap();
+    assert_eq!(
+        registry.names().collect::<Vec<_>>(),
+        ["registry-wal", "registry-chunks"]
+    );
+    assert_eq!(registry.get("registry-wal").unwrap().open_files(), 0);
+    assert!(registry.get("registry-missing").is_none());
+
+    // the shared memory shim allows WAL mode, and the statistics shim counts the writes
+    let conn = write(Path::new("/main.db"), "registry-wal");
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    assert!(
+        registry
+            .stats("registry-wal")
+            .unwrap()
+            .snapshot()
+            .writes
+            .count
+            > 0
+    );
+    assert!(registry.stats("registry-chunks").is_none());
+    drop(conn);
+
+    let conn = write(Path::new("/main.db"), "registry-chunks");
+    let result = conn.execute("INSERT INTO vals (val) SELECT randomblob(1 << 21)", []);
+    match result {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, rusqlite::ErrorCode::DiskFull)
+        }
+        result => panic!("expected an SQLite error, got {:?}", result),
+    }
+
+    // the stacks still in use stay registered
+    let registry = registry.unregister().unwrap_err();
+    assert_eq!(registry.names().collect::<Vec<_>>(), ["registry-chunks"]);
+    assert!(!registered("registry-wal"));
+    drop(conn);
+    registry.unregister().unwrap();
+    assert!(!registered("registry-chunks"));
+}
+
+#[test]
+fn custom_backends() {
+    let dir = TempDir::new("registry-custom");
+    let backends = Backends::new().with("fs", |params| {
+        assert_eq!(params.get("sync").map(String::as_str), Some("full"));
+        Ok(boxed(LockingVfs(FsVfs)))
+    });
+    let config = RegistryConfig {
+        vfs: vec![stack(
+            "registry-custom",
+            BackendConfig::Custom {
+                name: "fs".to_string(),
+                params: BTreeMap::from([("sync".to_string(), "full".to_string())]),
+            },
+            vec![ShimConfig::Multiplex { chunk_size: 1 }],
+        )],
+    };
+    let registry = VfsRegistry::from_config_with(&config, &backends).unwrap();
+    let conn = write(&dir.path("main.db"), "registry-custom");
+    conn.execute("INSERT INTO vals (val) SELECT randomblob(1 << 17)", [])
+        .unwrap();
+    drop(conn);
+    // the multiplex shim splits the database into chunks of 64KiB
+    assert!(dir.path("main.db").exists());
+    assert!(dir.path("main.db001").exists());
+    drop(registry);
+    assert!(!registered("registry-custom"));
+}
+
+#[test]
+fn errors() {
+    let config = RegistryConfig {
+        vfs: vec![
+            stack("registry-first", memory(), vec![]),
+            stack(
+                "registry-unknown",
+                BackendConfig::Custom {
+                    name: "cloud".to_string(),
+                    params: BTreeMap::new(),
+                },
+                vec![],
+            ),
+        ],
+    };
+    let err = VfsRegistry::from_config(&config).unwrap_err();
+    assert!(
+        matches!(&err, ConfigError::UnknownBackend { stack, backend }
+            if stack == "registry-unknown" && backend == "cloud"),
+        "{:?}",
+        err
+    );
+    // the stacks registered before are unregistered again
+    assert!(!registered("registry-first"));
+
+    let config = RegistryConfig {
+        vfs: vec![
+            stack("registry-twice", memory(), vec![]),
+            stack("registry-twice", memory(), vec![ShimConfig::Shm]),
+        ],
+    };
+    let err = VfsRegistry::from_config(&config).unwrap_err();
+    assert!(matches!(err, ConfigError::Duplicate(_)), "{:?}", err);
+    assert!(!registered("registry-twice"));
+
+    let backends = Backends::new().with("broken", |_| Err(std::io::Error::other("no credentials")));
+    let config = RegistryConfig {
+        vfs: vec![stack(
+            "registry-broken",
+            BackendConfig::Custom {
+                name: "broken".to_string(),
+                params: BTreeMap::new(),
+            },
+            vec![],
+        )],
+    };
+    let err = VfsRegistry::from_config_with(&config, &backends).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "building the backend of vfs stack registry-broken failed"
+    );
+    assert_eq!(
+        std::error::Error::source(&err).unwrap().to_string(),
+        "no credentials"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn deserialize() {
+    use sqlite_vfs::throttle::Priority;
+
+    let config: RegistryConfig = serde_json::from_str(
+        r#"{
+            "vfs": [
+                {
+                    "name": "registry-json",
+                    "backend": { "type": "custom", "name": "fs", "params": { "root": "/data" } },
+                    "shims": [
+                        {
+                            "type": "throttle",
+                            "write_bandwidth": 1048576,
+                            "burst_ms": 100,
+                            "priorities": { "/data/reports.db": "background" }
+                        },
+                        { "type": "shm" }
+                    ],
+                    "default": true
+                },
+                { "name": "registry-json-mem", "backend": { "type": "memory" } }
+            ]
+        }"#,
+    )
+    .unwrap();
+    let mut expected = stack(
+        "registry-json",
+        BackendConfig::Custom {
+            name: "fs".to_string(),
+            params: BTreeMap::from([("root".to_string(), "/data".to_string())]),
+        },
+        vec![
+            ShimConfig::Throttle {
+                read_bandwidth: None,
+                write_bandwidth: Some(1 << 20),
+                iops: None,
+                burst_ms: Some(100),
+                per_file: false,
+                priorities: [("/data/reports.db".to_string(), Priority::Background)].into(),
+            },
+            ShimConfig::Shm,
+        ],
+    );
+    expected.default = true;
+    assert_eq!(
+        config,
+        RegistryConfig {
+            vfs: vec![expected, stack("registry-json-mem", memory(), vec![])],
+        }
+    );
+
+    let err = serde_json::from_str::<RegistryConfig>(
+        r#"{
+            "vfs": [{ "name": "x", "backend": { "type": "memory" }, "shims": [{ "type": "gzip" }] }]
+        }"#,
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("unknown variant `gzip`"),
+        "{}",
+        err
+    );
+}
@@ -0,0 +1,140 @@
+//! With the `s3` feature, an [S3Vfs] stores files as chunks in an object store.
+#![cfg(feature = "s3")]
+
+mod common;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use common::{integrity_check, open};
+use futures::TryStreamExt;
+use object_store::memory::InMemory;
+use object_store::ObjectStore;
+use rusqlite::Connection;
+use sqlite_vfs::async_vfs::{BlockingVfs, CurrentThread};
+use sqlite_vfs::s3::{S3Options, S3Vfs};
+use sqlite_vfs::{register, VfsHandle};
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+fn register_s3(name: &str, store: &Arc<InMemory>) -> VfsHandle {
+    let options = S3Options {
+        prefix: "dbs".into(),
+        chunk_size: CHUNK_SIZE,
+        part_size: 4096,
+        cache_chunks: 2,
+        budget: None,
+    };
+    let vfs = S3Vfs::with_options(Arc::clone(store) as Arc<dyn ObjectStore>, options);
+    register(name, BlockingVfs::new(vfs, CurrentThread)).unwrap()
+}
+
+/// The keys and sizes of all objects in `store`.
+fn objects(store: &InMemory) -> Vec<(String, usize)> {
+    let objects = futures::executor::block_on(store.list(None).try_collect::<Vec<_>>()).unwrap();
+    let mut objects = objects
+        .into_iter()
+        .map(|meta| (meta.location.to_string(), meta.size))
+        .collect::<Vec<_>>();
+    objects.sort();
+    objects
+}
+
+fn create(conn: &Connection, rows: usize) {
+    conn.execute_batch(&format!(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {})
+        INSERT INTO vals (val) SELECT hex(randomblob(50)) FROM n;",
+        rows
+    ))
+    .unwrap();
+}
+
+fn count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn chunks() {
+    let store = Arc::new(InMemory::new());
+    let vfs = register_s3("s3-chunks", &store);
+    let path = Path::new("/s3/main.db");
+
+    let conn = open(path, "s3-chunks");
+    create(&conn, 1000);
+    let size: i64 = conn
+        .query_row(
+            "SELECT page_count * page_size FROM pragma_page_count, pragma_page_size",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    drop(conn);
+
+    // the database is split into complete chunks (and a last one with the rest), and the journal
+    // is gone
+    let objects = objects(&store);
+    let chunks = (size as usize).div_ceil(CHUNK_SIZE);
+    assert!(chunks > 2);
+    assert_eq!(objects.len(), chunks);
+    for (i, (key, len)) in objects.iter().enumerate() {
+        assert_eq!(key, &format!("dbs/s3/main.db/{:010}", i));
+        let expected = CHUNK_SIZE.min(size as usize - i * CHUNK_SIZE);
+        assert_eq!(*len, expected);
+    }
+
+    // a new VFS finds the database in the store, and reads it through its cache
+    drop(vfs);
+    let _vfs = register_s3("s3-chunks", &store);
+    let conn = open(path, "s3-chunks");
+    assert_eq!(count(&conn), 1000);
+    integrity_check(&conn);
+}
+
+#[test]
+fn truncate() {
+    let store = Arc::new(InMemory::new());
+    let _vfs = register_s3("s3-truncate", &store);
+    let path = Path::new("/s3/main.db");
+
+    let conn = open(path, "s3-truncate");
+    create(&conn, 2000);
+    let before = objects(&store).len();
+    conn.execute_batch("DELETE FROM vals WHERE id > 100; VACUUM;")
+        .unwrap();
+    assert_eq!(count(&conn), 100);
+    integrity_check(&conn);
+
+    // the chunks beyond the end of the database are deleted, and temporary files are never
+    // uploaded
+    let objects = objects(&store);
+    assert!(objects.len() < before);
+    assert!(objects
+        .iter()
+        .all(|(key, _)| key.starts_with("dbs/s3/main.db/")));
+}
+
+#[test]
+fn connections() {
+    let store = Arc::new(InMemory::new());
+    let _vfs = register_s3("s3-connections", &store);
+    let path = Path::new("/s3/main.db");
+
+    let conn = open(path, "s3-connections");
+    create(&conn, 10);
+    let other = open(path, "s3-connections");
+    assert_eq!(count(&other), 10);
+
+    // the connections of the VFS share the locks of a database
+    other.busy_timeout(std::time::Duration::ZERO).unwrap();
+    conn.execute_batch("BEGIN EXCLUSIVE; DELETE FROM vals WHERE id > 5;")
+        .unwrap();
+    assert!(other.query_row("SELECT 1", [], |_| Ok(())).is_ok());
+    assert!(other
+        .query_row("SELECT COUNT(*) FROM vals", [], |_| Ok(()))
+        .is_err());
+    conn.execute_batch("COMMIT").unwrap();
+    assert_eq!(count(&other), 5);
+    integrity_check(&other);
+}
@@ -0,0 +1,273 @@
+//! [VfsHandle::snapshot] copies databases while other connections use them, and
+//! [VfsHandle::restore] replaces them with such a copy.
+
+mod common;
+
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use common::{integrity_check, open, register_fs, TempDir};
+use rusqlite::Connection;
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::{register, OpenOptions, Vfs};
+
+fn create(path: &Path, vfs: &str, journal_mode: &str) -> Connection {
+    let conn = open(path, vfs);
+    conn.query_row(
+        &format!("PRAGMA journal_mode = {}", journal_mode),
+        [],
+        |_| Ok(()),
+    )
+    .unwrap();
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+    conn
+}
+
+fn count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+/// Write `data` to `path` and open it with SQLite's default VFS.
+fn restore(path: &Path, data: &[u8]) -> Connection {
+    fs::write(path, data).unwrap();
+    let conn = Connection::open(path).unwrap();
+    integrity_check(&conn);
+    conn
+}
+
+#[test]
+fn snapshot_during_write() {
+    for journal_mode in ["delete", "wal"] {
+        let name = format!("snapshot-{}", journal_mode);
+        let vfs = register_fs(&name);
+        let dir = TempDir::new(&name);
+        let path = dir.path("main.db");
+        let _conn = create(&path, &name, journal_mode);
+
+        // a write transaction is in progress, but not committed
+        let writer = open(&path, &name);
+        writer
+            .execute_batch("BEGIN IMMEDIATE; DELETE FROM vals WHERE id > 100;")
+            .unwrap();
+
+        let snapshot = vfs.snapshot(&path).unwrap();
+        assert!(!snapshot.is_empty());
+        let copy = restore(&dir.path("copy.db"), snapshot.as_ref());
+        assert_eq!(count(&copy), 500, "{}", journal_mode);
+
+        writer.execute_batch("COMMIT").unwrap();
+        let mut data = Vec::new();
+        vfs.snapshot(&path).unwrap().read_to_end(&mut data).unwrap();
+        let copy = restore(&dir.path("copy2.db"), &data);
+        assert_eq!(count(&copy), 100, "{}", journal_mode);
+    }
+}
+
+#[test]
+fn snapshot_of_locked_database() {
+    let vfs = register_fs("snapshot-locked");
+    let dir = TempDir::new("snapshot-locked");
+    let path = dir.path("main.db");
+    let conn = create(&path, "snapshot-locked", "delete");
+    conn.execute_batch(
+        "PRAGMA locking_mode = EXCLUSIVE;
+        UPDATE vals SET val = 'changed';",
+    )
+    .unwrap();
+
+    let err = vfs.snapshot(&path).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    let err = vfs.snapshot(dir.path("missing.db")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+}
+
+#[cfg(unix)]
+#[test]
+fn snapshot_of_non_utf8_path() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let vfs = register_fs("snapshot-non-utf8");
+    let dir = TempDir::new("snapshot-non-utf8");
+    let path = dir.path("").join(OsStr::from_bytes(b"main-\xff.db"));
+    let _conn = create(&path, "snapshot-non-utf8", "delete");
+    // a database at the lossily converted path must not be copied instead
+    create(&dir.path("main-\u{fffd}.db"), "snapshot-non-utf8", "delete")
+        .execute_batch("DELETE FROM vals")
+        .unwrap();
+
+    let snapshot = vfs.snapshot(&path).unwrap();
+    let copy = restore(&dir.path("copy.db"), snapshot.as_ref());
+    assert_eq!(count(&copy), 500);
+}
+
+fn data_version(conn: &Connection) -> i64 {
+    conn.query_row("PRAGMA data_version", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn restore_while_open() {
+    for journal_mode in ["delete", "wal"] {
+        let name = format!("snapshot-restore-{}", journal_mode);
+        let vfs = register_fs(&name);
+        let dir = TempDir::new(&name);
+        let path = dir.path("main.db");
+        let conn = create(&path, &name, journal_mode);
+        let snapshot = vfs.snapshot(&path).unwrap();
+
+        conn.execute_batch("DELETE FROM vals WHERE id > 100")
+            .unwrap();
+        let reader = open(&path, &name);
+        assert_eq!(count(&reader), 100);
+        let version = data_version(&reader);
+
+        // the connections drop the pages they cached and see the restored database
+        vfs.restore(&path, snapshot).unwrap();
+        assert_ne!(data_version(&reader), version, "{}", journal_mode);
+        assert_eq!(count(&reader), 500, "{}", journal_mode);
+        assert_eq!(count(&conn), 500, "{}", journal_mode);
+        integrity_check(&conn);
+        let mode: String = open(&path, &name)
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, journal_mode);
+        conn.execute("INSERT INTO vals (val) VALUES ('new')", [])
+            .unwrap();
+        assert_eq!(count(&reader), 501, "{}", journal_mode);
+
+        // a new database is created
+        let copy = dir.path("copy.db");
+        vfs.restore(&copy, vfs.snapshot(&path).unwrap()).unwrap();
+        assert_eq!(count(&open(&copy, &name)), 501, "{}", journal_mode);
+    }
+}
+
+#[test]
+fn restore_fails() {
+    let vfs = register_fs("snapshot-restore-fails");
+    let dir = TempDir::new("snapshot-restore-fails");
+    let path = dir.path("main.db");
+    let conn = create(&path, "snapshot-restore-fails", "delete");
+    let snapshot = vfs.snapshot(&path).unwrap();
+
+    let err = vfs.restore(&path, &[7; 8192][..]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+    assert_eq!(count(&conn), 500);
+
+    // the database is not replaced while another connection reads it
+    conn.execute_batch("BEGIN; SELECT COUNT(*) FROM vals;")
+        .unwrap();
+    let err = vfs.restore(&path, snapshot).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    conn.execute_batch("COMMIT").unwrap();
+    assert_eq!(count(&conn), 500);
+    integrity_check(&conn);
+}
+
+#[test]
+fn restore_by_rename() {
+    for journal_mode in ["delete", "wal"] {
+        let name = format!("snapshot-rename-{}", journal_mode);
+        let vfs = register_fs(&name);
+        let dir = TempDir::new(&name);
+        let path = dir.path("main.db");
+        let conn = create(&path, &name, journal_mode);
+        let snapshot = vfs.snapshot(&path).unwrap();
+        conn.execute_batch("DELETE FROM vals WHERE id > 100")
+            .unwrap();
+        drop(conn);
+
+        // without any open connections, the copy is moved over the database
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&fs::metadata(&path).unwrap());
+        vfs.restore(&path, snapshot).unwrap();
+        #[cfg(unix)]
+        assert_ne!(
+            std::os::unix::fs::MetadataExt::ino(&fs::metadata(&path).unwrap()),
+            inode
+        );
+        assert!(!dir.path("main.db-restore").exists());
+
+        let conn = open(&path, &name);
+        assert_eq!(count(&conn), 500, "{}", journal_mode);
+        integrity_check(&conn);
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, journal_mode);
+    }
+}
+
+/// A [MemVfs] that cannot rename its objects.
+struct NoRename(MemVfs);
+
+impl Vfs for NoRename {
+    type File = <MemVfs as Vfs>::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        self.0.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        self.0.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        self.0.exists(path)
+    }
+
+    fn rename(&self, _from: &Path, _to: &Path) -> Result<(), std::io::Error> {
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+#[test]
+fn snapshot_to() {
+    let dir = TempDir::new("snapshot-to");
+    let vfs = register_fs("snapshot-to");
+    let path = dir.path("main.db");
+    let _conn = create(&path, "snapshot-to", "wal");
+    let backup = dir.path("backup.db");
+    vfs.snapshot_to(&path, &backup).unwrap();
+    assert!(!dir.path("backup.db-snapshot").exists());
+    let copy = open(&backup, "snapshot-to");
+    assert_eq!(count(&copy), 500);
+    integrity_check(&copy);
+
+    // without rename, the copy is written to the destination directly
+    let mem = MemVfs::new();
+    let vfs = register("snapshot-to-copy", NoRename(mem.clone())).unwrap();
+    let path = PathBuf::from("/main.db");
+    let _conn = create(&path, "snapshot-to-copy", "delete");
+    let backup = PathBuf::from("/backup.db");
+    vfs.snapshot_to(&path, &backup).unwrap();
+    let copy = open(&backup, "snapshot-to-copy");
+    assert_eq!(count(&copy), 500);
+    integrity_check(&copy);
+    assert!(!mem.exists(Path::new("/backup.db-snapshot")).unwrap());
+}
+
+#[test]
+fn restore_without_rename() {
+    let vfs = register("snapshot-restore-copy", NoRename(MemVfs::new())).unwrap();
+    let path = PathBuf::from("/main.db");
+    let conn = create(&path, "snapshot-restore-copy", "delete");
+    let snapshot = vfs.snapshot(&path).unwrap();
+    conn.execute_batch("DELETE FROM vals WHERE id > 100")
+        .unwrap();
+    drop(conn);
+
+    vfs.restore(&path, snapshot).unwrap();
+    let conn = open(&path, "snapshot-restore-copy");
+    assert_eq!(count(&conn), 500);
+    integrity_check(&conn);
+}
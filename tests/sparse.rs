@@ -0,0 +1,131 @@
+//! With the `sparse` feature, a [SparseFile] punches holes where zeros are written, and reports how
+//! much storage the file still takes up.
+#![cfg(all(feature = "sparse", target_os = "linux"))]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{integrity_check, open, LockingVfs, TempDir};
+use sqlite_vfs::sparse::SparseFile;
+use sqlite_vfs::stats::StatsVfs;
+use sqlite_vfs::{register, File, OpenAccess, OpenOptions, Vfs};
+
+struct SparseFsVfs;
+
+impl Vfs for SparseFsVfs {
+    type File = SparseFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(opts.access != OpenAccess::Read)
+            .create(matches!(
+                opts.access,
+                OpenAccess::Create | OpenAccess::CreateNew
+            ))
+            .open(path)?;
+        SparseFile::new(file)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        Ok(path.is_file())
+    }
+}
+
+fn allocated(file: &SparseFile) -> u64 {
+    file.metadata().unwrap().allocated.unwrap()
+}
+
+#[test]
+fn punch_holes() {
+    let dir = TempDir::new("sparse-holes");
+    let file = fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dir.path("data"))
+        .unwrap();
+    let mut file = SparseFile::new(file).unwrap();
+
+    file.write_all_at(&[7; 1 << 20], 0).unwrap();
+    file.sync(Default::default()).unwrap();
+    let full = allocated(&file);
+    assert!(full >= 1 << 20, "{} bytes allocated", full);
+
+    // the zeros (apart from partial blocks) are not stored
+    file.write_all_at(&[0; (1 << 20) - 100], 50).unwrap();
+    file.sync(Default::default()).unwrap();
+    let mut buf = vec![1; 1 << 20];
+    assert_eq!(file.read_at(&mut buf, 0).unwrap(), 1 << 20);
+    assert!(buf[..50].iter().all(|byte| *byte == 7));
+    assert!(buf[50..(1 << 20) - 50].iter().all(|byte| *byte == 0));
+    assert!(buf[(1 << 20) - 50..].iter().all(|byte| *byte == 7));
+    if allocated(&file) == full {
+        // the file system cannot punch holes
+        return;
+    }
+    assert!(
+        allocated(&file) <= 64 << 10,
+        "{} bytes allocated",
+        allocated(&file)
+    );
+
+    // extending the file with zeros leaves a hole
+    file.write_all_at(&[0; 1 << 20], 2 << 20).unwrap();
+    assert_eq!(file.file_size().unwrap(), 3 << 20);
+    assert!(
+        allocated(&file) <= 64 << 10,
+        "{} bytes allocated",
+        allocated(&file)
+    );
+
+    file.truncate(4096).unwrap();
+    assert_eq!(file.file_size().unwrap(), 4096);
+    assert!(allocated(&file) <= 4096);
+}
+
+#[test]
+fn secure_delete() {
+    let dir = TempDir::new("sparse-secure-delete");
+    let vfs = StatsVfs::new(LockingVfs(SparseFsVfs));
+    let stats = vfs.stats();
+    let _vfs = register("sparse-secure-delete", vfs).unwrap();
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "sparse-secure-delete");
+    conn.execute_batch(
+        "PRAGMA secure_delete = ON;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY, val BLOB);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+        INSERT INTO vals (val) SELECT randomblob(100000) FROM n;",
+    )
+    .unwrap();
+    let size = fs::metadata(&path).unwrap().len();
+    let full = stats.snapshot().allocated;
+    assert!(full >= size, "{} of {} bytes allocated", full, size);
+
+    // the freed pages are zeroed, and their storage is given back
+    conn.execute_batch("DELETE FROM vals WHERE id > 10")
+        .unwrap();
+    integrity_check(&conn);
+    assert_eq!(fs::metadata(&path).unwrap().len(), size);
+    let allocated = stats.snapshot().allocated;
+    assert!(
+        allocated < size / 2,
+        "{} of {} bytes allocated",
+        allocated,
+        size
+    );
+    let blocks = std::os::unix::fs::MetadataExt::blocks(&fs::metadata(&path).unwrap());
+    assert!(blocks * 512 < size / 2);
+
+    drop(conn);
+    assert_eq!(stats.snapshot().allocated, 0);
+}
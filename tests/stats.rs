@@ -0,0 +1,177 @@
+//! A [StatsVfs] counts the operations on its files.
+
+mod common;
+
+use std::path::Path;
+use std::time::Duration;
+
+use common::{integrity_check, open};
+use sqlite_vfs::fault::FaultVfs;
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::shm::ShmVfs;
+use sqlite_vfs::stats::{Histogram, StatsVfs};
+use sqlite_vfs::{register, OpenKind};
+
+#[test]
+fn counters() {
+    let vfs = StatsVfs::new(MemVfs::new());
+    let stats = vfs.stats();
+    let _vfs = register("stats", vfs).unwrap();
+    let path = Path::new("/stats/main.db");
+
+    let conn = open(path, "stats");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+    integrity_check(&conn);
+
+    let pages: u64 = conn
+        .query_row("PRAGMA page_count", [], |row| row.get(0))
+        .unwrap();
+
+    let snapshot = stats.snapshot();
+    // the database and the journals of both transactions
+    assert!(snapshot.opens >= 3);
+    assert!(snapshot.writes.count >= pages);
+    assert!(snapshot.writes.bytes >= pages * 4096);
+    assert!(snapshot.reads.count > 0);
+    assert!(snapshot.syncs.count > 0);
+    assert_eq!(snapshot.syncs.bytes, 0);
+    for op in [&snapshot.reads, &snapshot.writes, &snapshot.syncs] {
+        assert_eq!(op.errors, 0);
+        assert_eq!(op.latency.count(), op.count);
+        assert!(op.mean() <= op.latency.quantile(1.0));
+    }
+
+    // reset returns what was collected so far
+    assert_eq!(stats.reset(), snapshot);
+    assert_eq!(stats.snapshot().writes.count, 0);
+    conn.execute("DELETE FROM vals WHERE id > 10", []).unwrap();
+    assert!(stats.snapshot().writes.count > 0);
+}
+
+#[test]
+fn allocated() {
+    let vfs = StatsVfs::new(MemVfs::new());
+    let stats = vfs.stats();
+    let _vfs = register("stats-allocated", vfs).unwrap();
+    let path = Path::new("/stats/main.db");
+
+    let conn = open(path, "stats-allocated");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+    let pages: u64 = conn
+        .query_row("PRAGMA page_count", [], |row| row.get(0))
+        .unwrap();
+
+    // the journals are deleted, so only the database is still allocated
+    let snapshot = stats.snapshot();
+    assert!(snapshot.allocated >= pages * 4096);
+    assert_eq!(
+        snapshot.kinds[&OpenKind::MainDb].allocated,
+        snapshot.allocated
+    );
+    assert_eq!(snapshot.kinds[&OpenKind::MainJournal].allocated, 0);
+    assert_eq!(stats.reset().allocated, snapshot.allocated);
+    assert_eq!(stats.snapshot().allocated, snapshot.allocated);
+
+    drop(conn);
+    assert_eq!(stats.snapshot().allocated, 0);
+}
+
+#[test]
+fn per_kind() {
+    let vfs = StatsVfs::new(ShmVfs::new(MemVfs::new()));
+    let stats = vfs.stats();
+    let _vfs = register("stats-kinds", vfs).unwrap();
+
+    let conn = open(Path::new("/stats-kinds/main.db"), "stats-kinds");
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+        .unwrap();
+    conn.execute_batch(
+        "PRAGMA synchronous = FULL;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);",
+    )
+    .unwrap();
+    let opened = stats.reset();
+    let db = &opened.kinds[&OpenKind::MainDb];
+    assert_eq!(db.opens, 1);
+    assert!(db.locks.count > 0);
+    assert_eq!(db.locks.bytes, 0);
+    assert_eq!(opened.kinds[&OpenKind::Wal].opens, 1);
+
+    for i in 0..10 {
+        conn.execute("INSERT INTO vals (val) VALUES (?)", [i.to_string()])
+            .unwrap();
+    }
+    // each commit appends to the WAL and syncs it, without writing to the database
+    let snapshot = stats.snapshot();
+    let wal = &snapshot.kinds[&OpenKind::Wal];
+    assert!(wal.writes.count >= 10);
+    assert!(wal.syncs.count >= 10);
+    assert_eq!(wal.syncs.latency.count(), wal.syncs.count);
+    assert!(wal.syncs.latency.quantile(0.99) > Duration::ZERO);
+    let db = snapshot.kinds.get(&OpenKind::MainDb);
+    assert_eq!(db.map_or(0, |db| db.writes.count), 0);
+
+    // the totals add up the kinds
+    assert_eq!(
+        snapshot.syncs.count,
+        snapshot.kinds.values().map(|k| k.syncs.count).sum::<u64>()
+    );
+    assert_eq!(
+        snapshot.locks.count,
+        snapshot.kinds.values().map(|k| k.locks.count).sum::<u64>()
+    );
+}
+
+#[test]
+fn errors() {
+    let faulty = FaultVfs::new(MemVfs::new());
+    let faults = faulty.faults();
+    let vfs = StatsVfs::new(faulty);
+    let stats = vfs.stats();
+    let _vfs = register("stats-errors", vfs).unwrap();
+
+    let conn = open(Path::new("/stats-errors/main.db"), "stats-errors");
+    faults.fail_write(1);
+    assert!(conn
+        .execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .is_err());
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.writes.errors, 1);
+    assert!(snapshot.writes.count >= 1);
+}
+
+#[test]
+fn histogram() {
+    let mut histogram = Histogram::default();
+    assert_eq!(histogram.quantile(0.5), Duration::ZERO);
+
+    for micros in [0, 1, 2, 3, 100, 100, 100, 5000] {
+        histogram.record(Duration::from_micros(micros));
+    }
+    histogram.record(Duration::from_secs(3600));
+    assert_eq!(histogram.count(), 9);
+
+    let buckets = histogram.buckets().collect::<Vec<_>>();
+    assert_eq!(buckets[0], (Duration::from_micros(1), 2));
+    assert_eq!(buckets[1], (Duration::from_micros(2), 1));
+    assert_eq!(buckets[2], (Duration::from_micros(4), 1));
+    assert_eq!(buckets[7], (Duration::from_micros(128), 3));
+    assert_eq!(buckets[13], (Duration::from_micros(8192), 1));
+    assert_eq!(*buckets.last().unwrap(), (Duration::MAX, 1));
+
+    assert_eq!(histogram.quantile(0.0), Duration::from_micros(1));
+    assert_eq!(histogram.quantile(0.5), Duration::from_micros(128));
+    assert_eq!(histogram.quantile(0.8), Duration::from_micros(8192));
+    assert_eq!(histogram.quantile(1.0), Duration::MAX);
+}
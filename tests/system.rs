@@ -0,0 +1,219 @@
+//! The services SQLite asks a [Vfs] for besides files (path names, randomness, sleeping and the
+//! current time).
+
+mod common;
+
+use std::ffi::CString;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use common::{integrity_check, open, FsVfs, LockingVfs, TempDir};
+use rusqlite::{ffi, Connection, ErrorCode, OpenFlags};
+use sqlite_vfs::{register, OpenOptions, Vfs};
+
+/// Keeps the databases of a tenant in its own directory.
+struct TenantVfs {
+    root: PathBuf,
+}
+
+impl Vfs for TenantVfs {
+    type File = std::fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        FsVfs.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+
+    fn full_pathname(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "databases must be inside the tenant directory",
+            ));
+        }
+        Ok(self.root.join(path))
+    }
+}
+
+/// The time at which the clock of [SimulatedVfs] starts.
+const SIMULATION_START: u64 = 1_000_000_000;
+
+/// A VFS for simulations, which provides deterministic services and only pretends to sleep. Its
+/// clock starts at [SIMULATION_START] and only advances while sleeping.
+#[derive(Default)]
+struct SimulatedVfs {
+    slept: Arc<Mutex<Vec<Duration>>>,
+}
+
+impl Vfs for SimulatedVfs {
+    type File = <LockingVfs<FsVfs> as Vfs>::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        LockingVfs(FsVfs).open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+
+    fn random(&self, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.slept.lock().unwrap().push(duration);
+        duration
+    }
+
+    fn current_time(&self) -> SystemTime {
+        let slept = self.slept.lock().unwrap().iter().sum::<Duration>();
+        SystemTime::UNIX_EPOCH + Duration::from_secs(SIMULATION_START) + slept
+    }
+}
+
+/// The `sqlite3_vfs` registered as `name`.
+fn find_vfs(name: &str) -> *mut ffi::sqlite3_vfs {
+    let name = CString::new(name).unwrap();
+    let vfs = unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) };
+    assert!(!vfs.is_null(), "vfs not registered");
+    vfs
+}
+
+#[test]
+fn tenant_paths() {
+    let dir = TempDir::new("system-tenant");
+    let _vfs = register("system-tenant", TenantVfs { root: dir.path("") }).unwrap();
+
+    let conn = open(Path::new("main.db"), "system-tenant");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY);
+        INSERT INTO vals VALUES (1);",
+    )
+    .unwrap();
+    integrity_check(&conn);
+    assert!(dir.path("main.db").exists());
+    assert!(!Path::new("main.db").exists());
+
+    match Connection::open_with_flags_and_vfs("../main.db", OpenFlags::default(), "system-tenant") {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => assert_eq!(err.code, ErrorCode::CannotOpen),
+        result => panic!(
+            "expected the path to be refused, got {:?}",
+            result.map(|_| ())
+        ),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_paths() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let _vfs = common::register_fs("system-non-utf8");
+    let dir = TempDir::new("system-non-utf8");
+    let path = dir.path("").join(OsStr::from_bytes(b"main-\xff.db"));
+
+    let conn = open(&path, "system-non-utf8");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY);
+        INSERT INTO vals VALUES (1);",
+    )
+    .unwrap();
+    integrity_check(&conn);
+    drop(conn);
+
+    let names = std::fs::read_dir(dir.path(""))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec![path.file_name().unwrap()]);
+}
+
+#[test]
+fn randomness() {
+    let _vfs = register("system-random", SimulatedVfs::default()).unwrap();
+    let vfs = find_vfs("system-random");
+
+    let mut buf = [0u8; 8];
+    let n = unsafe { ((*vfs).xRandomness.unwrap())(vfs, 8, buf.as_mut_ptr() as *mut _) };
+    assert_eq!(n, 8);
+    assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7]);
+}
+
+#[test]
+fn busy_wait() {
+    let vfs = SimulatedVfs::default();
+    let slept = Arc::clone(&vfs.slept);
+    let _vfs = register("system-sleep", vfs).unwrap();
+    let dir = TempDir::new("system-sleep");
+    let a = open(&dir.path("main.db"), "system-sleep");
+    let b = open(&dir.path("main.db"), "system-sleep");
+    b.busy_timeout(Duration::from_secs(10)).unwrap();
+
+    a.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY);
+        BEGIN IMMEDIATE;
+        INSERT INTO vals VALUES (1);",
+    )
+    .unwrap();
+
+    // b waits for the whole busy timeout, without actually sleeping
+    let start = Instant::now();
+    match b.execute("INSERT INTO vals VALUES (2)", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, ErrorCode::DatabaseBusy)
+        }
+        result => panic!("expected SQLITE_BUSY, got {:?}", result),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let slept = slept.lock().unwrap().iter().sum::<Duration>();
+    assert!(slept >= Duration::from_secs(9), "slept {:?}", slept);
+
+    a.execute_batch("COMMIT").unwrap();
+    b.execute("INSERT INTO vals VALUES (2)", []).unwrap();
+}
+
+#[test]
+fn clock() {
+    let vfs = SimulatedVfs::default();
+    let slept = Arc::clone(&vfs.slept);
+    let _vfs = register("system-clock", vfs).unwrap();
+    let dir = TempDir::new("system-clock");
+    let conn = open(&dir.path("main.db"), "system-clock");
+    let now = |conn: &Connection| -> String {
+        conn.query_row("SELECT datetime('now')", [], |row| row.get(0))
+            .unwrap()
+    };
+
+    assert_eq!(now(&conn), "2001-09-09 01:46:40");
+    slept.lock().unwrap().push(Duration::from_secs(90));
+    assert_eq!(now(&conn), "2001-09-09 01:48:10");
+
+    // the legacy floating point interface reports the same time, in days
+    let vfs = find_vfs("system-clock");
+    let mut days = 0.0;
+    assert_eq!(
+        unsafe { ((*vfs).xCurrentTime.unwrap())(vfs, &mut days) },
+        ffi::SQLITE_OK
+    );
+    let expected = 2440587.5 + (SIMULATION_START + 90) as f64 / 86400.0;
+    assert!((days - expected).abs() < 1e-6, "{} days", days);
+}
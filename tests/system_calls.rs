@@ -0,0 +1,167 @@
+//! A [Vfs] that provides [SystemCallOverrides] lets SQLite's `xSetSystemCall` replace the
+//! primitive operations it makes, e.g. to inject faults.
+
+mod common;
+
+use std::ffi::CStr;
+use std::mem::transmute;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use common::{integrity_check, open, FsVfs, TempDir};
+use rusqlite::ffi;
+use sqlite_vfs::{register, File, OpenOptions, SyncOptions, SystemCall, SystemCallOverrides, Vfs};
+
+/// Checks whether `len` bytes can be written, and returns an errno if not.
+type CheckWrite = unsafe extern "C" fn(len: usize) -> c_int;
+
+unsafe extern "C" fn check_write(_len: usize) -> c_int {
+    0
+}
+
+unsafe extern "C" fn disk_full(_len: usize) -> c_int {
+    28 // ENOSPC
+}
+
+#[derive(Default)]
+struct SystemCalls {
+    check_write: Mutex<Option<SystemCall>>,
+}
+
+impl SystemCallOverrides for SystemCalls {
+    fn names(&self) -> &[&'static CStr] {
+        &[c"check_write"]
+    }
+
+    fn get(&self, name: &CStr) -> Option<SystemCall> {
+        if name != c"check_write" {
+            return None;
+        }
+        let default = unsafe { transmute::<CheckWrite, SystemCall>(check_write) };
+        Some(self.check_write.lock().unwrap().unwrap_or(default))
+    }
+
+    fn set(&self, name: &CStr, call: Option<SystemCall>) -> bool {
+        if name != c"check_write" {
+            return false;
+        }
+        *self.check_write.lock().unwrap() = call;
+        true
+    }
+}
+
+/// Checks each write with the `check_write` system call.
+#[derive(Default)]
+struct CheckedVfs {
+    calls: Arc<SystemCalls>,
+}
+
+struct CheckedFile {
+    file: std::fs::File,
+    calls: Arc<SystemCalls>,
+}
+
+impl Vfs for CheckedVfs {
+    type File = CheckedFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(CheckedFile {
+            file: FsVfs.open(path, opts)?,
+            calls: Arc::clone(&self.calls),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+
+    fn system_calls(&self) -> Option<&dyn SystemCallOverrides> {
+        Some(&*self.calls)
+    }
+}
+
+impl File for CheckedFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let check = self.calls.get(c"check_write").unwrap();
+        let errno = unsafe { transmute::<SystemCall, CheckWrite>(check)(buf.len()) };
+        if errno != 0 {
+            return Err(std::io::Error::from_raw_os_error(errno));
+        }
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+}
+
+#[test]
+fn inject_fault() {
+    let handle = register("system-calls", CheckedVfs::default()).unwrap();
+    let vfs = unsafe { &*handle.as_raw() };
+    let dir = TempDir::new("system-calls");
+    let conn = open(&dir.path("main.db"), "system-calls");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+
+    unsafe {
+        let next = vfs.xNextSystemCall.unwrap();
+        let first = next(handle.as_raw(), std::ptr::null());
+        assert_eq!(CStr::from_ptr(first), c"check_write");
+        assert!(next(handle.as_raw(), first).is_null());
+
+        let fault = transmute::<CheckWrite, SystemCall>(disk_full);
+        let set = vfs.xSetSystemCall.unwrap();
+        assert_eq!(
+            set(handle.as_raw(), c"check_write".as_ptr(), Some(fault)),
+            ffi::SQLITE_OK
+        );
+        assert_eq!(
+            set(handle.as_raw(), c"open".as_ptr(), Some(fault)),
+            ffi::SQLITE_NOTFOUND
+        );
+        let get = vfs.xGetSystemCall.unwrap();
+        let current = get(handle.as_raw(), c"check_write".as_ptr()).unwrap();
+        assert_eq!(transmute::<SystemCall, CheckWrite>(current)(1), 28);
+    }
+    match conn.execute("INSERT INTO vals VALUES (1)", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, rusqlite::ErrorCode::DiskFull)
+        }
+        result => panic!("expected SQLITE_FULL, got {:?}", result),
+    }
+
+    // restore all system calls
+    unsafe {
+        let set = vfs.xSetSystemCall.unwrap();
+        assert_eq!(set(handle.as_raw(), std::ptr::null(), None), ffi::SQLITE_OK);
+    }
+    conn.execute("INSERT INTO vals VALUES (1)", []).unwrap();
+    integrity_check(&conn);
+}
+
+#[test]
+fn not_supported() {
+    let handle = common::register_fs("system-calls-unsupported");
+    let vfs = unsafe { &*handle.as_raw() };
+    assert!(vfs.xSetSystemCall.is_none());
+    assert!(vfs.xGetSystemCall.is_none());
+    assert!(vfs.xNextSystemCall.is_none());
+}
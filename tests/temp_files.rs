@@ -0,0 +1,145 @@
+//! Temporary files, which SQLite opens without a name, are opened at [Vfs::temporary_path] and
+//! deleted once they are closed.
+
+mod common;
+
+use std::ffi::CStr;
+use std::fs;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::{integrity_check, open, FsVfs, RawFile, TempDir};
+use rusqlite::ffi;
+use sqlite_vfs::{register, OpenKind, OpenOptions, Vfs};
+
+type Opened = Arc<Mutex<Vec<(OpenKind, PathBuf)>>>;
+
+/// Keeps the temporary files in its own directory.
+struct TempFilesVfs {
+    dir: PathBuf,
+    count: AtomicUsize,
+    opened: Opened,
+}
+
+impl Vfs for TempFilesVfs {
+    type File = std::fs::File;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        self.opened
+            .lock()
+            .unwrap()
+            .push((opts.kind, path.to_path_buf()));
+        FsVfs.open(path, opts)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        let n = self.count.fetch_add(1, Ordering::SeqCst);
+        self.dir.join(format!("temp-{}", n))
+    }
+}
+
+#[test]
+fn vacuum_with_temp_files() {
+    let dir = TempDir::new("temp-files");
+    let temp_dir = TempDir::new("temp-files-temporary");
+    let opened = Opened::default();
+    let _vfs = register(
+        "temp-files",
+        TempFilesVfs {
+            dir: temp_dir.path(""),
+            count: AtomicUsize::new(0),
+            opened: Arc::clone(&opened),
+        },
+    )
+    .unwrap();
+
+    let conn = open(&dir.path("main.db"), "temp-files");
+    conn.execute_batch(
+        "PRAGMA temp_store = FILE;
+        PRAGMA cache_size = 10;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY, val BLOB NOT NULL);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (val) SELECT randomblob(1000) FROM n;
+        DELETE FROM vals WHERE id % 2 = 0;
+        VACUUM;",
+    )
+    .unwrap();
+
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 250);
+    integrity_check(&conn);
+
+    let temporary = opened
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, path)| path.starts_with(temp_dir.path("")))
+        .map(|(kind, _)| *kind)
+        .collect::<Vec<_>>();
+    // with the small cache, the temporary database VACUUM builds the new one in spills to a file
+    assert_eq!(temporary, vec![OpenKind::TempDb]);
+    drop(conn);
+    assert_eq!(fs::read_dir(temp_dir.path("")).unwrap().count(), 0);
+}
+
+#[test]
+fn temp_file_name() {
+    let dir = TempDir::new("temp-files-name");
+    let temp_dir = TempDir::new("temp-files-name-temporary");
+    let _vfs = register(
+        "temp-files-name",
+        TempFilesVfs {
+            dir: temp_dir.path(""),
+            count: AtomicUsize::new(0),
+            opened: Opened::default(),
+        },
+    )
+    .unwrap();
+    let conn = open(&dir.path("main.db"), "temp-files-name");
+
+    let mut name: *mut c_char = std::ptr::null_mut();
+    let code = unsafe {
+        ffi::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            ffi::SQLITE_FCNTL_TEMPFILENAME,
+            &mut name as *mut *mut c_char as *mut _,
+        )
+    };
+    assert_eq!(code, ffi::SQLITE_OK);
+    let path = unsafe { CStr::from_ptr(name) }
+        .to_str()
+        .unwrap()
+        .to_string();
+    unsafe { ffi::sqlite3_free(name as *mut _) };
+    assert_eq!(Path::new(&path), temp_dir.path("temp-0"));
+}
+
+#[test]
+fn named_files_are_left_to_the_vfs() {
+    let dir = TempDir::new("temp-files-named");
+    let _vfs = common::register_fs("temp-files-named");
+
+    // FsVfs does not delete files on close, and the glue does not either for files SQLite named
+    let path = dir.path("main.db-journal");
+    let flags = ffi::SQLITE_OPEN_MAIN_JOURNAL
+        | ffi::SQLITE_OPEN_READWRITE
+        | ffi::SQLITE_OPEN_CREATE
+        | ffi::SQLITE_OPEN_DELETEONCLOSE;
+    let mut file = RawFile::open("temp-files-named", &path, flags).unwrap();
+    assert_eq!(file.write(b"journal", 0), ffi::SQLITE_OK);
+    drop(file);
+    assert_eq!(fs::read(&path).unwrap(), b"journal");
+}
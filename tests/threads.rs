@@ -0,0 +1,88 @@
+//! A registered VFS is used by connections on many threads at once, in both the serialized and
+//! the multi-thread threading mode of SQLite.
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use common::{integrity_check, open, register_fs, TempDir};
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::shm::ShmVfs;
+use sqlite_vfs::VfsHandle;
+
+const THREADS: i64 = 4;
+const ROWS: i64 = 25;
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn handle_and_wrappers_are_send_and_sync() {
+    assert_send_sync::<VfsHandle>();
+    assert_send_sync::<ShmVfs<common::LockingVfs<common::FsVfs>>>();
+}
+
+#[test]
+fn connections_on_many_threads() {
+    for (name, mutex) in [
+        ("threads-full-mutex", OpenFlags::SQLITE_OPEN_FULL_MUTEX),
+        ("threads-no-mutex", OpenFlags::SQLITE_OPEN_NO_MUTEX),
+    ] {
+        let _vfs = register_fs(name);
+        let dir = TempDir::new(name);
+        let path = dir.path("main.db");
+        open(&path, name)
+            .execute_batch(
+                "PRAGMA journal_mode = WAL; CREATE TABLE vals (thread INTEGER, row INTEGER);",
+            )
+            .unwrap();
+
+        thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let path = &path;
+                scope.spawn(move || {
+                    let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | mutex;
+                    let conn = Connection::open_with_flags_and_vfs(path, flags, name).unwrap();
+                    conn.busy_timeout(Duration::from_secs(10)).unwrap();
+                    for row in 0..ROWS {
+                        conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+                        conn.execute("INSERT INTO vals VALUES (?, ?)", [thread, row])
+                            .unwrap();
+                        conn.execute_batch("COMMIT").unwrap();
+                    }
+                });
+            }
+        });
+
+        let conn = open(&path, name);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, THREADS * ROWS, "{}", name);
+        integrity_check(&conn);
+    }
+}
+
+#[test]
+fn connection_moved_between_threads() {
+    let _vfs = register_fs("threads-moved");
+    let dir = TempDir::new("threads-moved");
+    let mut conn = open(&dir.path("main.db"), "threads-moved");
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+        .unwrap();
+
+    for id in 0..THREADS {
+        conn = thread::spawn(move || {
+            conn.execute("INSERT INTO vals VALUES (?)", [id]).unwrap();
+            conn
+        })
+        .join()
+        .unwrap();
+    }
+
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, THREADS);
+    integrity_check(&conn);
+}
@@ -0,0 +1,140 @@
+//! A [ThrottleVfs] delays reads and writes beyond its limits.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{integrity_check, open};
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::register;
+use sqlite_vfs::throttle::{Priority, ThrottleOptions, ThrottleVfs};
+use sqlite_vfs::{File, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+const OPTS: OpenOptions = OpenOptions {
+    kind: OpenKind::MainDb,
+    access: OpenAccess::Create,
+    delete_on_close: false,
+};
+
+/// How long it takes to write 10 times to each of `paths`, one after the other.
+fn write_each(vfs: &ThrottleVfs<MemVfs>, paths: &[&str]) -> Duration {
+    let mut files = paths
+        .iter()
+        .map(|path| vfs.open(Path::new(path), OPTS).unwrap())
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    for file in &mut files {
+        for i in 0..10 {
+            file.write_all_at(&[1; 512], i * 512).unwrap();
+        }
+    }
+    start.elapsed()
+}
+
+#[test]
+fn iops() {
+    let options = ThrottleOptions {
+        iops: Some(100),
+        burst: Duration::ZERO,
+        ..Default::default()
+    };
+    let vfs = ThrottleVfs::new(MemVfs::new(), options);
+    let mut file = vfs.open(Path::new("main.db"), OPTS).unwrap();
+
+    let start = Instant::now();
+    for i in 0..20 {
+        file.write_all_at(&[1; 512], i * 512).unwrap();
+    }
+    let mut buf = [0; 512];
+    for i in 0..10 {
+        assert_eq!(file.read_at(&mut buf, i * 512).unwrap(), 512);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(280));
+}
+
+#[test]
+fn burst() {
+    // 10 operations can run without waiting
+    let options = ThrottleOptions {
+        iops: Some(50),
+        burst: Duration::from_millis(200),
+        ..Default::default()
+    };
+
+    let vfs = ThrottleVfs::new(MemVfs::new(), options.clone());
+    assert!(write_each(&vfs, &["a.db"]) < Duration::from_millis(150));
+    // the limit is shared by all files
+    let vfs = ThrottleVfs::new(MemVfs::new(), options.clone());
+    assert!(write_each(&vfs, &["a.db", "b.db"]) >= Duration::from_millis(190));
+
+    let options = ThrottleOptions {
+        per_file: true,
+        ..options
+    };
+    let vfs = ThrottleVfs::new(MemVfs::new(), options);
+    assert!(write_each(&vfs, &["a.db", "b.db"]) < Duration::from_millis(150));
+}
+
+#[test]
+fn bandwidth() {
+    let options = ThrottleOptions {
+        write_bandwidth: Some(1024 * 1024),
+        burst: Duration::ZERO,
+        ..Default::default()
+    };
+    let _vfs = register("throttle", ThrottleVfs::new(MemVfs::new(), options)).unwrap();
+    let path = Path::new("/throttle/main.db");
+
+    let start = Instant::now();
+    let conn = open(path, "throttle");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val BLOB);
+        INSERT INTO vals (val) VALUES (zeroblob(256 * 1024));",
+    )
+    .unwrap();
+    // the blob is written to the database once (and not to the journal, as the table was empty)
+    assert!(start.elapsed() >= Duration::from_millis(240));
+
+    // reads are not limited
+    let start = Instant::now();
+    let len: i64 = conn
+        .query_row("SELECT length(val) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(len, 256 * 1024);
+    integrity_check(&conn);
+    assert!(start.elapsed() < Duration::from_millis(240));
+}
+
+#[test]
+fn priorities() {
+    let options = ThrottleOptions {
+        iops: Some(100),
+        burst: Duration::ZERO,
+        priorities: [(PathBuf::from("background.db"), Priority::Background)].into(),
+        ..Default::default()
+    };
+    let vfs = Arc::new(ThrottleVfs::new(MemVfs::new(), options));
+
+    let background = {
+        let vfs = Arc::clone(&vfs);
+        thread::spawn(move || {
+            let mut file = vfs.open(Path::new("background.db"), OPTS).unwrap();
+            for i in 0..30 {
+                file.write_all_at(&[1; 512], i * 512).unwrap();
+            }
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+
+    // the foreground gets all of the operations per second, as if the background did not run
+    let mut file = vfs.open(Path::new("foreground.db"), OPTS).unwrap();
+    let start = Instant::now();
+    for i in 0..10 {
+        file.write_all_at(&[1; 512], i * 512).unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_millis(180));
+    background.join().unwrap();
+}
@@ -0,0 +1,400 @@
+//! With the `tracing` feature, a [TraceVfs] emits an event for each operation, and a span for
+//! each write transaction and checkpoint.
+#![cfg(feature = "tracing")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{open, TempDir};
+use sqlite_vfs::fault::FaultVfs;
+use sqlite_vfs::mem::MemVfs;
+use sqlite_vfs::register;
+use sqlite_vfs::shm::ShmVfs;
+use sqlite_vfs::trace::{SqliteSink, TraceOptions, TraceVfs};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+type Fields = HashMap<&'static str, String>;
+
+/// Collects the fields of all events and spans of the `sqlite_vfs::trace` target (and of the spans
+/// of the tests).
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<Collected>>);
+
+#[derive(Default)]
+struct Collected {
+    /// The events, with the span they were recorded in.
+    events: Vec<(Level, Fields, Option<usize>)>,
+    spans: Vec<SpanData>,
+    /// The spans entered (on the only thread that records).
+    entered: Vec<usize>,
+}
+
+struct SpanData {
+    name: &'static str,
+    fields: Fields,
+    parent: Option<usize>,
+    refs: usize,
+}
+
+struct Visitor<'a>(&'a mut Fields);
+
+impl Visit for Visitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+}
+
+fn index(id: &Id) -> usize {
+    id.into_u64() as usize - 1
+}
+
+impl Subscriber for Collector {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target() == "sqlite_vfs::trace" || metadata.target() == "trace"
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut collected = self.0.lock().unwrap();
+        let mut fields = Fields::new();
+        span.record(&mut Visitor(&mut fields));
+        let parent = match span.parent() {
+            Some(parent) => Some(index(parent)),
+            None if span.is_contextual() => collected.entered.last().copied(),
+            None => None,
+        };
+        collected.spans.push(SpanData {
+            name: span.metadata().name(),
+            fields,
+            parent,
+            refs: 1,
+        });
+        Id::from_u64(collected.spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut collected = self.0.lock().unwrap();
+        values.record(&mut Visitor(&mut collected.spans[index(span)].fields));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::new();
+        event.record(&mut Visitor(&mut fields));
+        let level = *event.metadata().level();
+        let mut collected = self.0.lock().unwrap();
+        let span = collected.entered.last().copied();
+        collected.events.push((level, fields, span));
+    }
+
+    fn enter(&self, span: &Id) {
+        self.0.lock().unwrap().entered.push(index(span));
+    }
+
+    fn exit(&self, span: &Id) {
+        let mut collected = self.0.lock().unwrap();
+        assert_eq!(collected.entered.pop(), Some(index(span)));
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        self.0.lock().unwrap().spans[index(span)].refs += 1;
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut collected = self.0.lock().unwrap();
+        let span = &mut collected.spans[index(&span)];
+        span.refs -= 1;
+        span.refs == 0
+    }
+}
+
+impl Collector {
+    fn events(&self, op: &str) -> Vec<(Level, Fields)> {
+        let collected = self.0.lock().unwrap();
+        collected
+            .events
+            .iter()
+            .filter(|(_, fields, _)| fields["op"] == op)
+            .map(|(level, fields, _)| (*level, fields.clone()))
+            .collect()
+    }
+
+    /// The spans named `name`, with their fields, the name of their parent, and the events
+    /// recorded in them (as `op path`).
+    fn spans(&self, name: &str) -> Vec<(Fields, Option<&'static str>, Vec<String>)> {
+        let collected = self.0.lock().unwrap();
+        let spans = &collected.spans;
+        (0..spans.len())
+            .filter(|i| spans[*i].name == name)
+            .map(|i| {
+                assert_eq!(spans[i].refs, 0, "span {} was not closed", name);
+                let events = collected
+                    .events
+                    .iter()
+                    .filter(|(_, _, span)| *span == Some(i))
+                    .map(|(_, fields, _)| format!("{} {}", fields["op"], fields["path"]))
+                    .collect();
+                let parent = spans[i].parent.map(|parent| spans[parent].name);
+                (spans[i].fields.clone(), parent, events)
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn operations_are_traced() {
+    let _vfs = register("trace", TraceVfs::new(MemVfs::new())).unwrap();
+    let collector = Collector::default();
+
+    tracing::subscriber::with_default(collector.clone(), || {
+        let conn = open(Path::new("/trace/main.db"), "trace");
+        conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT)")
+            .unwrap();
+    });
+
+    let opens = collector.events("open");
+    assert!(opens
+        .iter()
+        .any(|(_, fields)| fields["path"] == "/trace/main.db"));
+    assert!(opens
+        .iter()
+        .any(|(_, fields)| fields["path"] == "/trace/main.db-journal"));
+
+    let writes = collector.events("write");
+    assert!(!writes.is_empty());
+    for (level, fields) in &writes {
+        assert_eq!(*level, Level::TRACE);
+        assert!(fields.contains_key("offset"));
+        assert!(fields.contains_key("duration_us"));
+        assert_eq!(fields["result"], "()");
+    }
+    // the first write to the database is page 1
+    let (_, first) = writes
+        .iter()
+        .find(|(_, fields)| fields["path"] == "/trace/main.db")
+        .unwrap();
+    assert_eq!(first["offset"], "0");
+    assert_eq!(first["len"], "4096");
+
+    assert!(!collector.events("sync").is_empty());
+    let locks = collector.events("lock");
+    assert!(locks
+        .iter()
+        .any(|(_, fields)| fields["result"] == "(Exclusive, true)"));
+}
+
+#[test]
+fn errors_are_traced() {
+    let vfs = FaultVfs::new(MemVfs::new());
+    let faults = vfs.faults();
+    let _vfs = register("trace-errors", TraceVfs::new(vfs)).unwrap();
+    let collector = Collector::default();
+
+    tracing::subscriber::with_default(collector.clone(), || {
+        let conn = open(Path::new("/trace-errors/main.db"), "trace-errors");
+        faults.fail_write(1);
+        assert!(conn
+            .execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY)")
+            .is_err());
+    });
+
+    let failed = collector
+        .events("write")
+        .into_iter()
+        .filter(|(level, _)| *level == Level::WARN)
+        .collect::<Vec<_>>();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].1["error"], "injected write fault");
+    assert!(!failed[0].1.contains_key("result"));
+}
+
+#[test]
+fn transactions_are_spans() {
+    let _vfs = register("trace-transactions", TraceVfs::new(MemVfs::new())).unwrap();
+    let collector = Collector::default();
+
+    tracing::subscriber::with_default(collector.clone(), || {
+        let conn = open(Path::new("/main.db"), "trace-transactions");
+        tracing::info_span!("request").in_scope(|| {
+            conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT)")
+                .unwrap();
+        });
+        conn.execute_batch("BEGIN; INSERT INTO vals (val) VALUES ('a'); ROLLBACK;")
+            .unwrap();
+        conn.query_row("SELECT COUNT(*) FROM vals", [], |_| Ok(()))
+            .unwrap();
+    });
+
+    let transactions = collector.spans("transaction");
+    assert_eq!(transactions.len(), 2);
+    let (fields, parent, events) = &transactions[0];
+    assert_eq!(fields["path"], "/main.db");
+    assert_eq!(fields["outcome"], "commit");
+    assert_eq!(*parent, Some("request"));
+    for event in [
+        "write /main.db-journal",
+        "write /main.db",
+        "sync /main.db",
+        "post_commit /main.db",
+    ] {
+        assert!(
+            events.iter().any(|e| e == event),
+            "{} in {:?}",
+            event,
+            events
+        );
+    }
+
+    let (fields, parent, events) = &transactions[1];
+    assert_eq!(fields["outcome"], "rollback");
+    assert_eq!(*parent, None);
+    assert!(!events.iter().any(|e| e == "post_commit /main.db"));
+
+    // reads outside of transactions belong to no span
+    let (_, _, span) = collector.0.lock().unwrap().events.last().cloned().unwrap();
+    assert_eq!(span, None);
+}
+
+#[test]
+fn checkpoints_are_spans() {
+    let vfs = ShmVfs::new(MemVfs::new());
+    let _vfs = register("trace-checkpoints", TraceVfs::new(vfs)).unwrap();
+    let collector = Collector::default();
+
+    tracing::subscriber::with_default(collector.clone(), || {
+        let conn = open(Path::new("/main.db"), "trace-checkpoints");
+        conn.query_row("PRAGMA journal_mode = wal", [], |_| Ok(()))
+            .unwrap();
+        conn.execute_batch(
+            "PRAGMA wal_autocheckpoint = 0;
+            CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+            INSERT INTO vals (val) VALUES ('a');
+            PRAGMA wal_checkpoint(TRUNCATE);",
+        )
+        .unwrap();
+    });
+
+    let transactions = collector.spans("transaction");
+    let (fields, _, events) = transactions.last().unwrap();
+    assert_eq!(fields["outcome"], "commit");
+    assert!(events.iter().any(|e| e == "write /main.db-wal"));
+    assert!(!events.iter().any(|e| e == "write /main.db"));
+
+    let checkpoints = collector.spans("checkpoint");
+    assert_eq!(checkpoints.len(), 1);
+    let (fields, _, events) = &checkpoints[0];
+    assert_eq!(fields["path"], "/main.db");
+    assert!(events.iter().any(|e| e == "read /main.db-wal"));
+    assert!(events.iter().any(|e| e == "write /main.db"));
+}
+
+#[test]
+fn slow_ops_are_info() {
+    let vfs = TraceVfs::with_slow_ops(MemVfs::new(), Duration::ZERO);
+    let _vfs = register("trace-slow", vfs).unwrap();
+    let collector = Collector::default();
+
+    tracing::subscriber::with_default(collector.clone(), || {
+        let conn = open(Path::new("/main.db"), "trace-slow");
+        conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT)")
+            .unwrap();
+    });
+
+    let writes = collector.events("write");
+    assert!(!writes.is_empty());
+    for (level, fields) in &writes {
+        assert_eq!(*level, Level::INFO);
+        assert_eq!(fields["result"], "()");
+    }
+}
+
+#[test]
+fn sqlite_sink() {
+    let dir = TempDir::new("trace-sink");
+    let sink = SqliteSink::open(dir.path("trace.db"), None).unwrap();
+    let vfs = FaultVfs::new(MemVfs::new());
+    let faults = vfs.faults();
+    let options = TraceOptions {
+        sink: Some(sink.clone()),
+        ..Default::default()
+    };
+    let vfs = register("trace-sink", TraceVfs::with_options(vfs, options)).unwrap();
+
+    let conn = open(Path::new("/main.db"), "trace-sink");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        INSERT INTO vals (val) VALUES ('a'), ('b');",
+    )
+    .unwrap();
+    faults.fail_write(1);
+    assert!(conn
+        .execute("INSERT INTO vals (val) VALUES ('c')", [])
+        .is_err());
+    drop(conn);
+    sink.flush();
+
+    // the operations are written even without a subscriber
+    let trace = rusqlite::Connection::open(dir.path("trace.db")).unwrap();
+    let writes: i64 = trace
+        .query_row(
+            "SELECT count(*) FROM operations
+            WHERE op = 'write' AND path = '/main.db' AND offset IS NOT NULL AND len = 4096
+                AND duration_us >= 0 AND result = '()' AND time_us > 0",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(writes > 0);
+    let error: String = trace
+        .query_row(
+            "SELECT error FROM operations WHERE op = 'write' AND error IS NOT NULL",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(error, "injected write fault");
+
+    // op, path and duration can be queried through indexes
+    for (column, index) in [
+        ("op", "operations_op"),
+        ("path", "operations_path"),
+        ("duration_us", "operations_duration_us"),
+    ] {
+        let plan: String = trace
+            .query_row(
+                &format!(
+                    "EXPLAIN QUERY PLAN SELECT count(*) FROM operations WHERE {} = 1",
+                    column
+                ),
+                [],
+                |row| row.get(3),
+            )
+            .unwrap();
+        assert!(plan.contains(index), "{}", plan);
+    }
+
+    // an existing database keeps the operations written before
+    drop(vfs);
+    drop(sink);
+    let count = |trace: &rusqlite::Connection| -> i64 {
+        trace
+            .query_row("SELECT count(*) FROM operations", [], |row| row.get(0))
+            .unwrap()
+    };
+    let before = count(&trace);
+    let sink = SqliteSink::open(dir.path("trace.db"), None).unwrap();
+    drop(sink);
+    assert_eq!(count(&trace), before);
+}
@@ -0,0 +1,218 @@
+//! Pages stay transformed at rest, and crash recovery works through a [TransformVfs], both with a
+//! rollback journal and with a WAL.
+
+mod common;
+
+use std::fs;
+
+use common::{integrity_check, open, FsVfs, LockingVfs, TempDir};
+use rusqlite::Connection;
+use sqlite_vfs::register;
+use sqlite_vfs::shm::ShmVfs;
+use sqlite_vfs::transform::{PageLocation, PageTransform, TransformVfs};
+
+const RESERVE_BYTES: u8 = 4;
+const DB_HEADER_SIZE: usize = 100;
+
+/// A toy cipher: XOR every byte with a key derived from the page location, and store a checksum of
+/// the plain page in the reserved bytes to detect pages decoded with the wrong location.
+struct XorTransform;
+
+impl XorTransform {
+    fn key(location: &PageLocation) -> u8 {
+        0x5a ^ (location.index as u8)
+    }
+
+    /// The part of `page` that is transformed (leaving the database header of page 1 unchanged).
+    fn payload<'a>(page: &'a mut [u8], location: &PageLocation) -> &'a mut [u8] {
+        let start = if location.kind == sqlite_vfs::OpenKind::MainDb && location.index == 0 {
+            DB_HEADER_SIZE
+        } else {
+            0
+        };
+        let end = page.len() - RESERVE_BYTES as usize;
+        &mut page[start..end]
+    }
+
+    fn checksum(data: &[u8]) -> [u8; 4] {
+        data.iter()
+            .fold(0u32, |sum, b| sum.wrapping_mul(31).wrapping_add(*b as u32))
+            .to_le_bytes()
+    }
+}
+
+impl PageTransform for XorTransform {
+    fn reserve_bytes(&self) -> u8 {
+        RESERVE_BYTES
+    }
+
+    fn encode(&self, page: &mut [u8], location: PageLocation) -> Result<(), std::io::Error> {
+        let len = page.len();
+        let checksum = Self::checksum(Self::payload(page, &location));
+        page[len - RESERVE_BYTES as usize..].copy_from_slice(&checksum);
+        let key = Self::key(&location);
+        for b in Self::payload(page, &location) {
+            *b ^= key;
+        }
+        Ok(())
+    }
+
+    fn decode(&self, page: &mut [u8], location: PageLocation) -> Result<(), std::io::Error> {
+        let len = page.len();
+        let key = Self::key(&location);
+        for b in Self::payload(page, &location) {
+            *b ^= key;
+        }
+        let checksum = Self::checksum(Self::payload(page, &location));
+        if page[len - RESERVE_BYTES as usize..] != checksum {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("checksum mismatch for {:?}", location),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn reserve_bytes(conn: &Connection) {
+    unsafe { sqlite_vfs::set_reserve_bytes(conn.handle(), c"main", RESERVE_BYTES) }.unwrap();
+}
+
+const MARKER: &str = "plain text marker";
+
+fn contains_marker(path: &std::path::Path) -> bool {
+    fs::read(path)
+        .unwrap()
+        .windows(MARKER.len())
+        .any(|w| w == MARKER.as_bytes())
+}
+
+/// Create a database in WAL mode with 500 rows, which are kept in the WAL.
+fn create_wal(path: &std::path::Path, vfs: &str) -> Connection {
+    let conn = open(path, vfs);
+    reserve_bytes(&conn);
+    conn.execute_batch("PRAGMA wal_autocheckpoint = 0").unwrap();
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    conn.execute_batch(&format!(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (text) SELECT '{}' || i FROM n;",
+        MARKER
+    ))
+    .unwrap();
+    conn
+}
+
+fn count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn hot_journal_recovery() {
+    let _vfs = register("transform-recovery", TransformVfs::new(FsVfs, XorTransform)).unwrap();
+    let dir = TempDir::new("transform-recovery");
+    let crash_dir = TempDir::new("transform-recovery-crashed");
+
+    let conn = open(&dir.path("main.db"), "transform-recovery");
+    reserve_bytes(&conn);
+    conn.execute_batch(&format!(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+        INSERT INTO vals (text) SELECT '{}' || i FROM n;",
+        MARKER
+    ))
+    .unwrap();
+    let committed = fs::read(dir.path("main.db")).unwrap();
+    assert!(
+        !committed
+            .windows(MARKER.len())
+            .any(|w| w == MARKER.as_bytes()),
+        "database stored in plain text"
+    );
+
+    // Force SQLite to write changed pages to the database before the commit, and copy the files
+    // in the middle of the transaction, as if the process crashed.
+    conn.execute_batch(
+        "PRAGMA cache_size = 1;
+        BEGIN;
+        UPDATE vals SET text = 'changed';
+        DELETE FROM vals WHERE id % 3 = 0;",
+    )
+    .unwrap();
+    for name in ["main.db", "main.db-journal"] {
+        fs::copy(dir.path(name), crash_dir.path(name)).unwrap();
+    }
+    assert_ne!(fs::read(crash_dir.path("main.db")).unwrap(), committed);
+    conn.execute_batch("ROLLBACK").unwrap();
+
+    // The hot journal is rolled back (decoding the journal pages) on the next read.
+    let conn = open(&crash_dir.path("main.db"), "transform-recovery");
+    integrity_check(&conn);
+    let (count, changed): (i64, i64) = conn
+        .query_row(
+            "SELECT COUNT(*), SUM(text = 'changed') FROM vals",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((count, changed), (500, 0));
+    assert!(!crash_dir.path("main.db-journal").exists());
+}
+
+#[test]
+fn wal_round_trip() {
+    let vfs = TransformVfs::new(ShmVfs::new(LockingVfs(FsVfs)), XorTransform);
+    let _vfs = register("transform-wal", vfs).unwrap();
+    let dir = TempDir::new("transform-wal");
+    let path = dir.path("main.db");
+
+    // the pages are only in the WAL, but not in plain text
+    let conn = create_wal(&path, "transform-wal");
+    assert!(!contains_marker(&dir.path("main.db-wal")));
+    let reader = open(&path, "transform-wal");
+    assert_eq!(count(&reader), 500);
+    integrity_check(&reader);
+
+    // the checkpoint decodes the pages in the WAL and encodes them again for the database
+    conn.execute_batch(
+        "UPDATE vals SET text = 'changed' WHERE id % 2 = 0;
+        PRAGMA wal_checkpoint(TRUNCATE);",
+    )
+    .unwrap();
+    assert!(!contains_marker(&path));
+    assert_eq!(fs::metadata(dir.path("main.db-wal")).unwrap().len(), 0);
+    let changed: i64 = reader
+        .query_row("SELECT SUM(text = 'changed') FROM vals", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(changed, 250);
+    integrity_check(&reader);
+}
+
+#[test]
+fn wal_recovery() {
+    let vfs = TransformVfs::new(ShmVfs::new(LockingVfs(FsVfs)), XorTransform);
+    let _vfs = register("transform-wal-recovery", vfs).unwrap();
+    let dir = TempDir::new("transform-wal-recovery");
+    let crash_dir = TempDir::new("transform-wal-recovery-crashed");
+
+    // copy the database and its WAL (but not the shared memory), as if the process crashed
+    let conn = create_wal(&dir.path("main.db"), "transform-wal-recovery");
+    conn.execute("DELETE FROM vals WHERE id % 5 = 0", [])
+        .unwrap();
+    for name in ["main.db", "main.db-wal"] {
+        fs::copy(dir.path(name), crash_dir.path(name)).unwrap();
+    }
+    assert!(fs::metadata(crash_dir.path("main.db-wal")).unwrap().len() > 0);
+
+    // the WAL index is rebuilt from the frames (read along with their headers, whose checksums
+    // cover the decoded pages)
+    let conn = open(&crash_dir.path("main.db"), "transform-wal-recovery");
+    assert_eq!(count(&conn), 400);
+    integrity_check(&conn);
+}
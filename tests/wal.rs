@@ -0,0 +1,443 @@
+//! WAL mode works with normal locking once the [File] of the database provides [SharedMemory],
+//! and between the connections of a process with [ShmVfs].
+
+mod common;
+
+use std::ops::Range;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{integrity_check, open, FsVfs, LockingVfs, TempDir};
+use rusqlite::ffi;
+use sqlite_vfs::shm::ShmVfs;
+use sqlite_vfs::{register, File, OpenKind, OpenOptions, SharedMemory, ShmLock, SyncOptions, Vfs};
+
+type Events = Arc<Mutex<Vec<String>>>;
+
+/// Gives each main database file its own shared memory, which is enough for a single connection.
+#[derive(Default)]
+struct PrivateShmVfs {
+    events: Events,
+}
+
+struct PrivateShmFile {
+    file: std::fs::File,
+    shm: Option<PrivateShm>,
+}
+
+struct PrivateShm {
+    /// Regions are allocated as `u64` to be aligned to 8 bytes.
+    regions: Vec<Box<[u64]>>,
+    events: Events,
+}
+
+impl Vfs for PrivateShmVfs {
+    type File = PrivateShmFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        let shm = (opts.kind == OpenKind::MainDb).then(|| PrivateShm {
+            regions: Vec::new(),
+            events: Arc::clone(&self.events),
+        });
+        Ok(PrivateShmFile {
+            file: FsVfs.open(path, opts)?,
+            shm,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for PrivateShmFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn shared_memory(&mut self) -> Option<&mut dyn SharedMemory> {
+        self.shm.as_mut().map(|shm| shm as &mut dyn SharedMemory)
+    }
+}
+
+unsafe impl SharedMemory for PrivateShm {
+    fn map(
+        &mut self,
+        index: u32,
+        size: usize,
+        extend: bool,
+    ) -> Result<Option<NonNull<u8>>, std::io::Error> {
+        let index = index as usize;
+        while self.regions.len() <= index {
+            if !extend {
+                return Ok(None);
+            }
+            self.regions
+                .push(vec![0u64; size.div_ceil(8)].into_boxed_slice());
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("map {}", self.regions.len() - 1));
+        }
+        Ok(NonNull::new(self.regions[index].as_mut_ptr() as *mut u8))
+    }
+
+    fn lock(&mut self, _slots: Range<u8>, _lock: ShmLock) -> Result<bool, std::io::Error> {
+        Ok(true)
+    }
+
+    fn unlock(&mut self, _slots: Range<u8>, _lock: ShmLock) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn unmap(&mut self, delete: bool) -> Result<(), std::io::Error> {
+        self.regions.clear();
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("unmap delete={}", delete));
+        Ok(())
+    }
+}
+
+fn count(conn: &rusqlite::Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn wal_mode() {
+    let vfs = PrivateShmVfs::default();
+    let events = Arc::clone(&vfs.events);
+    let _vfs = register("wal-private-shm", vfs).unwrap();
+    let dir = TempDir::new("wal-private-shm");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "wal-private-shm");
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    conn.execute_batch(
+        "PRAGMA wal_autocheckpoint = 0;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+        INSERT INTO vals (text) SELECT 'value ' || i FROM n;",
+    )
+    .unwrap();
+    assert!(dir.path("main.db-wal").exists());
+    assert_eq!(events.lock().unwrap().first().unwrap(), "map 0");
+
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    conn.execute("DELETE FROM vals WHERE id % 2 = 0", [])
+        .unwrap();
+    assert_eq!(count(&conn), 500);
+    integrity_check(&conn);
+    drop(conn);
+    assert_eq!(events.lock().unwrap().last().unwrap(), "unmap delete=true");
+    assert!(!dir.path("main.db-wal").exists());
+
+    let conn = open(&path, "wal-private-shm");
+    assert_eq!(count(&conn), 500);
+    integrity_check(&conn);
+}
+
+#[test]
+fn readers_keep_their_snapshot() {
+    let _vfs = register("wal-in-process", ShmVfs::new(LockingVfs(FsVfs))).unwrap();
+    let dir = TempDir::new("wal-in-process");
+    let path = dir.path("main.db");
+
+    let writer = open(&path, "wal-in-process");
+    writer
+        .execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+            INSERT INTO vals (text) VALUES ('a'), ('b');",
+        )
+        .unwrap();
+
+    let reader = open(&path, "wal-in-process");
+    reader.execute_batch("BEGIN").unwrap();
+    assert_eq!(count(&reader), 2);
+
+    // the writer is not blocked by the open read transaction ...
+    writer
+        .execute("INSERT INTO vals (text) VALUES ('c')", [])
+        .unwrap();
+    assert_eq!(count(&writer), 3);
+    // ... which still sees the database as of its start
+    assert_eq!(count(&reader), 2);
+    reader.execute_batch("COMMIT").unwrap();
+    assert_eq!(count(&reader), 3);
+
+    writer
+        .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    drop(writer);
+    integrity_check(&reader);
+    drop(reader);
+    assert!(!dir.path("main.db-wal").exists());
+}
+
+#[test]
+fn external_reader() {
+    let _vfs = register("wal-external-reader", ShmVfs::new(LockingVfs(FsVfs))).unwrap();
+    let dir = TempDir::new("wal-external-reader");
+    let path = dir.path("main.db");
+
+    let writer = open(&path, "wal-external-reader");
+    writer
+        .execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);")
+        .unwrap();
+    let reader = open(&path, "wal-external-reader");
+    reader.execute_batch("BEGIN").unwrap();
+    assert_eq!(count(&reader), 0);
+    // there is no shared memory in rollback journal mode
+    assert_eq!(external_readers(&writer), 0);
+    reader.execute_batch("COMMIT").unwrap();
+
+    writer
+        .execute_batch("PRAGMA journal_mode = WAL; INSERT INTO vals (text) VALUES ('a');")
+        .unwrap();
+    assert_eq!(external_readers(&writer), 0);
+    reader.execute_batch("BEGIN").unwrap();
+    assert_eq!(count(&reader), 1);
+    assert_eq!(external_readers(&writer), 1);
+    // a connection's own read transaction does not count
+    assert_eq!(external_readers(&reader), 0);
+
+    // a checkpoint cannot restart the WAL while the reader is using it
+    writer.busy_timeout(Duration::ZERO).unwrap();
+    writer
+        .execute("INSERT INTO vals (text) VALUES ('b')", [])
+        .unwrap();
+    let busy: i64 = writer
+        .query_row("PRAGMA wal_checkpoint(RESTART)", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(busy, 1);
+
+    reader.execute_batch("COMMIT").unwrap();
+    assert_eq!(external_readers(&writer), 0);
+    let busy: i64 = writer
+        .query_row("PRAGMA wal_checkpoint(RESTART)", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(busy, 0);
+    assert_eq!(count(&reader), 2);
+}
+
+/// Send `SQLITE_FCNTL_EXTERNAL_READER` to the main database of `conn`.
+fn external_readers(conn: &rusqlite::Connection) -> c_int {
+    let mut readers = -1;
+    let code = unsafe {
+        ffi::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            ffi::SQLITE_FCNTL_EXTERNAL_READER,
+            &mut readers as *mut c_int as *mut _,
+        )
+    };
+    assert_eq!(code, ffi::SQLITE_OK);
+    readers
+}
+
+#[test]
+fn regions_are_pooled() {
+    let vfs = ShmVfs::new(LockingVfs(FsVfs));
+    let pool = vfs.pool();
+    let _vfs = register("wal-pool", vfs).unwrap();
+    let dir = TempDir::new("wal-pool");
+    let path = dir.path("main.db");
+    let journal_mode = |conn: &rusqlite::Connection, mode: &str| {
+        conn.query_row(&format!("PRAGMA journal_mode = {}", mode), [], |_| Ok(()))
+            .unwrap();
+    };
+
+    let conn = open(&path, "wal-pool");
+    journal_mode(&conn, "wal");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+        INSERT INTO vals (text) VALUES ('a'), ('b');",
+    )
+    .unwrap();
+    let occupancy = pool.occupancy();
+    assert_eq!(occupancy.databases, 1);
+    assert!(occupancy.regions > 0);
+    assert_eq!(occupancy.mapped, occupancy.regions);
+    assert_eq!(occupancy.pooled, 0);
+    assert_eq!(occupancy.bytes, occupancy.regions * 32 * 1024);
+    assert_eq!(occupancy.reused, 0);
+
+    // leaving WAL mode unmaps the shared memory, whose regions stay allocated ...
+    journal_mode(&conn, "delete");
+    let unmapped = pool.occupancy();
+    assert_eq!(unmapped.pooled, occupancy.regions);
+    assert_eq!(unmapped.mapped, 0);
+
+    // ... until WAL mode is used again
+    journal_mode(&conn, "wal");
+    conn.execute("INSERT INTO vals (text) VALUES ('c')", [])
+        .unwrap();
+    let remapped = pool.occupancy();
+    assert_eq!(remapped.mapped, remapped.regions);
+    assert_eq!(remapped.reused, occupancy.regions as u64);
+    assert_eq!(count(&conn), 3);
+    integrity_check(&conn);
+
+    // the regions are freed when the last connection closes
+    drop(conn);
+    let closed = pool.occupancy();
+    assert_eq!((closed.databases, closed.regions, closed.bytes), (0, 0, 0));
+    let conn = open(&path, "wal-pool");
+    assert_eq!(count(&conn), 3);
+}
+
+#[test]
+fn persist_wal() {
+    let _vfs = common::register_fs("wal-persist");
+    let dir = TempDir::new("wal-persist");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "wal-persist");
+    conn.execute_batch(
+        "PRAGMA journal_mode = WAL;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+        INSERT INTO vals (text) VALUES ('a');",
+    )
+    .unwrap();
+    assert_eq!(persist(&conn, -1), 0);
+    assert_eq!(persist(&conn, 1), 1);
+    assert_eq!(persist(&conn, -1), 1);
+    drop(conn);
+    assert!(dir.path("main.db-wal").exists());
+
+    let conn = open(&path, "wal-persist");
+    assert_eq!(count(&conn), 1);
+    assert_eq!(persist(&conn, -1), 0);
+    drop(conn);
+    assert!(!dir.path("main.db-wal").exists());
+}
+
+/// Send `SQLITE_FCNTL_PERSIST_WAL` with `arg` to the main database of `conn`, and return the
+/// argument afterwards.
+fn persist(conn: &rusqlite::Connection, mut arg: c_int) -> c_int {
+    let code = unsafe {
+        ffi::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            ffi::SQLITE_FCNTL_PERSIST_WAL,
+            &mut arg as *mut c_int as *mut _,
+        )
+    };
+    assert_eq!(code, ffi::SQLITE_OK);
+    arg
+}
+
+/// Keeps the WAL files of all databases (like a backend on which creating them is expensive), and
+/// records what the application sets.
+#[derive(Default)]
+struct KeepWalVfs {
+    set: Arc<Mutex<Vec<bool>>>,
+}
+
+struct KeepWalFile {
+    file: std::fs::File,
+    set: Arc<Mutex<Vec<bool>>>,
+}
+
+impl Vfs for KeepWalVfs {
+    type File = KeepWalFile;
+
+    fn open(&self, path: &Path, opts: OpenOptions) -> Result<Self::File, std::io::Error> {
+        Ok(KeepWalFile {
+            file: FsVfs.open(path, opts)?,
+            set: Arc::clone(&self.set),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
+        FsVfs.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, std::io::Error> {
+        FsVfs.exists(path)
+    }
+}
+
+impl File for KeepWalFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, options: SyncOptions) -> Result<(), std::io::Error> {
+        File::sync(&mut self.file, options)
+    }
+
+    fn file_size(&self) -> Result<u64, std::io::Error> {
+        self.file.file_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.truncate(size)
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.set.lock().unwrap().push(persist);
+    }
+
+    fn persist_wal(&self) -> Option<bool> {
+        Some(true)
+    }
+}
+
+#[test]
+fn persist_wal_of_file() {
+    let vfs = KeepWalVfs::default();
+    let set = Arc::clone(&vfs.set);
+    let _vfs = register("wal-persist-file", ShmVfs::new(vfs)).unwrap();
+    let dir = TempDir::new("wal-persist-file");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "wal-persist-file");
+    conn.execute_batch(
+        "PRAGMA journal_mode = WAL;
+        CREATE TABLE vals (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+        INSERT INTO vals (text) VALUES ('a');",
+    )
+    .unwrap();
+    // the file keeps the WAL, whatever the application sets
+    assert_eq!(persist(&conn, -1), 1);
+    persist(&conn, 0);
+    assert_eq!(*set.lock().unwrap(), vec![false]);
+    assert_eq!(persist(&conn, -1), 1);
+    drop(conn);
+    assert!(dir.path("main.db-wal").exists());
+}
@@ -0,0 +1,202 @@
+//! Realistic workloads run through rusqlite, so that regressions in the glue are caught by real
+//! SQLite behavior.
+
+mod common;
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use common::{integrity_check, open, register_fs, TempDir};
+use rusqlite::{params, DatabaseName, ErrorCode};
+
+#[test]
+fn savepoints() {
+    let _vfs = register_fs("workloads-savepoints");
+    let dir = TempDir::new("savepoints");
+    let mut conn = open(&dir.path("main.db"), "workloads-savepoints");
+
+    conn.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT NOT NULL)")
+        .unwrap();
+
+    let mut sp = conn.savepoint().unwrap();
+    sp.execute("INSERT INTO vals (val) VALUES ('outer')", [])
+        .unwrap();
+    {
+        let mut inner = sp.savepoint().unwrap();
+        inner
+            .execute("INSERT INTO vals (val) VALUES ('rolled back')", [])
+            .unwrap();
+        inner.rollback().unwrap();
+        inner
+            .execute("INSERT INTO vals (val) VALUES ('inner')", [])
+            .unwrap();
+        inner.commit().unwrap();
+    }
+    sp.commit().unwrap();
+
+    let vals = conn
+        .prepare("SELECT val FROM vals ORDER BY id")
+        .unwrap()
+        .query_map([], |row| row.get::<_, String>(0))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(vals, vec!["outer", "inner"]);
+    integrity_check(&conn);
+}
+
+#[test]
+fn vacuum() {
+    let _vfs = register_fs("workloads-vacuum");
+    let dir = TempDir::new("vacuum");
+    let path = dir.path("main.db");
+    let conn = open(&path, "workloads-vacuum");
+
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val BLOB NOT NULL);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+         INSERT INTO vals (val) SELECT randomblob(1000) FROM n;",
+    )
+    .unwrap();
+    let before = std::fs::metadata(&path).unwrap().len();
+
+    conn.execute("DELETE FROM vals WHERE id > 10", []).unwrap();
+    conn.execute_batch("VACUUM").unwrap();
+    let after = std::fs::metadata(&path).unwrap().len();
+
+    assert!(after < before);
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 10);
+    integrity_check(&conn);
+}
+
+#[test]
+fn attach() {
+    let _vfs = register_fs("workloads-attach");
+    let dir = TempDir::new("attach");
+    let conn = open(&dir.path("main.db"), "workloads-attach");
+
+    conn.execute(
+        "ATTACH DATABASE ?1 AS other",
+        params![dir.path("other.db").to_string_lossy()],
+    )
+    .unwrap();
+    conn.execute_batch(
+        "CREATE TABLE main.a (id INTEGER PRIMARY KEY, val TEXT);
+         CREATE TABLE other.b (id INTEGER PRIMARY KEY, a_id INTEGER, val TEXT);
+         BEGIN;
+         INSERT INTO main.a (val) VALUES ('x'), ('y');
+         INSERT INTO other.b (a_id, val) VALUES (1, 'x1'), (2, 'y1'), (2, 'y2');
+         COMMIT;",
+    )
+    .unwrap();
+
+    let count: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM main.a JOIN other.b ON b.a_id = a.id WHERE a.val = 'y'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(count, 2);
+    assert!(dir.path("other.db").is_file());
+    integrity_check(&conn);
+}
+
+#[test]
+fn incremental_blob_io() {
+    let _vfs = register_fs("workloads-blob");
+    let dir = TempDir::new("blob");
+    let conn = open(&dir.path("main.db"), "workloads-blob");
+
+    conn.execute_batch(
+        "CREATE TABLE blobs (id INTEGER PRIMARY KEY, data BLOB);
+         INSERT INTO blobs (data) VALUES (zeroblob(100000));",
+    )
+    .unwrap();
+
+    let chunk = (0..=255).collect::<Vec<u8>>();
+    {
+        let mut blob = conn
+            .blob_open(DatabaseName::Main, "blobs", "data", 1, false)
+            .unwrap();
+        for _ in 0..100 {
+            blob.write_all(&chunk).unwrap();
+        }
+    }
+
+    let mut blob = conn
+        .blob_open(DatabaseName::Main, "blobs", "data", 1, true)
+        .unwrap();
+    blob.seek(SeekFrom::Start(256 * 50)).unwrap();
+    let mut buf = vec![0; 256];
+    blob.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, chunk);
+    blob.seek(SeekFrom::Start(256 * 100)).unwrap();
+    blob.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, vec![0; 256]);
+    drop(blob);
+
+    integrity_check(&conn);
+}
+
+#[test]
+fn wal() {
+    let _vfs = register_fs("workloads-wal");
+    let dir = TempDir::new("wal");
+    let conn = open(&dir.path("main.db"), "workloads-wal");
+
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode=WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+         INSERT INTO vals (val) VALUES ('a'), ('b');",
+    )
+    .unwrap();
+
+    let reader = open(&dir.path("main.db"), "workloads-wal");
+    let count: i64 = reader
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2);
+
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    integrity_check(&conn);
+}
+
+#[test]
+fn multi_connection_contention() {
+    let _vfs = register_fs("workloads-contention");
+    let dir = TempDir::new("contention");
+    let a = open(&dir.path("main.db"), "workloads-contention");
+    let b = open(&dir.path("main.db"), "workloads-contention");
+    // fail right away instead of waiting for the lock
+    b.busy_timeout(Duration::ZERO).unwrap();
+
+    a.execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT)")
+        .unwrap();
+    a.execute_batch("BEGIN IMMEDIATE; INSERT INTO vals (val) VALUES ('a');")
+        .unwrap();
+
+    match b.execute("INSERT INTO vals (val) VALUES ('b')", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, ErrorCode::DatabaseBusy)
+        }
+        result => panic!("expected SQLITE_BUSY, got {:?}", result),
+    }
+
+    a.execute_batch("COMMIT").unwrap();
+    b.execute("INSERT INTO vals (val) VALUES ('b')", [])
+        .unwrap();
+
+    let count: i64 = a
+        .query_row("SELECT COUNT(*) FROM vals", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2);
+    integrity_check(&a);
+}
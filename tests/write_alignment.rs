@@ -0,0 +1,87 @@
+//! [RegisterOptions::validate_writes] rejects writes to main databases that are not whole pages.
+
+mod common;
+
+use std::fs;
+
+use common::{integrity_check, open, FsVfs, RawFile, TempDir};
+use rusqlite::ffi;
+use sqlite_vfs::{register_with_options, RegisterOptions, VfsHandle};
+
+fn register(name: &str) -> VfsHandle {
+    register_with_options(
+        name,
+        FsVfs,
+        RegisterOptions {
+            validate_writes: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn sqlite_writes_pages() {
+    let _vfs = register("write-alignment-sqlite");
+    let dir = TempDir::new("write-alignment-sqlite");
+    let path = dir.path("main.db");
+
+    let conn = open(&path, "write-alignment-sqlite");
+    conn.execute_batch(
+        "CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+        INSERT INTO vals (val) SELECT 'value ' || i FROM n;
+        PRAGMA page_size = 1024;
+        VACUUM;
+        UPDATE vals SET val = 'changed' WHERE id % 2 = 0;",
+    )
+    .unwrap();
+    // checkpoints copy pages from the WAL (kept in heap memory, as the VFS has no shared memory)
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE")
+        .unwrap();
+    conn.query_row("PRAGMA journal_mode = wal", [], |_| Ok(()))
+        .unwrap();
+    conn.execute_batch("DELETE FROM vals WHERE id > 500; PRAGMA wal_checkpoint(TRUNCATE);")
+        .unwrap();
+    integrity_check(&conn);
+    let page_size: i64 = conn
+        .query_row("PRAGMA page_size", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(page_size, 1024);
+}
+
+#[test]
+fn other_writes_fail() {
+    let _vfs = register("write-alignment-raw");
+    let dir = TempDir::new("write-alignment-raw");
+    let path = dir.path("main.db");
+    open(&path, "write-alignment-raw")
+        .execute_batch("CREATE TABLE vals (id INTEGER PRIMARY KEY, val TEXT);")
+        .unwrap();
+    let before = fs::read(&path).unwrap();
+
+    let flags = ffi::SQLITE_OPEN_MAIN_DB | ffi::SQLITE_OPEN_READWRITE;
+    let mut file = RawFile::open("write-alignment-raw", &path, flags).unwrap();
+    assert_eq!(file.write(&[1; 4096], 4096), ffi::SQLITE_OK);
+    for (len, offset) in [(100, 4096), (4096, 2048), (6144, 4096), (50, 0)] {
+        assert_eq!(
+            file.write(&vec![2; len], offset),
+            ffi::SQLITE_IOERR_WRITE,
+            "{} bytes at {}",
+            len,
+            offset
+        );
+        assert!(file.last_error(256).contains("pages are 4096 bytes long"));
+    }
+    drop(file);
+    let after = fs::read(&path).unwrap();
+    assert_eq!(after[..4096], before[..4096]);
+    assert_eq!(after[4096..], [1; 4096]);
+
+    // other files are not checked
+    let flags =
+        ffi::SQLITE_OPEN_MAIN_JOURNAL | ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+    let mut journal =
+        RawFile::open("write-alignment-raw", &dir.path("main.db-journal"), flags).unwrap();
+    assert_eq!(journal.write(b"journal", 3), ffi::SQLITE_OK);
+}